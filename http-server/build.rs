@@ -0,0 +1,86 @@
+//! Embeds the dashboard in `web/` into the binary, see `src/assets.rs`.
+//!
+//! Every file is compressed here with gzip and brotli at their highest levels, which would be
+//! too slow to do per request, and given an ETag derived from its contents.
+
+use std::{
+    env,
+    fmt::Write as _,
+    fs,
+    io::Write as _,
+    path::{Path, PathBuf},
+};
+
+fn main() {
+    let web = Path::new(env!("CARGO_MANIFEST_DIR")).join("web");
+    let out = PathBuf::from(env::var("OUT_DIR").unwrap()).join("web");
+    println!("cargo:rerun-if-changed={}", web.display());
+
+    let mut files = Vec::new();
+    collect(&web, &mut files);
+    files.sort();
+
+    let mut table = String::from("&[\n");
+    for file in files {
+        println!("cargo:rerun-if-changed={}", file.display());
+        let path = file
+            .strip_prefix(&web)
+            .unwrap()
+            .to_str()
+            .expect("asset paths must be UTF-8")
+            .replace('\\', "/");
+        let body = fs::read(&file).unwrap();
+
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+        gzip.write_all(&body).unwrap();
+        let gzip = gzip.finish().unwrap();
+
+        let mut brotli = Vec::new();
+        brotli::CompressorWriter::new(&mut brotli, 4096, 11, 22)
+            .write_all(&body)
+            .unwrap();
+
+        let target = out.join(&path);
+        fs::create_dir_all(target.parent().unwrap()).unwrap();
+        let write = |extension: &str, bytes: &[u8]| {
+            // Compression isn't worth it for files it doesn't shrink, e.g. images.
+            if bytes.len() >= body.len() {
+                return "None".to_string();
+            }
+            let mut name = target.clone().into_os_string();
+            name.push(extension);
+            fs::write(&name, bytes).unwrap();
+            format!("Some(include_bytes!({:?}))", name)
+        };
+        writeln!(
+            table,
+            "    Asset {{ path: {:?}, etag: \"\\\"{:016x}\\\"\", body: include_bytes!({:?}), gzip: {}, brotli: {} }},",
+            path,
+            fnv1a(&body),
+            file,
+            write(".gz", &gzip),
+            write(".br", &brotli),
+        )
+        .unwrap();
+    }
+    table.push(']');
+    fs::write(out.with_file_name("assets.rs"), table).unwrap();
+}
+
+fn collect(dir: &Path, files: &mut Vec<PathBuf>) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            collect(&path, files);
+        } else {
+            files.push(path);
+        }
+    }
+}
+
+/// 64-bit FNV-1a, which is stable across builds unlike `DefaultHasher`.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
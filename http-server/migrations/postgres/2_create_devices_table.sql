@@ -0,0 +1,11 @@
+CREATE TABLE devices (
+    device_id bytea NOT NULL,
+    first_seen timestamp NOT NULL,
+    last_seen timestamp NOT NULL,
+    PRIMARY KEY (device_id)
+);
+
+INSERT INTO devices (device_id, first_seen, last_seen)
+SELECT device_id, min(device_timestamp), max(device_timestamp)
+FROM climate_metrics
+GROUP BY device_id;
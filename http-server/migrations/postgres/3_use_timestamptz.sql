@@ -0,0 +1,10 @@
+-- The columns were `timestamp` without time zone, so readings were converted to and from the
+-- session time zone on the way in and out. The server always connected with its session in
+-- UTC, so the stored values are UTC.
+ALTER TABLE climate_metrics
+    ALTER COLUMN device_timestamp TYPE timestamptz USING device_timestamp AT TIME ZONE 'UTC',
+    ALTER COLUMN created_at TYPE timestamptz USING created_at AT TIME ZONE 'UTC';
+
+ALTER TABLE devices
+    ALTER COLUMN first_seen TYPE timestamptz USING first_seen AT TIME ZONE 'UTC',
+    ALTER COLUMN last_seen TYPE timestamptz USING last_seen AT TIME ZONE 'UTC';
@@ -0,0 +1,5 @@
+ALTER TABLE climate_metrics
+    ADD COLUMN received_at timestamptz,
+    ADD COLUMN clock_skew_ms bigint;
+
+ALTER TABLE devices ADD COLUMN clock_skew_ms bigint;
@@ -0,0 +1 @@
+ALTER TABLE firmware_rollouts ADD COLUMN downgrade text;
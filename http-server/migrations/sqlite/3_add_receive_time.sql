@@ -0,0 +1,4 @@
+ALTER TABLE climate_metrics ADD COLUMN received_at timestamp;
+ALTER TABLE climate_metrics ADD COLUMN clock_skew_ms bigint;
+
+ALTER TABLE devices ADD COLUMN clock_skew_ms bigint;
@@ -0,0 +1,13 @@
+CREATE TABLE device_health (
+    device_id bytea NOT NULL,
+    device_timestamp timestamp NOT NULL,
+    received_at timestamp NOT NULL,
+    uptime_secs bigint NOT NULL,
+    free_heap_bytes bigint NOT NULL,
+    min_free_heap_bytes bigint NOT NULL,
+    wifi_rssi_dbm smallint,
+    reset_reason text NOT NULL,
+    firmware_version text NOT NULL,
+    failed_sends bigint NOT NULL,
+    PRIMARY KEY (device_id)
+);
@@ -0,0 +1,26 @@
+CREATE TABLE firmware_releases (
+    version text NOT NULL,
+    size bigint NOT NULL,
+    sha256 text NOT NULL,
+    uploaded_at timestamp NOT NULL,
+    image bytea NOT NULL,
+    PRIMARY KEY (version)
+);
+
+CREATE TABLE firmware_rollouts (
+    device_group text NOT NULL,
+    version text NOT NULL REFERENCES firmware_releases (version),
+    percent smallint NOT NULL,
+    updated_at timestamp NOT NULL,
+    PRIMARY KEY (device_group)
+);
+
+CREATE TABLE firmware_devices (
+    device_id bytea NOT NULL,
+    device_group text NOT NULL DEFAULT 'default',
+    report_version text,
+    report_outcome text,
+    report_message text,
+    reported_at timestamp,
+    PRIMARY KEY (device_id)
+);
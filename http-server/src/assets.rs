@@ -0,0 +1,216 @@
+//! The dashboard, embedded into the binary by `build.rs` or served from disk in dev mode.
+
+use std::path::Path;
+
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode, Uri},
+    response::{IntoResponse, Response},
+};
+use log::error;
+
+use crate::AppState;
+
+/// A file from `web/`, with its pre-compressed variants if they are smaller.
+pub struct Asset {
+    /// Path relative to `web/`, with `/` separators.
+    pub path: &'static str,
+    /// Quoted, as sent in the `ETag` header.
+    pub etag: &'static str,
+    pub body: &'static [u8],
+    pub gzip: Option<&'static [u8]>,
+    pub brotli: Option<&'static [u8]>,
+}
+
+static ASSETS: &[Asset] = include!(concat!(env!("OUT_DIR"), "/assets.rs"));
+
+/// Returns the embedded asset at `path`, relative to `web/`.
+pub fn get(path: &str) -> Option<&'static Asset> {
+    ASSETS.iter().find(|asset| asset.path == path)
+}
+
+/// The `Content-Type` of a file, by extension.
+pub fn content_type(path: &str) -> &'static str {
+    match path.rsplit_once('.').map(|(_, extension)| extension) {
+        Some("html") => "text/html; charset=utf-8",
+        Some("js") => "text/javascript; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("json") => "application/json",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("ico") => "image/x-icon",
+        Some("txt") => "text/plain; charset=utf-8",
+        _ => "application/octet-stream",
+    }
+}
+
+/// How long clients may reuse a file without asking again. Vendored libraries carry their
+/// version in the file name, everything else is revalidated with its ETag.
+fn cache_control(path: &str) -> &'static str {
+    if path.starts_with("vendor/") {
+        "public, max-age=31536000, immutable"
+    } else {
+        "no-cache"
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+    Identity,
+}
+
+/// Picks the best encoding the client accepts, preferring brotli. Quality values other than
+/// zero are treated alike.
+pub fn negotiate(accept_encoding: &str) -> Encoding {
+    let accepts = |name: &str| {
+        accept_encoding.split(',').any(|coding| {
+            let mut params = coding.split(';').map(str::trim);
+            params.next() == Some(name)
+                && !params.any(|param| {
+                    param.strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()) == Some(0.0)
+                })
+        })
+    };
+    if accepts("br") {
+        Encoding::Brotli
+    } else if accepts("gzip") {
+        Encoding::Gzip
+    } else {
+        Encoding::Identity
+    }
+}
+
+/// Whether an `If-None-Match` header matches `etag`.
+fn not_modified(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|tag| tag.trim().trim_start_matches("W/"))
+        .any(|tag| tag == etag || tag == "*")
+}
+
+/// Serves the dashboard file named by the request path, `index.html` for `/`.
+pub async fn serve(
+    State(state): State<AppState>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+) -> Response {
+    if method != Method::GET && method != Method::HEAD {
+        return StatusCode::METHOD_NOT_ALLOWED.into_response();
+    }
+    let path = match uri.path().trim_start_matches('/') {
+        "" => "index.html",
+        path => path,
+    };
+    match &state.static_dir {
+        Some(dir) => serve_from_disk(dir, path).await,
+        None => serve_embedded(path, &headers),
+    }
+}
+
+fn serve_embedded(path: &str, headers: &HeaderMap) -> Response {
+    let Some(asset) = get(path) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let mut response = if not_modified(headers, asset.etag) {
+        StatusCode::NOT_MODIFIED.into_response()
+    } else {
+        let accept_encoding = headers
+            .get(header::ACCEPT_ENCODING)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        let (body, encoding) = match (negotiate(accept_encoding), asset.brotli, asset.gzip) {
+            (Encoding::Brotli, Some(brotli), _) => (brotli, Some("br")),
+            (Encoding::Brotli | Encoding::Gzip, _, Some(gzip)) => (gzip, Some("gzip")),
+            _ => (asset.body, None),
+        };
+        let mut response = body.into_response();
+        let headers = response.headers_mut();
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static(content_type(path)),
+        );
+        if let Some(encoding) = encoding {
+            headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static(encoding));
+        }
+        response
+    };
+    let headers = response.headers_mut();
+    headers.insert(header::ETAG, HeaderValue::from_static(asset.etag));
+    headers.insert(
+        header::CACHE_CONTROL,
+        HeaderValue::from_static(cache_control(path)),
+    );
+    headers.insert(header::VARY, HeaderValue::from_static("accept-encoding"));
+    response
+}
+
+/// Reads the file on every request so that edits show up on reload.
+async fn serve_from_disk(dir: &Path, path: &str) -> Response {
+    // Only plain relative paths, so that requests can't escape the directory.
+    if path
+        .split('/')
+        .any(|segment| segment.is_empty() || segment == "." || segment == "..")
+    {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    let file = dir.join(path);
+    match tokio::fs::read(&file).await {
+        Ok(body) => (
+            [
+                (header::CONTENT_TYPE, content_type(path)),
+                (header::CACHE_CONTROL, "no-store"),
+            ],
+            body,
+        )
+            .into_response(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            error!("Failed to read {}: {}", file.display(), e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn embeds_the_dashboard() {
+        let index = get("index.html").unwrap();
+        assert!(std::str::from_utf8(index.body)
+            .unwrap()
+            .contains("ESP32 Metric Frontend"));
+        assert!(index.gzip.is_some() && index.brotli.is_some());
+        assert!(get("app.js").is_some());
+        assert!(get("style.css").is_some());
+        assert!(get("vendor/d3.v7.min.js").is_some());
+        assert!(get("missing.js").is_none());
+    }
+
+    #[test]
+    fn negotiates_encoding() {
+        assert_eq!(negotiate("gzip, deflate, br"), Encoding::Brotli);
+        assert_eq!(negotiate("gzip, br;q=0"), Encoding::Gzip);
+        assert_eq!(negotiate("br;q=0, gzip;q=0"), Encoding::Identity);
+        assert_eq!(negotiate("gzip;q=0.5"), Encoding::Gzip);
+        assert_eq!(negotiate(""), Encoding::Identity);
+    }
+
+    #[test]
+    fn maps_content_types() {
+        assert_eq!(content_type("index.html"), "text/html; charset=utf-8");
+        assert_eq!(
+            content_type("vendor/d3.v7.min.js"),
+            "text/javascript; charset=utf-8"
+        );
+        assert_eq!(content_type("style.css"), "text/css; charset=utf-8");
+        assert_eq!(content_type("LICENSE"), "application/octet-stream");
+    }
+}
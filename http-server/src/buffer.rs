@@ -0,0 +1,425 @@
+//! Optional write buffering: accepted readings are queued and stored in batches by a
+//! background writer instead of one INSERT per request.
+
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use log::{error, info, warn};
+use serde::Serialize;
+use tokio::{
+    sync::{
+        mpsc::{self, error::SendTimeoutError},
+        Notify,
+    },
+    task::JoinHandle,
+    time::Instant,
+};
+use types::{MetricRequestBody, Topic};
+
+use crate::{
+    config::IngestConfig,
+    ingest::Accepted,
+    storage::{ClimateReading, ReadingTime, Storage},
+};
+
+/// Attempts to store a batch before its readings are dropped.
+const MAX_ATTEMPTS: u32 = 3;
+
+/// A validated reading waiting to be stored.
+struct Pending {
+    time: ReadingTime,
+    payload: MetricRequestBody,
+}
+
+/// Why a reading couldn't be queued.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BufferError {
+    /// The queue stayed full for the whole enqueue timeout.
+    Full,
+    /// The writer stopped because the server is shutting down.
+    Closed,
+}
+
+impl fmt::Display for BufferError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BufferError::Full => write!(f, "ingestion queue is full"),
+            BufferError::Closed => write!(f, "server is shutting down"),
+        }
+    }
+}
+
+impl std::error::Error for BufferError {}
+
+#[derive(Default)]
+struct Counters {
+    written: AtomicU64,
+    duplicates: AtomicU64,
+    dropped: AtomicU64,
+}
+
+/// Counters of the write buffer since the server started.
+#[derive(Debug, Serialize)]
+pub struct BufferStats {
+    /// Readings waiting to be written.
+    pub queued: usize,
+    pub written: u64,
+    /// Readings skipped because the same reading was already stored.
+    pub duplicates: u64,
+    /// Readings dropped after the storage backend failed repeatedly.
+    pub dropped: u64,
+}
+
+/// The sending side of the buffer, cheap to clone.
+#[derive(Clone)]
+pub struct WriteBuffer {
+    sender: mpsc::Sender<Pending>,
+    enqueue_timeout: Duration,
+    counters: Arc<Counters>,
+    shutdown: Arc<Notify>,
+}
+
+/// The background writer, to wait for once the buffer is closed.
+pub struct Writer(JoinHandle<()>);
+
+impl WriteBuffer {
+    /// Starts the background writer. Stored readings are sent to `accepted`.
+    pub fn spawn(
+        config: &IngestConfig,
+        storage: Arc<dyn Storage>,
+        accepted: Accepted,
+    ) -> (Self, Writer) {
+        let (sender, receiver) = mpsc::channel(config.queue_capacity);
+        let buffer = WriteBuffer {
+            sender,
+            enqueue_timeout: Duration::from_millis(config.enqueue_timeout_ms),
+            counters: Arc::default(),
+            shutdown: Arc::default(),
+        };
+        let task = BatchWriter {
+            receiver,
+            storage,
+            accepted,
+            batch_size: config.batch_size,
+            flush_interval: Duration::from_millis(config.flush_interval_ms),
+            counters: buffer.counters.clone(),
+            shutdown: buffer.shutdown.clone(),
+        };
+        (buffer, Writer(tokio::spawn(task.run())))
+    }
+
+    /// Queues a validated reading, waiting while the queue is full for at most the enqueue
+    /// timeout so that clients are slowed down rather than dropped right away.
+    pub async fn push(
+        &self,
+        time: ReadingTime,
+        payload: MetricRequestBody,
+    ) -> Result<(), BufferError> {
+        let pending = Pending { time, payload };
+        self.sender
+            .send_timeout(pending, self.enqueue_timeout)
+            .await
+            .map_err(|e| match e {
+                SendTimeoutError::Timeout(_) => BufferError::Full,
+                SendTimeoutError::Closed(_) => BufferError::Closed,
+            })
+    }
+
+    /// Stops accepting readings. The writer stores what is queued and then exits.
+    pub fn close(&self) {
+        self.shutdown.notify_one();
+    }
+
+    pub fn stats(&self) -> BufferStats {
+        BufferStats {
+            queued: self.sender.max_capacity() - self.sender.capacity(),
+            written: self.counters.written.load(Ordering::Relaxed),
+            duplicates: self.counters.duplicates.load(Ordering::Relaxed),
+            dropped: self.counters.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Writer {
+    /// Waits until everything queued before [`WriteBuffer::close`] is stored.
+    pub async fn finish(self) {
+        if let Err(e) = self.0.await {
+            error!("Ingestion writer failed: {}", e);
+        }
+    }
+}
+
+struct BatchWriter {
+    receiver: mpsc::Receiver<Pending>,
+    storage: Arc<dyn Storage>,
+    accepted: Accepted,
+    batch_size: usize,
+    flush_interval: Duration,
+    counters: Arc<Counters>,
+    shutdown: Arc<Notify>,
+}
+
+impl BatchWriter {
+    /// Writes a batch once it is full or the oldest reading in it waited for the flush
+    /// interval, until shut down.
+    async fn run(mut self) {
+        let mut batch = Vec::with_capacity(self.batch_size);
+        let mut closing = false;
+        while !closing {
+            tokio::select! {
+                pending = self.receiver.recv() => match pending {
+                    Some(pending) => batch.push(pending),
+                    None => break,
+                },
+                _ = self.shutdown.notified() => break,
+            }
+            let deadline = Instant::now() + self.flush_interval;
+            while batch.len() < self.batch_size {
+                tokio::select! {
+                    pending = self.receiver.recv() => match pending {
+                        Some(pending) => batch.push(pending),
+                        None => break,
+                    },
+                    _ = tokio::time::sleep_until(deadline) => break,
+                    _ = self.shutdown.notified() => {
+                        closing = true;
+                        break;
+                    }
+                }
+            }
+            self.flush(&mut batch).await;
+        }
+
+        // Refuse new readings, then store the ones already queued.
+        self.receiver.close();
+        while let Some(pending) = self.receiver.recv().await {
+            batch.push(pending);
+            if batch.len() >= self.batch_size {
+                self.flush(&mut batch).await;
+            }
+        }
+        self.flush(&mut batch).await;
+        info!("Ingestion writer stopped");
+    }
+
+    async fn flush(&self, batch: &mut Vec<Pending>) {
+        if batch.is_empty() {
+            return;
+        }
+        let readings: Vec<_> = batch
+            .iter()
+            .filter_map(|pending| match &pending.payload.topic {
+                Topic::Climate(climate) => Some(ClimateReading {
+                    device_id: &pending.payload.device_id,
+                    time: pending.time,
+                    climate,
+                }),
+                // Only climate readings are queued, see `ingest::ingest`.
+                Topic::DeviceHealth(_) => None,
+            })
+            .collect();
+        let count = readings.len() as u64;
+        let mut attempt = 1;
+        let result = loop {
+            match self.storage.insert_climate_batch(&readings).await {
+                Err(e) if attempt < MAX_ATTEMPTS => {
+                    warn!("Failed to write {} readings, retrying: {}", count, e);
+                    tokio::time::sleep(Duration::from_secs(1 << attempt)).await;
+                    attempt += 1;
+                }
+                result => break result,
+            }
+        };
+        drop(readings);
+        match result {
+            Ok(inserted) => {
+                let written = inserted.len() as u64;
+                self.counters.written.fetch_add(written, Ordering::Relaxed);
+                self.counters
+                    .duplicates
+                    .fetch_add(count - written, Ordering::Relaxed);
+                // Duplicates were sent when they were stored the first time.
+                let mut pending: Vec<_> = batch.drain(..).map(Some).collect();
+                for position in inserted {
+                    if let Some(pending) = pending[position].take() {
+                        self.accepted.publish(pending.payload);
+                    }
+                }
+            }
+            Err(e) => {
+                error!("Dropped {} readings: {}", count, e);
+                self.counters.dropped.fetch_add(count, Ordering::Relaxed);
+                batch.clear();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::DatabaseConfig, storage};
+    use sqlx::types::time::OffsetDateTime;
+    use types::Climate;
+
+    const T0: i64 = 1_684_000_000;
+
+    fn reading(device_id: u8, timestamp: i64) -> (ReadingTime, MetricRequestBody) {
+        let device_timestamp = OffsetDateTime::from_unix_timestamp(timestamp).unwrap();
+        (
+            ReadingTime {
+                device_timestamp,
+                received_at: device_timestamp,
+                clock_skew_ms: 0,
+            },
+            MetricRequestBody {
+                topic: Topic::Climate(Climate {
+                    temperature_celsius: 21.0,
+                    humidity: 40.0,
+                    co2_ppm: 600,
+                }),
+                timestamp,
+                timestamp_millis: 0,
+                device_id: vec![device_id],
+            },
+        )
+    }
+
+    fn config(queue_capacity: usize, batch_size: usize) -> IngestConfig {
+        IngestConfig {
+            buffered: true,
+            queue_capacity,
+            batch_size,
+            flush_interval_ms: 10,
+            enqueue_timeout_ms: 10,
+        }
+    }
+
+    #[tokio::test]
+    async fn writes_batches_and_flushes_on_close() {
+        let storage: Arc<dyn Storage> = Arc::new(storage::MemoryStorage::default());
+        let accepted = Accepted::new(16);
+        let mut receiver = accepted.subscribe();
+        let (buffer, writer) = WriteBuffer::spawn(&config(16, 4), storage.clone(), accepted);
+        for timestamp in [T0, T0 + 1, T0 + 1] {
+            let (time, payload) = reading(1, timestamp);
+            buffer.push(time, payload).await.unwrap();
+        }
+        buffer.close();
+        writer.finish().await;
+
+        let start = OffsetDateTime::from_unix_timestamp(T0).unwrap();
+        let stored = storage.climate_range(start, start + Duration::from_secs(1));
+        assert_eq!(stored.await.unwrap().len(), 2);
+        let stats = buffer.stats();
+        assert_eq!((stats.written, stats.duplicates, stats.dropped), (2, 1, 0));
+        // Duplicates aren't sent again.
+        assert_eq!(receiver.recv().await.unwrap().timestamp, T0);
+        assert_eq!(receiver.recv().await.unwrap().timestamp, T0 + 1);
+        assert!(receiver.try_recv().is_err());
+
+        let (time, payload) = reading(1, T0 + 2);
+        assert_eq!(buffer.push(time, payload).await, Err(BufferError::Closed));
+    }
+
+    #[tokio::test]
+    async fn pushes_back_when_full() {
+        // A writer that never runs, so the queue fills up.
+        let (sender, _receiver) = mpsc::channel(1);
+        let buffer = WriteBuffer {
+            sender,
+            enqueue_timeout: Duration::from_millis(10),
+            counters: Arc::default(),
+            shutdown: Arc::default(),
+        };
+        let (time, payload) = reading(1, T0);
+        buffer.push(time, payload).await.unwrap();
+        let (time, payload) = reading(1, T0 + 1);
+        assert_eq!(buffer.push(time, payload).await, Err(BufferError::Full));
+        assert_eq!(buffer.stats().queued, 1);
+    }
+
+    /// Compares the throughput of direct and buffered ingestion into a SQLite file, and into
+    /// Postgres when `TEST_DATABASE_URL` is set. Run with
+    /// `cargo test --release -p http-server benchmark -- --ignored --nocapture`.
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn benchmark() {
+        const CLIENTS: usize = 50;
+        const READINGS: usize = 200;
+
+        let file = std::env::temp_dir().join(format!("benchmark-{}.db", std::process::id()));
+        let mut urls = vec![format!("sqlite://{}", file.display())];
+        urls.extend(std::env::var("TEST_DATABASE_URL"));
+        for url in urls {
+            let storage = storage::connect(&DatabaseConfig {
+                url: url.clone(),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+            let base = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs() as i64;
+
+            let start = Instant::now();
+            let mut clients = Vec::new();
+            for client in 0..CLIENTS {
+                let storage = storage.clone();
+                clients.push(tokio::spawn(async move {
+                    for i in 0..READINGS {
+                        let (time, payload) = reading(client as u8, base + i as i64);
+                        let Topic::Climate(climate) = &payload.topic else {
+                            unreachable!()
+                        };
+                        storage
+                            .insert_climate(&payload.device_id, time, climate)
+                            .await
+                            .unwrap();
+                    }
+                }));
+            }
+            for client in clients {
+                client.await.unwrap();
+            }
+            let direct = start.elapsed();
+
+            let (buffer, writer) =
+                WriteBuffer::spawn(&IngestConfig::default(), storage.clone(), Accepted::new(16));
+            let start = Instant::now();
+            let mut clients = Vec::new();
+            for client in 0..CLIENTS {
+                let buffer = buffer.clone();
+                clients.push(tokio::spawn(async move {
+                    for i in 0..READINGS {
+                        let (time, payload) = reading(client as u8, base + (READINGS + i) as i64);
+                        buffer.push(time, payload).await.unwrap();
+                    }
+                }));
+            }
+            for client in clients {
+                client.await.unwrap();
+            }
+            buffer.close();
+            writer.finish().await;
+            let buffered = start.elapsed();
+            assert_eq!(buffer.stats().written, (CLIENTS * READINGS) as u64);
+
+            let rate = |elapsed: Duration| (CLIENTS * READINGS) as f64 / elapsed.as_secs_f64();
+            println!(
+                "{}: direct {:.0} readings/s, buffered {:.0} readings/s",
+                url.split(':').next().unwrap(),
+                rate(direct),
+                rate(buffered)
+            );
+        }
+        let _ = std::fs::remove_file(file);
+    }
+}
@@ -0,0 +1,129 @@
+//! Clock skew between the devices and the server.
+//!
+//! Devices stamp their readings with their own clock, which is only right while SNTP is. Every
+//! reading is therefore compared with the time the server received it.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use log::debug;
+use sqlx::types::time::OffsetDateTime;
+
+use crate::{
+    config::ClockConfig,
+    storage::{self, ReadingTime},
+};
+
+/// How much skew is tolerated and what happens beyond it, shared by every request.
+pub struct ClockPolicy {
+    max_skew_ms: i64,
+    /// Store skewed readings at their receive time instead of the device time.
+    correct: bool,
+    skewed: AtomicU64,
+}
+
+impl ClockPolicy {
+    pub fn new(config: &ClockConfig) -> Self {
+        ClockPolicy {
+            max_skew_ms: i64::try_from(config.max_skew_secs.saturating_mul(1000))
+                .unwrap_or(i64::MAX),
+            correct: config.correct_skewed,
+            skewed: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether a device clock this far off the server's is beyond the tolerated skew.
+    pub fn is_skewed(&self, clock_skew_ms: i64) -> bool {
+        clock_skew_ms.saturating_abs() > self.max_skew_ms
+    }
+
+    /// Measures the skew of a reading the device stamped with `device_timestamp` and the server
+    /// received at `received_at`, and moves it to `received_at` if it is skewed and corrections
+    /// are enabled.
+    pub fn time(
+        &self,
+        device_id: &[u8],
+        device_timestamp: OffsetDateTime,
+        received_at: OffsetDateTime,
+    ) -> ReadingTime {
+        // Readings carry milliseconds at most, so the receive time doesn't need more either.
+        let received_at = received_at
+            .replace_millisecond(received_at.millisecond())
+            .expect("a millisecond is always valid");
+        let clock_skew_ms =
+            storage::unix_millis(device_timestamp) - storage::unix_millis(received_at);
+        let mut time = ReadingTime {
+            device_timestamp,
+            received_at,
+            clock_skew_ms,
+        };
+        if self.is_skewed(clock_skew_ms) {
+            self.skewed.fetch_add(1, Ordering::Relaxed);
+            debug!(
+                "Clock of device {:02x?} is off by {} ms",
+                device_id, clock_skew_ms
+            );
+            if self.correct {
+                time.device_timestamp = received_at;
+            }
+        }
+        time
+    }
+
+    /// How many readings arrived with a skewed timestamp.
+    pub fn skewed(&self) -> u64 {
+        self.skewed.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for ClockPolicy {
+    fn default() -> Self {
+        ClockPolicy::new(&ClockConfig::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn measures_and_corrects_skew() {
+        let received_at = OffsetDateTime::from_unix_timestamp(1_684_000_000).unwrap();
+        let at = |offset_ms: i64| {
+            OffsetDateTime::from_unix_timestamp_nanos(
+                (received_at.unix_timestamp() * 1000 + offset_ms) as i128 * 1_000_000,
+            )
+            .unwrap()
+        };
+        let config = ClockConfig {
+            max_skew_secs: 60,
+            correct_skewed: false,
+        };
+        let policy = ClockPolicy::new(&config);
+
+        let time = policy.time(&[1], at(-1500), received_at);
+        assert_eq!(time.device_timestamp, at(-1500));
+        assert_eq!(time.clock_skew_ms, -1500);
+        assert_eq!(policy.skewed(), 0);
+
+        // Skewed readings are only flagged unless correcting is enabled.
+        let time = policy.time(&[1], at(3_600_000), received_at);
+        assert_eq!(time.device_timestamp, at(3_600_000));
+        assert_eq!(time.clock_skew_ms, 3_600_000);
+        assert_eq!(policy.skewed(), 1);
+
+        let policy = ClockPolicy::new(&ClockConfig {
+            correct_skewed: true,
+            ..config
+        });
+        let time = policy.time(
+            &[1],
+            at(-3_600_000),
+            received_at + std::time::Duration::from_micros(1500),
+        );
+        assert_eq!(time.received_at, at(1));
+        assert_eq!(time.device_timestamp, time.received_at);
+        assert_eq!(time.clock_skew_ms, -3_600_001);
+        assert!(policy.is_skewed(time.clock_skew_ms));
+        assert!(!policy.is_skewed(60_000));
+    }
+}
@@ -0,0 +1,674 @@
+//! Firmware releases for pull-based updates.
+//!
+//! Releases are uploaded with `PUT /firmware/releases/<version>` and rolled out to a group of
+//! devices with `PUT /firmware/rollouts/<group>`, to a percentage of the group at first if
+//! wanted. Devices poll `GET /firmware/latest` (see [`types::firmware`]), which offers them the
+//! release of their group unless they run it already, aren't part of the rollout yet, or failed
+//! to install it before.
+
+use std::{cmp::Ordering, ops::Range};
+
+use axum::{
+    body::Bytes,
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, HeaderValue, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use log::{info, warn};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use sqlx::types::time::OffsetDateTime;
+use types::{
+    firmware::{compare_versions, FirmwareUpdate, UpdateOutcome, UpdateReport},
+    mqtt::decode_device_id,
+    HttpResponseBody,
+};
+
+use crate::{
+    internal_error,
+    storage::{
+        unix_millis, FirmwareDevice, FirmwareRelease, FirmwareReport, Rollout, StorageError,
+    },
+    AppState,
+};
+
+/// Group of the devices that weren't put in one.
+pub const DEFAULT_GROUP: &str = "default";
+
+/// Longest version or group name.
+const MAX_NAME_LEN: usize = 64;
+
+/// How long a device waits before it is offered a release again that it failed to install.
+pub const RETRY_FAILED_AFTER_MS: i64 = 6 * 3600 * 1000;
+
+type Error = (StatusCode, Json<HttpResponseBody>);
+
+fn error(status: StatusCode, message: String) -> Error {
+    let response = HttpResponseBody {
+        message: message.into_bytes(),
+    };
+    (status, Json(response))
+}
+
+/// Middleware letting only requests with `Authorization: Bearer <firmware.admin_token>` through
+/// to the routes it wraps, and none while no token is configured.
+pub async fn require_admin<B>(
+    State(state): State<AppState>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let Some(token) = &state.admin_token else {
+        let message = "firmware management is disabled, set firmware.admin_token".into();
+        return error(StatusCode::FORBIDDEN, message).into_response();
+    };
+    let given = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    // Comparing hashes takes as long however much of the token is right.
+    if given.map(Sha256::digest) != Some(Sha256::digest(token.as_bytes())) {
+        let response = HttpResponseBody {
+            message: b"missing or wrong admin token".to_vec(),
+        };
+        return (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Bearer")],
+            Json(response),
+        )
+            .into_response();
+    }
+    next.run(request).await
+}
+
+/// Whether `name` works as a version or group name: ASCII letters, digits, `.`, `-`, `_` and
+/// `+`, which can all be used in paths as they are.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'.' | b'-' | b'_' | b'+'))
+}
+
+/// Whether a device is among the first `percent` percent of devices that get `version`.
+///
+/// Devices are ordered by a hash of the version and their id, so raising the percentage only
+/// adds devices, and every release starts with different ones.
+pub fn in_rollout(device_id: &[u8], version: &str, percent: u8) -> bool {
+    if percent >= 100 {
+        return true;
+    }
+    let digest = Sha256::new()
+        .chain_update(version)
+        .chain_update([0])
+        .chain_update(device_id)
+        .finalize();
+    let position = u64::from_be_bytes(digest[..8].try_into().unwrap()) % 100;
+    position < u64::from(percent)
+}
+
+/// The rollout a device running `current` should update to at `now_ms`, if any.
+///
+/// An older release is only offered with a downgrade signature, which devices need to install
+/// it. A release the device reported failing is offered again [`RETRY_FAILED_AFTER_MS`] later,
+/// as the download may have just been cut off. One it reported installing but doesn't run isn't
+/// offered again, since that means the bootloader rolled it back.
+pub fn offered_rollout<'a>(
+    device_id: &[u8],
+    current: &str,
+    device: Option<&FirmwareDevice>,
+    rollouts: &'a [Rollout],
+    now_ms: i64,
+) -> Option<&'a Rollout> {
+    let group = device.map_or(DEFAULT_GROUP, |device| &device.group);
+    let rollout = rollouts.iter().find(|rollout| rollout.group == group)?;
+    if rollout.version == current || !in_rollout(device_id, &rollout.version, rollout.percent) {
+        return None;
+    }
+    let newer = matches!(
+        compare_versions(&rollout.version, current),
+        Some(Ordering::Greater | Ordering::Equal)
+    );
+    if !newer && rollout.downgrade.is_none() {
+        return None;
+    }
+    let blocked = device
+        .and_then(|device| device.last_report.as_ref())
+        .is_some_and(|report| {
+            report.version == rollout.version
+                && (report.outcome == UpdateOutcome::Installed
+                    || now_ms - report.received_at_ms < RETRY_FAILED_AFTER_MS)
+        });
+    if blocked {
+        return None;
+    }
+    Some(rollout)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Path the image of a release is downloaded from.
+fn image_path(version: &str) -> String {
+    format!("/firmware/releases/{}/image", version)
+}
+
+/// `PUT /firmware/releases/<version>` with the image as the body.
+pub async fn upload_release(
+    State(state): State<AppState>,
+    Path(version): Path<String>,
+    image: Bytes,
+) -> Result<(StatusCode, Json<FirmwareRelease>), Error> {
+    if !is_valid_name(&version) {
+        return Err(error(
+            StatusCode::BAD_REQUEST,
+            format!("invalid version: {}", version),
+        ));
+    }
+    if image.is_empty() {
+        return Err(error(StatusCode::BAD_REQUEST, "empty image".into()));
+    }
+    let release = FirmwareRelease {
+        version,
+        size: image.len() as i64,
+        sha256: hex(&Sha256::digest(&image)),
+        uploaded_at_ms: unix_millis(OffsetDateTime::now_utc()),
+    };
+    match state.storage.insert_firmware(&release, &image).await {
+        Ok(()) => {
+            info!(
+                "Stored firmware {} of {} bytes, SHA-256 {}",
+                release.version, release.size, release.sha256
+            );
+            Ok((StatusCode::CREATED, Json(release)))
+        }
+        Err(StorageError::Duplicate) => Err(error(
+            StatusCode::CONFLICT,
+            format!("firmware {} is stored already", release.version),
+        )),
+        Err(e) => Err(internal_error(e)),
+    }
+}
+
+pub async fn list_releases(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<FirmwareRelease>>, Error> {
+    let releases = state
+        .storage
+        .firmware_releases()
+        .await
+        .map_err(internal_error)?;
+    Ok(Json(releases))
+}
+
+/// The part of an image of `len` bytes a `Range` header asks for.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ByteRange {
+    /// All of it, also when the header asks for several ranges or can't be parsed, which
+    /// servers may ignore.
+    Full,
+    Partial(Range<usize>),
+    /// A range that starts past the end of the image.
+    Unsatisfiable,
+}
+
+/// Reads a `Range: bytes=<start>-[<end>]` or `bytes=-<suffix length>` header.
+pub fn byte_range(header: Option<&str>, len: usize) -> ByteRange {
+    let Some(spec) = header.and_then(|header| header.trim().strip_prefix("bytes=")) else {
+        return ByteRange::Full;
+    };
+    let Some((start, end)) = spec.split_once('-').filter(|_| !spec.contains(',')) else {
+        return ByteRange::Full;
+    };
+    let (start, end) = (start.trim(), end.trim());
+    if start.is_empty() {
+        return match end.parse::<usize>() {
+            Ok(0) => ByteRange::Unsatisfiable,
+            Ok(suffix) if len > 0 => ByteRange::Partial(len.saturating_sub(suffix)..len),
+            Ok(_) => ByteRange::Unsatisfiable,
+            Err(_) => ByteRange::Full,
+        };
+    }
+    let Ok(start) = start.parse::<usize>() else {
+        return ByteRange::Full;
+    };
+    let end = match end {
+        "" => len,
+        end => match end.parse::<usize>() {
+            Ok(end) if end >= start => end.saturating_add(1).min(len),
+            _ => return ByteRange::Full,
+        },
+    };
+    if start >= len {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Partial(start..end)
+}
+
+/// `GET /firmware/releases/<version>/image`, or the part of it asked for with `Range`, so that
+/// devices can resume interrupted downloads.
+pub async fn download_release(
+    State(state): State<AppState>,
+    Path(version): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, Error> {
+    let not_found = || error(StatusCode::NOT_FOUND, format!("no firmware {}", version));
+    let releases = state
+        .storage
+        .firmware_releases()
+        .await
+        .map_err(internal_error)?;
+    let Some(release) = releases
+        .into_iter()
+        .find(|release| release.version == version)
+    else {
+        return Err(not_found());
+    };
+    let len = release.size as usize;
+    let octet_stream = (
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/octet-stream"),
+    );
+    let accept_ranges = (header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    let range = headers
+        .get(header::RANGE)
+        .and_then(|range| range.to_str().ok());
+    let image = |range| async {
+        state
+            .storage
+            .firmware_image(&version, range)
+            .await
+            .map_err(internal_error)?
+            .ok_or_else(not_found)
+    };
+    match byte_range(range, len) {
+        ByteRange::Full => {
+            Ok(([octet_stream, accept_ranges], image(0..len).await?).into_response())
+        }
+        ByteRange::Partial(range) => {
+            let content_range = format!("bytes {}-{}/{}", range.start, range.end - 1, len);
+            Ok((
+                StatusCode::PARTIAL_CONTENT,
+                [
+                    octet_stream,
+                    accept_ranges,
+                    (
+                        header::CONTENT_RANGE,
+                        HeaderValue::from_str(&content_range).unwrap(),
+                    ),
+                ],
+                image(range).await?,
+            )
+                .into_response())
+        }
+        ByteRange::Unsatisfiable => {
+            let content_range = format!("bytes */{}", len);
+            Ok((
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                [(header::CONTENT_RANGE, content_range)],
+            )
+                .into_response())
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RolloutRequest {
+    version: String,
+    /// All of the group unless given.
+    #[serde(default = "all_devices")]
+    percent: u8,
+    /// Signature that lets devices install the release over a newer one, from
+    /// `sign-image allow-downgrade`.
+    #[serde(default)]
+    downgrade: Option<String>,
+}
+
+fn all_devices() -> u8 {
+    100
+}
+
+/// `PUT /firmware/rollouts/<group>`, replacing the group's rollout.
+pub async fn set_rollout(
+    State(state): State<AppState>,
+    Path(group): Path<String>,
+    Json(request): Json<RolloutRequest>,
+) -> Result<Json<Rollout>, Error> {
+    if !is_valid_name(&group) {
+        return Err(error(
+            StatusCode::BAD_REQUEST,
+            format!("invalid group: {}", group),
+        ));
+    }
+    if request.percent > 100 {
+        return Err(error(
+            StatusCode::BAD_REQUEST,
+            format!("invalid percent: {}", request.percent),
+        ));
+    }
+    // Only devices can check the signature, this just catches what can't be one.
+    let is_signature = |signature: &String| {
+        signature.len() == 128 && signature.bytes().all(|b| b.is_ascii_hexdigit())
+    };
+    if let Some(downgrade) = request.downgrade.as_ref().filter(|s| !is_signature(s)) {
+        return Err(error(
+            StatusCode::BAD_REQUEST,
+            format!("invalid downgrade signature: {}", downgrade),
+        ));
+    }
+    let releases = state
+        .storage
+        .firmware_releases()
+        .await
+        .map_err(internal_error)?;
+    if !releases
+        .iter()
+        .any(|release| release.version == request.version)
+    {
+        return Err(error(
+            StatusCode::NOT_FOUND,
+            format!("no firmware {}", request.version),
+        ));
+    }
+    let rollout = Rollout {
+        group,
+        version: request.version,
+        percent: request.percent,
+        updated_at_ms: unix_millis(OffsetDateTime::now_utc()),
+        downgrade: request.downgrade,
+    };
+    state
+        .storage
+        .set_rollout(&rollout)
+        .await
+        .map_err(internal_error)?;
+    info!(
+        "Rolling out firmware {} to {}% of group {}",
+        rollout.version, rollout.percent, rollout.group
+    );
+    Ok(Json(rollout))
+}
+
+pub async fn list_rollouts(State(state): State<AppState>) -> Result<Json<Vec<Rollout>>, Error> {
+    let rollouts = state.storage.rollouts().await.map_err(internal_error)?;
+    Ok(Json(rollouts))
+}
+
+#[derive(Deserialize)]
+pub struct DeviceGroupRequest {
+    group: String,
+}
+
+/// `PUT /firmware/devices/<hex device id>`, moving the device to another group.
+pub async fn set_device_group(
+    State(state): State<AppState>,
+    Path(device_id): Path<String>,
+    Json(request): Json<DeviceGroupRequest>,
+) -> Result<StatusCode, Error> {
+    let Some(device_id) = decode_device_id(&device_id) else {
+        return Err(error(
+            StatusCode::BAD_REQUEST,
+            format!("invalid device id: {}", device_id),
+        ));
+    };
+    if !is_valid_name(&request.group) {
+        return Err(error(
+            StatusCode::BAD_REQUEST,
+            format!("invalid group: {}", request.group),
+        ));
+    }
+    state
+        .storage
+        .set_device_group(&device_id, &request.group)
+        .await
+        .map_err(internal_error)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn list_devices(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<FirmwareDevice>>, Error> {
+    let devices = state
+        .storage
+        .firmware_devices()
+        .await
+        .map_err(internal_error)?;
+    Ok(Json(devices))
+}
+
+#[derive(Deserialize)]
+pub struct LatestQuery {
+    /// Hex encoded.
+    device_id: String,
+    /// Version the device runs.
+    current: String,
+}
+
+/// `GET /firmware/latest`, answering the update the device should install, or
+/// `204 No Content` if there is none.
+pub async fn latest(
+    State(state): State<AppState>,
+    Query(query): Query<LatestQuery>,
+) -> Result<Response, Error> {
+    let Some(device_id) = decode_device_id(&query.device_id) else {
+        return Err(error(
+            StatusCode::BAD_REQUEST,
+            format!("invalid device id: {}", query.device_id),
+        ));
+    };
+    let (device, rollouts) = tokio::try_join!(
+        state.storage.firmware_device(&device_id),
+        state.storage.rollouts(),
+    )
+    .map_err(internal_error)?;
+    let now_ms = unix_millis(OffsetDateTime::now_utc());
+    let Some(rollout) = offered_rollout(
+        &device_id,
+        &query.current,
+        device.as_ref(),
+        &rollouts,
+        now_ms,
+    ) else {
+        return Ok(StatusCode::NO_CONTENT.into_response());
+    };
+    let releases = state
+        .storage
+        .firmware_releases()
+        .await
+        .map_err(internal_error)?;
+    let Some(release) = releases
+        .into_iter()
+        .find(|release| release.version == rollout.version)
+    else {
+        return Ok(StatusCode::NO_CONTENT.into_response());
+    };
+    info!(
+        "Offering firmware {} to {} running {}",
+        release.version, query.device_id, query.current
+    );
+    Ok(Json(FirmwareUpdate {
+        url: image_path(&release.version),
+        version: release.version,
+        size: release.size as u64,
+        sha256: release.sha256,
+        downgrade: rollout.downgrade.clone(),
+    })
+    .into_response())
+}
+
+/// `POST /firmware/reports`
+pub async fn report(
+    State(state): State<AppState>,
+    Json(report): Json<UpdateReport>,
+) -> Result<StatusCode, Error> {
+    if report.device_id.is_empty() {
+        return Err(error(StatusCode::BAD_REQUEST, "empty device id".into()));
+    }
+    if !is_valid_name(&report.version) {
+        return Err(error(
+            StatusCode::BAD_REQUEST,
+            format!("invalid version: {}", report.version),
+        ));
+    }
+    match report.outcome {
+        UpdateOutcome::Installed => info!(
+            "Device {:?} installed firmware {}",
+            report.device_id, report.version
+        ),
+        UpdateOutcome::Failed => warn!(
+            "Device {:?} failed to install firmware {}: {}",
+            report.device_id,
+            report.version,
+            report.message.as_deref().unwrap_or("no reason given")
+        ),
+    }
+    let stored = FirmwareReport {
+        version: report.version,
+        outcome: report.outcome,
+        message: report.message,
+        received_at_ms: unix_millis(OffsetDateTime::now_utc()),
+    };
+    state
+        .storage
+        .insert_firmware_report(&report.device_id, &stored)
+        .await
+        .map_err(internal_error)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rollout(group: &str, version: &str, percent: u8) -> Rollout {
+        Rollout {
+            group: group.into(),
+            version: version.into(),
+            percent,
+            updated_at_ms: 0,
+            downgrade: None,
+        }
+    }
+
+    fn device(id: &[u8], group: &str, report: Option<(&str, UpdateOutcome)>) -> FirmwareDevice {
+        FirmwareDevice {
+            device_id: id.to_vec(),
+            group: group.into(),
+            last_report: report.map(|(version, outcome)| FirmwareReport {
+                version: version.into(),
+                outcome,
+                message: None,
+                received_at_ms: 0,
+            }),
+        }
+    }
+
+    #[test]
+    fn validates_names() {
+        assert!(is_valid_name("0.3.0"));
+        assert!(is_valid_name("v0.3.0-4-gabcdef+dirty"));
+        assert!(is_valid_name("canary_1"));
+        assert!(!is_valid_name(""));
+        assert!(!is_valid_name("0.3.0/../x"));
+        assert!(!is_valid_name("0.3 beta"));
+        assert!(!is_valid_name(&"1".repeat(MAX_NAME_LEN + 1)));
+    }
+
+    #[test]
+    fn rollout_percentage_only_adds_devices() {
+        let devices: Vec<[u8; 2]> = (0..1000_u16).map(u16::to_be_bytes).collect();
+        let included = |version: &str, percent| {
+            devices
+                .iter()
+                .filter(|id| in_rollout(&id[..], version, percent))
+                .collect::<Vec<_>>()
+        };
+        assert!(included("0.3.0", 0).is_empty());
+        assert_eq!(included("0.3.0", 100).len(), devices.len());
+
+        let ten = included("0.3.0", 10);
+        let fifty = included("0.3.0", 50);
+        assert!((50..150).contains(&ten.len()), "{}", ten.len());
+        assert!((400..600).contains(&fifty.len()), "{}", fifty.len());
+        assert!(ten.iter().all(|id| fifty.contains(id)));
+
+        // Another release starts with other devices.
+        assert_ne!(included("0.4.0", 10), ten);
+    }
+
+    #[test]
+    fn offers_the_rollout_of_the_group() {
+        let rollouts = [
+            rollout("default", "0.3.0", 100),
+            rollout("beta", "0.4.0", 100),
+        ];
+        let offered = |current, device: Option<&FirmwareDevice>| {
+            offered_rollout(b"a", current, device, &rollouts, 0).map(|r| r.version.as_str())
+        };
+        assert_eq!(offered("0.2.0", None), Some("0.3.0"));
+        assert_eq!(offered("0.3.0", None), None);
+
+        let beta = device(b"a", "beta", None);
+        assert_eq!(offered("0.3.0", Some(&beta)), Some("0.4.0"));
+        let unknown = device(b"a", "nightly", None);
+        assert_eq!(offered("0.3.0", Some(&unknown)), None);
+
+        let partial = [rollout("default", "0.3.0", 0)];
+        assert_eq!(offered_rollout(b"a", "0.2.0", None, &partial, 0), None);
+    }
+
+    #[test]
+    fn offers_older_releases_only_with_a_downgrade_signature() {
+        let unsigned = [rollout("default", "0.3.0", 100)];
+        // Devices would refuse these.
+        assert_eq!(offered_rollout(b"a", "0.4.0", None, &unsigned, 0), None);
+        assert_eq!(offered_rollout(b"a", "dev", None, &unsigned, 0), None);
+
+        let signed = [Rollout {
+            downgrade: Some("ab".repeat(64)),
+            ..rollout("default", "0.3.0", 100)
+        }];
+        assert!(offered_rollout(b"a", "0.4.0", None, &signed, 0).is_some());
+        assert!(offered_rollout(b"a", "dev", None, &signed, 0).is_some());
+    }
+
+    #[test]
+    fn retries_releases_that_failed_on_the_device() {
+        let rollouts = [rollout("default", "0.3.0", 100)];
+        let offered = |device: &FirmwareDevice, now_ms| {
+            offered_rollout(b"a", "0.2.0", Some(device), &rollouts, now_ms).is_some()
+        };
+        let failed = device(b"a", "default", Some(("0.3.0", UpdateOutcome::Failed)));
+        assert!(!offered(&failed, RETRY_FAILED_AFTER_MS - 1));
+        assert!(offered(&failed, RETRY_FAILED_AFTER_MS));
+        // Rolled back by the bootloader, which would happen again.
+        let rolled_back = device(b"a", "default", Some(("0.3.0", UpdateOutcome::Installed)));
+        assert!(!offered(&rolled_back, RETRY_FAILED_AFTER_MS));
+        let failed_before = device(b"a", "default", Some(("0.2.1", UpdateOutcome::Failed)));
+        assert!(offered(&failed_before, 0));
+    }
+
+    #[test]
+    fn reads_byte_ranges() {
+        let range = |header| byte_range(Some(header), 1000);
+        assert_eq!(range("bytes=0-499"), ByteRange::Partial(0..500));
+        assert_eq!(range("bytes=500-"), ByteRange::Partial(500..1000));
+        assert_eq!(range("bytes=900-5000"), ByteRange::Partial(900..1000));
+        assert_eq!(range("bytes=-100"), ByteRange::Partial(900..1000));
+        assert_eq!(range("bytes=-5000"), ByteRange::Partial(0..1000));
+        assert_eq!(range("bytes=999-999"), ByteRange::Partial(999..1000));
+
+        assert_eq!(range("bytes=1000-"), ByteRange::Unsatisfiable);
+        assert_eq!(range("bytes=-0"), ByteRange::Unsatisfiable);
+
+        assert_eq!(byte_range(None, 1000), ByteRange::Full);
+        assert_eq!(range("bytes=0-1,5-6"), ByteRange::Full);
+        assert_eq!(range("bytes=500-400"), ByteRange::Full);
+        assert_eq!(range("bytes=x-"), ByteRange::Full);
+        assert_eq!(range("items=0-1"), ByteRange::Full);
+    }
+}
@@ -0,0 +1,238 @@
+//! Announces devices to Home Assistant through MQTT discovery and publishes their readings as
+//! sensor state.
+//!
+//! See <https://www.home-assistant.io/integrations/mqtt/#mqtt-discovery>.
+
+use std::collections::HashSet;
+
+use log::{error, info, warn};
+use rumqttc::{AsyncClient, QoS};
+use serde_json::json;
+use tokio::sync::broadcast::error::RecvError;
+use types::{
+    mqtt::{self, Message, TopicKind},
+    MetricRequestBody, Topic,
+};
+
+use crate::AppState;
+
+/// Discovery prefix Home Assistant listens on unless configured otherwise.
+pub const DEFAULT_DISCOVERY_PREFIX: &str = "homeassistant";
+
+/// A sensor entity exposed for every device.
+struct Sensor {
+    object_id: &'static str,
+    name: &'static str,
+    device_class: &'static str,
+    unit: &'static str,
+    field: &'static str,
+}
+
+const CLIMATE_SENSORS: [Sensor; 3] = [
+    Sensor {
+        object_id: "temperature",
+        name: "Temperature",
+        device_class: "temperature",
+        unit: "°C",
+        field: "temperature_celsius",
+    },
+    Sensor {
+        object_id: "humidity",
+        name: "Humidity",
+        device_class: "humidity",
+        unit: "%",
+        field: "humidity",
+    },
+    Sensor {
+        object_id: "co2",
+        name: "CO2",
+        device_class: "carbon_dioxide",
+        unit: "ppm",
+        field: "co2_ppm",
+    },
+];
+
+/// Publishes discovery config for every known device, then keeps publishing discovery for new
+/// devices and state for every accepted reading until the task is dropped.
+pub async fn run(
+    client: AsyncClient,
+    discovery_prefix: String,
+    topic_prefix: String,
+    state: AppState,
+) {
+    // Subscribe before listing devices so that no reading falls between the two.
+    let mut accepted = state.accepted.subscribe();
+    let mut announced = HashSet::new();
+
+    match state.storage.devices().await {
+        Ok(devices) => {
+            for device in devices {
+                announce(&client, &discovery_prefix, &topic_prefix, &device.device_id).await;
+                announced.insert(device.device_id);
+            }
+        }
+        Err(e) => error!("Failed to list devices for Home Assistant discovery: {}", e),
+    }
+
+    loop {
+        let body = match accepted.recv().await {
+            Ok(body) => body,
+            Err(RecvError::Lagged(skipped)) => {
+                warn!("Skipped {} Home Assistant state updates", skipped);
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        if !announced.contains(&body.device_id) {
+            announce(&client, &discovery_prefix, &topic_prefix, &body.device_id).await;
+            announced.insert(body.device_id.clone());
+        }
+        if let Some(message) = state_message(&topic_prefix, &body) {
+            publish(&client, message).await;
+        }
+    }
+}
+
+async fn announce(
+    client: &AsyncClient,
+    discovery_prefix: &str,
+    topic_prefix: &str,
+    device_id: &[u8],
+) {
+    info!(
+        "Announcing device {} to Home Assistant",
+        mqtt::encode_device_id(device_id)
+    );
+    for message in discovery_messages(discovery_prefix, topic_prefix, device_id) {
+        publish(client, message).await;
+    }
+}
+
+async fn publish(client: &AsyncClient, message: Message) {
+    if let Err(e) = client
+        .publish(
+            &message.topic,
+            QoS::AtLeastOnce,
+            message.retain,
+            message.payload,
+        )
+        .await
+    {
+        error!("Failed to publish to {}: {}", message.topic, e);
+    }
+}
+
+/// Topic the server publishes a device's climate state on, read by the discovered sensors.
+fn state_topic(topic_prefix: &str, device_id: &[u8]) -> String {
+    format!(
+        "{}/state",
+        mqtt::topic(topic_prefix, device_id, TopicKind::Climate)
+    )
+}
+
+/// Builds the retained discovery config of every sensor of a device.
+fn discovery_messages(
+    discovery_prefix: &str,
+    topic_prefix: &str,
+    device_id: &[u8],
+) -> Vec<Message> {
+    let node_id = format!("esp32_{}", mqtt::encode_device_id(device_id));
+    let state_topic = state_topic(topic_prefix, device_id);
+    CLIMATE_SENSORS
+        .iter()
+        .map(|sensor| {
+            let config = json!({
+                "name": sensor.name,
+                "unique_id": format!("{}_{}", node_id, sensor.object_id),
+                "object_id": format!("{}_{}", node_id, sensor.object_id),
+                "state_topic": state_topic,
+                "value_template": format!("{{{{ value_json.{} }}}}", sensor.field),
+                "device_class": sensor.device_class,
+                "unit_of_measurement": sensor.unit,
+                "state_class": "measurement",
+                "device": {
+                    "identifiers": [node_id],
+                    "name": format!("ESP32 {}", mqtt::encode_device_id(device_id)),
+                    "manufacturer": "Espressif",
+                    "model": "ESP32 + SCD4x",
+                },
+            });
+            Message {
+                topic: format!(
+                    "{}/sensor/{}/{}/config",
+                    discovery_prefix, node_id, sensor.object_id
+                ),
+                payload: config.to_string().into_bytes(),
+                retain: true,
+            }
+        })
+        .collect()
+}
+
+/// Builds the retained state message for a reading, if it is one the sensors display.
+fn state_message(topic_prefix: &str, body: &MetricRequestBody) -> Option<Message> {
+    match &body.topic {
+        Topic::Climate(climate) => Some(Message {
+            topic: state_topic(topic_prefix, &body.device_id),
+            payload: json!({
+                "temperature_celsius": climate.temperature_celsius,
+                "humidity": climate.humidity,
+                "co2_ppm": climate.co2_ppm,
+            })
+            .to_string()
+            .into_bytes(),
+            retain: true,
+        }),
+        Topic::DeviceHealth(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use types::Climate;
+
+    #[test]
+    fn discovery_config_per_sensor() {
+        let messages = discovery_messages("homeassistant", "esp32", &[0xab]);
+        let topics: Vec<_> = messages.iter().map(|m| m.topic.as_str()).collect();
+        assert_eq!(
+            topics,
+            [
+                "homeassistant/sensor/esp32_ab/temperature/config",
+                "homeassistant/sensor/esp32_ab/humidity/config",
+                "homeassistant/sensor/esp32_ab/co2/config",
+            ]
+        );
+        assert!(messages.iter().all(|m| m.retain));
+
+        let co2: Value = serde_json::from_slice(&messages[2].payload).unwrap();
+        assert_eq!(co2["device_class"], "carbon_dioxide");
+        assert_eq!(co2["unit_of_measurement"], "ppm");
+        assert_eq!(co2["state_topic"], "esp32/ab/climate/state");
+        assert_eq!(co2["value_template"], "{{ value_json.co2_ppm }}");
+        assert_eq!(co2["device"]["identifiers"][0], "esp32_ab");
+    }
+
+    #[test]
+    fn state_matches_value_templates() {
+        let body = MetricRequestBody {
+            topic: Topic::Climate(Climate {
+                temperature_celsius: 21.5,
+                humidity: 40.0,
+                co2_ppm: 600,
+            }),
+            timestamp: 1_684_000_000,
+            timestamp_millis: 0,
+            device_id: vec![0xab],
+        };
+        let message = state_message("esp32", &body).unwrap();
+        assert_eq!(message.topic, "esp32/ab/climate/state");
+        let state: Value = serde_json::from_slice(&message.payload).unwrap();
+        for sensor in &CLIMATE_SENSORS {
+            assert!(state.get(sensor.field).is_some(), "{}", sensor.field);
+        }
+        assert_eq!(state["co2_ppm"], 600);
+    }
+}
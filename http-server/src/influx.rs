@@ -0,0 +1,768 @@
+//! InfluxDB line protocol support: `POST /write` ingestion and forwarding of accepted metrics to
+//! an Influx compatible endpoint.
+//!
+//! See <https://docs.influxdata.com/influxdb/v2.7/reference/syntax/line-protocol/>.
+
+use std::{collections::HashMap, fmt, sync::Arc};
+
+use anyhow::{anyhow, bail};
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::Utc;
+use hyper::{header, Body, Client, Method, Request, Uri};
+use log::{error, info, warn};
+use serde::Deserialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+use types::{
+    health::ResetReason, mqtt, Climate, DeviceHealth, HttpResponseBody, MetricRequestBody, Topic,
+};
+
+use crate::{ingest, AppState};
+
+/// Measurement name of [`Topic::Climate`] metrics.
+const CLIMATE_MEASUREMENT: &str = "climate";
+/// Measurement name of [`Topic::DeviceHealth`] reports.
+const HEALTH_MEASUREMENT: &str = "device_health";
+
+/// Most readings sent to the forwarding endpoint in a single request.
+const MAX_FORWARD_BATCH: usize = 100;
+
+/// An error that makes a line of line protocol unusable.
+#[derive(Debug, PartialEq)]
+pub enum LineProtocolError {
+    /// The line doesn't follow the `measurement[,tags] fields [timestamp]` syntax.
+    Syntax(usize, &'static str),
+    /// The measurement doesn't map onto a [`Topic`].
+    UnknownMeasurement(usize, String),
+    /// The `device_id` tag is missing or isn't hex encoded.
+    InvalidDeviceId(usize),
+    /// A field required by the measurement is missing or has the wrong type.
+    InvalidField(usize, &'static str),
+    /// The timestamp isn't an integer.
+    InvalidTimestamp(usize),
+}
+
+impl fmt::Display for LineProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LineProtocolError::Syntax(line, reason) => write!(f, "line {}: {}", line, reason),
+            LineProtocolError::UnknownMeasurement(line, measurement) => {
+                write!(f, "line {}: unknown measurement {}", line, measurement)
+            }
+            LineProtocolError::InvalidDeviceId(line) => {
+                write!(f, "line {}: missing or invalid device_id tag", line)
+            }
+            LineProtocolError::InvalidField(line, field) => {
+                write!(f, "line {}: missing or invalid field {}", line, field)
+            }
+            LineProtocolError::InvalidTimestamp(line) => {
+                write!(f, "line {}: invalid timestamp", line)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LineProtocolError {}
+
+/// Unit of the timestamps in a write request.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum Precision {
+    #[default]
+    #[serde(alias = "n", rename = "ns")]
+    Nanoseconds,
+    #[serde(alias = "u", rename = "us")]
+    Microseconds,
+    #[serde(rename = "ms")]
+    Milliseconds,
+    #[serde(rename = "s")]
+    Seconds,
+}
+
+impl Precision {
+    /// Converts a timestamp in this precision into unix seconds and the milliseconds past them,
+    /// dropping anything finer.
+    fn to_seconds_and_millis(self, timestamp: i64) -> (i64, u16) {
+        let units_per_milli = match self {
+            Precision::Nanoseconds => 1_000_000,
+            Precision::Microseconds => 1_000,
+            Precision::Milliseconds => 1,
+            Precision::Seconds => return (timestamp, 0),
+        };
+        let millis = timestamp.div_euclid(units_per_milli);
+        (millis.div_euclid(1000), millis.rem_euclid(1000) as u16)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum FieldValue {
+    Float(f64),
+    Integer(i64),
+    UInteger(u64),
+    String(String),
+    Boolean(bool),
+}
+
+impl FieldValue {
+    fn as_f64(&self) -> Option<f64> {
+        match *self {
+            FieldValue::Float(v) => Some(v),
+            FieldValue::Integer(v) => Some(v as f64),
+            FieldValue::UInteger(v) => Some(v as f64),
+            _ => None,
+        }
+    }
+
+    fn as_i64(&self) -> Option<i64> {
+        match *self {
+            FieldValue::Float(v) if v.fract() == 0.0 => Some(v as i64),
+            FieldValue::Integer(v) => Some(v),
+            FieldValue::UInteger(v) => i64::try_from(v).ok(),
+            _ => None,
+        }
+    }
+}
+
+/// A single parsed line.
+#[derive(Debug, PartialEq)]
+struct Point {
+    measurement: String,
+    tags: HashMap<String, String>,
+    fields: HashMap<String, FieldValue>,
+    timestamp: Option<i64>,
+}
+
+/// Splits `s` on every unescaped `separator` that isn't inside a double quoted string.
+fn split_unescaped(s: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+    let mut quoted = false;
+    for (i, c) in s.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => quoted = !quoted,
+            c if c == separator && !quoted => {
+                parts.push(&s[start..i]);
+                start = i + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => out.extend(chars.next()),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn parse_field_value(value: &str) -> Option<FieldValue> {
+    if let Some(quoted) = value.strip_prefix('"') {
+        return Some(FieldValue::String(unescape(quoted.strip_suffix('"')?)));
+    }
+    match value {
+        "t" | "T" | "true" | "True" | "TRUE" => return Some(FieldValue::Boolean(true)),
+        "f" | "F" | "false" | "False" | "FALSE" => return Some(FieldValue::Boolean(false)),
+        _ => {}
+    }
+    if let Some(integer) = value.strip_suffix('i') {
+        return integer.parse().ok().map(FieldValue::Integer);
+    }
+    if let Some(unsigned) = value.strip_suffix('u') {
+        return unsigned.parse().ok().map(FieldValue::UInteger);
+    }
+    value.parse().ok().map(FieldValue::Float)
+}
+
+fn parse_point(number: usize, line: &str) -> Result<Point, LineProtocolError> {
+    let sections = split_unescaped(line, ' ');
+    let (series, fields, timestamp) = match sections.as_slice() {
+        [series, fields] => (*series, *fields, None),
+        [series, fields, timestamp] => (*series, *fields, Some(*timestamp)),
+        _ => {
+            return Err(LineProtocolError::Syntax(
+                number,
+                "expected measurement, fields and optional timestamp",
+            ))
+        }
+    };
+
+    let mut series = split_unescaped(series, ',').into_iter();
+    let measurement = unescape(series.next().unwrap_or_default());
+    if measurement.is_empty() {
+        return Err(LineProtocolError::Syntax(number, "missing measurement"));
+    }
+    let mut tags = HashMap::new();
+    for tag in series {
+        match split_unescaped(tag, '=').as_slice() {
+            [key, value] if !key.is_empty() && !value.is_empty() => {
+                tags.insert(unescape(key), unescape(value));
+            }
+            _ => return Err(LineProtocolError::Syntax(number, "invalid tag")),
+        }
+    }
+
+    let mut parsed_fields = HashMap::new();
+    for field in split_unescaped(fields, ',') {
+        let (key, value) = match split_unescaped(field, '=').as_slice() {
+            [key, value] if !key.is_empty() => (unescape(key), *value),
+            _ => return Err(LineProtocolError::Syntax(number, "invalid field")),
+        };
+        let value = parse_field_value(value)
+            .ok_or(LineProtocolError::Syntax(number, "invalid field value"))?;
+        parsed_fields.insert(key, value);
+    }
+
+    let timestamp = timestamp
+        .map(|t| {
+            t.parse()
+                .map_err(|_| LineProtocolError::InvalidTimestamp(number))
+        })
+        .transpose()?;
+
+    Ok(Point {
+        measurement,
+        tags,
+        fields: parsed_fields,
+        timestamp,
+    })
+}
+
+/// The integer field `name` of a point, if it fits into `T`.
+fn integer<T: TryFrom<i64>>(
+    number: usize,
+    point: &Point,
+    name: &'static str,
+) -> Result<T, LineProtocolError> {
+    point
+        .fields
+        .get(name)
+        .and_then(FieldValue::as_i64)
+        .and_then(|v| T::try_from(v).ok())
+        .ok_or(LineProtocolError::InvalidField(number, name))
+}
+
+/// The string field `name` of a point.
+fn string<'a>(
+    number: usize,
+    point: &'a Point,
+    name: &'static str,
+) -> Result<&'a str, LineProtocolError> {
+    match point.fields.get(name) {
+        Some(FieldValue::String(value)) => Ok(value),
+        _ => Err(LineProtocolError::InvalidField(number, name)),
+    }
+}
+
+/// Maps a point onto the metric it describes.
+fn point_to_metric(
+    number: usize,
+    point: Point,
+    precision: Precision,
+    now: i64,
+) -> Result<MetricRequestBody, LineProtocolError> {
+    let device_id = point
+        .tags
+        .get("device_id")
+        .and_then(|id| mqtt::decode_device_id(id))
+        .ok_or(LineProtocolError::InvalidDeviceId(number))?;
+    let field = |name: &'static str| {
+        point
+            .fields
+            .get(name)
+            .ok_or(LineProtocolError::InvalidField(number, name))
+    };
+    let topic = match point.measurement.as_str() {
+        CLIMATE_MEASUREMENT => Topic::Climate(Climate {
+            temperature_celsius: field("temperature_celsius")?.as_f64().ok_or(
+                LineProtocolError::InvalidField(number, "temperature_celsius"),
+            )? as f32,
+            humidity: field("humidity")?
+                .as_f64()
+                .ok_or(LineProtocolError::InvalidField(number, "humidity"))?
+                as f32,
+            co2_ppm: field("co2_ppm")?
+                .as_i64()
+                .and_then(|v| i32::try_from(v).ok())
+                .ok_or(LineProtocolError::InvalidField(number, "co2_ppm"))?,
+        }),
+        HEALTH_MEASUREMENT => Topic::DeviceHealth(DeviceHealth {
+            uptime_secs: integer(number, &point, "uptime_secs")?,
+            free_heap_bytes: integer(number, &point, "free_heap_bytes")?,
+            min_free_heap_bytes: integer(number, &point, "min_free_heap_bytes")?,
+            // Left out while the device isn't connected.
+            wifi_rssi_dbm: match point.fields.contains_key("wifi_rssi_dbm") {
+                true => Some(integer(number, &point, "wifi_rssi_dbm")?),
+                false => None,
+            },
+            reset_reason: ResetReason::from_name(string(number, &point, "reset_reason")?),
+            firmware_version: string(number, &point, "firmware_version")?.to_string(),
+            failed_sends: integer(number, &point, "failed_sends")?,
+        }),
+        _ => {
+            return Err(LineProtocolError::UnknownMeasurement(
+                number,
+                point.measurement,
+            ))
+        }
+    };
+    let (timestamp, timestamp_millis) = point
+        .timestamp
+        .map(|t| precision.to_seconds_and_millis(t))
+        .unwrap_or((now, 0));
+    Ok(MetricRequestBody {
+        topic,
+        timestamp,
+        timestamp_millis,
+        device_id,
+    })
+}
+
+/// Parses a line protocol request body into metrics, stamping points without a timestamp with
+/// `now` (unix seconds). Fails on the first unusable line, reported 1-based.
+pub fn parse(
+    body: &str,
+    precision: Precision,
+    now: i64,
+) -> Result<Vec<MetricRequestBody>, LineProtocolError> {
+    body.lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(number, line)| {
+            parse_point(number, line).and_then(|p| point_to_metric(number, p, precision, now))
+        })
+        .collect()
+}
+
+fn escape_tag(s: &str) -> String {
+    s.replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
+
+fn quote_field(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Encodes a metric as a single line with a nanosecond timestamp.
+pub fn encode(body: &MetricRequestBody) -> String {
+    let device_id = escape_tag(&mqtt::encode_device_id(&body.device_id));
+    let timestamp =
+        i128::from(body.timestamp) * 1_000_000_000 + i128::from(body.timestamp_millis) * 1_000_000;
+    match &body.topic {
+        Topic::Climate(climate) => format!(
+            "{},device_id={} temperature_celsius={},humidity={},co2_ppm={}i {}",
+            CLIMATE_MEASUREMENT,
+            device_id,
+            climate.temperature_celsius,
+            climate.humidity,
+            climate.co2_ppm,
+            timestamp
+        ),
+        Topic::DeviceHealth(health) => {
+            let rssi = health
+                .wifi_rssi_dbm
+                .map(|rssi| format!(",wifi_rssi_dbm={}i", rssi))
+                .unwrap_or_default();
+            format!(
+                "{},device_id={} uptime_secs={}i,free_heap_bytes={}i,min_free_heap_bytes={}i{},reset_reason={},firmware_version={},failed_sends={}i {}",
+                HEALTH_MEASUREMENT,
+                device_id,
+                health.uptime_secs,
+                health.free_heap_bytes,
+                health.min_free_heap_bytes,
+                rssi,
+                quote_field(health.reset_reason.as_str()),
+                quote_field(&health.firmware_version),
+                health.failed_sends,
+                timestamp
+            )
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct WriteQuery {
+    #[serde(default)]
+    precision: Precision,
+}
+
+/// `POST /write`: ingests metrics in line protocol, answering `204 No Content` like InfluxDB.
+///
+/// Nothing is stored unless every line can be parsed. The lines are then ingested in order, and
+/// the first one that is turned down, e.g. as a duplicate or by a rate limit, answers the request
+/// with its error, leaving the lines before it stored.
+pub async fn write(
+    State(state): State<AppState>,
+    Query(query): Query<WriteQuery>,
+    body: String,
+) -> Result<StatusCode, Response> {
+    let metrics = parse(&body, query.precision, Utc::now().timestamp()).map_err(|e| {
+        let response = HttpResponseBody {
+            message: e.to_string().into_bytes(),
+        };
+        (StatusCode::BAD_REQUEST, Json(response)).into_response()
+    })?;
+    info!("Received {} metrics in line protocol", metrics.len());
+    for metric in metrics {
+        ingest::ingest(&state, metric)
+            .await
+            .map_err(crate::ingest_error)?;
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Settings for forwarding accepted metrics, read from the environment.
+#[derive(Debug, Clone)]
+pub struct InfluxConfig {
+    /// Full write URL, e.g. `http://localhost:8086/api/v2/write?org=lab&bucket=esp32`.
+    pub url: String,
+    pub token: Option<String>,
+}
+
+impl InfluxConfig {
+    /// Reads `INFLUX_FORWARD_URL`, which has to be an `http://` URL, and `INFLUX_TOKEN`.
+    ///
+    /// Returns `Ok(None)` when `INFLUX_FORWARD_URL` is unset, meaning forwarding is disabled.
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        let url = match std::env::var("INFLUX_FORWARD_URL") {
+            Ok(url) => url,
+            Err(_) => return Ok(None),
+        };
+        check_forward_url(&url)?;
+        Ok(Some(InfluxConfig {
+            url,
+            token: std::env::var("INFLUX_TOKEN").ok(),
+        }))
+    }
+}
+
+/// Checks that `url` can be forwarded to, which takes plain HTTP as the client has no TLS.
+/// Refusing `https://` up front beats failing every batch, and sending the token in the clear.
+fn check_forward_url(url: &str) -> anyhow::Result<()> {
+    let uri: Uri = url
+        .parse()
+        .map_err(|e| anyhow!("INFLUX_FORWARD_URL {:?} is invalid: {}", url, e))?;
+    match uri.scheme_str() {
+        Some("http") if uri.host().is_some() => Ok(()),
+        Some("https") => bail!(
+            "INFLUX_FORWARD_URL {:?} uses HTTPS, which isn't supported, \
+             forward to a local proxy that terminates TLS instead",
+            url
+        ),
+        _ => bail!("INFLUX_FORWARD_URL {:?} isn't an http:// URL", url),
+    }
+}
+
+/// Forwards every accepted metric to the configured endpoint, batching the ones that arrive
+/// while a request is in flight. Runs until the channel is closed.
+///
+/// Readings the endpoint rejects are logged and dropped.
+pub async fn forward(
+    config: InfluxConfig,
+    mut accepted: broadcast::Receiver<Arc<MetricRequestBody>>,
+) {
+    let client = Client::new();
+    info!("Forwarding metrics to {}", config.url);
+    loop {
+        let mut lines = Vec::new();
+        match accepted.recv().await {
+            Ok(body) => lines.push(encode(&body)),
+            Err(RecvError::Lagged(skipped)) => {
+                warn!("Skipped forwarding {} metrics", skipped);
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        }
+        while lines.len() < MAX_FORWARD_BATCH {
+            match accepted.try_recv() {
+                Ok(body) => lines.push(encode(&body)),
+                Err(_) => break,
+            }
+        }
+
+        let mut request = Request::builder()
+            .method(Method::POST)
+            .uri(&config.url)
+            .header(header::CONTENT_TYPE, "text/plain; charset=utf-8");
+        if let Some(token) = &config.token {
+            request = request.header(header::AUTHORIZATION, format!("Token {}", token));
+        }
+        let request = match request.body(Body::from(lines.join("\n"))) {
+            Ok(request) => request,
+            Err(e) => {
+                error!("Invalid forwarding request: {}", e);
+                continue;
+            }
+        };
+        match client.request(request).await {
+            Ok(response) if response.status().is_success() => {}
+            Ok(response) => error!(
+                "Forwarding {} metrics failed with status {}",
+                lines.len(),
+                response.status()
+            ),
+            Err(e) => error!("Forwarding {} metrics failed: {}", lines.len(), e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{extract::State, http::HeaderMap, routing::post, Router};
+    use tokio::sync::mpsc;
+
+    fn climate(metric: &MetricRequestBody) -> &Climate {
+        match &metric.topic {
+            Topic::Climate(climate) => climate,
+            Topic::DeviceHealth(_) => panic!("not a climate reading"),
+        }
+    }
+
+    #[test]
+    fn parses_climate_lines() {
+        let body = "# comment\n\
+            climate,device_id=01ab,room=lab temperature_celsius=21.5,humidity=40i,co2_ppm=600i 1684000000000000000\n\
+            \n\
+            climate,device_id=01ab co2_ppm=700,humidity=41.5,temperature_celsius=22\n";
+        let metrics = parse(body, Precision::Nanoseconds, 42).unwrap();
+        assert_eq!(metrics.len(), 2);
+        assert_eq!(metrics[0].device_id, vec![0x01, 0xab]);
+        assert_eq!(metrics[0].timestamp, 1_684_000_000);
+        assert_eq!(climate(&metrics[0]).temperature_celsius, 21.5);
+        assert_eq!(climate(&metrics[0]).humidity, 40.0);
+        assert_eq!(climate(&metrics[0]).co2_ppm, 600);
+        assert_eq!(metrics[1].timestamp, 42);
+        assert_eq!(climate(&metrics[1]).co2_ppm, 700);
+    }
+
+    #[test]
+    fn applies_precision() {
+        let line = "climate,device_id=01 temperature_celsius=1,humidity=1,co2_ppm=1i 1684000000123";
+        let metrics = parse(line, Precision::Milliseconds, 0).unwrap();
+        assert_eq!(metrics[0].timestamp, 1_684_000_000);
+        assert_eq!(metrics[0].timestamp_millis, 123);
+        let line =
+            "climate,device_id=01 temperature_celsius=1,humidity=1,co2_ppm=1i 1684000000123456789";
+        let metrics = parse(line, Precision::Nanoseconds, 0).unwrap();
+        assert_eq!(metrics[0].timestamp, 1_684_000_000);
+        assert_eq!(metrics[0].timestamp_millis, 123);
+        // Before 1970 the milliseconds still count forward from the second.
+        let line = "climate,device_id=01 temperature_celsius=1,humidity=1,co2_ppm=1i -1500";
+        let metrics = parse(line, Precision::Milliseconds, 0).unwrap();
+        assert_eq!(metrics[0].timestamp, -2);
+        assert_eq!(metrics[0].timestamp_millis, 500);
+        let line = "climate,device_id=01 temperature_celsius=1,humidity=1,co2_ppm=1i 1684000000";
+        let metrics = parse(line, Precision::Seconds, 0).unwrap();
+        assert_eq!(metrics[0].timestamp, 1_684_000_000);
+    }
+
+    #[test]
+    fn handles_escapes_and_strings() {
+        let point = parse_point(1, r#"my\ measurement,tag\,key=a\ b note="x, y=z",v=1u"#).unwrap();
+        assert_eq!(point.measurement, "my measurement");
+        assert_eq!(point.tags["tag,key"], "a b");
+        assert_eq!(point.fields["note"], FieldValue::String("x, y=z".into()));
+        assert_eq!(point.fields["v"], FieldValue::UInteger(1));
+        assert_eq!(point.timestamp, None);
+    }
+
+    #[test]
+    fn reports_the_failing_line() {
+        let ok = "climate,device_id=01 temperature_celsius=1,humidity=1,co2_ppm=1i";
+        let cases = [
+            (
+                "climate,device_id=01",
+                LineProtocolError::Syntax(2, "expected measurement, fields and optional timestamp"),
+            ),
+            (
+                "weather,device_id=01 temperature_celsius=1",
+                LineProtocolError::UnknownMeasurement(2, "weather".into()),
+            ),
+            (
+                "climate temperature_celsius=1,humidity=1,co2_ppm=1i",
+                LineProtocolError::InvalidDeviceId(2),
+            ),
+            (
+                "climate,device_id=01 temperature_celsius=1,humidity=1",
+                LineProtocolError::InvalidField(2, "co2_ppm"),
+            ),
+            (
+                "climate,device_id=01 temperature_celsius=\"hot\",humidity=1,co2_ppm=1i",
+                LineProtocolError::InvalidField(2, "temperature_celsius"),
+            ),
+            (
+                "climate,device_id=01 temperature_celsius=1,humidity=1,co2_ppm=1i soon",
+                LineProtocolError::InvalidTimestamp(2),
+            ),
+        ];
+        for (line, expected) in cases {
+            let body = format!("{}\n{}", ok, line);
+            assert_eq!(
+                parse(&body, Precision::Nanoseconds, 0).unwrap_err(),
+                expected,
+                "{}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn encode_round_trips() {
+        let metric = MetricRequestBody {
+            topic: Topic::Climate(Climate {
+                temperature_celsius: 21.5,
+                humidity: 40.0,
+                co2_ppm: 600,
+            }),
+            timestamp: 1_684_000_000,
+            timestamp_millis: 250,
+            device_id: vec![0x01, 0xab],
+        };
+        let line = encode(&metric);
+        assert_eq!(
+            line,
+            "climate,device_id=01ab temperature_celsius=21.5,humidity=40,co2_ppm=600i 1684000000250000000"
+        );
+        let parsed = parse(&line, Precision::Nanoseconds, 0).unwrap();
+        assert_eq!(parsed[0].device_id, metric.device_id);
+        assert_eq!(parsed[0].timestamp, metric.timestamp);
+        assert_eq!(parsed[0].timestamp_millis, metric.timestamp_millis);
+        assert_eq!(climate(&parsed[0]).co2_ppm, 600);
+    }
+
+    #[test]
+    fn encodes_health() {
+        let metric = MetricRequestBody {
+            topic: Topic::DeviceHealth(DeviceHealth {
+                uptime_secs: 3600,
+                free_heap_bytes: 120_000,
+                min_free_heap_bytes: 90_000,
+                wifi_rssi_dbm: None,
+                reset_reason: ResetReason::Panic,
+                firmware_version: "0.2.0 \"dev\"".into(),
+                failed_sends: 2,
+            }),
+            timestamp: 1_684_000_000,
+            timestamp_millis: 0,
+            device_id: vec![0x01, 0xab],
+        };
+        assert_eq!(
+            encode(&metric),
+            "device_health,device_id=01ab uptime_secs=3600i,free_heap_bytes=120000i,min_free_heap_bytes=90000i,\
+            reset_reason=\"panic\",firmware_version=\"0.2.0 \\\"dev\\\"\",failed_sends=2i 1684000000000000000"
+        );
+    }
+
+    #[test]
+    fn health_round_trips() {
+        let health = |wifi_rssi_dbm| DeviceHealth {
+            uptime_secs: 3600,
+            free_heap_bytes: 120_000,
+            min_free_heap_bytes: 90_000,
+            wifi_rssi_dbm,
+            reset_reason: ResetReason::TaskWatchdog,
+            firmware_version: "0.2.0 \"dev\", build=1".into(),
+            failed_sends: 2,
+        };
+        for health in [health(Some(-67)), health(None)] {
+            let metric = MetricRequestBody {
+                topic: Topic::DeviceHealth(health.clone()),
+                timestamp: 1_684_000_000,
+                timestamp_millis: 250,
+                device_id: vec![0x01, 0xab],
+            };
+            let parsed = parse(&encode(&metric), Precision::Nanoseconds, 0).unwrap();
+            assert_eq!(parsed[0].device_id, metric.device_id);
+            assert_eq!(parsed[0].timestamp, metric.timestamp);
+            assert_eq!(parsed[0].timestamp_millis, metric.timestamp_millis);
+            match &parsed[0].topic {
+                Topic::DeviceHealth(parsed) => assert_eq!(parsed, &health),
+                Topic::Climate(_) => panic!("not a health report"),
+            }
+        }
+
+        let line = "device_health,device_id=01 uptime_secs=1i,free_heap_bytes=1i,\
+            min_free_heap_bytes=1i,reset_reason=\"panic\",firmware_version=\"0.2.0\",failed_sends=-1i";
+        assert_eq!(
+            parse(line, Precision::Nanoseconds, 0).unwrap_err(),
+            LineProtocolError::InvalidField(1, "failed_sends")
+        );
+    }
+
+    /// Forwards to a stand-in endpoint that records what it receives.
+    #[tokio::test]
+    async fn forwards_accepted_metrics() {
+        let (received_tx, mut received) = mpsc::unbounded_channel();
+        let stand_in = Router::new()
+            .route(
+                "/api/v2/write",
+                post(
+                    |State(tx): State<mpsc::UnboundedSender<(HeaderMap, String)>>,
+                     headers: HeaderMap,
+                     body: String| async move {
+                        tx.send((headers, body)).unwrap();
+                        StatusCode::NO_CONTENT
+                    },
+                ),
+            )
+            .with_state(received_tx);
+        let server =
+            axum::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(stand_in.into_make_service());
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        let (accepted, receiver) = broadcast::channel(16);
+        let config = InfluxConfig {
+            url: format!("http://{}/api/v2/write?bucket=esp32", addr),
+            token: Some("secret".into()),
+        };
+        tokio::spawn(forward(config, receiver));
+
+        let metric = MetricRequestBody {
+            topic: Topic::Climate(Climate::default()),
+            timestamp: 1,
+            timestamp_millis: 0,
+            device_id: vec![2],
+        };
+        accepted.send(Arc::new(metric)).unwrap();
+
+        let (headers, body) = received.recv().await.unwrap();
+        assert_eq!(headers[header::AUTHORIZATION], "Token secret");
+        assert_eq!(
+            body,
+            "climate,device_id=02 temperature_celsius=0,humidity=0,co2_ppm=0i 1000000000"
+        );
+    }
+
+    #[test]
+    fn forwards_only_to_http_urls() {
+        assert!(
+            check_forward_url("http://localhost:8086/api/v2/write?org=lab&bucket=esp32").is_ok()
+        );
+        for url in [
+            "https://eu-central-1-1.aws.cloud2.influxdata.com/api/v2/write",
+            "ftp://localhost/write",
+            "localhost:8086/api/v2/write",
+            "/api/v2/write",
+            "http://",
+        ] {
+            assert!(check_forward_url(url).is_err(), "{}", url);
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use std::{fmt, sync::Arc, time::Duration};
+
+use axum::http::StatusCode;
+use sqlx::types::time::OffsetDateTime;
+use tokio::sync::broadcast;
+use types::{MetricRequestBody, Topic};
+
+use crate::{buffer::BufferError, storage::StorageError, AppState};
+
+/// An error that can happen while validating or storing a metric, regardless of whether it
+/// arrived over HTTP or MQTT.
+#[derive(Debug)]
+pub enum IngestError {
+    /// The device id was empty.
+    MissingDeviceId,
+    /// The timestamp can't be represented as a date.
+    InvalidTimestamp(i64),
+    /// The milliseconds past the timestamp were 1000 or more.
+    InvalidTimestampMillis(u16),
+    /// The device sent more readings than its rate limit allows.
+    RateLimited(Duration),
+    /// The storage backend rejected the insert.
+    Storage(StorageError),
+    /// The reading couldn't be queued for buffered ingestion.
+    Buffer(BufferError),
+}
+
+impl IngestError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            IngestError::MissingDeviceId
+            | IngestError::InvalidTimestamp(_)
+            | IngestError::InvalidTimestampMillis(_) => StatusCode::BAD_REQUEST,
+            IngestError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            IngestError::Storage(StorageError::Duplicate) => StatusCode::CONFLICT,
+            IngestError::Storage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            IngestError::Buffer(_) => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    /// How long the client should wait before sending again, if trying again can help.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            IngestError::RateLimited(retry_after) => Some(*retry_after),
+            IngestError::Buffer(BufferError::Full) => Some(Duration::from_secs(1)),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for IngestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IngestError::MissingDeviceId => write!(f, "device_id must not be empty"),
+            IngestError::InvalidTimestamp(timestamp) => {
+                write!(f, "invalid timestamp: {}", timestamp)
+            }
+            IngestError::InvalidTimestampMillis(millis) => {
+                write!(f, "timestamp_millis must be below 1000, got {}", millis)
+            }
+            IngestError::RateLimited(retry_after) => {
+                write!(f, "too many readings, retry in {:?}", retry_after)
+            }
+            IngestError::Storage(e) => e.fmt(f),
+            IngestError::Buffer(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for IngestError {}
+
+/// What [`ingest`] did with a metric.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Ingested {
+    /// Describes what was stored, for the response.
+    pub message: &'static str,
+    /// Whether the metric was only queued for the batch writer, not stored yet.
+    pub queued: bool,
+}
+
+/// Where stored metrics are sent, for consumers such as the Home Assistant state publisher.
+#[derive(Clone)]
+pub struct Accepted(broadcast::Sender<Arc<MetricRequestBody>>);
+
+impl Accepted {
+    /// Keeps up to `capacity` metrics for consumers that fall behind.
+    pub fn new(capacity: usize) -> Self {
+        Accepted(broadcast::channel(capacity).0)
+    }
+
+    /// Sends a stored metric to every consumer. Nobody listening is fine, consumers are
+    /// optional.
+    pub fn publish(&self, metric: MetricRequestBody) {
+        let _ = self.0.send(Arc::new(metric));
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<MetricRequestBody>> {
+        self.0.subscribe()
+    }
+}
+
+/// Validates and stores a metric.
+///
+/// This is the single entry point used by every transport so they all apply the same rules,
+/// including measuring the skew of the device clock against the time the metric arrived.
+/// Stored metrics are then sent to [`AppState::accepted`]. With buffered ingestion climate
+/// readings are only queued here, and sent once the writer has stored them.
+pub async fn ingest(state: &AppState, payload: MetricRequestBody) -> Result<Ingested, IngestError> {
+    if payload.device_id.is_empty() {
+        return Err(IngestError::MissingDeviceId);
+    }
+    if payload.timestamp_millis >= 1000 {
+        return Err(IngestError::InvalidTimestampMillis(
+            payload.timestamp_millis,
+        ));
+    }
+    let device_timestamp = OffsetDateTime::from_unix_timestamp(payload.timestamp)
+        .and_then(|t| t.replace_millisecond(payload.timestamp_millis))
+        .map_err(|_| IngestError::InvalidTimestamp(payload.timestamp))?;
+    state
+        .limits
+        .check_device(&payload.device_id)
+        .map_err(IngestError::RateLimited)?;
+    let time = state.clock.time(
+        &payload.device_id,
+        device_timestamp,
+        OffsetDateTime::now_utc(),
+    );
+    if let (Some(buffer), Topic::Climate(_)) = (&state.buffer, &payload.topic) {
+        buffer
+            .push(time, payload)
+            .await
+            .map_err(IngestError::Buffer)?;
+        return Ok(Ingested {
+            message: "Climate data queued",
+            queued: true,
+        });
+    }
+    // Health reports come every few minutes at most, so they aren't worth queueing.
+    let message = match &payload.topic {
+        Topic::Climate(data) => {
+            state
+                .storage
+                .insert_climate(&payload.device_id, time, data)
+                .await
+                .map_err(IngestError::Storage)?;
+            "Climate data inserted"
+        }
+        Topic::DeviceHealth(health) => {
+            state
+                .storage
+                .insert_health(&payload.device_id, time, health)
+                .await
+                .map_err(IngestError::Storage)?;
+            "Device health inserted"
+        }
+    };
+    state.accepted.publish(payload);
+    Ok(Ingested {
+        message,
+        queued: false,
+    })
+}
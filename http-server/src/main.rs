@@ -1,141 +1,452 @@
-use std::{fs::OpenOptions, io::Read, os::fd::IntoRawFd, path::PathBuf};
+use std::{net::SocketAddr, path::Path, sync::Arc, time::Duration};
 
 use axum::{
-    extract::{Query, State},
+    extract::{DefaultBodyLimit, Query, State},
     http::{header, HeaderValue, StatusCode},
-    response::{Html, IntoResponse},
-    routing::{get, post},
+    middleware,
+    response::{IntoResponse, Response},
+    routing::{get, post, put},
     Json, Router,
 };
+use axum_server::tls_rustls::RustlsConfig;
 use base64::Engine;
-use chrono::{DateTime, NaiveDateTime, Utc};
-use log::info;
+use buffer::{BufferStats, WriteBuffer};
+use chrono::Utc;
+use clap::Parser;
+use clock::ClockPolicy;
+use config::{Cli, Config, StatusConfig};
+use ingest::Accepted;
+use log::{error, info};
+use ratelimit::{Limits, ThrottleStats};
 use serde::{Deserialize, Serialize};
 use sqlx::types::time::OffsetDateTime;
-use sqlx::{postgres::PgPoolOptions, PgPool, Postgres, Row};
-use types::{HttpResponseBody, MetricRequestBody, Topic};
+use status::DeviceStatus;
+use storage::{ClimateAggregate, ClimateMetric, Device, DeviceHealthSnapshot, Storage};
+use types::{HttpResponseBody, MetricRequestBody};
+
+mod assets;
+mod buffer;
+mod clock;
+mod config;
+mod firmware;
+mod homeassistant;
+mod influx;
+mod ingest;
+mod mqtt;
+mod ratelimit;
+mod retention;
+mod status;
+mod storage;
+#[cfg(test)]
+mod tests;
+
+/// State shared by the HTTP handlers and the background tasks.
+#[derive(Clone)]
+pub struct AppState {
+    pub storage: Arc<dyn Storage>,
+    /// Every metric accepted by [`ingest::ingest`] is sent here.
+    pub accepted: Accepted,
+    /// Directory the dashboard is served from in dev mode, instead of the embedded copy.
+    pub static_dir: Option<Arc<Path>>,
+    pub limits: Arc<Limits>,
+    /// Queue of readings for the batch writer, if ingestion is buffered.
+    pub buffer: Option<WriteBuffer>,
+    pub clock: Arc<ClockPolicy>,
+    /// When devices count as stale or offline.
+    pub status: Arc<StatusConfig>,
+    /// Token that managing firmware needs, see [`firmware::require_admin`].
+    pub admin_token: Option<Arc<str>>,
+}
 
 #[tokio::main]
 async fn main() {
-    pretty_env_logger::init();
-    let db_connection_str = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+    let cli = Cli::parse();
+    let config = match Config::load(&cli) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    if cli.print_config {
+        print!("{}", config.to_toml());
+        return;
+    }
+    let (mqtt, influx) = match (
+        mqtt::MqttConfig::from_env(),
+        influx::InfluxConfig::from_env(),
+    ) {
+        (Ok(mqtt), Ok(influx)) => (mqtt, influx),
+        (Err(e), _) | (_, Err(e)) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    pretty_env_logger::formatted_builder()
+        .parse_filters(&config.logging.filter)
+        .init();
 
-    // setup connection pool
-    let pool = PgPoolOptions::new()
-        .max_connections(5)
-        .connect(&db_connection_str)
-        .await
-        .expect("can't connect to database");
+    // connect to the storage backend named by the url and run migrations, waiting for the
+    // database to come up unless we are asked to stop first
+    let storage = tokio::select! {
+        storage = storage::connect_with_retry(&config.database) => match storage {
+            Ok(storage) => storage,
+            Err(e) => {
+                error!("Can't connect to database: {}", e);
+                std::process::exit(1);
+            }
+        },
+        _ = shutdown_signal() => return,
+    };
 
-    // run migrations
-    sqlx::migrate!("./migrations")
-        .run(&pool)
-        .await
-        .expect("can't run migrations");
+    let accepted = Accepted::new(64);
+
+    // optionally store readings in batches from a queue
+    let (buffer, writer) = if config.ingest.buffered {
+        let (buffer, writer) =
+            WriteBuffer::spawn(&config.ingest, storage.clone(), accepted.clone());
+        (Some(buffer), Some(writer))
+    } else {
+        (None, None)
+    };
+
+    let state = AppState {
+        storage,
+        accepted,
+        static_dir: config.server.static_dir.as_deref().map(Arc::from),
+        limits: Arc::new(Limits::new(&config.limits)),
+        buffer: buffer.clone(),
+        clock: Arc::new(ClockPolicy::new(&config.clock)),
+        status: Arc::new(config.status.clone()),
+        admin_token: config.firmware.admin_token.as_deref().map(Arc::from),
+    };
+
+    // optionally delete readings past the retention period
+    if config.retention.days.is_some() {
+        tokio::spawn(retention::run(
+            config.retention.clone(),
+            state.storage.clone(),
+        ));
+    }
+
+    // optionally ingest metrics published to an MQTT broker
+    if let Some(config) = mqtt {
+        tokio::spawn(mqtt::run(config, state.clone()));
+    }
+
+    // optionally forward accepted metrics to InfluxDB
+    if let Some(config) = influx {
+        tokio::spawn(influx::forward(config, state.accepted.subscribe()));
+    }
 
     // build our application with a route
-    let app = Router::new()
-        .route("/favicon.ico", get(favicon))
-        .route("/", get(root))
-        .route("/index.html", get(root))
-        .route("/metrics", get(select_metrics))
-        .route("/metric", post(insert_metric))
-        .with_state(pool);
+    let app = router(state);
 
-    info!("Listening on 0.0.0.0:3000");
-    axum::Server::bind(&"0.0.0.0:3000".parse().unwrap())
-        .serve(app.into_make_service())
-        .await
-        .unwrap();
+    // on SIGTERM or Ctrl-C, stop accepting connections and finish the requests in flight
+    let addr = config.server.bind;
+    match config.server.tls.paths() {
+        Some((cert, key)) => {
+            let tls = RustlsConfig::from_pem_file(cert, key)
+                .await
+                .expect("can't load TLS certificate");
+            let handle = axum_server::Handle::new();
+            tokio::spawn({
+                let handle = handle.clone();
+                async move {
+                    shutdown_signal().await;
+                    handle.graceful_shutdown(None);
+                }
+            });
+            info!("Listening on https://{}", addr);
+            axum_server::bind_rustls(addr, tls)
+                .handle(handle)
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                .await
+                .unwrap();
+        }
+        None => {
+            info!("Listening on http://{}", addr);
+            axum::Server::bind(&addr)
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                .with_graceful_shutdown(shutdown_signal())
+                .await
+                .unwrap();
+        }
+    }
+    if let (Some(buffer), Some(writer)) = (buffer, writer) {
+        info!("Writing queued readings");
+        buffer.close();
+        writer.finish().await;
+    }
+    info!("Shut down");
 }
 
-type Resp = Result<(StatusCode, Json<HttpResponseBody>), (StatusCode, Json<HttpResponseBody>)>;
+/// Completes on SIGTERM, as sent by docker and Kubernetes, or Ctrl-C.
+async fn shutdown_signal() {
+    let interrupt = tokio::signal::ctrl_c();
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("can't listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = interrupt => {},
+        _ = terminate => {},
+    }
+    info!("Shutting down, finishing requests in flight");
+}
 
-async fn root() -> Html<String> {
-    let d = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
-        .join("web")
-        .join("index.html");
-    let mut file = OpenOptions::new().read(true).open(d).unwrap();
-    let mut buf = String::new();
-    file.read_to_string(&mut buf).unwrap();
-    Html(buf)
+/// Builds the application's routes around its state.
+fn router(state: AppState) -> Router {
+    // Ingestion is what misbehaving clients flood, so only it is limited per client.
+    let ingestion = Router::new()
+        .route("/metric", post(insert_metric))
+        .route("/write", post(influx::write))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            ratelimit::limit_clients,
+        ));
+    // Firmware images are far larger than anything else that is uploaded.
+    let firmware_uploads = Router::new()
+        .route("/firmware/releases/:version", put(firmware::upload_release))
+        .layer(DefaultBodyLimit::max(state.limits.max_firmware_bytes));
+    // What devices install is only up to the admin.
+    let firmware_management = Router::new()
+        .route("/firmware/rollouts/:group", put(firmware::set_rollout))
+        .route(
+            "/firmware/devices/:device_id",
+            put(firmware::set_device_group),
+        )
+        .merge(firmware_uploads)
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            firmware::require_admin,
+        ));
+    Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/stats", get(stats))
+        .route("/favicon.ico", get(favicon))
+        .route("/metrics", get(select_metrics))
+        .route("/metrics/aggregate", get(aggregate_metrics))
+        .route("/devices", get(select_devices))
+        .route("/devices/health", get(select_device_health))
+        .route("/devices/status", get(select_device_status))
+        .route("/firmware/releases", get(firmware::list_releases))
+        .route(
+            "/firmware/releases/:version/image",
+            get(firmware::download_release),
+        )
+        .route("/firmware/rollouts", get(firmware::list_rollouts))
+        .route("/firmware/devices", get(firmware::list_devices))
+        .route(types::firmware::LATEST_PATH, get(firmware::latest))
+        .route(types::firmware::REPORTS_PATH, post(firmware::report))
+        .merge(firmware_management)
+        .merge(ingestion)
+        .fallback(assets::serve)
+        .layer(DefaultBodyLimit::max(state.limits.max_body_bytes))
+        .with_state(state)
 }
 
+type Resp = Result<(StatusCode, Json<HttpResponseBody>), (StatusCode, Json<HttpResponseBody>)>;
+
 // NOTE: State must be the first argument
-async fn insert_metric(State(pool): State<PgPool>, Json(payload): Json<MetricRequestBody>) -> Resp {
+async fn insert_metric(
+    State(state): State<AppState>,
+    Json(payload): Json<MetricRequestBody>,
+) -> Result<(StatusCode, Json<HttpResponseBody>), Response> {
     info!("Received metric: {:?}", payload);
-    match payload.topic {
-        Topic::Climate(data) => {
-            sqlx::query("INSERT INTO climate_metrics (device_id, device_timestamp, temperature_celsius, humidity, co2_ppm) VALUES ($1, $2, $3, $4, $5)")
-                .bind(payload.device_id)
-                .bind(OffsetDateTime::from_unix_timestamp(payload.timestamp).unwrap())
-                .bind(data.temperature_celsius)
-                .bind(data.humidity)
-                .bind(data.co2_ppm)
-                .execute(&pool)
-                .await
-                .map_err(internal_error)?;
+    match ingest::ingest(&state, payload).await {
+        Ok(ingested) => {
             let response = HttpResponseBody {
-                message: "Climate data inserted".to_string().into_bytes(),
+                message: ingested.message.to_string().into_bytes(),
+            };
+            // A queued metric isn't stored yet.
+            let status = if ingested.queued {
+                StatusCode::ACCEPTED
+            } else {
+                StatusCode::CREATED
             };
-            Ok((StatusCode::CREATED, Json(response)))
+            Ok((status, Json(response)))
         }
+        Err(e) => Err(ingest_error(e)),
     }
 }
 
-#[derive(sqlx::FromRow)]
-struct ClimateMetricRow {
-    device_id: Vec<u8>,
-    device_timestamp: NaiveDateTime,
-    temperature_celsius: f64,
-    humidity: f64,
-    co2_ppm: i32,
-    created_at: NaiveDateTime,
-}
-
-#[derive(Serialize, Deserialize)]
-struct ClimateMetric {
-    device_id: Vec<u8>,
-    device_timestamp: i64,
-    temperature_celsius: f64,
-    humidity: f64,
-    co2_ppm: i32,
-}
-
 #[derive(Deserialize)]
 struct SelectMetricsQuery {
     start_timestamp: Option<i64>,
     end_timestamp: Option<i64>,
 }
 
+impl SelectMetricsQuery {
+    /// Returns the requested range, defaulting to everything up to now. The end second is
+    /// included as a whole, since readings can carry milliseconds.
+    fn range(
+        &self,
+    ) -> Result<(OffsetDateTime, OffsetDateTime), (StatusCode, Json<HttpResponseBody>)> {
+        let start = self.start_timestamp.unwrap_or(0);
+        let end = self.end_timestamp.unwrap_or(Utc::now().timestamp());
+        let start = OffsetDateTime::from_unix_timestamp(start)
+            .map_err(|_| bad_request(format!("invalid start_timestamp: {}", start)))?;
+        let end = OffsetDateTime::from_unix_timestamp(end)
+            .and_then(|end| end.replace_nanosecond(999_999_999))
+            .map_err(|_| bad_request(format!("invalid end_timestamp: {}", end)))?;
+        Ok((start, end))
+    }
+}
+
 async fn select_metrics(
-    State(pool): State<PgPool>,
+    State(state): State<AppState>,
     query: Query<SelectMetricsQuery>,
 ) -> Result<(StatusCode, Json<Vec<ClimateMetric>>), (StatusCode, Json<HttpResponseBody>)> {
-    let query = query.0;
-    let query_str = "select * from climate_metrics where device_timestamp between $1 and $2 order by device_timestamp desc";
-    let rows = sqlx::query_as::<Postgres, ClimateMetricRow>(query_str)
-        .bind(OffsetDateTime::from_unix_timestamp(query.start_timestamp.unwrap_or(0)).unwrap())
-        .bind(
-            OffsetDateTime::from_unix_timestamp(
-                query.end_timestamp.unwrap_or(Utc::now().timestamp()),
-            )
-            .unwrap(),
-        )
-        .fetch_all(&pool)
+    let (start, end) = query.range()?;
+    let metrics = state
+        .storage
+        .climate_range(start, end)
         .await
         .map_err(internal_error)?;
-    let metrics = rows
+    Ok((StatusCode::OK, Json(metrics)))
+}
+
+/// Widest aggregate bucket, a century, which keeps bucket arithmetic far from overflowing.
+const MAX_BUCKET_SECONDS: i64 = 100 * 366 * 24 * 3600;
+
+#[derive(Deserialize)]
+struct AggregateMetricsQuery {
+    #[serde(flatten)]
+    range: SelectMetricsQuery,
+    /// Width of each bucket, an hour unless given.
+    bucket_seconds: Option<i64>,
+}
+
+async fn aggregate_metrics(
+    State(state): State<AppState>,
+    query: Query<AggregateMetricsQuery>,
+) -> Result<(StatusCode, Json<Vec<ClimateAggregate>>), (StatusCode, Json<HttpResponseBody>)> {
+    let (start, end) = query.range.range()?;
+    let bucket_seconds = query.bucket_seconds.unwrap_or(3600);
+    if !(1..=MAX_BUCKET_SECONDS).contains(&bucket_seconds) {
+        return Err(bad_request(format!(
+            "invalid bucket_seconds: {}",
+            bucket_seconds
+        )));
+    }
+    let aggregates = state
+        .storage
+        .climate_aggregate(start, end, bucket_seconds)
+        .await
+        .map_err(internal_error)?;
+    Ok((StatusCode::OK, Json(aggregates)))
+}
+
+/// A device of the registry as `GET /devices` returns it.
+#[derive(Serialize)]
+struct DeviceResponse {
+    #[serde(flatten)]
+    device: Device,
+    /// Whether the clock skew of the device is beyond the configured maximum.
+    clock_skewed: bool,
+}
+
+async fn select_devices(
+    State(state): State<AppState>,
+) -> Result<(StatusCode, Json<Vec<DeviceResponse>>), (StatusCode, Json<HttpResponseBody>)> {
+    let devices = state.storage.devices().await.map_err(internal_error)?;
+    let devices = devices
         .into_iter()
-        .map(|row| ClimateMetric {
-            device_id: row.device_id,
-            device_timestamp: row.device_timestamp.timestamp(),
-            temperature_celsius: row.temperature_celsius,
-            humidity: row.humidity,
-            co2_ppm: row.co2_ppm,
+        .map(|device| DeviceResponse {
+            clock_skewed: device
+                .clock_skew_ms
+                .is_some_and(|skew| state.clock.is_skewed(skew)),
+            device,
         })
         .collect();
-    Ok((StatusCode::OK, Json(metrics)))
+    Ok((StatusCode::OK, Json(devices)))
+}
+
+async fn select_device_health(
+    State(state): State<AppState>,
+) -> Result<(StatusCode, Json<Vec<DeviceHealthSnapshot>>), (StatusCode, Json<HttpResponseBody>)> {
+    let health = state
+        .storage
+        .device_health()
+        .await
+        .map_err(internal_error)?;
+    Ok((StatusCode::OK, Json(health)))
+}
+
+async fn select_device_status(
+    State(state): State<AppState>,
+) -> Result<(StatusCode, Json<Vec<DeviceStatus>>), (StatusCode, Json<HttpResponseBody>)> {
+    let (devices, latest_climate, health) = tokio::try_join!(
+        state.storage.devices(),
+        state.storage.latest_climate(),
+        state.storage.device_health(),
+    )
+    .map_err(internal_error)?;
+    let statuses = status::fleet_status(
+        devices,
+        latest_climate,
+        health,
+        &state.status,
+        &state.clock,
+        storage::unix_millis(OffsetDateTime::now_utc()),
+    );
+    Ok((StatusCode::OK, Json(statuses)))
+}
+
+/// Liveness: the server is up and handling requests.
+async fn healthz() -> Resp {
+    let response = HttpResponseBody {
+        message: b"ok".to_vec(),
+    };
+    Ok((StatusCode::OK, Json(response)))
+}
+
+/// How long `/readyz` waits for the database before reporting it unavailable.
+const READY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Readiness: the database is reachable and migrated, so metrics can be stored.
+async fn readyz(State(state): State<AppState>) -> Resp {
+    let message = match tokio::time::timeout(READY_TIMEOUT, state.storage.ping()).await {
+        Ok(Ok(())) => {
+            let response = HttpResponseBody {
+                message: b"ready".to_vec(),
+            };
+            return Ok((StatusCode::OK, Json(response)));
+        }
+        Ok(Err(e)) => e.to_string(),
+        Err(_) => "database didn't answer in time".to_string(),
+    };
+    let response = HttpResponseBody {
+        message: message.into_bytes(),
+    };
+    Err((StatusCode::SERVICE_UNAVAILABLE, Json(response)))
+}
+
+/// Counters about the server itself, as opposed to the metrics it stores.
+#[derive(Serialize)]
+struct ServerStats {
+    #[serde(flatten)]
+    throttled: ThrottleStats,
+    /// Readings whose device clock was skewed, see [`ClockPolicy`].
+    skewed_readings: u64,
+    /// Only with buffered ingestion.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ingest_buffer: Option<BufferStats>,
+}
+
+async fn stats(State(state): State<AppState>) -> Json<ServerStats> {
+    Json(ServerStats {
+        throttled: ThrottleStats::from(state.limits.as_ref()),
+        skewed_readings: state.clock.skewed(),
+        ingest_buffer: state.buffer.as_ref().map(WriteBuffer::stats),
+    })
 }
 
 async fn favicon() -> impl IntoResponse {
@@ -161,3 +472,25 @@ where
     };
     (StatusCode::INTERNAL_SERVER_ERROR, Json(response))
 }
+
+/// Utility function for turning a message into a `400 Bad Request` response.
+fn bad_request(message: String) -> (StatusCode, Json<HttpResponseBody>) {
+    let response = HttpResponseBody {
+        message: message.into_bytes(),
+    };
+    (StatusCode::BAD_REQUEST, Json(response))
+}
+
+/// Maps an [`ingest::IngestError`] to a response with a matching status code.
+fn ingest_error(err: ingest::IngestError) -> Response {
+    let response = HttpResponseBody {
+        message: err.to_string().into_bytes(),
+    };
+    let mut response = (err.status_code(), Json(response)).into_response();
+    if let Some(retry_after) = err.retry_after() {
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, ratelimit::retry_after(retry_after));
+    }
+    response
+}
@@ -0,0 +1,478 @@
+use std::{fmt, net::Ipv6Addr, time::Duration};
+
+use anyhow::{anyhow, bail};
+use log::{error, info, warn};
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+use types::{
+    mqtt::{self, TopicKind},
+    MetricRequestBody,
+};
+
+use crate::{
+    homeassistant,
+    ingest::{self, IngestError},
+    AppState,
+};
+
+/// Settings for the optional MQTT subscriber, read from the environment.
+#[derive(Debug, Clone)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    pub topic_prefix: String,
+    /// Discovery prefix to announce devices to Home Assistant under, if enabled.
+    pub homeassistant_discovery: Option<String>,
+}
+
+impl MqttConfig {
+    /// Reads `MQTT_BROKER` (`host` or `host:port`, with IPv6 addresses in brackets like
+    /// `[::1]:1883`), `MQTT_CLIENT_ID`, `MQTT_TOPIC_PREFIX` and
+    /// `HOMEASSISTANT_DISCOVERY` (`true` to announce devices under the `homeassistant` prefix,
+    /// or the discovery prefix to use).
+    ///
+    /// Returns `Ok(None)` when `MQTT_BROKER` is unset, meaning MQTT ingestion is disabled.
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        let broker = match std::env::var("MQTT_BROKER") {
+            Ok(broker) => broker,
+            Err(_) => return Ok(None),
+        };
+        let (host, port) = parse_broker(&broker)?;
+        Ok(Some(MqttConfig {
+            host,
+            port,
+            client_id: std::env::var("MQTT_CLIENT_ID").unwrap_or_else(|_| "http-server".into()),
+            topic_prefix: std::env::var("MQTT_TOPIC_PREFIX")
+                .unwrap_or_else(|_| mqtt::DEFAULT_PREFIX.into()),
+            homeassistant_discovery: match std::env::var("HOMEASSISTANT_DISCOVERY") {
+                Ok(value) if value == "true" || value == "1" => {
+                    Some(homeassistant::DEFAULT_DISCOVERY_PREFIX.into())
+                }
+                Ok(value) if value == "false" || value == "0" || value.is_empty() => None,
+                Ok(prefix) => Some(prefix),
+                Err(_) => None,
+            },
+        }))
+    }
+}
+
+/// Splits `host`, `host:port`, `[ipv6]` or `[ipv6]:port` into the host and port, 1883 unless
+/// given. IPv6 addresses need the brackets, as `::1:1883` could be meant either way.
+fn parse_broker(broker: &str) -> anyhow::Result<(String, u16)> {
+    let (host, port) = match broker.strip_prefix('[') {
+        Some(rest) => {
+            let (address, port) = rest
+                .split_once(']')
+                .ok_or_else(|| anyhow!("MQTT_BROKER {:?} misses a closing ]", broker))?;
+            if address.parse::<Ipv6Addr>().is_err() {
+                bail!("MQTT_BROKER {:?} has no IPv6 address in brackets", broker);
+            }
+            let port = match port {
+                "" => None,
+                port => Some(port.strip_prefix(':').ok_or_else(|| {
+                    anyhow!("MQTT_BROKER {:?} has more than a port after ]", broker)
+                })?),
+            };
+            (address, port)
+        }
+        None if broker.matches(':').count() > 1 => {
+            bail!(
+                "MQTT_BROKER {:?} is ambiguous, write IPv6 addresses in brackets, e.g. [::1]:1883",
+                broker
+            )
+        }
+        None => match broker.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (broker, None),
+        },
+    };
+    if host.is_empty() {
+        bail!("MQTT_BROKER {:?} has no host", broker);
+    }
+    let port = match port {
+        Some(port) => port
+            .parse()
+            .map_err(|e| anyhow!("MQTT_BROKER {:?} has an invalid port: {}", broker, e))?,
+        None => 1883,
+    };
+    Ok((host.to_string(), port))
+}
+
+/// An error that can happen while handling a single MQTT message.
+#[derive(Debug)]
+pub enum MqttIngestError {
+    /// The topic doesn't follow the `<prefix>/<device_id>/<kind>` layout.
+    UnknownTopic(String),
+    /// The payload isn't a JSON encoded [`MetricRequestBody`].
+    InvalidPayload(serde_json::Error),
+    /// The device id or kind in the payload doesn't match the topic it was published on.
+    TopicMismatch(String),
+    Ingest(IngestError),
+}
+
+impl fmt::Display for MqttIngestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MqttIngestError::UnknownTopic(topic) => write!(f, "unknown topic {}", topic),
+            MqttIngestError::InvalidPayload(e) => write!(f, "invalid payload: {}", e),
+            MqttIngestError::TopicMismatch(topic) => {
+                write!(f, "payload doesn't match topic {}", topic)
+            }
+            MqttIngestError::Ingest(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for MqttIngestError {}
+
+/// Subscribes to the climate and health topics of every device and stores the received metrics.
+///
+/// When Home Assistant discovery is enabled the same connection is used to publish it.
+/// Runs until the task is dropped; connection errors are logged and retried.
+pub async fn run(config: MqttConfig, state: AppState) {
+    let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+    options.set_keep_alive(Duration::from_secs(30));
+    let (client, mut eventloop) = AsyncClient::new(options, 64);
+    let filters = [TopicKind::Climate, TopicKind::DeviceHealth]
+        .map(|kind| mqtt::subscription(&config.topic_prefix, kind));
+
+    if let Some(discovery_prefix) = config.homeassistant_discovery.clone() {
+        tokio::spawn(homeassistant::run(
+            client.clone(),
+            discovery_prefix,
+            config.topic_prefix.clone(),
+            state.clone(),
+        ));
+    }
+
+    info!("Connecting to MQTT broker {}:{}", config.host, config.port);
+    loop {
+        match eventloop.poll().await {
+            Ok(event) => handle_event(&client, &filters, &state, &config.topic_prefix, event).await,
+            Err(e) => {
+                error!("MQTT connection error: {}", e);
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+        }
+    }
+}
+
+/// Subscribes to `filters` once connected, and ingests what is published to them.
+async fn handle_event(
+    client: &AsyncClient,
+    filters: &[String],
+    state: &AppState,
+    prefix: &str,
+    event: Event,
+) {
+    match event {
+        Event::Incoming(Packet::ConnAck(_)) => {
+            // Subscriptions don't survive a reconnect with a clean session. They queue behind
+            // whatever Home Assistant published while disconnected, which only the event loop
+            // calling us makes room for, so they mustn't be waited for here.
+            let client = client.clone();
+            let filters = filters.to_vec();
+            tokio::spawn(async move {
+                for filter in filters {
+                    info!("Subscribing to {}", filter);
+                    if let Err(e) = client.subscribe(&filter, QoS::AtLeastOnce).await {
+                        error!("Failed to subscribe to {}: {}", filter, e);
+                    }
+                }
+            });
+        }
+        Event::Incoming(Packet::Publish(publish)) => {
+            if let Err(e) = handle_publish(state, prefix, &publish.topic, &publish.payload).await {
+                warn!("Dropping MQTT message on {}: {}", publish.topic, e);
+            }
+        }
+        _ => {}
+    }
+}
+
+async fn handle_publish(
+    state: &AppState,
+    prefix: &str,
+    topic: &str,
+    payload: &[u8],
+) -> Result<(), MqttIngestError> {
+    let payload = decode(prefix, topic, payload)?;
+    info!("Received metric over MQTT: {:?}", payload);
+    ingest::ingest(state, payload)
+        .await
+        .map_err(MqttIngestError::Ingest)?;
+    Ok(())
+}
+
+/// Decodes a message and checks it against the topic it was published on.
+fn decode(prefix: &str, topic: &str, payload: &[u8]) -> Result<MetricRequestBody, MqttIngestError> {
+    let parsed = mqtt::parse_topic(prefix, topic)
+        .ok_or_else(|| MqttIngestError::UnknownTopic(topic.to_string()))?;
+    let body: MetricRequestBody =
+        serde_json::from_slice(payload).map_err(MqttIngestError::InvalidPayload)?;
+    if body.device_id != parsed.device_id || TopicKind::of(&body.topic) != parsed.kind {
+        return Err(MqttIngestError::TopicMismatch(topic.to_string()));
+    }
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        time::{SystemTime, UNIX_EPOCH},
+    };
+
+    use super::*;
+    use crate::{
+        ingest::Accepted,
+        storage::{MemoryStorage, Storage},
+    };
+    use rumqttc::Publish;
+    use sqlx::types::time::OffsetDateTime;
+    use types::{Climate, Topic};
+
+    fn reading(device_id: &[u8], timestamp: i64) -> MetricRequestBody {
+        MetricRequestBody {
+            topic: Topic::Climate(Climate::default()),
+            timestamp,
+            timestamp_millis: 0,
+            device_id: device_id.to_vec(),
+        }
+    }
+
+    fn payload(device_id: &[u8]) -> Vec<u8> {
+        serde_json::to_vec(&reading(device_id, 1_684_000_000)).unwrap()
+    }
+
+    fn state(storage: Arc<MemoryStorage>) -> AppState {
+        AppState {
+            storage,
+            accepted: Accepted::new(1024),
+            static_dir: None,
+            limits: Arc::default(),
+            buffer: None,
+            clock: Arc::default(),
+            status: Arc::default(),
+            admin_token: None,
+        }
+    }
+
+    #[test]
+    fn parses_broker_addresses() {
+        let parsed = |broker| parse_broker(broker).map_err(|e| e.to_string());
+        assert_eq!(parsed("mqtt"), Ok(("mqtt".into(), 1883)));
+        assert_eq!(parsed("mqtt:1884"), Ok(("mqtt".into(), 1884)));
+        assert_eq!(
+            parsed("192.168.1.10:1883"),
+            Ok(("192.168.1.10".into(), 1883))
+        );
+        assert_eq!(parsed("[::1]"), Ok(("::1".into(), 1883)));
+        assert_eq!(parsed("[fd00::2]:8883"), Ok(("fd00::2".into(), 8883)));
+
+        for broker in [
+            "::1",
+            "fd00::2:1883",
+            "[::1",
+            "[mqtt]:1883",
+            "[::1]1883",
+            ":1883",
+            "mqtt:",
+            "mqtt:port",
+            "mqtt:70000",
+        ] {
+            assert!(parsed(broker).is_err(), "{}", broker);
+        }
+    }
+
+    #[tokio::test]
+    async fn stores_published_readings() {
+        let storage = Arc::new(MemoryStorage::default());
+        let state = state(storage.clone());
+        // Never polled, so nothing is sent to a broker.
+        let (client, _eventloop) = AsyncClient::new(MqttOptions::new("test", "localhost", 1883), 8);
+        let filters = [mqtt::subscription("esp32", TopicKind::Climate)];
+        let publish = |topic: &str| {
+            let publish = Publish::new(topic, QoS::AtLeastOnce, payload(&[1, 2]));
+            Event::Incoming(Packet::Publish(publish))
+        };
+
+        handle_event(
+            &client,
+            &filters,
+            &state,
+            "esp32",
+            publish("esp32/0102/climate"),
+        )
+        .await;
+        // Dropped, since it doesn't match the topic.
+        handle_event(
+            &client,
+            &filters,
+            &state,
+            "esp32",
+            publish("esp32/0909/climate"),
+        )
+        .await;
+
+        let time = OffsetDateTime::from_unix_timestamp(1_684_000_000).unwrap();
+        let stored = storage.climate_range(time, time).await.unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].device_id, vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn subscribes_without_blocking_the_event_loop() {
+        let state = state(Arc::default());
+        let (client, _eventloop) = AsyncClient::new(MqttOptions::new("test", "localhost", 1883), 2);
+        // Like the discovery messages piling up while the broker was away.
+        while client
+            .try_publish("homeassistant/config", QoS::AtLeastOnce, true, "{}")
+            .is_ok()
+        {}
+        let filters = [mqtt::subscription("esp32", TopicKind::Climate)];
+        let connected = Event::Incoming(Packet::ConnAck(rumqttc::ConnAck::new(
+            rumqttc::ConnectReturnCode::Success,
+            false,
+        )));
+        tokio::time::timeout(
+            Duration::from_secs(1),
+            handle_event(&client, &filters, &state, "esp32", connected),
+        )
+        .await
+        .expect("waited for room in the request queue");
+    }
+
+    /// Publishes a reading until the server stored it, as it may not be subscribed yet.
+    async fn publish_until_stored(
+        publisher: &AsyncClient,
+        prefix: &str,
+        storage: &MemoryStorage,
+        reading: MetricRequestBody,
+    ) {
+        let topic = mqtt::topic(prefix, &reading.device_id, TopicKind::Climate);
+        let payload = serde_json::to_vec(&reading).unwrap();
+        let time = OffsetDateTime::from_unix_timestamp(reading.timestamp).unwrap();
+        let stored = async {
+            loop {
+                publisher
+                    .publish(&topic, QoS::AtLeastOnce, false, payload.clone())
+                    .await
+                    .unwrap();
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                let metrics = storage.climate_range(time, time).await.unwrap();
+                if metrics.iter().any(|m| m.device_id == reading.device_id) {
+                    return;
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(30), stored)
+            .await
+            .expect("the reading wasn't stored");
+    }
+
+    /// Runs against the broker named by `TEST_MQTT_BROKER`, e.g. the docker-compose one at
+    /// `localhost:1883`.
+    #[tokio::test]
+    async fn ingests_from_a_broker() {
+        let Ok(broker) = std::env::var("TEST_MQTT_BROKER") else {
+            eprintln!("TEST_MQTT_BROKER not set, skipping the MQTT broker test");
+            return;
+        };
+        let (host, port) = parse_broker(&broker).unwrap();
+        let storage = Arc::new(MemoryStorage::default());
+        let state = state(storage.clone());
+        // More devices than the request queue holds discovery messages of, so that Home
+        // Assistant fills it before the connection is up.
+        for device_id in 0..40 {
+            ingest::ingest(&state, reading(&[device_id], 1_684_000_000))
+                .await
+                .unwrap();
+        }
+        // Unique so that runs sharing the broker don't get each other's readings.
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let prefix = format!("test-{}", nanos);
+        let config = MqttConfig {
+            host: host.clone(),
+            port,
+            client_id: format!("{}-server", prefix),
+            topic_prefix: prefix.clone(),
+            homeassistant_discovery: Some(format!("{}-homeassistant", prefix)),
+        };
+        let server = tokio::spawn(run(config.clone(), state));
+
+        let options = MqttOptions::new(format!("{}-device", prefix), &host, port);
+        let (publisher, mut eventloop) = AsyncClient::new(options, 16);
+        let device = tokio::spawn(async move {
+            loop {
+                if eventloop.poll().await.is_err() {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+            }
+        });
+        publish_until_stored(
+            &publisher,
+            &prefix,
+            &storage,
+            reading(&[0xee], 1_684_000_001),
+        )
+        .await;
+
+        // Connecting with the server's client id makes the broker drop the server's connection,
+        // so it has to connect and subscribe again.
+        let options = MqttOptions::new(config.client_id, &host, port);
+        let (_impostor, mut eventloop) = AsyncClient::new(options, 1);
+        while !matches!(
+            eventloop.poll().await.unwrap(),
+            Event::Incoming(Packet::ConnAck(_))
+        ) {}
+        drop(eventloop);
+        publish_until_stored(
+            &publisher,
+            &prefix,
+            &storage,
+            reading(&[0xee], 1_684_000_002),
+        )
+        .await;
+
+        server.abort();
+        device.abort();
+    }
+
+    #[test]
+    fn decodes_matching_payload() {
+        let body = decode("esp32", "esp32/0102/climate", &payload(&[1, 2])).unwrap();
+        assert_eq!(body.device_id, vec![1, 2]);
+    }
+
+    #[test]
+    fn rejects_device_id_mismatch() {
+        assert!(matches!(
+            decode("esp32", "esp32/0102/climate", &payload(&[9])),
+            Err(MqttIngestError::TopicMismatch(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_kind_mismatch() {
+        assert!(matches!(
+            decode("esp32", "esp32/0102/health", &payload(&[1, 2])),
+            Err(MqttIngestError::TopicMismatch(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_unknown_topic_and_bad_json() {
+        assert!(matches!(
+            decode("esp32", "esp32/0102/status", &payload(&[1, 2])),
+            Err(MqttIngestError::UnknownTopic(_))
+        ));
+        assert!(matches!(
+            decode("esp32", "esp32/0102/climate", b"{"),
+            Err(MqttIngestError::InvalidPayload(_))
+        ));
+    }
+}
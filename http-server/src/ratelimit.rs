@@ -0,0 +1,314 @@
+//! Token bucket rate limits, per device and per client address.
+
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{ConnectInfo, State},
+    http::{header, HeaderValue, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use types::HttpResponseBody;
+
+use crate::{config::LimitsConfig, AppState};
+
+/// Rate and burst of a token bucket.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rate {
+    /// Requests allowed per second on average.
+    pub per_second: f64,
+    /// Requests allowed at once after being idle.
+    pub burst: u32,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Most buckets kept, so that a stream of new keys can't grow the map forever.
+const MAX_BUCKETS: usize = 10_000;
+/// Buckets left after pruning, so that it doesn't run again for every new key.
+const PRUNED_BUCKETS: usize = MAX_BUCKETS * 9 / 10;
+
+/// Token buckets keyed by `K`, each starting full.
+pub struct RateLimiter<K> {
+    rate: Rate,
+    buckets: Mutex<HashMap<K, Bucket>>,
+    throttled: AtomicU64,
+}
+
+impl<K: Eq + Hash> RateLimiter<K> {
+    pub fn new(rate: Rate) -> Self {
+        RateLimiter {
+            rate,
+            buckets: Mutex::new(HashMap::new()),
+            throttled: AtomicU64::new(0),
+        }
+    }
+
+    /// Takes a token for `key`, or returns how long until one is available.
+    pub fn check(&self, key: K, now: Instant) -> Result<(), Duration> {
+        let burst = f64::from(self.rate.burst.max(1));
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_BUCKETS && !buckets.contains_key(&key) {
+            self.prune(&mut buckets, now);
+        }
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate.per_second).min(burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        self.throttled.fetch_add(1, Ordering::Relaxed);
+        Err(Duration::from_secs_f64(
+            (1.0 - bucket.tokens) / self.rate.per_second,
+        ))
+    }
+
+    /// Drops the buckets that filled up again, and then the longest unused ones down to
+    /// [`PRUNED_BUCKETS`]. Dropping a bucket only forgets a partly drained one, which starts
+    /// full again.
+    fn prune(&self, buckets: &mut HashMap<K, Bucket>, now: Instant) {
+        let burst = f64::from(self.rate.burst.max(1));
+        let rate = self.rate.per_second;
+        buckets.retain(|_, bucket| {
+            bucket.tokens + now.saturating_duration_since(bucket.updated).as_secs_f64() * rate
+                < burst
+        });
+        let excess = buckets.len().saturating_sub(PRUNED_BUCKETS);
+        if excess == 0 {
+            return;
+        }
+        let mut updated: Vec<Instant> = buckets.values().map(|bucket| bucket.updated).collect();
+        let (_, &mut cutoff, _) = updated.select_nth_unstable(excess - 1);
+        buckets.retain(|_, bucket| bucket.updated > cutoff);
+    }
+
+    /// How many requests [`RateLimiter::check`] turned down.
+    pub fn throttled(&self) -> u64 {
+        self.throttled.load(Ordering::Relaxed)
+    }
+}
+
+/// The configured limits, shared by every request.
+pub struct Limits {
+    pub max_body_bytes: usize,
+    /// Body limit of firmware uploads instead of `max_body_bytes`.
+    pub max_firmware_bytes: usize,
+    pub device: Option<RateLimiter<Vec<u8>>>,
+    pub client: Option<RateLimiter<IpAddr>>,
+    /// Whether to take the client address from `X-Forwarded-For`, when behind a proxy.
+    pub trust_forwarded_for: bool,
+    /// Proxies in front of the server, the entry the outermost one appended is the client.
+    pub trusted_proxies: usize,
+}
+
+impl Limits {
+    pub fn new(config: &LimitsConfig) -> Self {
+        Limits {
+            max_body_bytes: config.max_body_bytes,
+            max_firmware_bytes: config.max_firmware_bytes,
+            device: config.per_device.map(RateLimiter::new),
+            client: config.per_client.map(RateLimiter::new),
+            trust_forwarded_for: config.trust_forwarded_for,
+            trusted_proxies: config.trusted_proxies,
+        }
+    }
+
+    /// Takes a token for the device, if devices are limited.
+    pub fn check_device(&self, device_id: &[u8]) -> Result<(), Duration> {
+        match &self.device {
+            Some(limiter) => limiter.check(device_id.to_vec(), Instant::now()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Default for Limits {
+    /// No rate limits, and the default body size limit.
+    fn default() -> Self {
+        Limits::new(&LimitsConfig::default())
+    }
+}
+
+/// Counters of requests turned down by the rate limits.
+#[derive(Debug, Serialize)]
+pub struct ThrottleStats {
+    pub throttled_by_device: u64,
+    pub throttled_by_client: u64,
+}
+
+impl From<&Limits> for ThrottleStats {
+    fn from(limits: &Limits) -> Self {
+        ThrottleStats {
+            throttled_by_device: limits.device.as_ref().map_or(0, RateLimiter::throttled),
+            throttled_by_client: limits.client.as_ref().map_or(0, RateLimiter::throttled),
+        }
+    }
+}
+
+/// A `Retry-After` header value, in whole seconds rounded up.
+pub fn retry_after(duration: Duration) -> HeaderValue {
+    let seconds = duration.as_secs() + u64::from(duration.subsec_nanos() > 0);
+    HeaderValue::from(seconds.max(1))
+}
+
+/// A `429 Too Many Requests` response asking to come back after `retry_after`.
+pub fn too_many_requests(message: String, retry_after: Duration) -> Response {
+    let response = HttpResponseBody {
+        message: message.into_bytes(),
+    };
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(header::RETRY_AFTER, self::retry_after(retry_after))],
+        Json(response),
+    )
+        .into_response()
+}
+
+/// The address of the client, from `X-Forwarded-For` if trusted. Every proxy appends the
+/// address it got the request from, so only the last `trusted_proxies` entries can be relied
+/// on, anything before them was sent by the client.
+fn client_ip<B>(request: &Request<B>, limits: &Limits) -> Option<IpAddr> {
+    let forwarded = limits
+        .trust_forwarded_for
+        .then(|| request.headers().get_all("x-forwarded-for"))
+        .and_then(|values| {
+            let entries = values
+                .iter()
+                .map(|value| value.to_str().ok())
+                .collect::<Option<Vec<_>>>()?;
+            let entries: Vec<&str> = entries.iter().flat_map(|value| value.split(',')).collect();
+            let index = entries.len().checked_sub(limits.trusted_proxies)?;
+            entries[index].trim().parse().ok()
+        });
+    forwarded.or_else(|| {
+        request
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip())
+    })
+}
+
+/// Middleware applying the per client limit to the routes it wraps.
+pub async fn limit_clients<B>(
+    State(state): State<AppState>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if let Some(limiter) = &state.limits.client {
+        if let Some(ip) = client_ip(&request, &state.limits) {
+            if let Err(retry_after) = limiter.check(ip, Instant::now()) {
+                return too_many_requests(format!("too many requests from {}", ip), retry_after);
+            }
+        }
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refills_at_rate_up_to_burst() {
+        let limiter = RateLimiter::new(Rate {
+            per_second: 2.0,
+            burst: 3,
+        });
+        let t0 = Instant::now();
+        for _ in 0..3 {
+            assert_eq!(limiter.check("a", t0), Ok(()));
+        }
+        assert_eq!(limiter.check("a", t0), Err(Duration::from_millis(500)));
+        // Other keys have their own bucket.
+        assert_eq!(limiter.check("b", t0), Ok(()));
+
+        assert_eq!(limiter.check("a", t0 + Duration::from_millis(500)), Ok(()));
+        assert!(limiter.check("a", t0 + Duration::from_millis(600)).is_err());
+
+        // Idle time refills no more than the burst.
+        let later = t0 + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert_eq!(limiter.check("a", later), Ok(()));
+        }
+        assert!(limiter.check("a", later).is_err());
+        assert_eq!(limiter.throttled(), 3);
+    }
+
+    #[test]
+    fn bounds_the_buckets() {
+        let limiter = RateLimiter::new(Rate {
+            per_second: 0.001,
+            burst: 3,
+        });
+        let t0 = Instant::now();
+        // Every bucket stays partly drained, so none of them fills up again.
+        for i in 0..MAX_BUCKETS + 1000 {
+            let now = t0 + Duration::from_millis(i as u64);
+            assert_eq!(limiter.check(i, now), Ok(()));
+            assert!(limiter.buckets.lock().unwrap().len() <= MAX_BUCKETS);
+        }
+        let buckets = limiter.buckets.lock().unwrap();
+        // The longest unused buckets went first.
+        assert!(!buckets.contains_key(&0));
+        assert!(buckets.contains_key(&(MAX_BUCKETS + 999)));
+    }
+
+    #[test]
+    fn prefers_trusted_forwarded_for() {
+        let request = Request::builder()
+            .header("x-forwarded-for", "203.0.113.7, 198.51.100.2")
+            .header("x-forwarded-for", "192.0.2.9")
+            .extension(ConnectInfo(SocketAddr::from(([10, 0, 0, 1], 4000))))
+            .body(())
+            .unwrap();
+        let limits = |trust_forwarded_for, trusted_proxies| Limits {
+            trust_forwarded_for,
+            trusted_proxies,
+            ..Limits::default()
+        };
+        assert_eq!(
+            client_ip(&request, &limits(true, 1)),
+            Some(IpAddr::from([192, 0, 2, 9]))
+        );
+        assert_eq!(
+            client_ip(&request, &limits(true, 3)),
+            Some(IpAddr::from([203, 0, 113, 7]))
+        );
+        // Fewer entries than proxies, so the header wasn't set by them.
+        assert_eq!(
+            client_ip(&request, &limits(true, 4)),
+            Some(IpAddr::from([10, 0, 0, 1]))
+        );
+        assert_eq!(
+            client_ip(&request, &limits(false, 1)),
+            Some(IpAddr::from([10, 0, 0, 1]))
+        );
+    }
+
+    #[test]
+    fn rounds_retry_after_up() {
+        let response = too_many_requests(String::new(), Duration::from_millis(1500));
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "2");
+    }
+}
@@ -0,0 +1,24 @@
+use std::{sync::Arc, time::Duration};
+
+use log::{error, info};
+use sqlx::types::time::OffsetDateTime;
+
+use crate::{config::RetentionConfig, storage::Storage};
+
+/// Deletes readings older than the retention period every interval. Runs forever.
+pub async fn run(config: RetentionConfig, storage: Arc<dyn Storage>) {
+    let Some(days) = config.days else {
+        return;
+    };
+    let period = Duration::from_secs(u64::from(days) * 24 * 60 * 60);
+    let mut interval = tokio::time::interval(Duration::from_secs(config.interval_secs));
+    loop {
+        interval.tick().await;
+        let before = OffsetDateTime::now_utc() - period;
+        match storage.prune_climate(before).await {
+            Ok(0) => {}
+            Ok(count) => info!("Deleted {} readings older than {} days", count, days),
+            Err(e) => error!("Failed to delete old readings: {}", e),
+        }
+    }
+}
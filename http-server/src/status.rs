@@ -0,0 +1,237 @@
+//! Fleet overview: which devices are online, and what they reported last.
+//!
+//! A device counts as heard from whenever the server receives a reading or a health report of
+//! it, by the server clock, so a device with a wrong clock doesn't look online or offline.
+
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::{
+    clock::ClockPolicy,
+    config::StatusConfig,
+    storage::{ClimateMetric, Device, DeviceHealthSnapshot},
+};
+
+/// Whether a device is still reporting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Presence {
+    Online,
+    /// Missed a few reports, e.g. because of a WiFi outage.
+    Stale,
+    Offline,
+}
+
+impl Presence {
+    /// Classifies a device that wasn't heard from for `silent_ms`.
+    pub fn classify(config: &StatusConfig, silent_ms: i64) -> Self {
+        let secs = silent_ms.div_euclid(1000);
+        if secs >= config.offline_after_secs as i64 {
+            Presence::Offline
+        } else if secs >= config.stale_after_secs as i64 {
+            Presence::Stale
+        } else {
+            Presence::Online
+        }
+    }
+}
+
+/// A device as `GET /devices/status` returns it.
+#[derive(Debug, Serialize)]
+pub struct DeviceStatus {
+    pub device_id: Vec<u8>,
+    pub status: Presence,
+    /// Unix timestamp in milliseconds of when the device was last heard from.
+    pub last_seen_ms: i64,
+    /// Seconds since then.
+    pub silent_secs: i64,
+    /// From the latest health report, unknown until the device sent one.
+    pub firmware_version: Option<String>,
+    /// Whether the clock skew of the latest reading is beyond the configured maximum.
+    pub clock_skewed: bool,
+    /// The newest climate reading, unless there is none left after pruning.
+    pub latest_climate: Option<ClimateMetric>,
+}
+
+/// Merges what is stored about every device into its status at `now_ms`, ordered by device.
+///
+/// Devices that only sent health reports are included, and readings stored before receive
+/// times were recorded fall back to the device time.
+pub fn fleet_status(
+    devices: Vec<Device>,
+    latest_climate: Vec<ClimateMetric>,
+    health: Vec<DeviceHealthSnapshot>,
+    config: &StatusConfig,
+    clock: &ClockPolicy,
+    now_ms: i64,
+) -> Vec<DeviceStatus> {
+    #[derive(Default)]
+    struct Known {
+        device: Option<Device>,
+        climate: Option<ClimateMetric>,
+        health: Option<DeviceHealthSnapshot>,
+    }
+    let mut known: BTreeMap<Vec<u8>, Known> = BTreeMap::new();
+    for device in devices {
+        let device_id = device.device_id.clone();
+        known.entry(device_id).or_default().device = Some(device);
+    }
+    for metric in latest_climate {
+        let device_id = metric.device_id.clone();
+        known.entry(device_id).or_default().climate = Some(metric);
+    }
+    for snapshot in health {
+        let device_id = snapshot.device_id.clone();
+        known.entry(device_id).or_default().health = Some(snapshot);
+    }
+
+    known
+        .into_iter()
+        .map(|(device_id, known)| {
+            let heard = [
+                known
+                    .climate
+                    .as_ref()
+                    .map(|m| m.received_at_ms.unwrap_or(m.device_timestamp_ms)),
+                known.health.as_ref().map(|h| h.received_at_ms),
+            ];
+            let last_seen_ms = heard
+                .into_iter()
+                .flatten()
+                .max()
+                .or_else(|| known.device.as_ref().map(|d| d.last_seen * 1000))
+                .unwrap_or(0);
+            let silent_ms = (now_ms - last_seen_ms).max(0);
+            DeviceStatus {
+                device_id,
+                status: Presence::classify(config, silent_ms),
+                last_seen_ms,
+                silent_secs: silent_ms / 1000,
+                firmware_version: known.health.map(|h| h.health.firmware_version),
+                clock_skewed: known
+                    .device
+                    .and_then(|d| d.clock_skew_ms)
+                    .is_some_and(|skew| clock.is_skewed(skew)),
+                latest_climate: known.climate,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use types::DeviceHealth;
+
+    const NOW_MS: i64 = 1_684_000_000_000;
+
+    fn config() -> StatusConfig {
+        StatusConfig {
+            stale_after_secs: 60,
+            offline_after_secs: 600,
+        }
+    }
+
+    #[test]
+    fn classifies_by_silence() {
+        let config = config();
+        assert_eq!(Presence::classify(&config, 0), Presence::Online);
+        assert_eq!(Presence::classify(&config, 59_999), Presence::Online);
+        assert_eq!(Presence::classify(&config, 60_000), Presence::Stale);
+        assert_eq!(Presence::classify(&config, 599_999), Presence::Stale);
+        assert_eq!(Presence::classify(&config, 600_000), Presence::Offline);
+    }
+
+    fn device(id: u8, last_seen: i64, clock_skew_ms: Option<i64>) -> Device {
+        Device {
+            device_id: vec![id],
+            first_seen: last_seen,
+            last_seen,
+            clock_skew_ms,
+        }
+    }
+
+    fn reading(id: u8, device_timestamp_ms: i64, received_at_ms: Option<i64>) -> ClimateMetric {
+        ClimateMetric {
+            device_id: vec![id],
+            device_timestamp: device_timestamp_ms / 1000,
+            device_timestamp_ms,
+            received_at_ms,
+            clock_skew_ms: received_at_ms.map(|r| device_timestamp_ms - r),
+            temperature_celsius: 21.0,
+            humidity: 40.0,
+            co2_ppm: 600,
+        }
+    }
+
+    fn health(id: u8, received_at_ms: i64) -> DeviceHealthSnapshot {
+        DeviceHealthSnapshot {
+            device_id: vec![id],
+            device_timestamp_ms: received_at_ms,
+            received_at_ms,
+            health: DeviceHealth {
+                firmware_version: "0.2.0".into(),
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn merges_readings_and_health() {
+        let devices = vec![
+            device(1, NOW_MS / 1000, Some(0)),
+            // Its clock is an hour ahead, which must not keep it online.
+            device(2, NOW_MS / 1000 + 3600, Some(3_600_000)),
+            // Its readings were all pruned.
+            device(3, NOW_MS / 1000 - 7200, None),
+        ];
+        let latest = vec![
+            reading(1, NOW_MS - 5_000, Some(NOW_MS - 5_000)),
+            reading(2, NOW_MS + 3_600_000, Some(NOW_MS - 120_000)),
+        ];
+        let health = vec![health(1, NOW_MS - 30_000), health(4, NOW_MS - 1_000)];
+        let statuses = fleet_status(
+            devices,
+            latest,
+            health,
+            &config(),
+            &ClockPolicy::default(),
+            NOW_MS,
+        );
+
+        let summary: Vec<_> = statuses
+            .iter()
+            .map(|s| (s.device_id[0], s.status, s.silent_secs))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (1, Presence::Online, 5),
+                (2, Presence::Stale, 120),
+                (3, Presence::Offline, 7200),
+                (4, Presence::Online, 1),
+            ]
+        );
+        assert_eq!(statuses[0].firmware_version.as_deref(), Some("0.2.0"));
+        assert_eq!(statuses[1].firmware_version, None);
+        assert!(!statuses[0].clock_skewed);
+        assert!(statuses[1].clock_skewed);
+        assert!(statuses[2].latest_climate.is_none());
+        assert!(statuses[3].latest_climate.is_none());
+    }
+
+    #[test]
+    fn falls_back_to_device_time_of_old_readings() {
+        let statuses = fleet_status(
+            vec![device(1, NOW_MS / 1000 - 90, None)],
+            vec![reading(1, NOW_MS - 90_000, None)],
+            vec![],
+            &config(),
+            &ClockPolicy::default(),
+            NOW_MS,
+        );
+        assert_eq!(statuses[0].last_seen_ms, NOW_MS - 90_000);
+        assert_eq!(statuses[0].status, Presence::Stale);
+    }
+}
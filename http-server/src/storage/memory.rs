@@ -0,0 +1,379 @@
+use std::{collections::BTreeMap, ops::Range, sync::Mutex};
+
+use async_trait::async_trait;
+use sqlx::types::time::OffsetDateTime;
+use types::{Climate, DeviceHealth};
+
+use super::{
+    unix_millis, ClimateAggregate, ClimateMetric, ClimateReading, Device, DeviceHealthSnapshot,
+    FirmwareDevice, FirmwareRelease, FirmwareReport, ReadingTime, Result, Rollout, Storage,
+    StorageError, Summary,
+};
+use crate::firmware::DEFAULT_GROUP;
+
+/// Storage that keeps everything in memory until it is dropped, for tests and demos.
+#[derive(Default)]
+pub struct MemoryStorage {
+    tables: Mutex<Tables>,
+}
+
+#[derive(Default)]
+struct Tables {
+    /// Readings keyed like the primary key of `climate_metrics`, by unix milliseconds.
+    climate: BTreeMap<(Vec<u8>, i64), ClimateValues>,
+    devices: BTreeMap<Vec<u8>, DeviceEntry>,
+    health: BTreeMap<Vec<u8>, (ReadingTime, DeviceHealth)>,
+    firmware: BTreeMap<String, (FirmwareRelease, Vec<u8>)>,
+    rollouts: BTreeMap<String, Rollout>,
+    firmware_devices: BTreeMap<Vec<u8>, FirmwareDevice>,
+}
+
+impl Tables {
+    /// The entry of a device in `firmware_devices`, added to the default group if it is new.
+    fn firmware_device(&mut self, device_id: &[u8]) -> &mut FirmwareDevice {
+        self.firmware_devices
+            .entry(device_id.to_vec())
+            .or_insert_with(|| FirmwareDevice {
+                device_id: device_id.to_vec(),
+                group: DEFAULT_GROUP.to_string(),
+                last_report: None,
+            })
+    }
+}
+
+/// First and last reading of a device, in unix milliseconds, and its latest clock skew.
+struct DeviceEntry {
+    first_seen: i64,
+    last_seen: i64,
+    clock_skew_ms: i64,
+}
+
+#[derive(Clone, Copy)]
+struct ClimateValues {
+    received_at_ms: i64,
+    clock_skew_ms: i64,
+    temperature_celsius: f64,
+    humidity: f64,
+    co2_ppm: i32,
+}
+
+fn climate_metric(device_id: &[u8], timestamp: i64, values: &ClimateValues) -> ClimateMetric {
+    ClimateMetric {
+        device_id: device_id.to_vec(),
+        device_timestamp: timestamp.div_euclid(1000),
+        device_timestamp_ms: timestamp,
+        received_at_ms: Some(values.received_at_ms),
+        clock_skew_ms: Some(values.clock_skew_ms),
+        temperature_celsius: values.temperature_celsius,
+        humidity: values.humidity,
+        co2_ppm: values.co2_ppm,
+    }
+}
+
+/// Accumulates a [`Summary`].
+struct Accumulator {
+    min: f64,
+    max: f64,
+    sum: f64,
+}
+
+impl Accumulator {
+    fn new(value: f64) -> Self {
+        Accumulator {
+            min: value,
+            max: value,
+            sum: value,
+        }
+    }
+
+    fn add(&mut self, value: f64) {
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.sum += value;
+    }
+
+    fn summary(&self, samples: i64) -> Summary {
+        Summary {
+            min: self.min,
+            max: self.max,
+            avg: self.sum / samples as f64,
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn insert_climate(
+        &self,
+        device_id: &[u8],
+        time: ReadingTime,
+        climate: &Climate,
+    ) -> Result<()> {
+        let timestamp = unix_millis(time.device_timestamp);
+        let key = (device_id.to_vec(), timestamp);
+        let mut tables = self.tables.lock().unwrap();
+        if tables.climate.contains_key(&key) {
+            return Err(StorageError::Duplicate);
+        }
+        tables.climate.insert(
+            key,
+            ClimateValues {
+                received_at_ms: unix_millis(time.received_at),
+                clock_skew_ms: time.clock_skew_ms,
+                temperature_celsius: climate.temperature_celsius.into(),
+                humidity: climate.humidity.into(),
+                co2_ppm: climate.co2_ppm,
+            },
+        );
+        tables
+            .devices
+            .entry(device_id.to_vec())
+            .and_modify(|device| {
+                device.first_seen = timestamp.min(device.first_seen);
+                device.last_seen = timestamp.max(device.last_seen);
+                device.clock_skew_ms = time.clock_skew_ms;
+            })
+            .or_insert(DeviceEntry {
+                first_seen: timestamp,
+                last_seen: timestamp,
+                clock_skew_ms: time.clock_skew_ms,
+            });
+        Ok(())
+    }
+
+    async fn insert_climate_batch(&self, readings: &[ClimateReading<'_>]) -> Result<Vec<usize>> {
+        let mut inserted = Vec::new();
+        for (position, reading) in readings.iter().enumerate() {
+            match self
+                .insert_climate(reading.device_id, reading.time, reading.climate)
+                .await
+            {
+                Ok(()) => inserted.push(position),
+                Err(StorageError::Duplicate) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(inserted)
+    }
+
+    async fn climate_range(
+        &self,
+        start: OffsetDateTime,
+        end: OffsetDateTime,
+    ) -> Result<Vec<ClimateMetric>> {
+        let range = unix_millis(start)..=unix_millis(end);
+        let mut metrics: Vec<_> = self
+            .tables
+            .lock()
+            .unwrap()
+            .climate
+            .iter()
+            .filter(|((_, timestamp), _)| range.contains(timestamp))
+            .map(|((device_id, timestamp), values)| climate_metric(device_id, *timestamp, values))
+            .collect();
+        metrics.sort_by_key(|metric| std::cmp::Reverse(metric.device_timestamp_ms));
+        Ok(metrics)
+    }
+
+    async fn latest_climate(&self) -> Result<Vec<ClimateMetric>> {
+        let tables = self.tables.lock().unwrap();
+        let mut latest: BTreeMap<&[u8], (i64, &ClimateValues)> = BTreeMap::new();
+        // Keys are ordered by device and then time, so the last entry per device wins.
+        for ((device_id, timestamp), values) in tables.climate.iter() {
+            latest.insert(device_id, (*timestamp, values));
+        }
+        Ok(latest
+            .into_iter()
+            .map(|(device_id, (timestamp, values))| climate_metric(device_id, timestamp, values))
+            .collect())
+    }
+
+    async fn climate_aggregate(
+        &self,
+        start: OffsetDateTime,
+        end: OffsetDateTime,
+        bucket_seconds: i64,
+    ) -> Result<Vec<ClimateAggregate>> {
+        let range = unix_millis(start)..=unix_millis(end);
+        // Saturating still puts every reading into the bucket at 0 or the one before it.
+        let bucket_millis = bucket_seconds.saturating_mul(1000);
+        let mut buckets: BTreeMap<(Vec<u8>, i64), (i64, [Accumulator; 3])> = BTreeMap::new();
+        for ((device_id, timestamp), values) in self.tables.lock().unwrap().climate.iter() {
+            if !range.contains(timestamp) {
+                continue;
+            }
+            let bucket_start = timestamp.div_euclid(bucket_millis) * bucket_seconds;
+            let values = [
+                values.temperature_celsius,
+                values.humidity,
+                values.co2_ppm.into(),
+            ];
+            buckets
+                .entry((device_id.clone(), bucket_start))
+                .and_modify(|(samples, accumulators)| {
+                    *samples += 1;
+                    for (accumulator, value) in accumulators.iter_mut().zip(values) {
+                        accumulator.add(value);
+                    }
+                })
+                .or_insert_with(|| (1, values.map(Accumulator::new)));
+        }
+        Ok(buckets
+            .into_iter()
+            .map(
+                |((device_id, bucket_start), (samples, [temperature, humidity, co2]))| {
+                    ClimateAggregate {
+                        device_id,
+                        bucket_start,
+                        samples,
+                        temperature_celsius: temperature.summary(samples),
+                        humidity: humidity.summary(samples),
+                        co2_ppm: co2.summary(samples),
+                    }
+                },
+            )
+            .collect())
+    }
+
+    async fn devices(&self) -> Result<Vec<Device>> {
+        Ok(self
+            .tables
+            .lock()
+            .unwrap()
+            .devices
+            .iter()
+            .map(|(device_id, device)| Device {
+                device_id: device_id.clone(),
+                first_seen: device.first_seen.div_euclid(1000),
+                last_seen: device.last_seen.div_euclid(1000),
+                clock_skew_ms: Some(device.clock_skew_ms),
+            })
+            .collect())
+    }
+
+    async fn insert_health(
+        &self,
+        device_id: &[u8],
+        time: ReadingTime,
+        health: &DeviceHealth,
+    ) -> Result<()> {
+        self.tables
+            .lock()
+            .unwrap()
+            .health
+            .insert(device_id.to_vec(), (time, health.clone()));
+        Ok(())
+    }
+
+    async fn device_health(&self) -> Result<Vec<DeviceHealthSnapshot>> {
+        Ok(self
+            .tables
+            .lock()
+            .unwrap()
+            .health
+            .iter()
+            .map(|(device_id, (time, health))| DeviceHealthSnapshot {
+                device_id: device_id.clone(),
+                device_timestamp_ms: unix_millis(time.device_timestamp),
+                received_at_ms: unix_millis(time.received_at),
+                health: health.clone(),
+            })
+            .collect())
+    }
+
+    async fn insert_firmware(&self, release: &FirmwareRelease, image: &[u8]) -> Result<()> {
+        let firmware = &mut self.tables.lock().unwrap().firmware;
+        if firmware.contains_key(&release.version) {
+            return Err(StorageError::Duplicate);
+        }
+        firmware.insert(release.version.clone(), (release.clone(), image.to_vec()));
+        Ok(())
+    }
+
+    async fn firmware_releases(&self) -> Result<Vec<FirmwareRelease>> {
+        let mut releases: Vec<_> = self
+            .tables
+            .lock()
+            .unwrap()
+            .firmware
+            .values()
+            .map(|(release, _)| release.clone())
+            .collect();
+        releases.sort_by_key(|release| std::cmp::Reverse(release.uploaded_at_ms));
+        Ok(releases)
+    }
+
+    async fn firmware_image(&self, version: &str, range: Range<usize>) -> Result<Option<Vec<u8>>> {
+        let tables = self.tables.lock().unwrap();
+        Ok(tables.firmware.get(version).map(|(_, image)| {
+            let end = range.end.min(image.len());
+            image[range.start.min(end)..end].to_vec()
+        }))
+    }
+
+    async fn set_rollout(&self, rollout: &Rollout) -> Result<()> {
+        self.tables
+            .lock()
+            .unwrap()
+            .rollouts
+            .insert(rollout.group.clone(), rollout.clone());
+        Ok(())
+    }
+
+    async fn rollouts(&self) -> Result<Vec<Rollout>> {
+        Ok(self
+            .tables
+            .lock()
+            .unwrap()
+            .rollouts
+            .values()
+            .cloned()
+            .collect())
+    }
+
+    async fn set_device_group(&self, device_id: &[u8], group: &str) -> Result<()> {
+        self.tables.lock().unwrap().firmware_device(device_id).group = group.to_string();
+        Ok(())
+    }
+
+    async fn insert_firmware_report(
+        &self,
+        device_id: &[u8],
+        report: &FirmwareReport,
+    ) -> Result<()> {
+        self.tables
+            .lock()
+            .unwrap()
+            .firmware_device(device_id)
+            .last_report = Some(report.clone());
+        Ok(())
+    }
+
+    async fn firmware_device(&self, device_id: &[u8]) -> Result<Option<FirmwareDevice>> {
+        let tables = self.tables.lock().unwrap();
+        Ok(tables.firmware_devices.get(device_id).cloned())
+    }
+
+    async fn firmware_devices(&self) -> Result<Vec<FirmwareDevice>> {
+        Ok(self
+            .tables
+            .lock()
+            .unwrap()
+            .firmware_devices
+            .values()
+            .cloned()
+            .collect())
+    }
+
+    async fn prune_climate(&self, before: OffsetDateTime) -> Result<u64> {
+        let before = unix_millis(before);
+        let readings = &mut self.tables.lock().unwrap().climate;
+        let count = readings.len();
+        readings.retain(|(_, timestamp), _| *timestamp >= before);
+        Ok((count - readings.len()) as u64)
+    }
+
+    async fn ping(&self) -> Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,1195 @@
+//! Storage backends for metrics and the device registry.
+//!
+//! The backend is picked from the scheme of `DATABASE_URL` by [`connect`]. The SQL backends run
+//! their own migrations, in `migrations/postgres` and `migrations/sqlite`.
+//!
+//! Timestamps are handled as UTC throughout: Postgres stores them as `timestamptz`, and SQLite,
+//! which has no time zones, as UTC text.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt,
+    ops::Range,
+    sync::Arc,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use sqlx::{migrate::Migrator, types::time::OffsetDateTime};
+use types::{firmware::UpdateOutcome, health::ResetReason, Climate, DeviceHealth};
+
+use crate::config::DatabaseConfig;
+
+mod memory;
+mod postgres;
+mod sqlite;
+
+pub use self::memory::MemoryStorage;
+pub use self::postgres::PostgresStorage;
+pub use self::sqlite::SqliteStorage;
+
+pub type Result<T> = std::result::Result<T, StorageError>;
+
+/// An error that can happen while talking to a storage backend.
+#[derive(Debug)]
+pub enum StorageError {
+    /// `DATABASE_URL` doesn't name a supported backend.
+    UnsupportedUrl(String),
+    /// A reading of the same device with the same timestamp, or a firmware release of the same
+    /// version, is already stored.
+    Duplicate,
+    Database(sqlx::Error),
+    Migration(sqlx::migrate::MigrateError),
+    /// The database is behind the migrations this server was built with.
+    NotMigrated {
+        applied: i64,
+        latest: i64,
+    },
+}
+
+impl StorageError {
+    /// Whether the database may just not be up yet, so connecting again can succeed.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            StorageError::Database(sqlx::Error::Io(_) | sqlx::Error::PoolTimedOut) => true,
+            // Postgres connection exceptions (08) and e.g. `cannot_connect_now` while starting.
+            StorageError::Database(sqlx::Error::Database(db)) => db
+                .code()
+                .is_some_and(|code| code.starts_with("08") || code.starts_with("57")),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::UnsupportedUrl(url) => {
+                write!(
+                    f,
+                    "unsupported database url {}, expected postgres://, sqlite: or memory:",
+                    url
+                )
+            }
+            StorageError::Duplicate => write!(f, "already stored"),
+            StorageError::Database(e) => e.fmt(f),
+            StorageError::Migration(e) => e.fmt(f),
+            StorageError::NotMigrated { applied, latest } => write!(
+                f,
+                "database is at migration {}, expected {}",
+                applied, latest
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+/// Checks that the newest migration a database reports as applied is the newest one we have.
+fn check_migrated(migrator: &Migrator, applied: Option<i64>) -> Result<()> {
+    let latest = migrator.iter().map(|m| m.version).max().unwrap_or(0);
+    let applied = applied.unwrap_or(0);
+    if applied < latest {
+        return Err(StorageError::NotMigrated { applied, latest });
+    }
+    Ok(())
+}
+
+/// Postgres `unique_violation` and SQLite `SQLITE_CONSTRAINT_PRIMARYKEY` error codes.
+const DUPLICATE_CODES: [&str; 2] = ["23505", "1555"];
+
+impl From<sqlx::Error> for StorageError {
+    fn from(e: sqlx::Error) -> Self {
+        match &e {
+            sqlx::Error::Database(db)
+                if db
+                    .code()
+                    .is_some_and(|code| DUPLICATE_CODES.contains(&code.as_ref())) =>
+            {
+                StorageError::Duplicate
+            }
+            _ => StorageError::Database(e),
+        }
+    }
+}
+
+impl From<sqlx::migrate::MigrateError> for StorageError {
+    fn from(e: sqlx::migrate::MigrateError) -> Self {
+        StorageError::Migration(e)
+    }
+}
+
+/// A stored climate reading.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ClimateMetric {
+    pub device_id: Vec<u8>,
+    /// Unix timestamp in seconds.
+    pub device_timestamp: i64,
+    /// Unix timestamp in milliseconds.
+    pub device_timestamp_ms: i64,
+    /// Unix timestamp in milliseconds of when the server received the reading, unknown for
+    /// readings stored before it was recorded.
+    pub received_at_ms: Option<i64>,
+    /// How far the device clock was ahead of the server's when the reading was received, in
+    /// milliseconds. Negative when it was behind.
+    pub clock_skew_ms: Option<i64>,
+    pub temperature_celsius: f64,
+    pub humidity: f64,
+    pub co2_ppm: i32,
+}
+
+/// Unix timestamp in milliseconds, rounded down.
+pub fn unix_millis(timestamp: OffsetDateTime) -> i64 {
+    timestamp.unix_timestamp_nanos().div_euclid(1_000_000) as i64
+}
+
+/// The instant `millis` after the unix epoch.
+pub fn from_unix_millis(millis: i64) -> OffsetDateTime {
+    OffsetDateTime::from_unix_timestamp_nanos(i128::from(millis) * 1_000_000)
+        .expect("millisecond timestamps are in range")
+}
+
+/// Minimum, maximum and average of a value over a bucket.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Summary {
+    pub min: f64,
+    pub max: f64,
+    pub avg: f64,
+}
+
+/// The climate readings of one device over one time bucket.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ClimateAggregate {
+    pub device_id: Vec<u8>,
+    /// Unix timestamp in seconds of the start of the bucket.
+    pub bucket_start: i64,
+    pub samples: i64,
+    pub temperature_celsius: Summary,
+    pub humidity: Summary,
+    pub co2_ppm: Summary,
+}
+
+/// A device that has reported at least one metric.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Device {
+    pub device_id: Vec<u8>,
+    /// Unix timestamp in seconds of the oldest reading of the device.
+    pub first_seen: i64,
+    /// Unix timestamp in seconds of the newest reading of the device.
+    pub last_seen: i64,
+    /// Clock skew of the reading received last, see [`ClimateMetric::clock_skew_ms`].
+    pub clock_skew_ms: Option<i64>,
+}
+
+/// The latest health report of a device.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct DeviceHealthSnapshot {
+    pub device_id: Vec<u8>,
+    /// Unix timestamp in milliseconds the device reported at.
+    pub device_timestamp_ms: i64,
+    /// Unix timestamp in milliseconds of when the server received the report.
+    pub received_at_ms: i64,
+    #[serde(flatten)]
+    pub health: DeviceHealth,
+}
+
+/// A firmware release devices can pull, without its image.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FirmwareRelease {
+    pub version: String,
+    /// Size of the image in bytes.
+    pub size: i64,
+    /// Lowercase hex encoded SHA-256 of the image.
+    pub sha256: String,
+    /// Unix timestamp in milliseconds of when the release was uploaded.
+    pub uploaded_at_ms: i64,
+}
+
+/// The release a group of devices is updated to.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Rollout {
+    pub group: String,
+    pub version: String,
+    /// Share of the group that is offered the release, see [`crate::firmware::in_rollout`].
+    pub percent: u8,
+    /// Unix timestamp in milliseconds of when the rollout was last changed.
+    pub updated_at_ms: i64,
+    /// Signature that lets devices install the release over a newer one, see
+    /// [`types::firmware::FirmwareUpdate::downgrade`].
+    pub downgrade: Option<String>,
+}
+
+/// What a device reported about the last update it tried to install.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FirmwareReport {
+    pub version: String,
+    pub outcome: UpdateOutcome,
+    pub message: Option<String>,
+    /// Unix timestamp in milliseconds of when the server received the report.
+    pub received_at_ms: i64,
+}
+
+/// A device that was put in a group or reported an update.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FirmwareDevice {
+    pub device_id: Vec<u8>,
+    /// [`crate::firmware::DEFAULT_GROUP`] unless the device was put in another one.
+    pub group: String,
+    pub last_report: Option<FirmwareReport>,
+}
+
+/// Row shape of the aggregation queries, shared by the SQL backends.
+#[derive(sqlx::FromRow)]
+struct ClimateAggregateRow {
+    device_id: Vec<u8>,
+    bucket_start: i64,
+    samples: i64,
+    temperature_min: f64,
+    temperature_max: f64,
+    temperature_avg: f64,
+    humidity_min: f64,
+    humidity_max: f64,
+    humidity_avg: f64,
+    co2_min: f64,
+    co2_max: f64,
+    co2_avg: f64,
+}
+
+impl From<ClimateAggregateRow> for ClimateAggregate {
+    fn from(row: ClimateAggregateRow) -> Self {
+        ClimateAggregate {
+            device_id: row.device_id,
+            bucket_start: row.bucket_start,
+            samples: row.samples,
+            temperature_celsius: Summary {
+                min: row.temperature_min,
+                max: row.temperature_max,
+                avg: row.temperature_avg,
+            },
+            humidity: Summary {
+                min: row.humidity_min,
+                max: row.humidity_max,
+                avg: row.humidity_avg,
+            },
+            co2_ppm: Summary {
+                min: row.co2_min,
+                max: row.co2_max,
+                avg: row.co2_avg,
+            },
+        }
+    }
+}
+
+/// The columns of `device_health` that hold the report itself, shared by the SQL backends.
+#[derive(sqlx::FromRow)]
+struct DeviceHealthColumns {
+    uptime_secs: i64,
+    free_heap_bytes: i64,
+    min_free_heap_bytes: i64,
+    wifi_rssi_dbm: Option<i16>,
+    reset_reason: String,
+    firmware_version: String,
+    failed_sends: i64,
+}
+
+impl From<DeviceHealthColumns> for DeviceHealth {
+    fn from(columns: DeviceHealthColumns) -> Self {
+        DeviceHealth {
+            uptime_secs: columns.uptime_secs as u64,
+            free_heap_bytes: columns.free_heap_bytes as u32,
+            min_free_heap_bytes: columns.min_free_heap_bytes as u32,
+            wifi_rssi_dbm: columns.wifi_rssi_dbm.map(|rssi| rssi as i8),
+            reset_reason: ResetReason::from_name(&columns.reset_reason),
+            firmware_version: columns.firmware_version,
+            failed_sends: columns.failed_sends as u32,
+        }
+    }
+}
+
+/// The columns of `firmware_devices` that hold the last report, shared by the SQL backends.
+#[derive(sqlx::FromRow)]
+struct FirmwareReportColumns {
+    report_version: Option<String>,
+    report_outcome: Option<String>,
+    report_message: Option<String>,
+}
+
+impl FirmwareReportColumns {
+    /// The report received at `received_at_ms`, if the device sent one.
+    fn into_report(self, received_at_ms: Option<i64>) -> Option<FirmwareReport> {
+        Some(FirmwareReport {
+            version: self.report_version?,
+            outcome: UpdateOutcome::from_name(&self.report_outcome?)?,
+            message: self.report_message,
+            received_at_ms: received_at_ms?,
+        })
+    }
+}
+
+/// When a reading was taken and when it arrived.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReadingTime {
+    /// The time the reading is stored at, by the device clock unless it was corrected.
+    pub device_timestamp: OffsetDateTime,
+    pub received_at: OffsetDateTime,
+    /// Device clock minus server clock at `received_at`, in milliseconds.
+    pub clock_skew_ms: i64,
+}
+
+/// A climate reading to store with [`Storage::insert_climate_batch`].
+#[derive(Debug)]
+pub struct ClimateReading<'a> {
+    pub device_id: &'a [u8],
+    pub time: ReadingTime,
+    pub climate: &'a Climate,
+}
+
+/// What a batch changes about a device in the registry.
+struct DeviceSpan {
+    first: OffsetDateTime,
+    last: OffsetDateTime,
+    /// Of the reading received last, i.e. the last one in the batch.
+    clock_skew_ms: i64,
+}
+
+/// The oldest and newest reading of every device in a batch, to update the registry with.
+fn device_spans<'a>(readings: &[ClimateReading<'a>]) -> BTreeMap<&'a [u8], DeviceSpan> {
+    let mut spans = BTreeMap::new();
+    for reading in readings {
+        let ReadingTime {
+            device_timestamp,
+            clock_skew_ms,
+            ..
+        } = reading.time;
+        spans
+            .entry(reading.device_id)
+            .and_modify(|span: &mut DeviceSpan| {
+                span.first = device_timestamp.min(span.first);
+                span.last = device_timestamp.max(span.last);
+                span.clock_skew_ms = clock_skew_ms;
+            })
+            .or_insert(DeviceSpan {
+                first: device_timestamp,
+                last: device_timestamp,
+                clock_skew_ms,
+            });
+    }
+    spans
+}
+
+/// Positions in `readings` of the readings stored under `keys`, the device and timestamp in
+/// milliseconds that an INSERT returned. Of readings in a batch twice, the first one counts.
+fn inserted_positions(readings: &[ClimateReading<'_>], keys: Vec<(Vec<u8>, i64)>) -> Vec<usize> {
+    let mut keys: HashSet<_> = keys.into_iter().collect();
+    readings
+        .iter()
+        .enumerate()
+        .filter(|(_, reading)| {
+            let key = (
+                reading.device_id.to_vec(),
+                unix_millis(reading.time.device_timestamp),
+            );
+            keys.remove(&key)
+        })
+        .map(|(position, _)| position)
+        .collect()
+}
+
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Stores a climate reading and records the device in the registry.
+    async fn insert_climate(
+        &self,
+        device_id: &[u8],
+        time: ReadingTime,
+        climate: &Climate,
+    ) -> Result<()>;
+
+    /// Stores many climate readings in one transaction, skipping the ones already stored
+    /// instead of failing, and returns the positions in `readings` of the ones that were new.
+    async fn insert_climate_batch(&self, readings: &[ClimateReading<'_>]) -> Result<Vec<usize>>;
+
+    /// Returns the climate readings between `start` and `end` (inclusive), newest first.
+    async fn climate_range(
+        &self,
+        start: OffsetDateTime,
+        end: OffsetDateTime,
+    ) -> Result<Vec<ClimateMetric>>;
+
+    /// Returns the newest climate reading of every device, ordered by device.
+    async fn latest_climate(&self) -> Result<Vec<ClimateMetric>>;
+
+    /// Summarizes the climate readings between `start` and `end` (inclusive) per device in
+    /// buckets of `bucket_seconds` aligned to the unix epoch, ordered by device and bucket.
+    async fn climate_aggregate(
+        &self,
+        start: OffsetDateTime,
+        end: OffsetDateTime,
+        bucket_seconds: i64,
+    ) -> Result<Vec<ClimateAggregate>>;
+
+    /// Returns every registered device.
+    async fn devices(&self) -> Result<Vec<Device>>;
+
+    /// Stores a health report of a device, replacing the one received before it.
+    async fn insert_health(
+        &self,
+        device_id: &[u8],
+        time: ReadingTime,
+        health: &DeviceHealth,
+    ) -> Result<()>;
+
+    /// Returns the latest health report of every device that sent one, ordered by device.
+    async fn device_health(&self) -> Result<Vec<DeviceHealthSnapshot>>;
+
+    /// Stores a firmware release and its image, failing with [`StorageError::Duplicate`] if the
+    /// version is stored already.
+    async fn insert_firmware(&self, release: &FirmwareRelease, image: &[u8]) -> Result<()>;
+
+    /// Returns every firmware release, newest upload first.
+    async fn firmware_releases(&self) -> Result<Vec<FirmwareRelease>>;
+
+    /// Returns the bytes in `range` of the image of a firmware release, up to its end, unless
+    /// there is no such release. Only those bytes are read, so that resumed downloads don't
+    /// load the whole image again.
+    async fn firmware_image(&self, version: &str, range: Range<usize>) -> Result<Option<Vec<u8>>>;
+
+    /// Sets the release a group is updated to, replacing the group's previous rollout. The
+    /// release must be stored.
+    async fn set_rollout(&self, rollout: &Rollout) -> Result<()>;
+
+    /// Returns the rollout of every group that has one, ordered by group.
+    async fn rollouts(&self) -> Result<Vec<Rollout>>;
+
+    /// Moves a device to another group, keeping its last report.
+    async fn set_device_group(&self, device_id: &[u8], group: &str) -> Result<()>;
+
+    /// Stores what a device reported about an update, replacing its previous report.
+    async fn insert_firmware_report(&self, device_id: &[u8], report: &FirmwareReport)
+        -> Result<()>;
+
+    /// Returns a device that was put in a group or reported an update.
+    async fn firmware_device(&self, device_id: &[u8]) -> Result<Option<FirmwareDevice>>;
+
+    /// Returns every device that was put in a group or reported an update, ordered by device.
+    async fn firmware_devices(&self) -> Result<Vec<FirmwareDevice>>;
+
+    /// Deletes the climate readings older than `before`, returning how many there were.
+    ///
+    /// Devices stay registered with their first and last reading.
+    async fn prune_climate(&self, before: OffsetDateTime) -> Result<u64>;
+
+    /// Checks that the database is reachable and fully migrated.
+    async fn ping(&self) -> Result<()>;
+}
+
+/// Connects to the backend named by the scheme of the configured url and runs the migrations.
+pub async fn connect(config: &DatabaseConfig) -> Result<Arc<dyn Storage>> {
+    let url = config.url.as_str();
+    if url.starts_with("postgres://") || url.starts_with("postgresql://") {
+        Ok(Arc::new(PostgresStorage::connect(config).await?))
+    } else if url.starts_with("sqlite:") {
+        Ok(Arc::new(SqliteStorage::connect(config).await?))
+    } else if url == "memory:" {
+        Ok(Arc::new(MemoryStorage::default()))
+    } else {
+        Err(StorageError::UnsupportedUrl(url.to_string()))
+    }
+}
+
+/// Longest wait between two attempts of [`connect_with_retry`].
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Like [`connect`], but keeps trying while the database isn't reachable, e.g. because it is
+/// still starting up, waiting twice as long after every attempt.
+pub async fn connect_with_retry(config: &DatabaseConfig) -> Result<Arc<dyn Storage>> {
+    let mut delay = Duration::from_secs(1);
+    loop {
+        match connect(config).await {
+            Err(e) if e.is_transient() => {
+                warn!("Can't connect to database, retrying in {:?}: {}", delay, e);
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_RETRY_DELAY);
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        sync::atomic::{AtomicU8, Ordering},
+        time::{SystemTime, UNIX_EPOCH},
+    };
+
+    fn database(url: &str) -> DatabaseConfig {
+        DatabaseConfig {
+            url: url.into(),
+            ..Default::default()
+        }
+    }
+
+    fn at(timestamp: i64) -> OffsetDateTime {
+        OffsetDateTime::from_unix_timestamp(timestamp).unwrap()
+    }
+
+    /// A reading received `clock_skew_ms` before the time the device stamped it with.
+    fn received(device_timestamp: OffsetDateTime, clock_skew_ms: i64) -> ReadingTime {
+        ReadingTime {
+            device_timestamp,
+            received_at: device_timestamp - Duration::from_millis(clock_skew_ms as u64),
+            clock_skew_ms,
+        }
+    }
+
+    fn climate(temperature_celsius: f32, humidity: f32, co2_ppm: i32) -> Climate {
+        Climate {
+            temperature_celsius,
+            humidity,
+            co2_ppm,
+        }
+    }
+
+    /// Device ids unique to this call, so that the tests can share a database with other data.
+    fn device_ids() -> (Vec<u8>, Vec<u8>) {
+        static CALLS: AtomicU8 = AtomicU8::new(0);
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos()
+            .to_be_bytes();
+        let mut a = nanos.to_vec();
+        a.push(CALLS.fetch_add(1, Ordering::Relaxed));
+        let mut b = a.clone();
+        a.push(b'a');
+        b.push(b'b');
+        (a, b)
+    }
+
+    /// Aligned to the minute so that buckets are predictable.
+    const T0: i64 = 16_666_667 * 60;
+
+    /// Stores the readings of two devices that most climate tests look at, out of order.
+    async fn insert_readings(storage: &dyn Storage, t0: i64) -> (Vec<u8>, Vec<u8>) {
+        let (a, b) = device_ids();
+        storage
+            .insert_climate(&a, received(at(t0 + 10), 0), &climate(20.0, 40.0, 500))
+            .await
+            .unwrap();
+        storage
+            .insert_climate(&a, received(at(t0), 0), &climate(22.0, 50.0, 700))
+            .await
+            .unwrap();
+        storage
+            .insert_climate(&a, received(at(t0 + 70), 1500), &climate(25.0, 60.0, 900))
+            .await
+            .unwrap();
+        storage
+            .insert_climate(&b, received(at(t0 + 30), 0), &climate(18.0, 30.0, 400))
+            .await
+            .unwrap();
+        (a, b)
+    }
+
+    /// The device and timestamp of the readings of `devices`, in the order they came.
+    fn keys(metrics: Vec<ClimateMetric>, devices: &[&[u8]]) -> Vec<(Vec<u8>, i64)> {
+        metrics
+            .into_iter()
+            .filter(|m| devices.contains(&m.device_id.as_slice()))
+            .map(|m| (m.device_id, m.device_timestamp))
+            .collect()
+    }
+
+    async fn climate_readings(storage: &dyn Storage) {
+        let t0 = T0;
+        let (a, b) = insert_readings(storage, t0).await;
+
+        // The key is the device and the timestamp to the millisecond.
+        assert!(matches!(
+            storage
+                .insert_climate(&b, received(at(t0 + 30), 0), &climate(0.0, 0.0, 0))
+                .await,
+            Err(StorageError::Duplicate)
+        ));
+
+        // Range queries are inclusive and newest first.
+        let metrics = storage.climate_range(at(t0), at(t0 + 70)).await.unwrap();
+        assert_eq!(
+            keys(metrics, &[&a, &b]),
+            vec![
+                (a.clone(), t0 + 70),
+                (b.clone(), t0 + 30),
+                (a.clone(), t0 + 10),
+                (a.clone(), t0),
+            ]
+        );
+        let metrics = storage
+            .climate_range(at(t0 + 1), at(t0 + 30))
+            .await
+            .unwrap();
+        assert_eq!(
+            keys(metrics, &[&a, &b]),
+            vec![(b.clone(), t0 + 30), (a.clone(), t0 + 10)]
+        );
+
+        let metrics = storage.climate_range(at(t0), at(t0)).await.unwrap();
+        let metric = metrics.into_iter().find(|m| m.device_id == a).unwrap();
+        assert_eq!(
+            metric,
+            ClimateMetric {
+                device_id: a.clone(),
+                device_timestamp: t0,
+                device_timestamp_ms: t0 * 1000,
+                received_at_ms: Some(t0 * 1000),
+                clock_skew_ms: Some(0),
+                temperature_celsius: 22.0,
+                humidity: 50.0,
+                co2_ppm: 700,
+            }
+        );
+
+        let metrics = storage
+            .climate_range(at(t0 + 70), at(t0 + 70))
+            .await
+            .unwrap();
+        let metric = metrics.into_iter().find(|m| m.device_id == a).unwrap();
+        assert_eq!(
+            (metric.received_at_ms, metric.clock_skew_ms),
+            (Some((t0 + 70) * 1000 - 1500), Some(1500))
+        );
+    }
+
+    async fn latest_climate(storage: &dyn Storage) {
+        let (a, b) = insert_readings(storage, T0).await;
+        // The newest reading of each device, not the one received last.
+        let latest: Vec<_> = storage
+            .latest_climate()
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|m| m.device_id == a || m.device_id == b)
+            .map(|m| (m.device_id, m.device_timestamp, m.temperature_celsius))
+            .collect();
+        assert_eq!(
+            latest,
+            vec![(a.clone(), T0 + 70, 25.0), (b.clone(), T0 + 30, 18.0)]
+        );
+    }
+
+    async fn climate_aggregates(storage: &dyn Storage) {
+        let t0 = T0;
+        let (a, b) = insert_readings(storage, t0).await;
+        // Aggregates are per device and bucket.
+        let aggregates: Vec<_> = storage
+            .climate_aggregate(at(t0), at(t0 + 120), 60)
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|agg| agg.device_id == a || agg.device_id == b)
+            .collect();
+        let mut expected = vec![
+            ClimateAggregate {
+                device_id: a.clone(),
+                bucket_start: t0,
+                samples: 2,
+                temperature_celsius: Summary {
+                    min: 20.0,
+                    max: 22.0,
+                    avg: 21.0,
+                },
+                humidity: Summary {
+                    min: 40.0,
+                    max: 50.0,
+                    avg: 45.0,
+                },
+                co2_ppm: Summary {
+                    min: 500.0,
+                    max: 700.0,
+                    avg: 600.0,
+                },
+            },
+            ClimateAggregate {
+                device_id: a.clone(),
+                bucket_start: t0 + 60,
+                samples: 1,
+                temperature_celsius: Summary {
+                    min: 25.0,
+                    max: 25.0,
+                    avg: 25.0,
+                },
+                humidity: Summary {
+                    min: 60.0,
+                    max: 60.0,
+                    avg: 60.0,
+                },
+                co2_ppm: Summary {
+                    min: 900.0,
+                    max: 900.0,
+                    avg: 900.0,
+                },
+            },
+            ClimateAggregate {
+                device_id: b.clone(),
+                bucket_start: t0,
+                samples: 1,
+                temperature_celsius: Summary {
+                    min: 18.0,
+                    max: 18.0,
+                    avg: 18.0,
+                },
+                humidity: Summary {
+                    min: 30.0,
+                    max: 30.0,
+                    avg: 30.0,
+                },
+                co2_ppm: Summary {
+                    min: 400.0,
+                    max: 400.0,
+                    avg: 400.0,
+                },
+            },
+        ];
+        expected.sort_by(|x, y| x.device_id.cmp(&y.device_id));
+        assert_eq!(aggregates, expected);
+
+        // Buckets wider than every timestamp put all readings since 1970 into one.
+        let aggregates = storage
+            .climate_aggregate(at(t0), at(t0 + 120), 1 << 61)
+            .await
+            .unwrap();
+        let buckets: Vec<_> = aggregates
+            .iter()
+            .filter(|agg| agg.device_id == a)
+            .map(|agg| (agg.bucket_start, agg.samples))
+            .collect();
+        assert_eq!(buckets, vec![(0, 3)]);
+    }
+
+    async fn device_registry(storage: &dyn Storage) {
+        let t0 = T0;
+        let (a, b) = insert_readings(storage, t0).await;
+        // The registry tracks the oldest and newest reading, whatever order they arrive in.
+        let devices: Vec<_> = storage
+            .devices()
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|d| d.device_id == a || d.device_id == b)
+            .collect();
+        assert_eq!(
+            devices,
+            vec![
+                Device {
+                    device_id: a.clone(),
+                    first_seen: t0,
+                    last_seen: t0 + 70,
+                    // Of the reading received last, not the newest one.
+                    clock_skew_ms: Some(1500),
+                },
+                Device {
+                    device_id: b.clone(),
+                    first_seen: t0 + 30,
+                    last_seen: t0 + 30,
+                    clock_skew_ms: Some(0),
+                },
+            ]
+        );
+    }
+
+    async fn pruning(storage: &dyn Storage) {
+        // Before what the other tests store, so that pruning leaves their readings alone.
+        let t0 = T0 - 365 * 24 * 3600;
+        let (a, b) = insert_readings(storage, t0).await;
+        // Pruning deletes readings strictly older than the cutoff.
+        assert!(storage.prune_climate(at(t0 + 10)).await.unwrap() >= 1);
+        let metrics = storage.climate_range(at(t0), at(t0 + 70)).await.unwrap();
+        assert_eq!(
+            keys(metrics, &[&a, &b]),
+            vec![
+                (a.clone(), t0 + 70),
+                (b.clone(), t0 + 30),
+                (a.clone(), t0 + 10)
+            ]
+        );
+        assert_eq!(storage.prune_climate(at(t0 + 10)).await.unwrap(), 0);
+        // Devices stay registered.
+        let devices = storage.devices().await.unwrap();
+        let device = devices.iter().find(|d| d.device_id == a).unwrap();
+        assert_eq!((device.first_seen, device.last_seen), (t0, t0 + 70));
+    }
+
+    async fn climate_batches(storage: &dyn Storage) {
+        let t0 = T0;
+        let (a, _) = insert_readings(storage, t0).await;
+        // Batches skip what is already stored, also within the batch, and register devices.
+        let (c, _) = device_ids();
+        let warm = climate(30.0, 20.0, 1000);
+        let reading = |device_id, offset| ClimateReading {
+            device_id,
+            time: received(at(t0 + offset), 0),
+            climate: &warm,
+        };
+        let batch = [
+            reading(&a, 70),
+            reading(&c, 90),
+            reading(&c, 80),
+            reading(&c, 90),
+            reading(&a, 100),
+        ];
+        assert_eq!(
+            storage.insert_climate_batch(&batch).await.unwrap(),
+            vec![1, 2, 4]
+        );
+        assert_eq!(
+            storage.insert_climate_batch(&[]).await.unwrap(),
+            Vec::<usize>::new()
+        );
+        let metrics = storage
+            .climate_range(at(t0 + 70), at(t0 + 100))
+            .await
+            .unwrap();
+        let stored: Vec<_> = metrics
+            .iter()
+            .filter(|m| m.device_id == a || m.device_id == c)
+            .map(|m| {
+                (
+                    m.device_id.clone(),
+                    m.device_timestamp,
+                    m.temperature_celsius,
+                )
+            })
+            .collect();
+        assert_eq!(
+            stored,
+            vec![
+                (a.clone(), t0 + 100, 30.0),
+                (c.clone(), t0 + 90, 30.0),
+                (c.clone(), t0 + 80, 30.0),
+                // The reading already stored wins.
+                (a.clone(), t0 + 70, 25.0),
+            ]
+        );
+        let devices = storage.devices().await.unwrap();
+        let device = |id: &[u8]| devices.iter().find(|d| d.device_id == id).unwrap();
+        assert_eq!(
+            (device(&a).first_seen, device(&a).last_seen),
+            (t0, t0 + 100)
+        );
+        assert_eq!(
+            (device(&c).first_seen, device(&c).last_seen),
+            (t0 + 80, t0 + 90)
+        );
+    }
+
+    async fn millisecond_timestamps(storage: &dyn Storage) {
+        // Readings within the same second are told apart by their milliseconds.
+        let (d, _) = device_ids();
+        let warm = climate(30.0, 20.0, 1000);
+        let millis =
+            |offset: i64, millis: u16| at(T0 + offset).replace_millisecond(millis).unwrap();
+        for ms in [250, 750] {
+            storage
+                .insert_climate(&d, received(millis(200, ms), 0), &warm)
+                .await
+                .unwrap();
+        }
+        let metrics = storage
+            .climate_range(millis(200, 0), millis(200, 500))
+            .await
+            .unwrap();
+        let stored: Vec<_> = metrics
+            .iter()
+            .filter(|m| m.device_id == d)
+            .map(|m| (m.device_timestamp, m.device_timestamp_ms))
+            .collect();
+        assert_eq!(stored, vec![(T0 + 200, (T0 + 200) * 1000 + 250)]);
+    }
+
+    async fn daylight_saving_time(storage: &dyn Storage) {
+        // Both 01:30 in New York on 2023-11-05, an hour apart, when clocks went back.
+        let (e, _) = device_ids();
+        let warm = climate(30.0, 20.0, 1000);
+        let edt = 1_699_162_200;
+        let est = edt + 3600;
+        for timestamp in [edt, est] {
+            storage
+                .insert_climate(&e, received(at(timestamp), 0), &warm)
+                .await
+                .unwrap();
+        }
+        let metrics = storage.climate_range(at(edt), at(est)).await.unwrap();
+        let stored: Vec<_> = metrics
+            .iter()
+            .filter(|m| m.device_id == e)
+            .map(|m| m.device_timestamp)
+            .collect();
+        assert_eq!(stored, vec![est, edt]);
+        let buckets = storage
+            .climate_aggregate(at(edt), at(est), 3600)
+            .await
+            .unwrap();
+        let buckets: Vec<_> = buckets
+            .iter()
+            .filter(|b| b.device_id == e)
+            .map(|b| (b.bucket_start, b.samples))
+            .collect();
+        assert_eq!(buckets, vec![(edt - 1800, 1), (est - 1800, 1)]);
+        let devices = storage.devices().await.unwrap();
+        let device = devices.iter().find(|device| device.device_id == e).unwrap();
+        assert_eq!((device.first_seen, device.last_seen), (edt, est));
+    }
+
+    async fn health_reports(storage: &dyn Storage) {
+        // Only the health report received last is kept, and reports don't register devices.
+        let (f, _) = device_ids();
+        let report = |uptime_secs, wifi_rssi_dbm| DeviceHealth {
+            uptime_secs,
+            free_heap_bytes: 120_000,
+            min_free_heap_bytes: 90_000,
+            wifi_rssi_dbm,
+            reset_reason: ResetReason::PowerOn,
+            firmware_version: "0.1.0".into(),
+            failed_sends: 0,
+        };
+        storage
+            .insert_health(&f, received(at(T0), 0), &report(3600, Some(-67)))
+            .await
+            .unwrap();
+        let crashed = DeviceHealth {
+            reset_reason: ResetReason::Panic,
+            failed_sends: 3,
+            ..report(5, None)
+        };
+        storage
+            .insert_health(&f, received(at(T0 + 300), 250), &crashed)
+            .await
+            .unwrap();
+        let reports = storage.device_health().await.unwrap();
+        let ours: Vec<_> = reports.iter().filter(|r| r.device_id == f).collect();
+        assert_eq!(
+            ours,
+            vec![&DeviceHealthSnapshot {
+                device_id: f.clone(),
+                device_timestamp_ms: (T0 + 300) * 1000,
+                received_at_ms: (T0 + 300) * 1000 - 250,
+                health: crashed,
+            }]
+        );
+        let devices = storage.devices().await.unwrap();
+        assert!(devices.iter().all(|device| device.device_id != f));
+    }
+
+    /// Hex encoded device id, to name versions and groups unique to a test.
+    fn run_name(device_id: &[u8]) -> String {
+        device_id.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// Stores an older and a newer release named after `run`.
+    async fn insert_releases(
+        storage: &dyn Storage,
+        run: &str,
+    ) -> (FirmwareRelease, FirmwareRelease) {
+        let release = |version: String, uploaded_at_ms| FirmwareRelease {
+            version,
+            size: 3,
+            sha256: "ab".repeat(32),
+            uploaded_at_ms,
+        };
+        let old = release(format!("{}-1", run), T0 * 1000);
+        let new = release(format!("{}-2", run), T0 * 1000 + 1);
+        storage.insert_firmware(&old, b"old").await.unwrap();
+        storage.insert_firmware(&new, b"new").await.unwrap();
+        (old, new)
+    }
+
+    async fn firmware_releases(storage: &dyn Storage) {
+        // Releases keep their image, and a version can't be uploaded twice.
+        let run = run_name(&device_ids().0);
+        let (old, new) = insert_releases(storage, &run).await;
+        assert!(matches!(
+            storage.insert_firmware(&old, b"again").await,
+            Err(StorageError::Duplicate)
+        ));
+        let releases = storage.firmware_releases().await.unwrap();
+        let ours: Vec<_> = releases
+            .iter()
+            .filter(|r| r.version.starts_with(&run))
+            .collect();
+        assert_eq!(ours, vec![&new, &old]);
+        assert_eq!(
+            storage.firmware_image(&old.version, 0..3).await.unwrap(),
+            Some(b"old".to_vec())
+        );
+        assert_eq!(
+            storage.firmware_image(&old.version, 1..10).await.unwrap(),
+            Some(b"ld".to_vec())
+        );
+        assert_eq!(storage.firmware_image(&run, 0..3).await.unwrap(), None);
+    }
+
+    async fn firmware_rollouts(storage: &dyn Storage) {
+        // Setting the rollout of a group replaces it.
+        let run = run_name(&device_ids().0);
+        let (old, new) = insert_releases(storage, &run).await;
+        let rollout = |version: &FirmwareRelease, percent| Rollout {
+            group: run.clone(),
+            version: version.version.clone(),
+            percent,
+            updated_at_ms: T0 * 1000,
+            downgrade: None,
+        };
+        storage.set_rollout(&rollout(&old, 100)).await.unwrap();
+        storage.set_rollout(&rollout(&new, 25)).await.unwrap();
+        let rollouts = storage.rollouts().await.unwrap();
+        let ours: Vec<_> = rollouts.iter().filter(|r| r.group == run).collect();
+        assert_eq!(ours, vec![&rollout(&new, 25)]);
+        // Rolling back keeps the signature that lets devices downgrade.
+        let rollback = Rollout {
+            downgrade: Some("ab".repeat(64)),
+            ..rollout(&old, 100)
+        };
+        storage.set_rollout(&rollback).await.unwrap();
+        let rollouts = storage.rollouts().await.unwrap();
+        let ours: Vec<_> = rollouts.iter().filter(|r| r.group == run).collect();
+        assert_eq!(ours, vec![&rollback]);
+    }
+
+    async fn firmware_devices(storage: &dyn Storage) {
+        // Devices join the default group when they first report, and keep their group after.
+        let (g, h) = device_ids();
+        let run = run_name(&g);
+        let (_, new) = insert_releases(storage, &run).await;
+        assert_eq!(storage.firmware_device(&g).await.unwrap(), None);
+        let failed = FirmwareReport {
+            version: new.version.clone(),
+            outcome: UpdateOutcome::Failed,
+            message: Some("hash mismatch".into()),
+            received_at_ms: T0 * 1000 + 5,
+        };
+        storage.insert_firmware_report(&g, &failed).await.unwrap();
+        storage.set_device_group(&h, &run).await.unwrap();
+        let installed = FirmwareReport {
+            outcome: UpdateOutcome::Installed,
+            message: None,
+            ..failed.clone()
+        };
+        storage
+            .insert_firmware_report(&h, &installed)
+            .await
+            .unwrap();
+        assert_eq!(
+            storage.firmware_device(&g).await.unwrap(),
+            Some(FirmwareDevice {
+                device_id: g.clone(),
+                group: crate::firmware::DEFAULT_GROUP.into(),
+                last_report: Some(failed),
+            })
+        );
+        storage.set_device_group(&g, &run).await.unwrap();
+        let devices = storage.firmware_devices().await.unwrap();
+        let ours: Vec<_> = devices
+            .iter()
+            .filter(|d| d.device_id == g || d.device_id == h)
+            .map(|d| (&d.group, d.last_report.as_ref().map(|r| r.outcome)))
+            .collect();
+        assert_eq!(
+            ours,
+            vec![
+                (&run, Some(UpdateOutcome::Failed)),
+                (&run, Some(UpdateOutcome::Installed))
+            ]
+        );
+    }
+
+    /// The behavior every backend must have, one storage feature at a time.
+    async fn suite(storage: Arc<dyn Storage>) {
+        let storage = &*storage;
+        storage.ping().await.unwrap();
+        climate_readings(storage).await;
+        latest_climate(storage).await;
+        climate_aggregates(storage).await;
+        device_registry(storage).await;
+        pruning(storage).await;
+        climate_batches(storage).await;
+        millisecond_timestamps(storage).await;
+        daylight_saving_time(storage).await;
+        health_reports(storage).await;
+        firmware_releases(storage).await;
+        firmware_rollouts(storage).await;
+        firmware_devices(storage).await;
+    }
+
+    #[tokio::test]
+    async fn memory() {
+        suite(connect(&database("memory:")).await.unwrap()).await;
+    }
+
+    #[tokio::test]
+    async fn sqlite() {
+        suite(connect(&database("sqlite::memory:")).await.unwrap()).await;
+    }
+
+    /// Runs against the database named by `TEST_DATABASE_URL`, e.g. the docker-compose one.
+    #[tokio::test]
+    async fn postgres() {
+        let Ok(url) = std::env::var("TEST_DATABASE_URL") else {
+            eprintln!("TEST_DATABASE_URL not set, skipping the Postgres storage tests");
+            return;
+        };
+        suite(connect(&database(&url)).await.unwrap()).await;
+    }
+
+    /// The session time zone must not change what is stored or read back.
+    #[tokio::test]
+    async fn postgres_in_another_time_zone() {
+        let Ok(url) = std::env::var("TEST_DATABASE_URL") else {
+            eprintln!("TEST_DATABASE_URL not set, skipping the Postgres storage tests");
+            return;
+        };
+        let separator = if url.contains('?') { '&' } else { '?' };
+        let url = format!(
+            "{}{}options=-c%20TimeZone%3DAmerica%2FNew_York",
+            url, separator
+        );
+        suite(connect(&database(&url)).await.unwrap()).await;
+    }
+
+    #[test]
+    fn checks_migrations() {
+        let migrator = &postgres::MIGRATOR;
+        let latest = migrator.iter().map(|m| m.version).max().unwrap();
+        assert!(check_migrated(migrator, Some(latest)).is_ok());
+        assert!(matches!(
+            check_migrated(migrator, Some(latest - 1)),
+            Err(StorageError::NotMigrated { .. })
+        ));
+        assert!(matches!(
+            check_migrated(migrator, None),
+            Err(StorageError::NotMigrated { applied: 0, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn retries_only_unreachable_databases() {
+        let config = DatabaseConfig {
+            acquire_timeout_secs: 1,
+            ..database("postgres://postgres@127.0.0.1:1/metrics")
+        };
+        let refused = connect(&config).await.err().unwrap();
+        assert!(refused.is_transient(), "{}", refused);
+        assert!(!StorageError::UnsupportedUrl("mysql://".into()).is_transient());
+        assert!(!StorageError::Duplicate.is_transient());
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_scheme() {
+        assert!(matches!(
+            connect(&database("mysql://localhost/metrics")).await,
+            Err(StorageError::UnsupportedUrl(_))
+        ));
+    }
+}
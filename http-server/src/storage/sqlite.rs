@@ -0,0 +1,517 @@
+use std::{ops::Range, str::FromStr};
+
+use async_trait::async_trait;
+use chrono::NaiveDateTime;
+use sqlx::types::time::OffsetDateTime;
+use sqlx::{
+    migrate::Migrator,
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+    QueryBuilder, Sqlite, SqlitePool,
+};
+use types::{Climate, DeviceHealth};
+
+use crate::config::DatabaseConfig;
+
+use super::{
+    check_migrated, device_spans, from_unix_millis, inserted_positions, unix_millis,
+    ClimateAggregate, ClimateAggregateRow, ClimateMetric, ClimateReading, Device,
+    DeviceHealthColumns, DeviceHealthSnapshot, FirmwareDevice, FirmwareRelease, FirmwareReport,
+    FirmwareReportColumns, ReadingTime, Result, Rollout, Storage,
+};
+
+/// The migrations in `migrations/sqlite`, applied when connecting.
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations/sqlite");
+
+/// Storage backed by a SQLite database file, or an in-memory one for `sqlite::memory:`.
+///
+/// Timestamps are stored as UTC text (`YYYY-MM-DD HH:MM:SS`, with milliseconds when there are
+/// any), the same format as SQLite's own `CURRENT_TIMESTAMP`, so they compare and sort
+/// chronologically.
+pub struct SqliteStorage {
+    pool: SqlitePool,
+}
+
+impl SqliteStorage {
+    pub async fn connect(config: &DatabaseConfig) -> Result<Self> {
+        let options = SqliteConnectOptions::from_str(&config.url)?.create_if_missing(true);
+        let pool = if config.url.contains(":memory:") {
+            // Every connection to an in-memory database gets its own, empty database, so
+            // there must be exactly one that is never closed.
+            SqlitePoolOptions::new()
+                .max_connections(1)
+                .idle_timeout(None)
+                .max_lifetime(None)
+        } else {
+            SqlitePoolOptions::new()
+                .max_connections(config.max_connections)
+                .min_connections(config.min_connections)
+        }
+        .acquire_timeout(config.acquire_timeout())
+        .connect_with(options)
+        .await?;
+        MIGRATOR.run(&pool).await?;
+        Ok(SqliteStorage { pool })
+    }
+}
+
+fn to_utc(timestamp: OffsetDateTime) -> NaiveDateTime {
+    NaiveDateTime::from_timestamp_opt(timestamp.unix_timestamp(), timestamp.nanosecond())
+        .expect("OffsetDateTime is always in range")
+}
+
+fn from_utc(timestamp: NaiveDateTime) -> OffsetDateTime {
+    OffsetDateTime::from_unix_timestamp(timestamp.timestamp())
+        .and_then(|t| t.replace_nanosecond(timestamp.timestamp_subsec_nanos()))
+        .expect("stored timestamps are in range")
+}
+
+/// Rows per INSERT, below the 999 bind parameters older SQLite versions allow per statement.
+const BATCH_ROWS: usize = 100;
+
+#[derive(sqlx::FromRow)]
+struct ClimateMetricRow {
+    device_id: Vec<u8>,
+    device_timestamp: NaiveDateTime,
+    received_at: Option<NaiveDateTime>,
+    clock_skew_ms: Option<i64>,
+    temperature_celsius: f64,
+    humidity: f64,
+    co2_ppm: i32,
+}
+
+impl From<ClimateMetricRow> for ClimateMetric {
+    fn from(row: ClimateMetricRow) -> Self {
+        let device_timestamp = from_utc(row.device_timestamp);
+        ClimateMetric {
+            device_id: row.device_id,
+            device_timestamp: device_timestamp.unix_timestamp(),
+            device_timestamp_ms: unix_millis(device_timestamp),
+            received_at_ms: row
+                .received_at
+                .map(|received_at| unix_millis(from_utc(received_at))),
+            clock_skew_ms: row.clock_skew_ms,
+            temperature_celsius: row.temperature_celsius,
+            humidity: row.humidity,
+            co2_ppm: row.co2_ppm,
+        }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct DeviceRow {
+    device_id: Vec<u8>,
+    first_seen: NaiveDateTime,
+    last_seen: NaiveDateTime,
+    clock_skew_ms: Option<i64>,
+}
+
+#[derive(sqlx::FromRow)]
+struct DeviceHealthRow {
+    device_id: Vec<u8>,
+    device_timestamp: NaiveDateTime,
+    received_at: NaiveDateTime,
+    #[sqlx(flatten)]
+    health: DeviceHealthColumns,
+}
+
+#[derive(sqlx::FromRow)]
+struct FirmwareReleaseRow {
+    version: String,
+    size: i64,
+    sha256: String,
+    uploaded_at: NaiveDateTime,
+}
+
+impl From<FirmwareReleaseRow> for FirmwareRelease {
+    fn from(row: FirmwareReleaseRow) -> Self {
+        FirmwareRelease {
+            version: row.version,
+            size: row.size,
+            sha256: row.sha256,
+            uploaded_at_ms: unix_millis(from_utc(row.uploaded_at)),
+        }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct RolloutRow {
+    device_group: String,
+    version: String,
+    percent: i16,
+    updated_at: NaiveDateTime,
+    downgrade: Option<String>,
+}
+
+#[derive(sqlx::FromRow)]
+struct FirmwareDeviceRow {
+    device_id: Vec<u8>,
+    device_group: String,
+    reported_at: Option<NaiveDateTime>,
+    #[sqlx(flatten)]
+    report: FirmwareReportColumns,
+}
+
+impl From<FirmwareDeviceRow> for FirmwareDevice {
+    fn from(row: FirmwareDeviceRow) -> Self {
+        FirmwareDevice {
+            device_id: row.device_id,
+            group: row.device_group,
+            last_report: row.report.into_report(
+                row.reported_at
+                    .map(|reported_at| unix_millis(from_utc(reported_at))),
+            ),
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn insert_climate(
+        &self,
+        device_id: &[u8],
+        time: ReadingTime,
+        climate: &Climate,
+    ) -> Result<()> {
+        let device_timestamp = to_utc(time.device_timestamp);
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("INSERT INTO climate_metrics (device_id, device_timestamp, received_at, clock_skew_ms, temperature_celsius, humidity, co2_ppm) VALUES (?, ?, ?, ?, ?, ?, ?)")
+            .bind(device_id)
+            .bind(device_timestamp)
+            .bind(to_utc(time.received_at))
+            .bind(time.clock_skew_ms)
+            .bind(climate.temperature_celsius)
+            .bind(climate.humidity)
+            .bind(climate.co2_ppm)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query(
+            "INSERT INTO devices (device_id, first_seen, last_seen, clock_skew_ms) VALUES (?1, ?2, ?2, ?3) \
+            ON CONFLICT (device_id) DO UPDATE SET \
+            first_seen = min(devices.first_seen, excluded.first_seen), \
+            last_seen = max(devices.last_seen, excluded.last_seen), \
+            clock_skew_ms = excluded.clock_skew_ms",
+        )
+        .bind(device_id)
+        .bind(device_timestamp)
+        .bind(time.clock_skew_ms)
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn insert_climate_batch(&self, readings: &[ClimateReading<'_>]) -> Result<Vec<usize>> {
+        let mut tx = self.pool.begin().await?;
+        let mut inserted = Vec::new();
+        for (i, chunk) in readings.chunks(BATCH_ROWS).enumerate() {
+            let mut query = QueryBuilder::<Sqlite>::new(
+                "INSERT INTO climate_metrics (device_id, device_timestamp, received_at, clock_skew_ms, temperature_celsius, humidity, co2_ppm) ",
+            );
+            query.push_values(chunk, |mut row, reading| {
+                row.push_bind(reading.device_id)
+                    .push_bind(to_utc(reading.time.device_timestamp))
+                    .push_bind(to_utc(reading.time.received_at))
+                    .push_bind(reading.time.clock_skew_ms)
+                    .push_bind(reading.climate.temperature_celsius)
+                    .push_bind(reading.climate.humidity)
+                    .push_bind(reading.climate.co2_ppm);
+            });
+            query.push(" ON CONFLICT DO NOTHING RETURNING device_id, device_timestamp");
+            let keys = query
+                .build_query_as::<(Vec<u8>, NaiveDateTime)>()
+                .fetch_all(&mut tx)
+                .await?
+                .into_iter()
+                .map(|(device_id, timestamp)| (device_id, unix_millis(from_utc(timestamp))))
+                .collect();
+            inserted.extend(
+                inserted_positions(chunk, keys)
+                    .into_iter()
+                    .map(|position| i * BATCH_ROWS + position),
+            );
+        }
+        for (device_id, span) in device_spans(readings) {
+            sqlx::query(
+                "INSERT INTO devices (device_id, first_seen, last_seen, clock_skew_ms) VALUES (?1, ?2, ?3, ?4) \
+                ON CONFLICT (device_id) DO UPDATE SET \
+                first_seen = min(devices.first_seen, excluded.first_seen), \
+                last_seen = max(devices.last_seen, excluded.last_seen), \
+                clock_skew_ms = excluded.clock_skew_ms",
+            )
+            .bind(device_id)
+            .bind(to_utc(span.first))
+            .bind(to_utc(span.last))
+            .bind(span.clock_skew_ms)
+            .execute(&mut tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(inserted)
+    }
+
+    async fn climate_range(
+        &self,
+        start: OffsetDateTime,
+        end: OffsetDateTime,
+    ) -> Result<Vec<ClimateMetric>> {
+        let query_str = "select * from climate_metrics where device_timestamp between ? and ? order by device_timestamp desc";
+        let rows = sqlx::query_as::<Sqlite, ClimateMetricRow>(query_str)
+            .bind(to_utc(start))
+            .bind(to_utc(end))
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rows.into_iter().map(ClimateMetric::from).collect())
+    }
+
+    async fn latest_climate(&self) -> Result<Vec<ClimateMetric>> {
+        let query_str = "select c.* from climate_metrics c join \
+            (select device_id, max(device_timestamp) as device_timestamp from climate_metrics group by device_id) newest \
+            on c.device_id = newest.device_id and c.device_timestamp = newest.device_timestamp \
+            order by c.device_id";
+        let rows = sqlx::query_as::<Sqlite, ClimateMetricRow>(query_str)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rows.into_iter().map(ClimateMetric::from).collect())
+    }
+
+    async fn climate_aggregate(
+        &self,
+        start: OffsetDateTime,
+        end: OffsetDateTime,
+        bucket_seconds: i64,
+    ) -> Result<Vec<ClimateAggregate>> {
+        // Integer division truncates towards zero, so buckets before 1970 are shifted.
+        let query_str = "select device_id, \
+            cast(strftime('%s', device_timestamp) as integer) / ?3 * ?3 as bucket_start, \
+            count(*) as samples, \
+            cast(min(temperature_celsius) as real) as temperature_min, \
+            cast(max(temperature_celsius) as real) as temperature_max, \
+            avg(temperature_celsius) as temperature_avg, \
+            cast(min(humidity) as real) as humidity_min, \
+            cast(max(humidity) as real) as humidity_max, \
+            avg(humidity) as humidity_avg, \
+            cast(min(co2_ppm) as real) as co2_min, \
+            cast(max(co2_ppm) as real) as co2_max, \
+            avg(co2_ppm) as co2_avg \
+            from climate_metrics where device_timestamp between ?1 and ?2 \
+            group by device_id, bucket_start order by device_id, bucket_start";
+        let rows = sqlx::query_as::<Sqlite, ClimateAggregateRow>(query_str)
+            .bind(to_utc(start))
+            .bind(to_utc(end))
+            .bind(bucket_seconds)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rows.into_iter().map(ClimateAggregate::from).collect())
+    }
+
+    async fn devices(&self) -> Result<Vec<Device>> {
+        let rows = sqlx::query_as::<Sqlite, DeviceRow>(
+            "select device_id, first_seen, last_seen, clock_skew_ms from devices order by device_id",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| Device {
+                device_id: row.device_id,
+                first_seen: row.first_seen.timestamp(),
+                last_seen: row.last_seen.timestamp(),
+                clock_skew_ms: row.clock_skew_ms,
+            })
+            .collect())
+    }
+
+    async fn insert_health(
+        &self,
+        device_id: &[u8],
+        time: ReadingTime,
+        health: &DeviceHealth,
+    ) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO device_health (device_id, device_timestamp, received_at, uptime_secs, free_heap_bytes, min_free_heap_bytes, wifi_rssi_dbm, reset_reason, firmware_version, failed_sends) \
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?) \
+            ON CONFLICT (device_id) DO UPDATE SET \
+            device_timestamp = excluded.device_timestamp, \
+            received_at = excluded.received_at, \
+            uptime_secs = excluded.uptime_secs, \
+            free_heap_bytes = excluded.free_heap_bytes, \
+            min_free_heap_bytes = excluded.min_free_heap_bytes, \
+            wifi_rssi_dbm = excluded.wifi_rssi_dbm, \
+            reset_reason = excluded.reset_reason, \
+            firmware_version = excluded.firmware_version, \
+            failed_sends = excluded.failed_sends",
+        )
+        .bind(device_id)
+        .bind(to_utc(time.device_timestamp))
+        .bind(to_utc(time.received_at))
+        .bind(health.uptime_secs as i64)
+        .bind(i64::from(health.free_heap_bytes))
+        .bind(i64::from(health.min_free_heap_bytes))
+        .bind(health.wifi_rssi_dbm.map(i16::from))
+        .bind(health.reset_reason.as_str())
+        .bind(&health.firmware_version)
+        .bind(i64::from(health.failed_sends))
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn device_health(&self) -> Result<Vec<DeviceHealthSnapshot>> {
+        let rows = sqlx::query_as::<Sqlite, DeviceHealthRow>(
+            "select * from device_health order by device_id",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| DeviceHealthSnapshot {
+                device_id: row.device_id,
+                device_timestamp_ms: unix_millis(from_utc(row.device_timestamp)),
+                received_at_ms: unix_millis(from_utc(row.received_at)),
+                health: row.health.into(),
+            })
+            .collect())
+    }
+
+    async fn insert_firmware(&self, release: &FirmwareRelease, image: &[u8]) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO firmware_releases (version, size, sha256, uploaded_at, image) VALUES (?1, ?2, ?3, ?4, ?5)",
+        )
+        .bind(&release.version)
+        .bind(release.size)
+        .bind(&release.sha256)
+        .bind(to_utc(from_unix_millis(release.uploaded_at_ms)))
+        .bind(image)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn firmware_releases(&self) -> Result<Vec<FirmwareRelease>> {
+        let rows = sqlx::query_as::<Sqlite, FirmwareReleaseRow>(
+            "select version, size, sha256, uploaded_at from firmware_releases order by uploaded_at desc, version",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(FirmwareRelease::from).collect())
+    }
+
+    async fn firmware_image(&self, version: &str, range: Range<usize>) -> Result<Option<Vec<u8>>> {
+        Ok(sqlx::query_scalar(
+            "select substr(image, ?2, ?3) from firmware_releases where version = ?1",
+        )
+        .bind(version)
+        .bind(range.start as i64 + 1)
+        .bind(range.len() as i64)
+        .fetch_optional(&self.pool)
+        .await?)
+    }
+
+    async fn set_rollout(&self, rollout: &Rollout) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO firmware_rollouts (device_group, version, percent, updated_at, downgrade) VALUES (?1, ?2, ?3, ?4, ?5) \
+            ON CONFLICT (device_group) DO UPDATE SET \
+            version = excluded.version, \
+            percent = excluded.percent, \
+            updated_at = excluded.updated_at, \
+            downgrade = excluded.downgrade",
+        )
+        .bind(&rollout.group)
+        .bind(&rollout.version)
+        .bind(i16::from(rollout.percent))
+        .bind(to_utc(from_unix_millis(rollout.updated_at_ms)))
+        .bind(&rollout.downgrade)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn rollouts(&self) -> Result<Vec<Rollout>> {
+        let rows = sqlx::query_as::<Sqlite, RolloutRow>(
+            "select * from firmware_rollouts order by device_group",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| Rollout {
+                group: row.device_group,
+                version: row.version,
+                percent: row.percent as u8,
+                updated_at_ms: unix_millis(from_utc(row.updated_at)),
+                downgrade: row.downgrade,
+            })
+            .collect())
+    }
+
+    async fn set_device_group(&self, device_id: &[u8], group: &str) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO firmware_devices (device_id, device_group) VALUES (?1, ?2) \
+            ON CONFLICT (device_id) DO UPDATE SET device_group = excluded.device_group",
+        )
+        .bind(device_id)
+        .bind(group)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn insert_firmware_report(
+        &self,
+        device_id: &[u8],
+        report: &FirmwareReport,
+    ) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO firmware_devices (device_id, report_version, report_outcome, report_message, reported_at) \
+            VALUES (?1, ?2, ?3, ?4, ?5) \
+            ON CONFLICT (device_id) DO UPDATE SET \
+            report_version = excluded.report_version, \
+            report_outcome = excluded.report_outcome, \
+            report_message = excluded.report_message, \
+            reported_at = excluded.reported_at",
+        )
+        .bind(device_id)
+        .bind(&report.version)
+        .bind(report.outcome.as_str())
+        .bind(&report.message)
+        .bind(to_utc(from_unix_millis(report.received_at_ms)))
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn firmware_device(&self, device_id: &[u8]) -> Result<Option<FirmwareDevice>> {
+        let row = sqlx::query_as::<Sqlite, FirmwareDeviceRow>(
+            "select * from firmware_devices where device_id = ?1",
+        )
+        .bind(device_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(FirmwareDevice::from))
+    }
+
+    async fn firmware_devices(&self) -> Result<Vec<FirmwareDevice>> {
+        let rows = sqlx::query_as::<Sqlite, FirmwareDeviceRow>(
+            "select * from firmware_devices order by device_id",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(FirmwareDevice::from).collect())
+    }
+
+    async fn prune_climate(&self, before: OffsetDateTime) -> Result<u64> {
+        let result = sqlx::query("delete from climate_metrics where device_timestamp < ?")
+            .bind(to_utc(before))
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn ping(&self) -> Result<()> {
+        let applied: Option<i64> =
+            sqlx::query_scalar("select max(version) from _sqlx_migrations where success")
+                .fetch_one(&self.pool)
+                .await?;
+        check_migrated(&MIGRATOR, applied)
+    }
+}
@@ -0,0 +1,1066 @@
+//! End-to-end tests of the HTTP API, run against the in-memory storage backend.
+
+use std::{net::SocketAddr, sync::Arc};
+
+use axum::{
+    body::Body,
+    extract::ConnectInfo,
+    http::{header, Method, Request, StatusCode},
+    Router,
+};
+use serde_json::{json, Value};
+use sqlx::types::time::OffsetDateTime;
+use tokio::sync::broadcast;
+use tower::ServiceExt;
+use types::{Climate, MetricRequestBody};
+
+use crate::{
+    buffer::WriteBuffer,
+    clock::ClockPolicy,
+    config::{ClockConfig, IngestConfig, LimitsConfig, StatusConfig},
+    ingest::Accepted,
+    ratelimit::{Limits, Rate},
+    router,
+    storage::{MemoryStorage, ReadingTime, Storage},
+    AppState,
+};
+
+const T0: i64 = 1_684_000_000;
+const ADMIN_TOKEN: &str = "admin-secret";
+
+fn app() -> (Router, broadcast::Receiver<Arc<MetricRequestBody>>) {
+    app_with_limits(LimitsConfig::default())
+}
+
+fn app_with_limits(limits: LimitsConfig) -> (Router, broadcast::Receiver<Arc<MetricRequestBody>>) {
+    let accepted = Accepted::new(16);
+    let receiver = accepted.subscribe();
+    let state = AppState {
+        storage: Arc::new(MemoryStorage::default()),
+        accepted,
+        static_dir: None,
+        limits: Arc::new(Limits::new(&limits)),
+        buffer: None,
+        clock: Arc::default(),
+        status: Arc::default(),
+        admin_token: Some(ADMIN_TOKEN.into()),
+    };
+    (router(state), receiver)
+}
+
+fn metric(device_id: &[u8], timestamp: i64, temperature_celsius: f32) -> Value {
+    json!({
+        "topic": {
+            "Climate": {
+                "temperature_celsius": temperature_celsius,
+                "humidity": 40.0,
+                "co2_ppm": 600,
+            }
+        },
+        "timestamp": timestamp,
+        "device_id": device_id,
+    })
+}
+
+fn health(device_id: &[u8], timestamp: i64, uptime_secs: u64) -> Value {
+    json!({
+        "topic": {
+            "DeviceHealth": {
+                "uptime_secs": uptime_secs,
+                "free_heap_bytes": 120_000,
+                "min_free_heap_bytes": 90_000,
+                "wifi_rssi_dbm": -67,
+                "reset_reason": "power_on",
+                "firmware_version": "0.1.0",
+                "failed_sends": 0,
+            }
+        },
+        "timestamp": timestamp,
+        "device_id": device_id,
+    })
+}
+
+/// Sends a request and returns the status, content type and body of the response.
+async fn send(app: &Router, request: Request<Body>) -> (StatusCode, Option<String>, Vec<u8>) {
+    let response = app.clone().oneshot(request).await.unwrap();
+    let status = response.status();
+    let content_type = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .map(|value| value.to_str().unwrap().to_string());
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    (status, content_type, body.to_vec())
+}
+
+async fn get(app: &Router, uri: &str) -> (StatusCode, Value) {
+    let request = Request::get(uri).body(Body::empty()).unwrap();
+    let (status, _, body) = send(app, request).await;
+    let body = serde_json::from_slice(&body).unwrap_or(Value::Null);
+    (status, body)
+}
+
+async fn post_json(app: &Router, body: &Value) -> StatusCode {
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri("/metric")
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap();
+    send(app, request).await.0
+}
+
+/// The message of an error response, which is sent as bytes.
+fn message(body: &Value) -> String {
+    let bytes: Vec<u8> = serde_json::from_value(body["message"].clone()).unwrap();
+    String::from_utf8(bytes).unwrap()
+}
+
+#[tokio::test]
+async fn serves_favicon() {
+    let (app, _) = app();
+    let request = Request::get("/favicon.ico").body(Body::empty()).unwrap();
+    let (status, content_type, body) = send(&app, request).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(content_type.as_deref(), Some("image/png"));
+    assert!(body.starts_with(b"\x89PNG"));
+}
+
+#[tokio::test]
+async fn reports_health_and_readiness() {
+    let (app, _) = app();
+    let (status, body) = get(&app, "/healthz").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(message(&body), "ok");
+    let (status, body) = get(&app, "/readyz").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(message(&body), "ready");
+}
+
+#[tokio::test]
+async fn serves_dashboard() {
+    let (app, _) = app();
+    for uri in ["/", "/index.html"] {
+        let request = Request::get(uri).body(Body::empty()).unwrap();
+        let (status, content_type, body) = send(&app, request).await;
+        assert_eq!(status, StatusCode::OK, "{}", uri);
+        assert!(content_type.unwrap().starts_with("text/html"));
+        assert!(String::from_utf8(body)
+            .unwrap()
+            .contains("ESP32 Metric Frontend"));
+    }
+
+    let request = Request::get("/app.js").body(Body::empty()).unwrap();
+    let (status, content_type, _) = send(&app, request).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        content_type.as_deref(),
+        Some("text/javascript; charset=utf-8")
+    );
+
+    let request = Request::get("/vendor/d3.v7.min.js")
+        .body(Body::empty())
+        .unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers()[header::CACHE_CONTROL],
+        "public, max-age=31536000, immutable"
+    );
+
+    let request = Request::get("/missing.js").body(Body::empty()).unwrap();
+    assert_eq!(send(&app, request).await.0, StatusCode::NOT_FOUND);
+    let request = Request::post("/index.html").body(Body::empty()).unwrap();
+    assert_eq!(send(&app, request).await.0, StatusCode::METHOD_NOT_ALLOWED);
+}
+
+#[tokio::test]
+async fn compresses_and_revalidates_dashboard() {
+    let (app, _) = app();
+    let request = Request::get("/")
+        .header(header::ACCEPT_ENCODING, "gzip, deflate, br")
+        .body(Body::empty())
+        .unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::CONTENT_ENCODING], "br");
+    assert_eq!(response.headers()[header::VARY], "accept-encoding");
+    assert_eq!(response.headers()[header::CACHE_CONTROL], "no-cache");
+    let etag = response.headers()[header::ETAG].clone();
+
+    let request = Request::get("/")
+        .header(header::ACCEPT_ENCODING, "gzip")
+        .body(Body::empty())
+        .unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+    assert_eq!(response.headers()[header::ETAG], etag);
+
+    let request = Request::get("/")
+        .header(header::IF_NONE_MATCH, etag)
+        .body(Body::empty())
+        .unwrap();
+    let (status, _, body) = send(&app, request).await;
+    assert_eq!(status, StatusCode::NOT_MODIFIED);
+    assert!(body.is_empty());
+}
+
+#[tokio::test]
+async fn serves_dashboard_from_disk_in_dev_mode() {
+    let dir = std::env::temp_dir().join(format!("http-server-web-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("index.html"), "<h1>edited</h1>").unwrap();
+
+    let app = router(AppState {
+        storage: Arc::new(MemoryStorage::default()),
+        accepted: Accepted::new(16),
+        static_dir: Some(dir.as_path().into()),
+        limits: Arc::default(),
+        buffer: None,
+        clock: Arc::default(),
+        status: Arc::default(),
+        admin_token: None,
+    });
+    let request = Request::get("/").body(Body::empty()).unwrap();
+    let (status, content_type, body) = send(&app, request).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(content_type.as_deref(), Some("text/html; charset=utf-8"));
+    assert_eq!(body, b"<h1>edited</h1>");
+
+    let request = Request::get("/../Cargo.toml").body(Body::empty()).unwrap();
+    assert_eq!(send(&app, request).await.0, StatusCode::NOT_FOUND);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn ingests_and_queries_metrics() {
+    let (app, mut accepted) = app();
+    assert_eq!(
+        post_json(&app, &metric(&[1, 2], T0, 21.5)).await,
+        StatusCode::CREATED
+    );
+    assert_eq!(
+        post_json(&app, &metric(&[1, 2], T0 + 60, 22.0)).await,
+        StatusCode::CREATED
+    );
+    assert_eq!(accepted.recv().await.unwrap().timestamp, T0);
+    assert_eq!(accepted.recv().await.unwrap().timestamp, T0 + 60);
+
+    let (status, metrics) = get(&app, "/metrics").await;
+    assert_eq!(status, StatusCode::OK);
+    let metrics = metrics.as_array().unwrap();
+    assert_eq!(metrics.len(), 2);
+    // Newest first.
+    assert_eq!(metrics[0]["device_timestamp"], T0 + 60);
+    assert_eq!(metrics[0]["device_id"], json!([1, 2]));
+    assert_eq!(metrics[1]["temperature_celsius"], 21.5);
+    assert_eq!(metrics[1]["co2_ppm"], 600);
+
+    // Milliseconds are kept, and a range ending at a second includes all of it.
+    let mut precise = metric(&[1, 2], T0 + 60, 22.5);
+    precise["timestamp_millis"] = json!(500);
+    assert_eq!(post_json(&app, &precise).await, StatusCode::CREATED);
+    let uri = format!(
+        "/metrics?start_timestamp={}&end_timestamp={}",
+        T0 + 1,
+        T0 + 60
+    );
+    let (status, metrics) = get(&app, &uri).await;
+    assert_eq!(status, StatusCode::OK);
+    let metrics = metrics.as_array().unwrap();
+    assert_eq!(metrics.len(), 2);
+    assert_eq!(metrics[0]["device_timestamp"], T0 + 60);
+    assert_eq!(metrics[0]["device_timestamp_ms"], (T0 + 60) * 1000 + 500);
+    assert_eq!(metrics[1]["device_timestamp_ms"], (T0 + 60) * 1000);
+
+    let (status, devices) = get(&app, "/devices").await;
+    assert_eq!(status, StatusCode::OK);
+    let devices = devices.as_array().unwrap();
+    assert_eq!(devices.len(), 1);
+    assert_eq!(devices[0]["device_id"], json!([1, 2]));
+    assert_eq!(devices[0]["first_seen"], T0);
+    assert_eq!(devices[0]["last_seen"], T0 + 60);
+}
+
+#[tokio::test]
+async fn reports_device_health() {
+    let (app, mut accepted) = app();
+    assert_eq!(
+        post_json(&app, &health(&[1], T0, 60)).await,
+        StatusCode::CREATED
+    );
+    let mut crashed = health(&[1], T0 + 300, 5);
+    crashed["topic"]["DeviceHealth"]["reset_reason"] = json!("task_watchdog");
+    crashed["topic"]["DeviceHealth"]["wifi_rssi_dbm"] = Value::Null;
+    assert_eq!(post_json(&app, &crashed).await, StatusCode::CREATED);
+    assert_eq!(
+        post_json(&app, &health(&[2], T0, 600)).await,
+        StatusCode::CREATED
+    );
+    assert_eq!(accepted.recv().await.unwrap().device_id, vec![1]);
+
+    // Only the latest report of each device is kept.
+    let (status, reports) = get(&app, "/devices/health").await;
+    assert_eq!(status, StatusCode::OK);
+    let reports = reports.as_array().unwrap();
+    assert_eq!(reports.len(), 2);
+    assert_eq!(reports[0]["device_id"], json!([1]));
+    assert_eq!(reports[0]["device_timestamp_ms"], (T0 + 300) * 1000);
+    assert_eq!(reports[0]["uptime_secs"], 5);
+    assert_eq!(reports[0]["reset_reason"], "task_watchdog");
+    assert_eq!(reports[0]["wifi_rssi_dbm"], Value::Null);
+    assert_eq!(reports[0]["firmware_version"], "0.1.0");
+    assert_eq!(reports[1]["device_id"], json!([2]));
+    assert_eq!(reports[1]["free_heap_bytes"], 120_000);
+
+    // Health reports aren't readings.
+    let (_, metrics) = get(&app, "/metrics").await;
+    assert_eq!(metrics, json!([]));
+    let (_, devices) = get(&app, "/devices").await;
+    assert_eq!(devices, json!([]));
+}
+
+#[tokio::test]
+async fn reports_fleet_status() {
+    let storage = Arc::new(MemoryStorage::default());
+    let app = router(AppState {
+        storage: storage.clone(),
+        accepted: Accepted::new(16),
+        static_dir: None,
+        limits: Arc::default(),
+        buffer: None,
+        clock: Arc::default(),
+        status: Arc::new(StatusConfig {
+            stale_after_secs: 60,
+            offline_after_secs: 600,
+        }),
+        admin_token: None,
+    });
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    assert_eq!(
+        post_json(&app, &metric(&[1], now, 21.5)).await,
+        StatusCode::CREATED
+    );
+    assert_eq!(
+        post_json(&app, &health(&[2], now, 60)).await,
+        StatusCode::CREATED
+    );
+    // Received two hours ago, as if the device has been gone since.
+    let long_ago = OffsetDateTime::from_unix_timestamp(now - 7200).unwrap();
+    let time = ReadingTime {
+        device_timestamp: long_ago,
+        received_at: long_ago,
+        clock_skew_ms: 0,
+    };
+    let climate = Climate {
+        temperature_celsius: 18.0,
+        humidity: 30.0,
+        co2_ppm: 400,
+    };
+    storage.insert_climate(&[3], time, &climate).await.unwrap();
+
+    let (status, devices) = get(&app, "/devices/status").await;
+    assert_eq!(status, StatusCode::OK);
+    let devices = devices.as_array().unwrap();
+    assert_eq!(devices.len(), 3);
+    assert_eq!(devices[0]["device_id"], json!([1]));
+    assert_eq!(devices[0]["status"], "online");
+    assert_eq!(devices[0]["latest_climate"]["temperature_celsius"], 21.5);
+    assert_eq!(devices[0]["firmware_version"], Value::Null);
+    assert_eq!(devices[1]["status"], "online");
+    assert_eq!(devices[1]["firmware_version"], "0.1.0");
+    assert_eq!(devices[1]["latest_climate"], Value::Null);
+    assert_eq!(devices[2]["status"], "offline");
+    assert_eq!(devices[2]["last_seen_ms"], (now - 7200) * 1000);
+    assert!(devices[2]["silent_secs"].as_i64().unwrap() >= 7200);
+}
+
+#[tokio::test]
+async fn aggregates_metrics() {
+    let (app, _) = app();
+    for (offset, temperature) in [(0, 20.0), (60, 22.0), (3600, 30.0)] {
+        let status = post_json(&app, &metric(&[7], T0 - T0 % 3600 + offset, temperature)).await;
+        assert_eq!(status, StatusCode::CREATED);
+    }
+
+    let (status, buckets) = get(&app, "/metrics/aggregate").await;
+    assert_eq!(status, StatusCode::OK);
+    let buckets = buckets.as_array().unwrap();
+    assert_eq!(buckets.len(), 2);
+    assert_eq!(buckets[0]["samples"], 2);
+    assert_eq!(buckets[0]["temperature_celsius"]["avg"], 21.0);
+    assert_eq!(buckets[1]["temperature_celsius"]["max"], 30.0);
+
+    let (status, buckets) = get(&app, "/metrics/aggregate?bucket_seconds=86400").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(buckets.as_array().unwrap().len(), 1);
+
+    let (status, body) = get(&app, "/metrics/aggregate?bucket_seconds=0").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(message(&body), "invalid bucket_seconds: 0");
+    let (status, body) = get(
+        &app,
+        "/metrics/aggregate?bucket_seconds=2305843009213693952",
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(
+        message(&body),
+        "invalid bucket_seconds: 2305843009213693952"
+    );
+}
+
+#[tokio::test]
+async fn rejects_malformed_json() {
+    let (app, _) = app();
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri("/metric")
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from("{\"topic\":"))
+        .unwrap();
+    assert_eq!(send(&app, request).await.0, StatusCode::BAD_REQUEST);
+
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri("/metric")
+        .body(Body::from(metric(&[1], T0, 20.0).to_string()))
+        .unwrap();
+    assert_eq!(
+        send(&app, request).await.0,
+        StatusCode::UNSUPPORTED_MEDIA_TYPE
+    );
+
+    let mut missing_field = metric(&[1], T0, 20.0);
+    missing_field.as_object_mut().unwrap().remove("timestamp");
+    assert_eq!(
+        post_json(&app, &missing_field).await,
+        StatusCode::UNPROCESSABLE_ENTITY
+    );
+
+    let (_, metrics) = get(&app, "/metrics").await;
+    assert_eq!(metrics, json!([]));
+}
+
+#[tokio::test]
+async fn rejects_invalid_metrics() {
+    let (app, _) = app();
+    assert_eq!(
+        post_json(&app, &metric(&[1], i64::MAX, 20.0)).await,
+        StatusCode::BAD_REQUEST
+    );
+    assert_eq!(
+        post_json(&app, &metric(&[], T0, 20.0)).await,
+        StatusCode::BAD_REQUEST
+    );
+    let mut too_precise = metric(&[1], T0, 20.0);
+    too_precise["timestamp_millis"] = json!(1000);
+    let request = Request::post("/metric")
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(too_precise.to_string()))
+        .unwrap();
+    let (status, _, body) = send(&app, request).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(
+        message(&serde_json::from_slice(&body).unwrap()),
+        "timestamp_millis must be below 1000, got 1000"
+    );
+    let (_, metrics) = get(&app, "/metrics").await;
+    assert_eq!(metrics, json!([]));
+
+    assert_eq!(
+        post_json(&app, &metric(&[1], T0, 20.0)).await,
+        StatusCode::CREATED
+    );
+    assert_eq!(
+        post_json(&app, &metric(&[1], T0, 25.0)).await,
+        StatusCode::CONFLICT
+    );
+}
+
+#[tokio::test]
+async fn rejects_invalid_query_timestamps() {
+    let (app, _) = app();
+    let (status, body) = get(&app, &format!("/metrics?start_timestamp={}", i64::MAX)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(
+        message(&body),
+        format!("invalid start_timestamp: {}", i64::MAX)
+    );
+
+    let (status, body) = get(&app, &format!("/metrics?end_timestamp={}", i64::MIN)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(
+        message(&body),
+        format!("invalid end_timestamp: {}", i64::MIN)
+    );
+
+    let (status, _) = get(&app, "/metrics/aggregate?end_timestamp=yesterday").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn writes_line_protocol() {
+    let (app, _) = app();
+    let body = format!(
+        "climate,device_id=0a0b temperature_celsius=19.5,humidity=55,co2_ppm=800i {}",
+        T0
+    );
+    let request = Request::post("/write?precision=s")
+        .body(Body::from(body))
+        .unwrap();
+    assert_eq!(send(&app, request).await.0, StatusCode::NO_CONTENT);
+
+    let request = Request::post("/write").body(Body::from("weather")).unwrap();
+    assert_eq!(send(&app, request).await.0, StatusCode::BAD_REQUEST);
+
+    let (_, metrics) = get(&app, "/metrics").await;
+    assert_eq!(metrics[0]["device_id"], json!([10, 11]));
+    assert_eq!(metrics[0]["device_timestamp"], T0);
+}
+
+#[tokio::test]
+async fn limits_readings_per_device() {
+    let (app, _) = app_with_limits(LimitsConfig {
+        per_device: Some(Rate {
+            per_second: 0.1,
+            burst: 2,
+        }),
+        ..Default::default()
+    });
+    for offset in 0..2 {
+        let status = post_json(&app, &metric(&[1], T0 + offset, 20.0)).await;
+        assert_eq!(status, StatusCode::CREATED);
+    }
+    let request = Request::post("/metric")
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(metric(&[1], T0 + 2, 20.0).to_string()))
+        .unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(response.headers()[header::RETRY_AFTER], "10");
+    // Other devices aren't affected.
+    assert_eq!(
+        post_json(&app, &metric(&[2], T0, 20.0)).await,
+        StatusCode::CREATED
+    );
+
+    let (_, metrics) = get(&app, "/metrics").await;
+    assert_eq!(metrics.as_array().unwrap().len(), 3);
+    let (status, stats) = get(&app, "/stats").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        stats,
+        json!({ "throttled_by_device": 1, "throttled_by_client": 0, "skewed_readings": 3 })
+    );
+}
+
+#[tokio::test]
+async fn limits_requests_per_client() {
+    let (app, _) = app_with_limits(LimitsConfig {
+        per_client: Some(Rate {
+            per_second: 1.0,
+            burst: 1,
+        }),
+        ..Default::default()
+    });
+    let from = |ip: [u8; 4], device_id: u8| {
+        Request::post("/metric")
+            .header(header::CONTENT_TYPE, "application/json")
+            .extension(ConnectInfo(SocketAddr::from((ip, 50000))))
+            .body(Body::from(metric(&[device_id], T0, 20.0).to_string()))
+            .unwrap()
+    };
+    assert_eq!(
+        send(&app, from([10, 0, 0, 1], 1)).await.0,
+        StatusCode::CREATED
+    );
+    let response = app.clone().oneshot(from([10, 0, 0, 1], 2)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(response.headers()[header::RETRY_AFTER], "1");
+    assert_eq!(
+        send(&app, from([10, 0, 0, 2], 3)).await.0,
+        StatusCode::CREATED
+    );
+
+    // Reads aren't limited.
+    let request = Request::get("/metrics")
+        .extension(ConnectInfo(SocketAddr::from(([10, 0, 0, 1], 50000))))
+        .body(Body::empty())
+        .unwrap();
+    assert_eq!(send(&app, request).await.0, StatusCode::OK);
+
+    // Without trusting the proxy, spoofed addresses change nothing.
+    let spoofed = |forwarded_for: &str, device_id: u8| {
+        let mut request = from([10, 0, 0, 1], device_id);
+        request
+            .headers_mut()
+            .insert("x-forwarded-for", forwarded_for.parse().unwrap());
+        request
+    };
+    let response = app.clone().oneshot(spoofed("192.0.2.1", 4)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    let (_, stats) = get(&app, "/stats").await;
+    assert_eq!(stats["throttled_by_client"], 2);
+
+    // Behind a proxy only the address it appended counts, not what the client sent before it.
+    let (app, _) = app_with_limits(LimitsConfig {
+        per_client: Some(Rate {
+            per_second: 1.0,
+            burst: 1,
+        }),
+        trust_forwarded_for: true,
+        ..Default::default()
+    });
+    assert_eq!(
+        send(&app, spoofed("192.0.2.1, 198.51.100.7", 1)).await.0,
+        StatusCode::CREATED
+    );
+    let response = app
+        .clone()
+        .oneshot(spoofed("192.0.2.2, 198.51.100.7", 2))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    let response = app
+        .clone()
+        .oneshot(spoofed("198.51.100.7", 3))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(
+        send(&app, spoofed("198.51.100.7, 198.51.100.8", 4)).await.0,
+        StatusCode::CREATED
+    );
+
+    // With two proxies, the entry the outer one appended is the client.
+    let (app, _) = app_with_limits(LimitsConfig {
+        per_client: Some(Rate {
+            per_second: 1.0,
+            burst: 1,
+        }),
+        trust_forwarded_for: true,
+        trusted_proxies: 2,
+        ..Default::default()
+    });
+    assert_eq!(
+        send(&app, spoofed("192.0.2.1, 198.51.100.7, 10.0.0.5", 1))
+            .await
+            .0,
+        StatusCode::CREATED
+    );
+    let response = app
+        .clone()
+        .oneshot(spoofed("192.0.2.2, 198.51.100.7, 10.0.0.5", 2))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[tokio::test]
+async fn rejects_large_bodies() {
+    let (app, _) = app_with_limits(LimitsConfig {
+        max_body_bytes: 256,
+        ..Default::default()
+    });
+    let mut body = metric(&[1], T0, 20.0);
+    body["padding"] = json!("x".repeat(256));
+    assert_eq!(post_json(&app, &body).await, StatusCode::PAYLOAD_TOO_LARGE);
+
+    let lines = format!(
+        "climate,device_id=01 temperature_celsius=1,humidity=1,co2_ppm=1i {}\n",
+        T0
+    )
+    .repeat(10);
+    let request = Request::post("/write?precision=s")
+        .body(Body::from(lines))
+        .unwrap();
+    assert_eq!(send(&app, request).await.0, StatusCode::PAYLOAD_TOO_LARGE);
+}
+
+#[tokio::test]
+async fn buffers_ingestion() {
+    let accepted = Accepted::new(16);
+    let mut receiver = accepted.subscribe();
+    let storage = Arc::new(MemoryStorage::default());
+    let config = IngestConfig {
+        buffered: true,
+        batch_size: 2,
+        flush_interval_ms: 60_000,
+        ..Default::default()
+    };
+    let (buffer, writer) = WriteBuffer::spawn(&config, storage.clone(), accepted.clone());
+    let app = router(AppState {
+        storage,
+        accepted,
+        static_dir: None,
+        limits: Arc::default(),
+        buffer: Some(buffer.clone()),
+        clock: Arc::default(),
+        status: Arc::default(),
+        admin_token: None,
+    });
+
+    let (status, body) = {
+        let request = Request::post("/metric")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(metric(&[1], T0, 20.0).to_string()))
+            .unwrap();
+        let (status, _, body) = send(&app, request).await;
+        (status, serde_json::from_slice::<Value>(&body).unwrap())
+    };
+    assert_eq!(status, StatusCode::ACCEPTED);
+    assert_eq!(message(&body), "Climate data queued");
+    // Still queued, as the batch isn't full and the interval is long.
+    let (_, metrics) = get(&app, "/metrics").await;
+    assert_eq!(metrics, json!([]));
+
+    // A full batch is written right away.
+    assert_eq!(
+        post_json(&app, &metric(&[1], T0 + 60, 21.0)).await,
+        StatusCode::ACCEPTED
+    );
+    receiver.recv().await.unwrap();
+    receiver.recv().await.unwrap();
+    let (_, metrics) = get(&app, "/metrics").await;
+    assert_eq!(metrics.as_array().unwrap().len(), 2);
+
+    // Health reports aren't queued.
+    assert_eq!(
+        post_json(&app, &health(&[1], T0, 60)).await,
+        StatusCode::CREATED
+    );
+    let (_, reports) = get(&app, "/devices/health").await;
+    assert_eq!(reports.as_array().unwrap().len(), 1);
+    receiver.recv().await.unwrap();
+
+    // Closing writes what is left.
+    assert_eq!(
+        post_json(&app, &metric(&[2], T0, 22.0)).await,
+        StatusCode::ACCEPTED
+    );
+    buffer.close();
+    writer.finish().await;
+    let (_, metrics) = get(&app, "/metrics").await;
+    assert_eq!(metrics.as_array().unwrap().len(), 3);
+
+    let (_, stats) = get(&app, "/stats").await;
+    assert_eq!(
+        stats["ingest_buffer"],
+        json!({ "queued": 0, "written": 3, "duplicates": 0, "dropped": 0 })
+    );
+    assert_eq!(
+        post_json(&app, &metric(&[2], T0 + 60, 22.0)).await,
+        StatusCode::SERVICE_UNAVAILABLE
+    );
+}
+
+#[tokio::test]
+async fn tracks_clock_skew() {
+    let state = AppState {
+        storage: Arc::new(MemoryStorage::default()),
+        accepted: Accepted::new(16),
+        static_dir: None,
+        limits: Arc::default(),
+        buffer: None,
+        clock: Arc::new(ClockPolicy::new(&ClockConfig {
+            max_skew_secs: 60,
+            correct_skewed: false,
+        })),
+        status: Arc::default(),
+        admin_token: None,
+    };
+    let app = router(state.clone());
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    assert_eq!(
+        post_json(&app, &metric(&[1], now, 20.0)).await,
+        StatusCode::CREATED
+    );
+    // A device that lost its time and counts from 1970.
+    assert_eq!(
+        post_json(&app, &metric(&[2], 120, 20.0)).await,
+        StatusCode::CREATED
+    );
+
+    let (_, metrics) = get(&app, "/metrics").await;
+    let metrics = metrics.as_array().unwrap();
+    assert_eq!(metrics[1]["device_timestamp"], 120);
+    assert!(metrics[1]["clock_skew_ms"].as_i64().unwrap() <= (120 - now) * 1000);
+    assert!(metrics[0]["clock_skew_ms"].as_i64().unwrap().abs() < 60_000);
+    let (_, devices) = get(&app, "/devices").await;
+    assert_eq!(devices[0]["clock_skewed"], false);
+    assert_eq!(devices[1]["clock_skewed"], true);
+    let (_, stats) = get(&app, "/stats").await;
+    assert_eq!(stats["skewed_readings"], 1);
+
+    // Corrected readings are stored at the time they arrived.
+    let app = router(AppState {
+        clock: Arc::new(ClockPolicy::new(&ClockConfig {
+            max_skew_secs: 60,
+            correct_skewed: true,
+        })),
+        ..state
+    });
+    assert_eq!(
+        post_json(&app, &metric(&[2], 180, 20.0)).await,
+        StatusCode::CREATED
+    );
+    let (_, metrics) = get(&app, "/metrics").await;
+    let metrics = metrics.as_array().unwrap();
+    assert_eq!(metrics.len(), 3);
+    assert_eq!(metrics[0]["device_id"], json!([2]));
+    assert!(metrics[0]["device_timestamp"].as_i64().unwrap() >= now);
+    assert_eq!(
+        metrics[0]["device_timestamp_ms"],
+        metrics[0]["received_at_ms"]
+    );
+}
+
+fn admin_put(uri: &str) -> axum::http::request::Builder {
+    Request::put(uri).header(header::AUTHORIZATION, format!("Bearer {}", ADMIN_TOKEN))
+}
+
+async fn put_bytes(app: &Router, uri: &str, body: Vec<u8>) -> (StatusCode, Value) {
+    let request = admin_put(uri).body(Body::from(body)).unwrap();
+    let (status, _, body) = send(app, request).await;
+    (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+}
+
+async fn put_json(app: &Router, uri: &str, body: &Value) -> (StatusCode, Value) {
+    let request = admin_put(uri)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap();
+    let (status, _, body) = send(app, request).await;
+    (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+}
+
+#[tokio::test]
+async fn hosts_firmware_releases() {
+    let (app, _) = app_with_limits(LimitsConfig {
+        max_body_bytes: 256,
+        max_firmware_bytes: 1024,
+        ..Default::default()
+    });
+    let image = vec![0xe9; 512];
+    let (status, release) = put_bytes(&app, "/firmware/releases/1.1.0", image.clone()).await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(release["size"], 512);
+    assert_eq!(
+        release["sha256"],
+        "69302b88588044c3518a4a876dc6dcb3c145fba5f4f729b1de7e3f4fa92122a0"
+    );
+
+    let (status, body) = put_bytes(&app, "/firmware/releases/1.1.0", vec![1]).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(message(&body), "firmware 1.1.0 is stored already");
+    let (status, _) = put_bytes(&app, "/firmware/releases/big", vec![0; 2048]).await;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    let (status, _) = put_bytes(&app, "/firmware/releases/1.2.0", vec![]).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = put_bytes(&app, "/firmware/releases/1.2.0%20beta", vec![1]).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let (_, releases) = get(&app, "/firmware/releases").await;
+    assert_eq!(releases, json!([release]));
+    let request = Request::get("/firmware/releases/1.1.0/image")
+        .body(Body::empty())
+        .unwrap();
+    let (status, content_type, body) = send(&app, request).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(content_type.as_deref(), Some("application/octet-stream"));
+    assert_eq!(body, image);
+    let (status, _) = get(&app, "/firmware/releases/1.0.0/image").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn requires_admin_token_to_manage_firmware() {
+    let (app, _) = app();
+    let put = |request: axum::http::request::Builder| {
+        let app = app.clone();
+        async move {
+            send(&app, request.body(Body::from(vec![1])).unwrap())
+                .await
+                .0
+        }
+    };
+    let uri = "/firmware/releases/1.1.0";
+    assert_eq!(put(Request::put(uri)).await, StatusCode::UNAUTHORIZED);
+    let wrong = Request::put(uri).header(header::AUTHORIZATION, "Bearer admin");
+    assert_eq!(put(wrong).await, StatusCode::UNAUTHORIZED);
+    let rollout = Request::put("/firmware/rollouts/default");
+    assert_eq!(put(rollout).await, StatusCode::UNAUTHORIZED);
+    let group = Request::put("/firmware/devices/0b");
+    assert_eq!(put(group).await, StatusCode::UNAUTHORIZED);
+    let (_, releases) = get(&app, "/firmware/releases").await;
+    assert_eq!(releases, json!([]));
+    assert_eq!(put(admin_put(uri)).await, StatusCode::CREATED);
+
+    // Without a configured token, nobody can manage firmware.
+    let app = router(AppState {
+        storage: Arc::new(MemoryStorage::default()),
+        accepted: Accepted::new(16),
+        static_dir: None,
+        limits: Arc::default(),
+        buffer: None,
+        clock: Arc::default(),
+        status: Arc::default(),
+        admin_token: None,
+    });
+    let request = admin_put(uri).body(Body::from(vec![1])).unwrap();
+    assert_eq!(send(&app, request).await.0, StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn rolls_out_firmware() {
+    let (app, _) = app();
+    put_bytes(&app, "/firmware/releases/1.1.0", vec![1, 2, 3]).await;
+    let latest = |device_id: &str| {
+        format!(
+            "{}?device_id={}&current=1.0.0",
+            types::firmware::LATEST_PATH,
+            device_id
+        )
+    };
+
+    // Nothing is rolled out yet.
+    let (status, _) = get(&app, &latest("0a")).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+
+    let (status, _) = put_json(
+        &app,
+        "/firmware/rollouts/default",
+        &json!({"version": "2.0.0"}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) = put_json(
+        &app,
+        "/firmware/rollouts/default",
+        &json!({"version": "1.1.0", "percent": 101}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, rollout) = put_json(
+        &app,
+        "/firmware/rollouts/default",
+        &json!({"version": "1.1.0"}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(rollout["percent"], 100);
+
+    let (status, update) = get(&app, &latest("0a")).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(update["version"], "1.1.0");
+    assert_eq!(update["size"], 3);
+    assert_eq!(update["url"], "/firmware/releases/1.1.0/image");
+    let (status, _) = get(
+        &app,
+        &format!(
+            "{}?device_id=0a&current=1.1.0",
+            types::firmware::LATEST_PATH
+        ),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, _) = get(&app, &latest("zz")).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    // Rolling back passes on the signature that lets devices install an older release.
+    put_bytes(&app, "/firmware/releases/1.0.1", vec![4, 5]).await;
+    let (status, _) = put_json(
+        &app,
+        "/firmware/rollouts/default",
+        &json!({"version": "1.0.1", "downgrade": "yes"}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let signature = "0f".repeat(64);
+    let (status, _) = put_json(
+        &app,
+        "/firmware/rollouts/default",
+        &json!({"version": "1.0.1", "downgrade": signature}),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let (_, update) = get(
+        &app,
+        &format!(
+            "{}?device_id=0a&current=1.1.0",
+            types::firmware::LATEST_PATH
+        ),
+    )
+    .await;
+    assert_eq!(update["version"], "1.0.1");
+    assert_eq!(update["downgrade"], signature);
+    put_json(
+        &app,
+        "/firmware/rollouts/default",
+        &json!({"version": "1.1.0"}),
+    )
+    .await;
+    let (_, update) = get(&app, &latest("0a")).await;
+    assert_eq!(update.get("downgrade"), None);
+
+    // Devices in another group only get what is rolled out to it.
+    let (status, _) = put_json(&app, "/firmware/devices/0b", &json!({"group": "lab"})).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, _) = get(&app, &latest("0b")).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+
+    // A release a device failed to install isn't offered to it again.
+    let report = json!({
+        "device_id": [10],
+        "version": "1.1.0",
+        "outcome": "failed",
+        "message": "sha-256 mismatch",
+    });
+    let request = Request::post(types::firmware::REPORTS_PATH)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(report.to_string()))
+        .unwrap();
+    assert_eq!(send(&app, request).await.0, StatusCode::NO_CONTENT);
+    let (status, _) = get(&app, &latest("0a")).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+
+    let (_, devices) = get(&app, "/firmware/devices").await;
+    assert_eq!(devices[0]["device_id"], json!([10]));
+    assert_eq!(devices[0]["group"], "default");
+    assert_eq!(devices[0]["last_report"]["outcome"], "failed");
+    assert_eq!(devices[1]["group"], "lab");
+    assert_eq!(devices[1]["last_report"], Value::Null);
+}
+
+#[tokio::test]
+async fn resumes_firmware_downloads() {
+    let (app, _) = app();
+    let image: Vec<u8> = (0..=255).collect();
+    put_bytes(&app, "/firmware/releases/1.1.0", image.clone()).await;
+    let download = |range: Option<&str>| {
+        let mut request = Request::get("/firmware/releases/1.1.0/image");
+        if let Some(range) = range {
+            request = request.header(header::RANGE, range);
+        }
+        app.clone().oneshot(request.body(Body::empty()).unwrap())
+    };
+
+    let response = download(None).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::ACCEPT_RANGES], "bytes");
+
+    let response = download(Some("bytes=200-")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(
+        response.headers()[header::CONTENT_RANGE],
+        "bytes 200-255/256"
+    );
+    assert_eq!(response.headers()[header::CONTENT_LENGTH], "56");
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(body, image[200..]);
+
+    let response = download(Some("bytes=256-")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+    assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes */256");
+}
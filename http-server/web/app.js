@@ -0,0 +1,254 @@
+let metrics = [];
+let chart = "temperature";
+function toHexString(byteArray) {
+  return byteArray.reduce((output, elem) =>
+    (output + ('0' + elem.toString(16)).slice(-2)),
+    '');
+}
+function makeRequest(startTime, endTime) {
+  fetch("/metrics?start_timestamp=" + startTime + "&end_timestamp=" + endTime).then((response) => {
+    if (response.status != 200) {
+      console.log("Error: " + response.status);
+    } else {
+      console.log("Success");
+    }
+    return response.json();
+  }).then((data) => {
+    if ('message' in data) {
+      let decoder = new TextDecoder('UTF-8');
+      let array = new Uint8Array(data.message);
+      let message = decoder.decode(array);
+      console.log("Error: " + message);
+      return;
+    }
+    for (metric of data) {
+      metric.time = new Date(metric.device_timestamp_ms);
+      metric.temp = celsiusToFahrenheit(metric.temperature_celsius);
+    }
+    metrics = data;
+    updateMetrics();
+    addChartButtons();
+    loadDevices();
+    loadDeviceHealth();
+  }).catch((error) => {
+    console.log(error);
+  });
+}
+function updateMetrics() {
+  if (metrics.length == 0) {
+    return;
+  }
+  var metricsDiv = document.getElementById("metrics");
+  metricsDiv.innerHTML = "<h3>Most recent data point</h3>" +
+    "<p>Humidity: " + metrics[0].humidity + "</p>" +
+    "<p>Temperature: " + metrics[0].temperature_celsius + "°C</p>" +
+    "<p>Temperature: " + metrics[0].temp + "°F</p>" +
+    "<p>CO2 PPM: " + metrics[0].co2_ppm + "</p>" +
+    "<p>Device Timestamp: " + new Date(metrics[0].device_timestamp_ms).toLocaleString() + "</p>" +
+    (metrics[0].received_at_ms == null ? "" :
+      "<p>Received: " + new Date(metrics[0].received_at_ms).toLocaleString() +
+      " (device clock " + formatSkew(metrics[0].clock_skew_ms) + ")</p>");
+  switch (chart) {
+    case "temperature":
+      temperatureChart();
+      break;
+    case "humidity":
+      humidityChart();
+      break;
+    case "co2":
+      co2Chart();
+  }
+}
+function formatSkew(skewMs) {
+  if (skewMs == null) {
+    return "unknown";
+  }
+  const seconds = Math.abs(skewMs) / 1000;
+  return seconds.toFixed(1) + " s " + (skewMs < 0 ? "behind" : "ahead");
+}
+function loadDevices() {
+  fetch("/devices").then((response) => response.json()).then((devices) => {
+    let rows = devices.map((device) =>
+      "<tr" + (device.clock_skewed ? " class=\"skewed\"" : "") + ">" +
+      "<td>" + toHexString(device.device_id) + "</td>" +
+      "<td>" + new Date(device.last_seen * 1000).toLocaleString() + "</td>" +
+      "<td>" + formatSkew(device.clock_skew_ms) + (device.clock_skewed ? " ⚠" : "") + "</td>" +
+      "</tr>");
+    document.getElementById("devices").innerHTML = "<h3>Devices</h3>" +
+      "<table><tr><th>Device</th><th>Last seen</th><th>Clock</th></tr>" + rows.join("") + "</table>";
+  }).catch((error) => {
+    console.log(error);
+  });
+}
+function formatUptime(seconds) {
+  const days = Math.floor(seconds / 86400);
+  const hours = Math.floor((seconds % 86400) / 3600);
+  const minutes = Math.floor((seconds % 3600) / 60);
+  return (days > 0 ? days + "d " : "") + hours + "h " + minutes + "m";
+}
+function tableRow(cells, className) {
+  const row = document.createElement("tr");
+  row.className = className;
+  for (const text of cells) {
+    const cell = document.createElement("td");
+    cell.textContent = text;
+    row.appendChild(cell);
+  }
+  return row;
+}
+const crashResets = ["panic", "interrupt_watchdog", "task_watchdog", "watchdog", "brownout"];
+function loadDeviceHealth() {
+  fetch("/devices/health").then((response) => response.json()).then((reports) => {
+    if (reports.length == 0) {
+      document.getElementById("device-health").innerHTML = "";
+      return;
+    }
+    const table = document.createElement("table");
+    table.innerHTML = "<tr><th>Device</th><th>Firmware</th><th>Uptime</th><th>Free heap</th><th>WiFi</th>" +
+      "<th>Last reset</th><th>Failed sends</th><th>Reported</th></tr>";
+    // Reports come from devices, so their values are set as text and never parsed as HTML.
+    for (const report of reports) {
+      table.appendChild(tableRow([
+        toHexString(report.device_id),
+        report.firmware_version,
+        formatUptime(report.uptime_secs),
+        (report.free_heap_bytes / 1024).toFixed(1) + " KiB (min " + (report.min_free_heap_bytes / 1024).toFixed(1) + " KiB)",
+        report.wifi_rssi_dbm == null ? "-" : report.wifi_rssi_dbm + " dBm",
+        report.reset_reason.replace(/_/g, " "),
+        report.failed_sends,
+        new Date(report.received_at_ms).toLocaleString(),
+      ], crashResets.includes(report.reset_reason) ? "crashed" : ""));
+    }
+    const heading = document.createElement("h3");
+    heading.textContent = "Device health";
+    document.getElementById("device-health").replaceChildren(heading, table);
+  }).catch((error) => {
+    console.log(error);
+  });
+}
+function celsiusToFahrenheit(celsius) {
+  return celsius * 9 / 5 + 32;
+}
+
+function addChartButtons() {
+  var chartButtonsDiv = document.getElementById("chart-buttons");
+  chartButtonsDiv.innerHTML = "<button onclick=\"temperatureChart()\">Temperature Chart</button>" +
+    "<button onclick=\"humidityChart()\">Humidity Chart</button>" +
+    "<button onclick=\"co2Chart()\">CO2 Chart</button>";
+}
+
+function removeChartButtons() {
+  var chartButtonsDiv = document.getElementById("chart-buttons");
+  chartButtonsDiv.innerHTML = "";
+}
+
+function initD3Svg(title) {
+  d3.selectAll("#chart-container > *").remove();
+  console.log("" + window.innerWidth);
+  let width = 800;
+  if (window.innerWidth < 1000) {
+    width = window.innerWidth - 50;
+  } 
+  console.log("" + width);
+  const height = 400;
+  const svg = d3.select('#chart-container')
+    .append('svg')
+    .attr('width', width)
+    .attr('height', height)
+    .attr('class', 'chart');
+  svg.append("text")
+    .attr("x", width / 2)
+    .attr("y", 24)
+    .attr("class", "chart-title")
+    .attr("text-anchor", "middle")
+    .text(title);
+  return svg;
+}
+
+function temperatureChart() {
+  chart = "temperature";
+  const endTime = metrics[0].time;
+  const startTime = metrics[metrics.length - 1].time;
+  const svg = initD3Svg('Temp (°F) - ' + startTime.toLocaleString() + ' - ' + endTime.toLocaleString());
+  const xScale = d3.scaleTime()
+    .domain(d3.extent(metrics, d => d.time))
+    .range([50, 750]);
+  const yScale = d3.scaleLinear()
+    .domain([0, d3.max(metrics, d => d.temp)])
+    .range([350, 50]);
+  const xAxis = d3.axisBottom(xScale);
+  const yAxis = d3.axisLeft(yScale);
+  svg.append('g')
+    .attr('transform', 'translate(0, 350)')
+    .call(xAxis);
+  svg.append('g')
+    .attr('transform', 'translate(50, 0)')
+    .call(yAxis);
+  const line = d3.line()
+    .x(d => xScale(d.time))
+    .y(d => yScale(d.temp));
+  svg.append('path')
+    .datum(metrics)
+    .attr('fill', 'none')
+    .attr('stroke', 'red')
+    .attr('stroke-width', 2)
+    .attr('d', line);
+}
+function humidityChart() {
+  chart = "humidity";
+  const endTime = metrics[0].time;
+  const startTime = metrics[metrics.length - 1].time;
+  const svg = initD3Svg('Humidity % - ' + startTime.toLocaleString() + ' - ' + endTime.toLocaleString());
+  const xScale = d3.scaleTime()
+    .domain(d3.extent(metrics, d => d.time))
+    .range([50, 750]);
+  const yScale = d3.scaleLinear()
+    .domain([0, d3.max(metrics, d => d.humidity)])
+    .range([350, 50]);
+  const xAxis = d3.axisBottom(xScale);
+  const yAxis = d3.axisLeft(yScale);
+  svg.append('g')
+    .attr('transform', 'translate(0, 350)')
+    .call(xAxis);
+  svg.append('g')
+    .attr('transform', 'translate(50, 0)')
+    .call(yAxis);
+  const line = d3.line()
+    .x(d => xScale(d.time))
+    .y(d => yScale(d.humidity));
+  svg.append('path')
+    .datum(metrics)
+    .attr('fill', 'none')
+    .attr('stroke', 'blue')
+    .attr('stroke-width', 2)
+    .attr('d', line);
+}
+function co2Chart() {
+  chart = "co2";
+  const endTime = metrics[0].time;
+  const startTime = metrics[metrics.length - 1].time;
+  const svg = initD3Svg('CO2 PPM - ' + startTime.toLocaleString() + ' - ' + endTime.toLocaleString());
+  const xScale = d3.scaleTime()
+    .domain(d3.extent(metrics, d => d.time))
+    .range([50, 750]);
+  const yScale = d3.scaleLinear()
+    .domain([0, d3.max(metrics, d => d.co2_ppm)])
+    .range([350, 50]);
+  const xAxis = d3.axisBottom(xScale);
+  const yAxis = d3.axisLeft(yScale);
+  svg.append('g')
+    .attr('transform', 'translate(0, 350)')
+    .call(xAxis);
+  svg.append('g')
+    .attr('transform', 'translate(50, 0)')
+    .call(yAxis);
+  const line = d3.line()
+    .x(d => xScale(d.time))
+    .y(d => yScale(d.co2_ppm));
+  svg.append('path')
+    .datum(metrics)
+    .attr('fill', 'none')
+    .attr('stroke', 'green')
+    .attr('stroke-width', 2)
+    .attr('d', line);
+}
@@ -0,0 +1,103 @@
+// Fleet status page: every device from /devices/status in a table sortable by any column.
+
+const statusOrder = { online: 0, stale: 1, offline: 2 };
+let devices = [];
+let sortKey = "status";
+let ascending = true;
+
+function toHexString(byteArray) {
+  return byteArray.reduce((output, elem) =>
+    (output + ('0' + elem.toString(16)).slice(-2)),
+    '');
+}
+
+function formatSilence(seconds) {
+  if (seconds < 60) {
+    return seconds + " s ago";
+  } else if (seconds < 3600) {
+    return Math.floor(seconds / 60) + " min ago";
+  } else if (seconds < 86400) {
+    return Math.floor(seconds / 3600) + " h ago";
+  }
+  return Math.floor(seconds / 86400) + " days ago";
+}
+
+// The value a row is sorted by for a column, missing values last.
+function sortValue(device, key) {
+  const climate = device.latest_climate;
+  switch (key) {
+    case "device":
+      return toHexString(device.device_id);
+    case "status":
+      return statusOrder[device.status];
+    case "temperature_celsius":
+    case "humidity":
+    case "co2_ppm":
+      return climate == null ? null : climate[key];
+    default:
+      return device[key];
+  }
+}
+
+function compare(a, b) {
+  const x = sortValue(a, sortKey);
+  const y = sortValue(b, sortKey);
+  if (x == null || y == null) {
+    return (x == null) - (y == null);
+  }
+  const order = x < y ? -1 : x > y ? 1 : 0;
+  return ascending ? order : -order;
+}
+
+function render() {
+  // Devices report their firmware version, so every value is set as text and never parsed as HTML.
+  const rows = devices.slice().sort(compare).map((device) => {
+    const climate = device.latest_climate;
+    const value = (key, unit) => climate == null ? "-" : climate[key] + unit;
+    const row = document.createElement("tr");
+    row.className = device.status;
+    for (const text of [
+      toHexString(device.device_id),
+      device.status + (device.clock_skewed ? " (clock skewed)" : ""),
+      formatSilence(device.silent_secs),
+      device.firmware_version == null ? "-" : device.firmware_version,
+      value("temperature_celsius", " °C"),
+      value("humidity", " %"),
+      value("co2_ppm", " ppm"),
+    ]) {
+      const cell = document.createElement("td");
+      cell.textContent = text;
+      row.appendChild(cell);
+    }
+    row.cells[2].title = new Date(device.last_seen_ms).toLocaleString();
+    return row;
+  });
+  document.querySelector("#fleet tbody").replaceChildren(...rows);
+  document.querySelectorAll("#fleet th").forEach((th) => {
+    th.classList.toggle("sorted", th.dataset.key == sortKey);
+    th.classList.toggle("descending", th.dataset.key == sortKey && !ascending);
+  });
+  const counts = Object.keys(statusOrder).map((status) =>
+    devices.filter((device) => device.status == status).length + " " + status);
+  document.getElementById("fleet-summary").textContent = devices.length + " devices: " + counts.join(", ");
+}
+
+function loadStatus() {
+  fetch("/devices/status").then((response) => response.json()).then((data) => {
+    devices = data;
+    render();
+  }).catch((error) => {
+    console.log(error);
+  });
+}
+
+document.querySelectorAll("#fleet th").forEach((th) => {
+  th.addEventListener("click", () => {
+    ascending = th.dataset.key == sortKey ? !ascending : true;
+    sortKey = th.dataset.key;
+    render();
+  });
+});
+
+loadStatus();
+setInterval(loadStatus, 30 * 1000);
@@ -4,6 +4,10 @@ use embuild::{
 };
 
 fn main() -> anyhow::Result<()> {
+    // Host builds, i.e. the tests, don't link against ESP-IDF.
+    if std::env::var("CARGO_CFG_TARGET_OS").as_deref() != Ok("espidf") {
+        return Ok(());
+    }
     // Necessary because of this issue: https://github.com/rust-lang/cargo/issues/9641
     LinkArgs::output_propagated("ESP_IDF")?;
     let cfg = CfgArgs::try_from_env("ESP_IDF")?;
@@ -1 +1 @@
-cargo espflash --release --erase-otadata --monitor --partition-table partitions.csv /dev/ttyUSB0
+cargo espflash --release --bin rust-esp32-ota --erase-otadata --monitor --partition-table partitions.csv /dev/ttyUSB0
@@ -1 +1 @@
-cargo espflash save-image --release --partition-table partitions.csv ESP32 app.bin
+cargo espflash save-image --release --bin rust-esp32-ota --partition-table partitions.csv ESP32 app.bin
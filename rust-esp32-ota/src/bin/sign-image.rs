@@ -0,0 +1,118 @@
+//! Signs app images for the OTA server. Runs on the host, see the README.
+
+use std::{
+    env, fs,
+    io::{Read, Write},
+    process,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use ed25519_dalek::SigningKey;
+use rust_esp32_ota::{
+    checksum::decode_hex,
+    image::ImageHeader,
+    signature::{self, parse_public_key, SignedImage, TRAILER_MAGIC},
+};
+use sha2::{Digest, Sha256};
+
+const USAGE: &str = "\
+Usage:
+  sign-image keygen <key file>
+      Writes a new signing key and prints the public key to build the firmware with.
+  sign-image sign <key file> <image> <signed image>
+      Appends the signature of an image written by image.sh.
+  sign-image verify <public key> <signed image>
+      Checks the signature of a signed image.
+  sign-image allow-downgrade <key file> <signed image>
+      Prints the signature that lets devices pull a signed image older than what they run.";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let result = match args[..] {
+        ["keygen", key_file] => keygen(key_file),
+        ["sign", key_file, image, signed] => sign(key_file, image, signed),
+        ["verify", public_key, signed] => verify(public_key, signed),
+        ["allow-downgrade", key_file, signed] => allow_downgrade(key_file, signed),
+        _ => {
+            eprintln!("{USAGE}");
+            process::exit(2);
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("Error: {e:#}");
+        process::exit(1);
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn keygen(key_file: &str) -> Result<()> {
+    let mut secret = [0; 32];
+    fs::File::open("/dev/urandom")
+        .and_then(|mut random| random.read_exact(&mut secret))
+        .context("Reading random bytes")?;
+    let key = SigningKey::from_bytes(&secret);
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options
+        .open(key_file)
+        .with_context(|| format!("Creating {key_file}"))?;
+    writeln!(file, "{}", hex(&secret))?;
+
+    println!("Wrote the signing key to {key_file}, keep it secret.");
+    println!("Build the firmware with:");
+    println!("OTA_PUBLIC_KEY={}", hex(key.verifying_key().as_bytes()));
+    Ok(())
+}
+
+fn read_key(key_file: &str) -> Result<SigningKey> {
+    let hex = fs::read_to_string(key_file).with_context(|| format!("Reading {key_file}"))?;
+    let secret = decode_hex(&hex).ok_or_else(|| anyhow!("{key_file} is not a signing key"))?;
+    Ok(SigningKey::from_bytes(&secret))
+}
+
+fn sign(key_file: &str, image_file: &str, signed_file: &str) -> Result<()> {
+    let key = read_key(key_file)?;
+    let image = fs::read(image_file).with_context(|| format!("Reading {image_file}"))?;
+    let header = ImageHeader::parse(&image).with_context(|| format!("Parsing {image_file}"))?;
+    if image.ends_with(&TRAILER_MAGIC) {
+        bail!("{image_file} is signed already");
+    }
+
+    let mut signed = image.clone();
+    signed.extend(signature::sign(&key, &image));
+    fs::write(signed_file, &signed).with_context(|| format!("Writing {signed_file}"))?;
+    println!(
+        "Signed {image_file} for {} as {signed_file}, SHA-256 {}",
+        header.chip,
+        hex(&Sha256::digest(&signed))
+    );
+    Ok(())
+}
+
+fn verify(public_key: &str, signed_file: &str) -> Result<()> {
+    let key = parse_public_key(public_key)?;
+    let signed = fs::read(signed_file).with_context(|| format!("Reading {signed_file}"))?;
+    let mut image = SignedImage::new(key);
+    image.update(&signed, |_| Ok::<_, ()>(())).unwrap();
+    image.verify()?;
+    println!("{signed_file} is signed by {public_key}");
+    Ok(())
+}
+
+fn allow_downgrade(key_file: &str, signed_file: &str) -> Result<()> {
+    let key = read_key(key_file)?;
+    let signed = fs::read(signed_file).with_context(|| format!("Reading {signed_file}"))?;
+    if !signed.ends_with(&TRAILER_MAGIC) {
+        bail!("{signed_file} is not signed");
+    }
+    let signature = signature::sign_downgrade(&key, &Sha256::digest(&signed).into());
+    println!("{}", hex(&signature));
+    Ok(())
+}
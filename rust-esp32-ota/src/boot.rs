@@ -0,0 +1,316 @@
+//! Deciding whether an app that an OTA update just installed works, or has to be rolled back.
+//!
+//! With rollback enabled (`CONFIG_BOOTLOADER_APP_ROLLBACK_ENABLE`), ESP-IDF boots a new app in
+//! the pending verify state, and boots the previous app again if the new one restarts before it
+//! is marked valid. [`BootValidation`] collects the results of the checks an app runs after such
+//! a boot, and decides when to mark it valid or to roll back, instead of waiting for a crash that
+//! may never come.
+
+use core::fmt;
+
+use serde::Serialize;
+
+/// How long the checks may take when no deadline is configured.
+pub const DEFAULT_DEADLINE_SECS: u64 = 120;
+
+/// State of an app partition in the OTA data, `esp_ota_img_states_t`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImageState {
+    /// Written, but not booted yet.
+    New,
+    /// Booted for the first time, and neither marked valid nor invalid yet.
+    PendingVerify,
+    Valid,
+    Invalid,
+    /// Restarted before it was marked valid, and rolled back by the bootloader.
+    Aborted,
+    /// Rollback is disabled, or the app wasn't installed by an OTA update.
+    Undefined,
+}
+
+impl ImageState {
+    /// Converts the state returned by `esp_ota_get_state_partition()`.
+    pub fn from_esp(state: u32) -> Self {
+        match state {
+            0 => ImageState::New,
+            1 => ImageState::PendingVerify,
+            2 => ImageState::Valid,
+            3 => ImageState::Invalid,
+            4 => ImageState::Aborted,
+            _ => ImageState::Undefined,
+        }
+    }
+}
+
+/// Something a new app has to show to work.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Check {
+    /// WiFi connected and got an address.
+    Wifi,
+    /// The sensor passed its self-test.
+    Sensor,
+    /// The server accepted a report.
+    Server,
+}
+
+impl Check {
+    pub const ALL: [Check; 3] = [Check::Wifi, Check::Sensor, Check::Server];
+
+    /// The name it is configured as.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Check::Wifi => "wifi",
+            Check::Sensor => "sensor",
+            Check::Server => "server",
+        }
+    }
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+/// A set of [`Check`]s.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct Checks(u8);
+
+impl Checks {
+    pub const NONE: Checks = Checks(0);
+
+    /// Parses a comma-separated list of check names, like `wifi,server`.
+    pub fn parse(names: &str) -> Result<Self, UnknownCheck> {
+        let mut checks = Checks::NONE;
+        for name in names.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+            let check = Check::ALL
+                .into_iter()
+                .find(|check| check.name() == name)
+                .ok_or(UnknownCheck)?;
+            checks.insert(check);
+        }
+        Ok(checks)
+    }
+
+    pub fn insert(&mut self, check: Check) {
+        self.0 |= check.bit();
+    }
+
+    pub fn contains(&self, check: Check) -> bool {
+        self.0 & check.bit() != 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// The checks in both `self` and `other`.
+    pub fn intersection(&self, other: Checks) -> Checks {
+        Checks(self.0 & other.0)
+    }
+
+    /// The checks in `self` that aren't in `other`.
+    pub fn without(&self, other: Checks) -> Checks {
+        Checks(self.0 & !other.0)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Check> + '_ {
+        Check::ALL.into_iter().filter(|check| self.contains(*check))
+    }
+}
+
+impl fmt::Display for Checks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, check) in self.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            f.write_str(check.name())?;
+        }
+        Ok(())
+    }
+}
+
+/// A check name [`Checks::parse`] doesn't know.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct UnknownCheck;
+
+impl fmt::Display for UnknownCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Unknown boot check, expected wifi, sensor or server")
+    }
+}
+
+impl std::error::Error for UnknownCheck {}
+
+/// Which checks a new app has to pass, and how long they may take.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct BootConfig {
+    pub checks: Checks,
+    /// Seconds since boot after which the app is rolled back if checks are still missing.
+    pub deadline_secs: u64,
+}
+
+/// What to do with the running app.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Decision {
+    /// Checks are still missing, and the deadline hasn't passed.
+    Pending,
+    /// All checks passed.
+    MarkValid,
+    /// A check failed, or didn't pass before the deadline.
+    RollBack { failed: Checks, missing: Checks },
+}
+
+/// Collects check results after booting a new app, see the [module documentation](self).
+#[derive(Debug, Clone)]
+pub struct BootValidation {
+    config: BootConfig,
+    passed: Checks,
+    failed: Checks,
+}
+
+impl BootValidation {
+    pub fn new(config: BootConfig) -> Self {
+        Self {
+            config,
+            passed: Checks::NONE,
+            failed: Checks::NONE,
+        }
+    }
+
+    /// Records a check that passed.
+    pub fn passed(&mut self, check: Check) {
+        self.passed.insert(check);
+    }
+
+    /// Records a check that failed for good, e.g. a failed self-test, rather than one that can
+    /// still pass later. This outweighs it passing.
+    pub fn failed(&mut self, check: Check) {
+        self.failed.insert(check);
+    }
+
+    /// Decides what to do `uptime_secs` after boot.
+    pub fn decide(&self, uptime_secs: u64) -> Decision {
+        let failed = self.config.checks.intersection(self.failed);
+        let missing = self.config.checks.without(self.passed).without(failed);
+        if !failed.is_empty() {
+            Decision::RollBack { failed, missing }
+        } else if missing.is_empty() {
+            Decision::MarkValid
+        } else if uptime_secs >= self.config.deadline_secs {
+            Decision::RollBack { failed, missing }
+        } else {
+            Decision::Pending
+        }
+    }
+}
+
+#[cfg(target_os = "espidf")]
+impl BootValidation {
+    /// Starts validating the running app, if an OTA update just installed it and it still has to
+    /// be marked valid.
+    pub fn after_ota_boot(config: BootConfig) -> crate::ota::Result<Option<Self>> {
+        match crate::ota::running_state(&crate::ota::EspOta)? {
+            ImageState::PendingVerify => {
+                log::info!("Validating the new app, checks: {}", config.checks);
+                Ok(Some(Self::new(config)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Marks the running app valid or rolls back to the previous one, once that is decided.
+    ///
+    /// Returns whether validation is over, and doesn't return at all if rolling back worked.
+    pub fn apply(&self) -> crate::ota::Result<bool> {
+        let uptime_secs = unsafe { esp_idf_sys::esp_timer_get_time() } / 1_000_000;
+        match self.decide(uptime_secs as u64) {
+            Decision::Pending => Ok(false),
+            Decision::MarkValid => {
+                log::info!("The new app passed its checks, marking it valid");
+                crate::ota::mark_app_valid(&crate::ota::EspOta)?;
+                Ok(true)
+            }
+            Decision::RollBack { failed, missing } => {
+                log::error!(
+                    "The new app failed checks [{}] or didn't pass [{}] in time, rolling back",
+                    failed,
+                    missing
+                );
+                match crate::ota::rollback_and_reboot(&crate::ota::EspOta)? {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn boot_validation(checks: &str) -> BootValidation {
+        BootValidation::new(BootConfig {
+            checks: Checks::parse(checks).unwrap(),
+            deadline_secs: 60,
+        })
+    }
+
+    #[test]
+    fn converts_esp_image_states() {
+        assert_eq!(ImageState::from_esp(1), ImageState::PendingVerify);
+        assert_eq!(ImageState::from_esp(4), ImageState::Aborted);
+        assert_eq!(ImageState::from_esp(u32::MAX), ImageState::Undefined);
+    }
+
+    #[test]
+    fn parses_check_lists() {
+        let checks = Checks::parse(" server, wifi ").unwrap();
+        assert!(checks.contains(Check::Wifi));
+        assert!(!checks.contains(Check::Sensor));
+        assert_eq!(checks.to_string(), "wifi,server");
+        assert_eq!(Checks::parse(""), Ok(Checks::NONE));
+        assert_eq!(Checks::parse("wifi,bluetooth"), Err(UnknownCheck));
+    }
+
+    #[test]
+    fn marks_valid_once_all_checks_passed() {
+        let mut validation = boot_validation("wifi,sensor,server");
+        assert_eq!(validation.decide(0), Decision::Pending);
+        validation.passed(Check::Wifi);
+        validation.passed(Check::Sensor);
+        assert_eq!(validation.decide(59), Decision::Pending);
+        validation.passed(Check::Server);
+        assert_eq!(validation.decide(59), Decision::MarkValid);
+
+        // Checks that aren't configured don't matter.
+        let mut validation = boot_validation("wifi");
+        validation.failed(Check::Sensor);
+        validation.passed(Check::Wifi);
+        assert_eq!(validation.decide(0), Decision::MarkValid);
+        assert_eq!(boot_validation("").decide(0), Decision::MarkValid);
+    }
+
+    #[test]
+    fn rolls_back_on_failures_and_after_the_deadline() {
+        let mut validation = boot_validation("wifi,sensor,server");
+        validation.passed(Check::Wifi);
+        validation.failed(Check::Sensor);
+        assert_eq!(
+            validation.decide(1),
+            Decision::RollBack {
+                failed: Checks::parse("sensor").unwrap(),
+                missing: Checks::parse("server").unwrap(),
+            }
+        );
+
+        let mut validation = boot_validation("wifi,server");
+        validation.passed(Check::Wifi);
+        assert_eq!(validation.decide(59), Decision::Pending);
+        assert_eq!(
+            validation.decide(60),
+            Decision::RollBack {
+                failed: Checks::NONE,
+                missing: Checks::parse("server").unwrap(),
+            }
+        );
+    }
+}
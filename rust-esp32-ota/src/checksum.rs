@@ -0,0 +1,173 @@
+//! Checks that an uploaded image arrived complete and unmodified before it is finalized.
+//!
+//! Uploads announce the image size in `Content-Length` and its SHA-256 in the
+//! `X-Firmware-SHA256` header, and [`ImageCheck`] compares both against the bytes as they are
+//! written.
+
+use core::fmt;
+
+use sha2::{Digest, Sha256};
+
+/// Header carrying the hex-encoded SHA-256 of an uploaded image.
+pub const SHA256_HEADER: &str = "X-Firmware-SHA256";
+
+/// Why an image was rejected.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ChecksumError {
+    /// The SHA-256 isn't 64 hexadecimal digits.
+    InvalidSha256,
+    /// More bytes arrived than announced.
+    TooLong { expected: usize },
+    /// The body ended before all announced bytes arrived.
+    TooShort { expected: usize, received: usize },
+    /// The image doesn't hash to the announced SHA-256.
+    Sha256Mismatch,
+}
+
+impl fmt::Display for ChecksumError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChecksumError::InvalidSha256 => write!(f, "SHA-256 must be 64 hexadecimal digits"),
+            ChecksumError::TooLong { expected } => {
+                write!(f, "Received more than the announced {expected} bytes")
+            }
+            ChecksumError::TooShort { expected, received } => {
+                write!(f, "Received {received} of the announced {expected} bytes")
+            }
+            ChecksumError::Sha256Mismatch => write!(f, "SHA-256 of the image doesn't match"),
+        }
+    }
+}
+
+impl std::error::Error for ChecksumError {}
+
+/// Parses a hex-encoded SHA-256, in either case.
+pub fn parse_sha256(hex: &str) -> Result<[u8; 32], ChecksumError> {
+    decode_hex(hex).ok_or(ChecksumError::InvalidSha256)
+}
+
+/// Decodes exactly `N` hex-encoded bytes, in either case.
+pub fn decode_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    let hex = hex.trim().as_bytes();
+    if hex.len() != 2 * N {
+        return None;
+    }
+    let mut bytes = [0; N];
+    for (byte, pair) in bytes.iter_mut().zip(hex.chunks(2)) {
+        let digit = |d: u8| char::from(d).to_digit(16);
+        *byte = (digit(pair[0])? * 16 + digit(pair[1])?) as u8;
+    }
+    Some(bytes)
+}
+
+/// Tracks the length and hash of an image while it is written.
+#[derive(Debug, Clone)]
+pub struct ImageCheck {
+    expected_len: usize,
+    expected_sha256: [u8; 32],
+    received: usize,
+    hasher: Sha256,
+}
+
+impl ImageCheck {
+    pub fn new(expected_len: usize, expected_sha256: [u8; 32]) -> Self {
+        Self {
+            expected_len,
+            expected_sha256,
+            received: 0,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// Accounts for the next chunk, which must be done before writing it so that nothing past
+    /// the announced length is written.
+    pub fn update(&mut self, chunk: &[u8]) -> Result<(), ChecksumError> {
+        if chunk.len() > self.expected_len - self.received {
+            return Err(ChecksumError::TooLong {
+                expected: self.expected_len,
+            });
+        }
+        self.received += chunk.len();
+        self.hasher.update(chunk);
+        Ok(())
+    }
+
+    /// How many bytes the image must have.
+    pub fn expected(&self) -> usize {
+        self.expected_len
+    }
+
+    /// How many bytes were accounted for up to now.
+    pub fn received(&self) -> usize {
+        self.received
+    }
+
+    /// Checks the image once all of it was received.
+    pub fn finish(self) -> Result<(), ChecksumError> {
+        if self.received != self.expected_len {
+            return Err(ChecksumError::TooShort {
+                expected: self.expected_len,
+                received: self.received,
+            });
+        }
+        if self.hasher.finalize().as_slice() != self.expected_sha256 {
+            return Err(ChecksumError::Sha256Mismatch);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// SHA-256 of `abc`.
+    const ABC_SHA256: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    #[test]
+    fn parses_hex_sha256() {
+        let sha256 = parse_sha256(ABC_SHA256).unwrap();
+        assert_eq!(sha256[..3], [0xba, 0x78, 0x16]);
+        assert_eq!(parse_sha256(&ABC_SHA256.to_uppercase()), Ok(sha256));
+
+        assert_eq!(
+            parse_sha256(&ABC_SHA256[1..]),
+            Err(ChecksumError::InvalidSha256)
+        );
+        let not_hex = ABC_SHA256.replace('b', "g");
+        assert_eq!(parse_sha256(&not_hex), Err(ChecksumError::InvalidSha256));
+    }
+
+    #[test]
+    fn accepts_the_announced_image() {
+        let mut check = ImageCheck::new(3, parse_sha256(ABC_SHA256).unwrap());
+        check.update(b"a").unwrap();
+        check.update(b"").unwrap();
+        check.update(b"bc").unwrap();
+        assert_eq!(check.received(), 3);
+        assert_eq!(check.finish(), Ok(()));
+    }
+
+    #[test]
+    fn rejects_other_images() {
+        let sha256 = parse_sha256(ABC_SHA256).unwrap();
+
+        let mut check = ImageCheck::new(3, sha256);
+        check.update(b"ab").unwrap();
+        assert_eq!(
+            check.update(b"cd"),
+            Err(ChecksumError::TooLong { expected: 3 })
+        );
+        assert_eq!(
+            check.finish(),
+            Err(ChecksumError::TooShort {
+                expected: 3,
+                received: 2
+            })
+        );
+
+        let mut check = ImageCheck::new(3, sha256);
+        check.update(b"abd").unwrap();
+        assert_eq!(check.finish(), Err(ChecksumError::Sha256Mismatch));
+    }
+}
@@ -0,0 +1,343 @@
+//! Downloading an image over a connection that may drop, resuming where it stopped.
+//!
+//! After an interruption the image is requested again with `Range: bytes=<received>-`, and the
+//! same [`ImageCheck`] and writer carry on, so the OTA update stays open and the SHA-256 covers
+//! every byte once, however often the download was resumed.
+
+use core::fmt;
+use std::{thread, time::Duration};
+
+use anyhow::Result;
+use log::warn;
+
+use crate::checksum::ImageCheck;
+
+/// How much of the image is read at a time.
+const CHUNK_SIZE: usize = 1024;
+
+/// A connection to the server the image is downloaded from.
+pub trait Transfer {
+    /// Requests the image from `offset` on, with a `Range` header unless `offset` is 0, and
+    /// returns the status and the `Content-Range` header of the answer.
+    fn request(&mut self, offset: usize) -> Result<(u16, Option<String>)>;
+
+    /// Reads the next bytes of the answer, returning 0 at its end.
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize>;
+}
+
+/// Why a download was given up.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum DownloadError {
+    /// The server answered with neither the image nor the requested part of it.
+    Status(u16),
+    /// The server answered a resumed request with another part of the image.
+    UnexpectedRange { requested: usize },
+    /// The download was interrupted too often in a row without getting any further.
+    Interrupted { received: usize, expected: usize },
+}
+
+impl fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DownloadError::Status(status) => write!(f, "Server answered {status}"),
+            DownloadError::UnexpectedRange { requested } => {
+                write!(f, "Server didn't resume at byte {requested}")
+            }
+            DownloadError::Interrupted { received, expected } => {
+                write!(f, "Download stopped at {received} of {expected} bytes")
+            }
+        }
+    }
+}
+
+impl std::error::Error for DownloadError {}
+
+/// How often and how soon an interrupted download is resumed.
+#[derive(Debug, Clone)]
+pub struct Resume {
+    /// Attempts in a row that didn't receive anything before giving up.
+    pub max_attempts: u32,
+    /// Wait before resuming.
+    pub delay: Duration,
+}
+
+impl Default for Resume {
+    fn default() -> Self {
+        Resume {
+            max_attempts: 5,
+            delay: Duration::from_secs(2),
+        }
+    }
+}
+
+/// Reads the start of a `Content-Range: bytes <start>-<end>/<length>` header.
+pub fn content_range_start(header: &str) -> Option<usize> {
+    let range = header.trim().strip_prefix("bytes ")?;
+    let (start, _) = range.split_once('-')?;
+    start.trim().parse().ok()
+}
+
+/// What ended an attempt that didn't get all of the image.
+enum Stop {
+    /// The connection failed, so resuming may get further.
+    Interrupted(anyhow::Error),
+    /// Resuming won't help.
+    Failed(anyhow::Error),
+}
+
+/// Downloads the image `check` expects, passing it to `write` a chunk at a time.
+///
+/// Returns once all of it was received, or the first error of `check` or `write`. The image still
+/// has to be checked with [`ImageCheck::finish`].
+pub fn download(
+    transfer: &mut impl Transfer,
+    check: &mut ImageCheck,
+    resume: &Resume,
+    mut write: impl FnMut(&[u8]) -> Result<()>,
+) -> Result<()> {
+    let mut failed_attempts = 0;
+    loop {
+        let offset = check.received();
+        let error = match attempt(transfer, check, offset, &mut write) {
+            Ok(()) if check.received() == check.expected() => return Ok(()),
+            // The server closed the connection early.
+            Ok(()) => None,
+            Err(Stop::Interrupted(e)) => Some(e),
+            Err(Stop::Failed(e)) => return Err(e),
+        };
+        if check.received() > offset {
+            failed_attempts = 0;
+        } else {
+            failed_attempts += 1;
+        }
+        if failed_attempts >= resume.max_attempts {
+            return Err(DownloadError::Interrupted {
+                received: check.received(),
+                expected: check.expected(),
+            }
+            .into());
+        }
+        warn!(
+            "Download interrupted at {} of {} bytes ({}), resuming",
+            check.received(),
+            check.expected(),
+            error.map_or("connection closed".to_string(), |e| format!("{e:#}"))
+        );
+        thread::sleep(resume.delay);
+    }
+}
+
+/// Requests the image from `offset` on and writes what arrives.
+fn attempt(
+    transfer: &mut impl Transfer,
+    check: &mut ImageCheck,
+    offset: usize,
+    write: &mut impl FnMut(&[u8]) -> Result<()>,
+) -> Result<(), Stop> {
+    let (status, content_range) = transfer.request(offset).map_err(Stop::Interrupted)?;
+    // Servers that ignore `Range` send all of the image again, and what arrived is skipped.
+    let mut skip = match status {
+        200 => offset,
+        206 if content_range.as_deref().and_then(content_range_start) == Some(offset) => 0,
+        206 => {
+            return Err(Stop::Failed(
+                DownloadError::UnexpectedRange { requested: offset }.into(),
+            ))
+        }
+        500..=599 => return Err(Stop::Interrupted(DownloadError::Status(status).into())),
+        _ => return Err(Stop::Failed(DownloadError::Status(status).into())),
+    };
+    let mut chunk = [0_u8; CHUNK_SIZE];
+    loop {
+        let read = transfer.read(&mut chunk).map_err(Stop::Interrupted)?;
+        if read == 0 {
+            return Ok(());
+        }
+        let skipped = skip.min(read);
+        skip -= skipped;
+        let data = &chunk[skipped..read];
+        if data.is_empty() {
+            continue;
+        }
+        check.update(data).map_err(|e| Stop::Failed(e.into()))?;
+        write(data).map_err(Stop::Failed)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checksum::ChecksumError;
+    use anyhow::anyhow;
+    use sha2::{Digest, Sha256};
+
+    /// Serves `image`, dropping the connection after the given number of bytes of each answer.
+    struct FlakyServer {
+        image: Vec<u8>,
+        drops: Vec<usize>,
+        honors_range: bool,
+        requests: Vec<usize>,
+        body: Vec<u8>,
+        drop_after: Option<usize>,
+    }
+
+    impl FlakyServer {
+        fn new(image: &[u8], drops: &[usize]) -> Self {
+            FlakyServer {
+                image: image.to_vec(),
+                drops: drops.to_vec(),
+                honors_range: true,
+                requests: Vec::new(),
+                body: Vec::new(),
+                drop_after: None,
+            }
+        }
+    }
+
+    impl Transfer for FlakyServer {
+        fn request(&mut self, offset: usize) -> Result<(u16, Option<String>)> {
+            self.requests.push(offset);
+            self.drop_after = (!self.drops.is_empty()).then(|| self.drops.remove(0));
+            if offset == 0 || !self.honors_range {
+                self.body = self.image.clone();
+                return Ok((200, None));
+            }
+            self.body = self.image[offset..].to_vec();
+            let range = format!(
+                "bytes {}-{}/{}",
+                offset,
+                self.image.len() - 1,
+                self.image.len()
+            );
+            Ok((206, Some(range)))
+        }
+
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            let mut len = buf.len().min(self.body.len());
+            if let Some(left) = &mut self.drop_after {
+                if *left == 0 {
+                    return Err(anyhow!("connection reset"));
+                }
+                len = len.min(*left);
+                *left -= len;
+            }
+            buf[..len].copy_from_slice(&self.body[..len]);
+            self.body.drain(..len);
+            Ok(len)
+        }
+    }
+
+    fn image() -> Vec<u8> {
+        (0..5000_u32).map(|i| (i * 7) as u8).collect()
+    }
+
+    fn check_for(image: &[u8]) -> ImageCheck {
+        ImageCheck::new(image.len(), Sha256::digest(image).into())
+    }
+
+    fn instantly() -> Resume {
+        Resume {
+            max_attempts: 3,
+            delay: Duration::ZERO,
+        }
+    }
+
+    fn run(server: &mut FlakyServer, check: &mut ImageCheck) -> Result<Vec<u8>> {
+        let mut written = Vec::new();
+        download(server, check, &instantly(), |data| {
+            written.extend_from_slice(data);
+            Ok(())
+        })?;
+        Ok(written)
+    }
+
+    #[test]
+    fn reads_content_ranges() {
+        assert_eq!(content_range_start("bytes 200-255/256"), Some(200));
+        assert_eq!(content_range_start("bytes */256"), None);
+        assert_eq!(content_range_start("items 0-1/2"), None);
+    }
+
+    #[test]
+    fn resumes_where_the_connection_dropped() {
+        let image = image();
+        let mut server = FlakyServer::new(&image, &[1500, 0, 2000]);
+        let mut check = check_for(&image);
+        let written = run(&mut server, &mut check).unwrap();
+        assert_eq!(written, image);
+        assert_eq!(server.requests, vec![0, 1500, 1500, 3500]);
+        assert_eq!(check.finish(), Ok(()));
+    }
+
+    #[test]
+    fn skips_what_arrived_when_range_is_ignored() {
+        let image = image();
+        let mut server = FlakyServer::new(&image, &[2500]);
+        server.honors_range = false;
+        let mut check = check_for(&image);
+        let written = run(&mut server, &mut check).unwrap();
+        assert_eq!(written, image);
+        assert_eq!(server.requests, vec![0, 2500]);
+        assert_eq!(check.finish(), Ok(()));
+    }
+
+    #[test]
+    fn gives_up_without_progress() {
+        let image = image();
+        let mut server = FlakyServer::new(&image, &[100, 0, 0, 0, 0]);
+        let mut check = check_for(&image);
+        let e = run(&mut server, &mut check).unwrap_err();
+        assert_eq!(
+            e.downcast_ref(),
+            Some(&DownloadError::Interrupted {
+                received: 100,
+                expected: 5000
+            })
+        );
+        assert_eq!(server.requests, vec![0, 100, 100, 100]);
+    }
+
+    #[test]
+    fn hashes_across_resumes() {
+        let image = image();
+        let mut server = FlakyServer::new(&image, &[1000]);
+        let mut tampered = image.clone();
+        tampered[4000] ^= 1;
+        let mut check = check_for(&tampered);
+        run(&mut server, &mut check).unwrap();
+        assert_eq!(check.finish(), Err(ChecksumError::Sha256Mismatch));
+    }
+
+    #[test]
+    fn stops_on_write_errors_and_other_ranges() {
+        let image = image();
+        let mut server = FlakyServer::new(&image, &[]);
+        let mut check = check_for(&image);
+        let e = download(&mut server, &mut check, &instantly(), |_| {
+            Err(anyhow!("flash write failed"))
+        })
+        .unwrap_err();
+        assert_eq!(e.to_string(), "flash write failed");
+        assert_eq!(server.requests, vec![0]);
+
+        /// Answers every request with the start of the image.
+        struct Restarting(FlakyServer);
+        impl Transfer for Restarting {
+            fn request(&mut self, offset: usize) -> Result<(u16, Option<String>)> {
+                self.0.request(0)?;
+                let status = if offset == 0 { 200 } else { 206 };
+                Ok((status, Some("bytes 0-4999/5000".into())))
+            }
+            fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+                self.0.read(buf)
+            }
+        }
+        let mut server = Restarting(FlakyServer::new(&image, &[10]));
+        let mut check = check_for(&image);
+        let e = download(&mut server, &mut check, &instantly(), |_| Ok(())).unwrap_err();
+        assert_eq!(
+            e.downcast_ref(),
+            Some(&DownloadError::UnexpectedRange { requested: 10 })
+        );
+    }
+}
@@ -0,0 +1,123 @@
+//! Errors of the ESP-IDF OTA functions, which don't need ESP-IDF to be matched on.
+
+use core::fmt;
+
+use serde::Serialize;
+
+use crate::ffi::esp_err_t;
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// An error that can happen during ESP OTA operations.
+#[derive(Debug)]
+pub struct Error {
+    kind: ErrorKind,
+    unexpected: Option<Unexpected>,
+}
+
+/// Where an [`ErrorKind::Unexpected`] code came from.
+#[derive(Debug)]
+struct Unexpected {
+    function: &'static str,
+    name: &'static str,
+}
+
+impl Error {
+    pub(crate) fn from_kind(kind: ErrorKind) -> Self {
+        Self {
+            kind,
+            unexpected: None,
+        }
+    }
+
+    /// An error code `function` isn't documented to return, named by `esp_err_to_name()`.
+    pub(crate) fn unexpected(function: &'static str, code: esp_err_t, name: &'static str) -> Self {
+        Self {
+            kind: ErrorKind::Unexpected(code),
+            unexpected: Some(Unexpected { function, name }),
+        }
+    }
+
+    /// Returns the kind of error as an enum, that can be matched on.
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    /// Returns the name of an unexpected error code, like `ESP_ERR_INVALID_SIZE`.
+    pub fn code_name(&self) -> Option<&'static str> {
+        self.unexpected.as_ref().map(|unexpected| unexpected.name)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.unexpected, self.kind) {
+            (Some(Unexpected { function, name }), ErrorKind::Unexpected(code)) => {
+                write!(f, "Unexpected {function} return code {code} ({name})")
+            }
+            _ => self.kind.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// No suitable partition for writing OTA update to found.
+    NoOtaPartition,
+    /// Cannot allocate memory for OTA operation.
+    AllocFailed,
+    /// Rollback enabled, but the currently running application is still pending. The currently
+    /// running application must confirm itself before downloading and flashing a new app.
+    InvalidRollbackState,
+    /// First byte of image contains invalid app image magic byte.
+    InvalidMagicByte,
+    /// Flash write operation timed out.
+    FlashTimeout,
+    /// Flash write operation failed.
+    FlashFailed,
+    /// OTA data partition has invalid contents.
+    InvalidOtaPartitionData,
+    /// The [`OtaUpdate`](crate::ota::OtaUpdate) handle was finalized before any app image was written to it.
+    NothingWritten,
+    /// OTA image is invalid (either not a valid app image, or - if secure boot is enabled - signature failed to verify.)
+    InvalidImage,
+    /// If flash encryption is enabled, this result indicates an internal error writing the final encrypted bytes to flash.
+    WritingEncryptedFailed,
+    /// The rollback failed.
+    RollbackFailed,
+    /// The rollback is not possible due to flash does not have any apps.
+    RollbackFailedNoApps,
+    /// An ESP-IDF function returned a code it isn't documented to return, or one that points at
+    /// a broken partition table or flash configuration.
+    Unexpected(esp_err_t),
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use ErrorKind::*;
+        match self {
+            Unexpected(code) => return write!(f, "Unexpected ESP-IDF error code {code}"),
+            NoOtaPartition => "No suitable partition for writing OTA update to found",
+            AllocFailed => "Cannot allocate memory for OTA operation",
+            InvalidRollbackState => {
+                "Rollback enabled, but the currently running application is still pending"
+            }
+            InvalidMagicByte => "First byte of image contains invalid app image magic byte",
+            FlashTimeout => "Flash write operation timed out",
+            FlashFailed => "Flash write operation failed",
+            InvalidOtaPartitionData => "OTA data partition has invalid contents",
+            NothingWritten => "OtaUpdate was never written to",
+            InvalidImage => "OTA image is invalid",
+            WritingEncryptedFailed => "Internal error writing the final encrypted bytes to flash",
+            RollbackFailed => "The rollback failed",
+            RollbackFailedNoApps => {
+                "The rollback is not possible due to flash does not have any apps"
+            }
+        }
+        .fmt(f)
+    }
+}
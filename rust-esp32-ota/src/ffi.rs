@@ -0,0 +1,301 @@
+//! The ESP-IDF OTA functions [`ota`](crate::ota) calls, behind a trait so that tests can stand in
+//! for them.
+//!
+//! [`OtaFfi`] mirrors the `esp_ota_*` functions closely, returning their `esp_err_t` codes as
+//! they are, so that turning those into errors is left to [`ota`](crate::ota) and can be tested
+//! on the host. The error codes are defined here as well, as `esp_idf_sys` only builds for the
+//! device, and checked against it there.
+
+#![allow(non_camel_case_types)]
+
+use core::fmt;
+
+/// An ESP-IDF error code.
+pub type esp_err_t = i32;
+/// An ongoing OTA update, `esp_ota_handle_t`.
+pub type esp_ota_handle_t = u32;
+/// The state of an app in the OTA data, `esp_ota_img_states_t`.
+pub type esp_ota_img_states_t = u32;
+
+pub const ESP_OK: esp_err_t = 0;
+pub const ESP_FAIL: esp_err_t = -1;
+pub const ESP_ERR_NO_MEM: esp_err_t = 0x101;
+pub const ESP_ERR_INVALID_ARG: esp_err_t = 0x102;
+pub const ESP_ERR_INVALID_STATE: esp_err_t = 0x103;
+pub const ESP_ERR_INVALID_SIZE: esp_err_t = 0x104;
+pub const ESP_ERR_NOT_FOUND: esp_err_t = 0x105;
+pub const ESP_ERR_NOT_SUPPORTED: esp_err_t = 0x106;
+pub const ESP_ERR_OTA_PARTITION_CONFLICT: esp_err_t = 0x1501;
+pub const ESP_ERR_OTA_SELECT_INFO_INVALID: esp_err_t = 0x1502;
+pub const ESP_ERR_OTA_VALIDATE_FAILED: esp_err_t = 0x1503;
+pub const ESP_ERR_OTA_ROLLBACK_FAILED: esp_err_t = 0x1504;
+pub const ESP_ERR_OTA_ROLLBACK_INVALID_STATE: esp_err_t = 0x1505;
+pub const ESP_ERR_FLASH_OP_FAIL: esp_err_t = 0x6001;
+pub const ESP_ERR_FLASH_OP_TIMEOUT: esp_err_t = 0x6002;
+
+/// Names the codes above like `esp_err_to_name()`, for stand-ins on the host.
+pub fn err_name(code: esp_err_t) -> &'static str {
+    match code {
+        ESP_OK => "ESP_OK",
+        ESP_FAIL => "ESP_FAIL",
+        ESP_ERR_NO_MEM => "ESP_ERR_NO_MEM",
+        ESP_ERR_INVALID_ARG => "ESP_ERR_INVALID_ARG",
+        ESP_ERR_INVALID_STATE => "ESP_ERR_INVALID_STATE",
+        ESP_ERR_INVALID_SIZE => "ESP_ERR_INVALID_SIZE",
+        ESP_ERR_NOT_FOUND => "ESP_ERR_NOT_FOUND",
+        ESP_ERR_NOT_SUPPORTED => "ESP_ERR_NOT_SUPPORTED",
+        ESP_ERR_OTA_PARTITION_CONFLICT => "ESP_ERR_OTA_PARTITION_CONFLICT",
+        ESP_ERR_OTA_SELECT_INFO_INVALID => "ESP_ERR_OTA_SELECT_INFO_INVALID",
+        ESP_ERR_OTA_VALIDATE_FAILED => "ESP_ERR_OTA_VALIDATE_FAILED",
+        ESP_ERR_OTA_ROLLBACK_FAILED => "ESP_ERR_OTA_ROLLBACK_FAILED",
+        ESP_ERR_OTA_ROLLBACK_INVALID_STATE => "ESP_ERR_OTA_ROLLBACK_INVALID_STATE",
+        ESP_ERR_FLASH_OP_FAIL => "ESP_ERR_FLASH_OP_FAIL",
+        ESP_ERR_FLASH_OP_TIMEOUT => "ESP_ERR_FLASH_OP_TIMEOUT",
+        _ => "UNKNOWN ERROR",
+    }
+}
+
+/// The ESP-IDF OTA functions, named after them without the `esp_` prefix.
+pub trait OtaFfi {
+    /// Identifies a partition, an `EspPartition` from ESP-IDF's own getters on the device.
+    type Partition: Copy + fmt::Debug + PartialEq;
+
+    /// `esp_ota_get_running_partition()`
+    fn ota_get_running_partition(&self) -> Self::Partition;
+
+    /// `esp_ota_get_boot_partition()`, `None` for a null pointer.
+    fn ota_get_boot_partition(&self) -> Option<Self::Partition>;
+
+    /// `esp_ota_get_next_update_partition(NULL)`, `None` for a null pointer.
+    fn ota_get_next_update_partition(&self) -> Option<Self::Partition>;
+
+    /// `esp_ota_begin()` with `OTA_SIZE_UNKNOWN`, erasing all of the partition.
+    fn ota_begin(&self, partition: Self::Partition, handle: &mut esp_ota_handle_t) -> esp_err_t;
+
+    /// `esp_ota_write()`
+    fn ota_write(&self, handle: esp_ota_handle_t, data: &[u8]) -> esp_err_t;
+
+    /// `esp_ota_end()`
+    fn ota_end(&self, handle: esp_ota_handle_t) -> esp_err_t;
+
+    /// `esp_ota_abort()`
+    fn ota_abort(&self, handle: esp_ota_handle_t) -> esp_err_t;
+
+    /// `esp_ota_set_boot_partition()`
+    fn ota_set_boot_partition(&self, partition: Self::Partition) -> esp_err_t;
+
+    /// `esp_ota_get_state_partition()`
+    fn ota_get_state_partition(
+        &self,
+        partition: Self::Partition,
+        state: &mut esp_ota_img_states_t,
+    ) -> esp_err_t;
+
+    /// `esp_ota_mark_app_valid_cancel_rollback()`
+    fn ota_mark_app_valid_cancel_rollback(&self) -> esp_err_t;
+
+    /// `esp_ota_mark_app_invalid_rollback_and_reboot()`, which only returns if rolling back
+    /// failed.
+    fn ota_mark_app_invalid_rollback_and_reboot(&self) -> esp_err_t;
+
+    /// `esp_err_to_name()`
+    fn err_to_name(&self, code: esp_err_t) -> &'static str;
+}
+
+impl<T: OtaFfi> OtaFfi for &T {
+    type Partition = T::Partition;
+
+    fn ota_get_running_partition(&self) -> Self::Partition {
+        (**self).ota_get_running_partition()
+    }
+
+    fn ota_get_boot_partition(&self) -> Option<Self::Partition> {
+        (**self).ota_get_boot_partition()
+    }
+
+    fn ota_get_next_update_partition(&self) -> Option<Self::Partition> {
+        (**self).ota_get_next_update_partition()
+    }
+
+    fn ota_begin(&self, partition: Self::Partition, handle: &mut esp_ota_handle_t) -> esp_err_t {
+        (**self).ota_begin(partition, handle)
+    }
+
+    fn ota_write(&self, handle: esp_ota_handle_t, data: &[u8]) -> esp_err_t {
+        (**self).ota_write(handle, data)
+    }
+
+    fn ota_end(&self, handle: esp_ota_handle_t) -> esp_err_t {
+        (**self).ota_end(handle)
+    }
+
+    fn ota_abort(&self, handle: esp_ota_handle_t) -> esp_err_t {
+        (**self).ota_abort(handle)
+    }
+
+    fn ota_set_boot_partition(&self, partition: Self::Partition) -> esp_err_t {
+        (**self).ota_set_boot_partition(partition)
+    }
+
+    fn ota_get_state_partition(
+        &self,
+        partition: Self::Partition,
+        state: &mut esp_ota_img_states_t,
+    ) -> esp_err_t {
+        (**self).ota_get_state_partition(partition, state)
+    }
+
+    fn ota_mark_app_valid_cancel_rollback(&self) -> esp_err_t {
+        (**self).ota_mark_app_valid_cancel_rollback()
+    }
+
+    fn ota_mark_app_invalid_rollback_and_reboot(&self) -> esp_err_t {
+        (**self).ota_mark_app_invalid_rollback_and_reboot()
+    }
+
+    fn err_to_name(&self, code: esp_err_t) -> &'static str {
+        (**self).err_to_name(code)
+    }
+}
+
+#[cfg(target_os = "espidf")]
+mod esp {
+    use core::ffi::CStr;
+    use core::ptr;
+
+    use esp_idf_sys::{
+        esp_err_to_name, esp_ota_abort, esp_ota_begin, esp_ota_end, esp_ota_get_boot_partition,
+        esp_ota_get_next_update_partition, esp_ota_get_running_partition,
+        esp_ota_get_state_partition, esp_ota_mark_app_invalid_rollback_and_reboot,
+        esp_ota_mark_app_valid_cancel_rollback, esp_ota_set_boot_partition, esp_ota_write,
+        esp_partition_t, OTA_SIZE_UNKNOWN,
+    };
+
+    use super::*;
+
+    // The codes above have to be the ones ESP-IDF returns.
+    const _: () = {
+        assert!(ESP_OK == esp_idf_sys::ESP_OK as esp_err_t);
+        assert!(ESP_FAIL == esp_idf_sys::ESP_FAIL as esp_err_t);
+        assert!(ESP_ERR_NO_MEM == esp_idf_sys::ESP_ERR_NO_MEM as esp_err_t);
+        assert!(ESP_ERR_INVALID_ARG == esp_idf_sys::ESP_ERR_INVALID_ARG as esp_err_t);
+        assert!(ESP_ERR_INVALID_STATE == esp_idf_sys::ESP_ERR_INVALID_STATE as esp_err_t);
+        assert!(ESP_ERR_INVALID_SIZE == esp_idf_sys::ESP_ERR_INVALID_SIZE as esp_err_t);
+        assert!(ESP_ERR_NOT_FOUND == esp_idf_sys::ESP_ERR_NOT_FOUND as esp_err_t);
+        assert!(ESP_ERR_NOT_SUPPORTED == esp_idf_sys::ESP_ERR_NOT_SUPPORTED as esp_err_t);
+        assert!(
+            ESP_ERR_OTA_PARTITION_CONFLICT
+                == esp_idf_sys::ESP_ERR_OTA_PARTITION_CONFLICT as esp_err_t
+        );
+        assert!(
+            ESP_ERR_OTA_SELECT_INFO_INVALID
+                == esp_idf_sys::ESP_ERR_OTA_SELECT_INFO_INVALID as esp_err_t
+        );
+        assert!(
+            ESP_ERR_OTA_VALIDATE_FAILED == esp_idf_sys::ESP_ERR_OTA_VALIDATE_FAILED as esp_err_t
+        );
+        assert!(
+            ESP_ERR_OTA_ROLLBACK_FAILED == esp_idf_sys::ESP_ERR_OTA_ROLLBACK_FAILED as esp_err_t
+        );
+        assert!(
+            ESP_ERR_OTA_ROLLBACK_INVALID_STATE
+                == esp_idf_sys::ESP_ERR_OTA_ROLLBACK_INVALID_STATE as esp_err_t
+        );
+        assert!(ESP_ERR_FLASH_OP_FAIL == esp_idf_sys::ESP_ERR_FLASH_OP_FAIL as esp_err_t);
+        assert!(ESP_ERR_FLASH_OP_TIMEOUT == esp_idf_sys::ESP_ERR_FLASH_OP_TIMEOUT as esp_err_t);
+    };
+
+    /// A partition of the partition table, as returned by ESP-IDF.
+    ///
+    /// Only made from the non-null pointers ESP-IDF's getters return, which point into the
+    /// partition table it keeps for as long as the app runs, so that the partition functions
+    /// can be called with it safely.
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub struct EspPartition(*const esp_partition_t);
+
+    impl EspPartition {
+        fn new(partition: *const esp_partition_t) -> Option<Self> {
+            (!partition.is_null()).then_some(EspPartition(partition))
+        }
+
+        pub fn as_ptr(self) -> *const esp_partition_t {
+            self.0
+        }
+
+        /// The partition's entry in the partition table.
+        pub fn get(self) -> &'static esp_partition_t {
+            unsafe { &*self.0 }
+        }
+    }
+
+    /// The OTA functions of ESP-IDF itself.
+    #[derive(Debug, Copy, Clone, Default)]
+    pub struct EspOta;
+
+    impl OtaFfi for EspOta {
+        type Partition = EspPartition;
+
+        fn ota_get_running_partition(&self) -> Self::Partition {
+            EspPartition::new(unsafe { esp_ota_get_running_partition() })
+                .expect("the running app is in a partition")
+        }
+
+        fn ota_get_boot_partition(&self) -> Option<Self::Partition> {
+            EspPartition::new(unsafe { esp_ota_get_boot_partition() })
+        }
+
+        fn ota_get_next_update_partition(&self) -> Option<Self::Partition> {
+            EspPartition::new(unsafe { esp_ota_get_next_update_partition(ptr::null()) })
+        }
+
+        fn ota_begin(
+            &self,
+            partition: Self::Partition,
+            handle: &mut esp_ota_handle_t,
+        ) -> esp_err_t {
+            unsafe { esp_ota_begin(partition.as_ptr(), OTA_SIZE_UNKNOWN as usize, handle) }
+        }
+
+        fn ota_write(&self, handle: esp_ota_handle_t, data: &[u8]) -> esp_err_t {
+            unsafe { esp_ota_write(handle, data.as_ptr().cast(), data.len()) }
+        }
+
+        fn ota_end(&self, handle: esp_ota_handle_t) -> esp_err_t {
+            unsafe { esp_ota_end(handle) }
+        }
+
+        fn ota_abort(&self, handle: esp_ota_handle_t) -> esp_err_t {
+            unsafe { esp_ota_abort(handle) }
+        }
+
+        fn ota_set_boot_partition(&self, partition: Self::Partition) -> esp_err_t {
+            unsafe { esp_ota_set_boot_partition(partition.as_ptr()) }
+        }
+
+        fn ota_get_state_partition(
+            &self,
+            partition: Self::Partition,
+            state: &mut esp_ota_img_states_t,
+        ) -> esp_err_t {
+            unsafe { esp_ota_get_state_partition(partition.as_ptr(), state) }
+        }
+
+        fn ota_mark_app_valid_cancel_rollback(&self) -> esp_err_t {
+            unsafe { esp_ota_mark_app_valid_cancel_rollback() }
+        }
+
+        fn ota_mark_app_invalid_rollback_and_reboot(&self) -> esp_err_t {
+            unsafe { esp_ota_mark_app_invalid_rollback_and_reboot() }
+        }
+
+        fn err_to_name(&self, code: esp_err_t) -> &'static str {
+            // Returns static strings, "UNKNOWN ERROR" for codes it doesn't know.
+            unsafe { CStr::from_ptr(esp_err_to_name(code)) }
+                .to_str()
+                .unwrap_or("UNKNOWN ERROR")
+        }
+    }
+}
+
+#[cfg(target_os = "espidf")]
+pub use self::esp::{EspOta, EspPartition};
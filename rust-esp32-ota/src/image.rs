@@ -0,0 +1,490 @@
+//! ESP app image headers, read from the start of an upload before it is flashed.
+//!
+//! ESP-IDF itself only checks the magic byte while writing, so [`ImagePolicy`] is what rejects
+//! images built for another chip or project, and older versions of the running app.
+//!
+//! An image starts with an `esp_image_header_t`, followed by the header of its first segment,
+//! which starts with the `esp_app_desc_t`. See
+//! <https://docs.espressif.com/projects/esp-idf/en/v4.4/esp32/api-reference/system/app_image_format.html>.
+
+use core::{cmp::Ordering, fmt};
+
+use types::firmware::compare_versions;
+
+/// First byte of every app image.
+pub const IMAGE_MAGIC: u8 = 0xe9;
+/// First word of `esp_app_desc_t`.
+pub const APP_DESC_MAGIC: u32 = 0xabcd_5432;
+/// Size of `esp_image_header_t`.
+pub const IMAGE_HEADER_LEN: usize = 24;
+/// Size of `esp_image_segment_header_t`.
+const SEGMENT_HEADER_LEN: usize = 8;
+/// Size of `esp_app_desc_t`.
+pub const APP_DESC_LEN: usize = 256;
+/// How much of an image has to be read to parse it.
+pub const HEADER_LEN: usize = IMAGE_HEADER_LEN + SEGMENT_HEADER_LEN + APP_DESC_LEN;
+
+/// Why the start of an image couldn't be parsed.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ImageError {
+    /// The image is shorter than its headers.
+    TooShort,
+    /// The image doesn't start with [`IMAGE_MAGIC`].
+    InvalidMagicByte(u8),
+    /// The first segment doesn't start with an app description.
+    InvalidAppDescMagic(u32),
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageError::TooShort => write!(f, "Image is shorter than its headers"),
+            ImageError::InvalidMagicByte(magic) => {
+                write!(
+                    f,
+                    "Image starts with {magic:#04x} instead of the magic byte"
+                )
+            }
+            ImageError::InvalidAppDescMagic(magic) => {
+                write!(f, "Image has no app description, found {magic:#010x}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ImageError {}
+
+/// The chip an image was built for, `esp_chip_id_t`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Chip {
+    Esp32,
+    Esp32S2,
+    Esp32C3,
+    Esp32S3,
+    Esp32C2,
+    Esp32C6,
+    Esp32H2,
+    /// A chip added by a later ESP-IDF version.
+    Other(u16),
+}
+
+impl Chip {
+    pub fn from_id(id: u16) -> Self {
+        match id {
+            0x0000 => Chip::Esp32,
+            0x0002 => Chip::Esp32S2,
+            0x0005 => Chip::Esp32C3,
+            0x0009 => Chip::Esp32S3,
+            0x000c => Chip::Esp32C2,
+            0x000d => Chip::Esp32C6,
+            0x0010 => Chip::Esp32H2,
+            id => Chip::Other(id),
+        }
+    }
+}
+
+impl fmt::Display for Chip {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Chip::Esp32 => write!(f, "ESP32"),
+            Chip::Esp32S2 => write!(f, "ESP32-S2"),
+            Chip::Esp32C3 => write!(f, "ESP32-C3"),
+            Chip::Esp32S3 => write!(f, "ESP32-S3"),
+            Chip::Esp32C2 => write!(f, "ESP32-C2"),
+            Chip::Esp32C6 => write!(f, "ESP32-C6"),
+            Chip::Esp32H2 => write!(f, "ESP32-H2"),
+            Chip::Other(id) => write!(f, "chip {id:#06x}"),
+        }
+    }
+}
+
+/// What `esp_image_header_t` says about an image.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ImageHeader {
+    pub segment_count: u8,
+    pub entry_addr: u32,
+    pub chip: Chip,
+    pub min_chip_rev: u8,
+    /// Whether a SHA-256 of the image is appended to it.
+    pub hash_appended: bool,
+}
+
+impl ImageHeader {
+    pub fn parse(bytes: &[u8]) -> Result<Self, ImageError> {
+        let bytes = bytes.get(..IMAGE_HEADER_LEN).ok_or(ImageError::TooShort)?;
+        if bytes[0] != IMAGE_MAGIC {
+            return Err(ImageError::InvalidMagicByte(bytes[0]));
+        }
+        Ok(Self {
+            segment_count: bytes[1],
+            entry_addr: u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+            chip: Chip::from_id(u16::from_le_bytes([bytes[12], bytes[13]])),
+            min_chip_rev: bytes[14],
+            hash_appended: bytes[23] == 1,
+        })
+    }
+}
+
+/// The app description, `esp_app_desc_t`, that ESP-IDF embeds in every app.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct AppDescriptor {
+    /// Version for anti-rollback, which ESP-IDF refuses to boot below if it is enabled.
+    pub secure_version: u32,
+    pub version: String,
+    pub project_name: String,
+    pub compile_time: String,
+    pub compile_date: String,
+    pub idf_version: String,
+    pub app_elf_sha256: [u8; 32],
+}
+
+impl AppDescriptor {
+    pub fn parse(bytes: &[u8]) -> Result<Self, ImageError> {
+        let bytes = bytes.get(..APP_DESC_LEN).ok_or(ImageError::TooShort)?;
+        let word = |at: usize| {
+            u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+        };
+        let magic = word(0);
+        if magic != APP_DESC_MAGIC {
+            return Err(ImageError::InvalidAppDescMagic(magic));
+        }
+        let mut app_elf_sha256 = [0; 32];
+        app_elf_sha256.copy_from_slice(&bytes[144..176]);
+        Ok(Self {
+            secure_version: word(4),
+            version: c_string(&bytes[16..48]),
+            project_name: c_string(&bytes[48..80]),
+            compile_time: c_string(&bytes[80..96]),
+            compile_date: c_string(&bytes[96..112]),
+            idf_version: c_string(&bytes[112..144]),
+            app_elf_sha256,
+        })
+    }
+}
+
+/// Reads a NUL-terminated string from a fixed size field.
+pub(crate) fn c_string(field: &[u8]) -> String {
+    let len = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..len]).into_owned()
+}
+
+/// The headers at the start of an app image.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct AppImage {
+    pub header: ImageHeader,
+    pub app: AppDescriptor,
+}
+
+impl AppImage {
+    /// Parses the first [`HEADER_LEN`] bytes of an image.
+    pub fn parse(bytes: &[u8]) -> Result<Self, ImageError> {
+        let header = ImageHeader::parse(bytes)?;
+        let app = AppDescriptor::parse(
+            bytes
+                .get(IMAGE_HEADER_LEN + SEGMENT_HEADER_LEN..)
+                .unwrap_or_default(),
+        )?;
+        Ok(Self { header, app })
+    }
+}
+
+/// Collects the start of an image from the chunks it arrives in, until it can be parsed.
+#[derive(Debug)]
+pub struct HeaderBuffer {
+    bytes: [u8; HEADER_LEN],
+    len: usize,
+}
+
+impl Default for HeaderBuffer {
+    fn default() -> Self {
+        Self {
+            bytes: [0; HEADER_LEN],
+            len: 0,
+        }
+    }
+}
+
+impl HeaderBuffer {
+    /// Takes what is still missing from `chunk`, returning how many bytes were taken.
+    pub fn fill(&mut self, chunk: &[u8]) -> usize {
+        let taken = chunk.len().min(HEADER_LEN - self.len);
+        self.bytes[self.len..self.len + taken].copy_from_slice(&chunk[..taken]);
+        self.len += taken;
+        taken
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == HEADER_LEN
+    }
+
+    /// The bytes collected up to now.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+/// Why [`ImagePolicy`] rejected an image.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum PolicyViolation {
+    WrongChip {
+        expected: Chip,
+        found: Chip,
+    },
+    WrongProject {
+        expected: String,
+        found: String,
+    },
+    /// The image is older than the running app.
+    Downgrade {
+        running: String,
+        found: String,
+    },
+    /// One of the versions isn't numeric, so it can't be told whether this is a downgrade.
+    UnknownVersion {
+        running: String,
+        found: String,
+    },
+    /// The image would be refused at boot by anti-rollback.
+    SecureVersionTooLow {
+        running: u32,
+        found: u32,
+    },
+}
+
+impl fmt::Display for PolicyViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use PolicyViolation::*;
+        match self {
+            WrongChip { expected, found } => {
+                write!(f, "Image is built for {found}, not {expected}")
+            }
+            WrongProject { expected, found } => {
+                write!(f, "Image is of project {found:?}, not {expected:?}")
+            }
+            Downgrade { running, found } => {
+                write!(
+                    f,
+                    "Image version {found} is older than the running {running}"
+                )
+            }
+            UnknownVersion { running, found } => {
+                write!(
+                    f,
+                    "Can't compare image version {found:?} to the running {running:?}"
+                )
+            }
+            SecureVersionTooLow { running, found } => write!(
+                f,
+                "Image secure version {found} is below the running {running}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PolicyViolation {}
+
+/// Which images may replace the running app.
+#[derive(Debug, Clone)]
+pub struct ImagePolicy {
+    pub chip: Chip,
+    pub project_name: String,
+    pub version: String,
+    pub secure_version: u32,
+    /// Accept images older than [`version`](ImagePolicy::version), e.g. to undo a bad update.
+    pub allow_downgrade: bool,
+}
+
+impl ImagePolicy {
+    /// Only accepts the same or newer versions of the running app.
+    pub fn for_running(chip: Chip, running: &AppDescriptor) -> Self {
+        Self {
+            chip,
+            project_name: running.project_name.clone(),
+            version: running.version.clone(),
+            secure_version: running.secure_version,
+            allow_downgrade: false,
+        }
+    }
+
+    pub fn check(&self, image: &AppImage) -> Result<(), PolicyViolation> {
+        if image.header.chip != self.chip {
+            return Err(PolicyViolation::WrongChip {
+                expected: self.chip,
+                found: image.header.chip,
+            });
+        }
+        if image.app.project_name != self.project_name {
+            return Err(PolicyViolation::WrongProject {
+                expected: self.project_name.clone(),
+                found: image.app.project_name.clone(),
+            });
+        }
+        if image.app.secure_version < self.secure_version {
+            return Err(PolicyViolation::SecureVersionTooLow {
+                running: self.secure_version,
+                found: image.app.secure_version,
+            });
+        }
+        if self.allow_downgrade {
+            return Ok(());
+        }
+        match compare_versions(&image.app.version, &self.version) {
+            Some(Ordering::Less) => Err(PolicyViolation::Downgrade {
+                running: self.version.clone(),
+                found: image.app.version.clone(),
+            }),
+            Some(_) => Ok(()),
+            None => Err(PolicyViolation::UnknownVersion {
+                running: self.version.clone(),
+                found: image.app.version.clone(),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The start of an image as `espflash save-image` writes it.
+    fn sample_image(chip_id: u16, project_name: &str, version: &str) -> Vec<u8> {
+        let mut image = vec![0; HEADER_LEN + 16];
+        image[..4].copy_from_slice(&[IMAGE_MAGIC, 5, 2, 0x20]);
+        image[4..8].copy_from_slice(&0x4008_1234_u32.to_le_bytes());
+        image[12..14].copy_from_slice(&chip_id.to_le_bytes());
+        image[14] = 3;
+        image[23] = 1;
+        // The first segment, in flash mapped DROM.
+        image[24..28].copy_from_slice(&0x3f40_0020_u32.to_le_bytes());
+        image[28..32].copy_from_slice(&0x1234_u32.to_le_bytes());
+
+        let desc = &mut image[32..32 + APP_DESC_LEN];
+        desc[..4].copy_from_slice(&APP_DESC_MAGIC.to_le_bytes());
+        desc[4..8].copy_from_slice(&2_u32.to_le_bytes());
+        desc[16..16 + version.len()].copy_from_slice(version.as_bytes());
+        desc[48..48 + project_name.len()].copy_from_slice(project_name.as_bytes());
+        desc[80..88].copy_from_slice(b"12:34:56");
+        desc[96..107].copy_from_slice(b"May 20 2023");
+        desc[112..118].copy_from_slice(b"v4.4.4");
+        desc[144..176].fill(0xab);
+        image
+    }
+
+    fn running() -> AppDescriptor {
+        AppDescriptor::parse(&sample_image(0, "rust-esp32-ota", "0.2.0")[32..]).unwrap()
+    }
+
+    #[test]
+    fn parses_image_headers() {
+        let image = AppImage::parse(&sample_image(9, "rust-esp32-ota", "0.2.0")).unwrap();
+        assert_eq!(
+            image.header,
+            ImageHeader {
+                segment_count: 5,
+                entry_addr: 0x4008_1234,
+                chip: Chip::Esp32S3,
+                min_chip_rev: 3,
+                hash_appended: true,
+            }
+        );
+        assert_eq!(
+            image.app,
+            AppDescriptor {
+                secure_version: 2,
+                version: "0.2.0".into(),
+                project_name: "rust-esp32-ota".into(),
+                compile_time: "12:34:56".into(),
+                compile_date: "May 20 2023".into(),
+                idf_version: "v4.4.4".into(),
+                app_elf_sha256: [0xab; 32],
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_other_data() {
+        let image = sample_image(0, "rust-esp32-ota", "0.2.0");
+        assert_eq!(
+            AppImage::parse(&image[..HEADER_LEN - 1]),
+            Err(ImageError::TooShort)
+        );
+
+        let mut not_an_image = image.clone();
+        not_an_image[0] = 0x00;
+        assert_eq!(
+            AppImage::parse(&not_an_image),
+            Err(ImageError::InvalidMagicByte(0x00))
+        );
+
+        let mut no_app_desc = image;
+        no_app_desc[32] = 0x00;
+        assert_eq!(
+            AppImage::parse(&no_app_desc),
+            Err(ImageError::InvalidAppDescMagic(0xabcd_5400))
+        );
+    }
+
+    #[test]
+    fn buffers_the_header_across_chunks() {
+        let image = sample_image(0, "rust-esp32-ota", "0.2.0");
+        let mut buffer = HeaderBuffer::default();
+        assert_eq!(buffer.fill(&image[..100]), 100);
+        assert!(!buffer.is_full());
+        assert_eq!(buffer.fill(&image[100..]), HEADER_LEN - 100);
+        assert!(buffer.is_full());
+        assert_eq!(buffer.fill(&image[HEADER_LEN..]), 0);
+        assert_eq!(buffer.as_bytes(), &image[..HEADER_LEN]);
+    }
+
+    #[test]
+    fn accepts_only_newer_images_of_the_project() {
+        let policy = ImagePolicy::for_running(Chip::Esp32, &running());
+        let check = |chip_id, project_name, version| {
+            policy.check(&AppImage::parse(&sample_image(chip_id, project_name, version)).unwrap())
+        };
+        assert_eq!(check(0, "rust-esp32-ota", "0.2.1"), Ok(()));
+        assert_eq!(check(0, "rust-esp32-ota", "0.2.0"), Ok(()));
+        assert_eq!(
+            check(5, "rust-esp32-ota", "0.2.1"),
+            Err(PolicyViolation::WrongChip {
+                expected: Chip::Esp32,
+                found: Chip::Esp32C3
+            })
+        );
+        assert_eq!(
+            check(0, "rust-esp32-std", "0.2.1"),
+            Err(PolicyViolation::WrongProject {
+                expected: "rust-esp32-ota".into(),
+                found: "rust-esp32-std".into()
+            })
+        );
+        assert_eq!(
+            check(0, "rust-esp32-ota", "0.1.9"),
+            Err(PolicyViolation::Downgrade {
+                running: "0.2.0".into(),
+                found: "0.1.9".into()
+            })
+        );
+        assert!(matches!(
+            check(0, "rust-esp32-ota", "dev"),
+            Err(PolicyViolation::UnknownVersion { .. })
+        ));
+    }
+
+    #[test]
+    fn forced_updates_may_downgrade_but_not_below_the_secure_version() {
+        let mut policy = ImagePolicy::for_running(Chip::Esp32, &running());
+        policy.allow_downgrade = true;
+        let mut image = AppImage::parse(&sample_image(0, "rust-esp32-ota", "0.1.0")).unwrap();
+        assert_eq!(policy.check(&image), Ok(()));
+
+        image.app.secure_version = 1;
+        assert_eq!(
+            policy.check(&image),
+            Err(PolicyViolation::SecureVersionTooLow {
+                running: 2,
+                found: 1
+            })
+        );
+    }
+}
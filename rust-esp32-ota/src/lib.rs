@@ -1 +1,16 @@
+pub mod boot;
+pub mod checksum;
+pub mod download;
+pub mod error;
+pub mod ffi;
+pub mod image;
+pub mod multipart;
 pub mod ota;
+pub mod pull;
+pub mod signature;
+// Only for testing on the host, so it stays out of the firmware.
+#[cfg(any(test, not(target_os = "espidf")))]
+pub mod sim;
+pub mod status;
+#[cfg(target_os = "espidf")]
+pub mod writer;
@@ -17,12 +17,39 @@ use esp_idf_svc::{
     wifi::*,
 };
 use log::{error, info, warn};
-use rust_esp32_ota::ota::OtaUpdate;
+use rust_esp32_ota::{
+    boot::{self, BootConfig, BootValidation, Check, Checks},
+    checksum::{parse_sha256, ChecksumError, ImageCheck, SHA256_HEADER},
+    image::{Chip, ImageError, ImagePolicy, PolicyViolation},
+    multipart::{Event, Multipart, MultipartError},
+    ota::{self, EspOta, OtaUpdate},
+    signature::{parse_public_key, verify_downgrade, SignatureError, DOWNGRADE_HEADER},
+    status::StatusTracker,
+    writer::ImageWriter,
+};
+use serde::Serialize;
 use std::{env, io::Read, net::Ipv4Addr, thread, time::*};
 
 const SSID: &str = env!("WIFI_SSID");
 const PASS: &str = env!("WIFI_PASSWORD");
 const LOOP_DELAY_MS: &str = env!("ESP_LOOP_DELAY_MS");
+/// Hex-encoded Ed25519 key that uploaded images must be signed with.
+const OTA_PUBLIC_KEY: &str = env!("OTA_PUBLIC_KEY");
+
+/// Checks a new app has to pass after an OTA update, `ESP_BOOT_CHECKS` or just connecting to
+/// WiFi, within `ESP_BOOT_DEADLINE_SECS` or two minutes.
+fn boot_config() -> BootConfig {
+    BootConfig {
+        checks: Checks::parse(option_env!("ESP_BOOT_CHECKS").unwrap_or("wifi"))
+            .expect("ESP_BOOT_CHECKS must list wifi, sensor or server"),
+        deadline_secs: option_env!("ESP_BOOT_DEADLINE_SECS")
+            .map(|secs| {
+                secs.parse()
+                    .expect("ESP_BOOT_DEADLINE_SECS must be a number")
+            })
+            .unwrap_or(boot::DEFAULT_DEADLINE_SECS),
+    }
+}
 
 fn main() -> Result<()> {
     esp_idf_sys::link_patches();
@@ -30,12 +57,18 @@ fn main() -> Result<()> {
     let peripherals = Peripherals::take().unwrap();
     let sysloop = EspSystemEventLoop::take()?;
 
+    // Restarting before the checks passed rolls back as well.
+    let mut boot_validation = BootValidation::after_ota_boot(boot_config())?;
+
     // Ntp Time Sync
     let sntp = EspSntp::new_default()?;
     let mut time_synced = false;
 
     // Connect to Wifi
     let mut wifi = wifi(peripherals.modem, sysloop)?;
+    if let Some(validation) = &mut boot_validation {
+        validation.passed(Check::Wifi);
+    }
 
     // Create OtaServer
     let _ota_server = OtaServer::new()?;
@@ -47,6 +80,11 @@ fn main() -> Result<()> {
                 .parse()
                 .expect("ESP_LOOP_DELAY_MS must be a number"),
         ));
+        if let Some(validation) = &boot_validation {
+            if validation.apply()? {
+                boot_validation = None;
+            }
+        }
         let time_sync_status = sntp.get_sync_status();
         if time_sync_status != SyncStatus::Completed && !time_synced {
             warn!("NTP sync not completed yet, skipping loop iteration");
@@ -139,6 +177,8 @@ struct OtaServer {
 
 impl OtaServer {
     fn new() -> Result<OtaServer> {
+        let public_key = parse_public_key(OTA_PUBLIC_KEY)?;
+        let status = StatusTracker::default();
         let mut registry = ServerRegistry::new();
         let configuration = esp_idf_svc::httpd::Configuration::default();
         registry = registry.handler(Handler::new(
@@ -154,135 +194,334 @@ impl OtaServer {
 <html>
   <head>
     <meta charset="UTF-8">
-    <title>File Upload Form</title>
+    <title>Firmware Update</title>
   </head>
   <body>
-    <h1>File Upload Form</h1>
-    <form method="post" enctype="multipart/form-data" action="" id="file-upload-form">
+    <h1>Firmware Update</h1>
+    <form id="file-upload-form">
       <input type="file" name="file">
       <input type="submit" value="Upload">
     </form>
+    <p><progress id="progress" max="100" value="0" hidden></progress> <span id="percent"></span></p>
+    <p id="result"></p>
     <script>
-      // get the form element
       const form = document.getElementById('file-upload-form');
+      const progress = document.getElementById('progress');
+      const percent = document.getElementById('percent');
+      const result = document.getElementById('result');
 
-      // set the action attribute of the form to the current host
-      form.action = `http://${window.location.host}/ota`;
+      // The server answers one request at a time, so progress comes from the upload itself
+      // and the status is only asked for once the upload was answered.
+      function showStatus(answer) {
+        fetch('/ota/status')
+          .then(response => response.json())
+          .then(status => {
+            if (status.state === 'rebooting') {
+              result.textContent = `${answer} The device is restarting into the new firmware.`;
+            } else if (status.state === 'failed') {
+              // Unexpected ESP-IDF codes come as {"unexpected": code}.
+              const error = typeof status.error === 'string' ? status.error : JSON.stringify(status.error);
+              const kind = status.error ? ` (${error})` : '';
+              result.textContent = `Update failed${kind}: ${status.message}`;
+            } else {
+              result.textContent = answer;
+            }
+          })
+          .catch(() => { result.textContent = answer; });
+      }
 
-      // add an event listener to the form's submit button to prevent the default form submission behavior
       form.addEventListener('submit', (event) => {
         event.preventDefault();
-        // add your own code to handle the form submission and file upload here
-
-        console.log("I will upload the file now");
-
-        // get the file input element
-        const fileInput = document.querySelector('input[type=file]');
-
-        // get the file from the input element
-        const file = fileInput.files[0];
-
-        // create a FormData object to store the file data
+        const file = form.querySelector('input[type=file]').files[0];
+        if (!file) {
+          result.textContent = 'Choose a firmware file first.';
+          return;
+        }
         const formData = new FormData();
         formData.append('file', file);
 
-        // create the fetch request with the form data
-        fetch(form.action, {
-          method: 'POST',
-          body: formData
-        })
-        .then(response => {
-            console.log(response);
-        })
-        .catch(error => {
-          console.error(error);
-        });
+        const xhr = new XMLHttpRequest();
+        xhr.open('POST', '/ota');
+        xhr.upload.onprogress = (event) => {
+          if (event.lengthComputable) {
+            progress.value = Math.round(event.loaded * 100 / event.total);
+            percent.textContent = `${progress.value}%`;
+          }
+        };
+        xhr.upload.onload = () => { result.textContent = 'Verifying...'; };
+        xhr.onload = () => showStatus(`${xhr.status}: ${xhr.responseText}.`);
+        xhr.onerror = () => { result.textContent = 'Upload failed: the connection was lost.'; };
+        progress.hidden = false;
+        progress.value = 0;
+        percent.textContent = '0%';
+        result.textContent = 'Uploading...';
+        xhr.send(formData);
       });
     </script>
   </body>
 </html>"#
-                    .to_vec(),
+                        .to_vec(),
                 ));
                 Ok(response)
             },
         ))?;
+        let upload_status = status.clone();
         registry = registry.handler(Handler::new(
             "/ota",
             embedded_svc::httpd::Method::Post,
-            |request: Request| {
-                info!(
-                    "Got request for OTA size {}",
-                    request.content_len().unwrap()
-                );
-                let mut n = 0;
-                const BLOCK_SIZE: usize = 4096;
-                let mut bytea = [0_u8; BLOCK_SIZE];
-                let mut written_blocks = 0;
-                let mut count = 0;
-                let mut body_started = false;
-                let mut ota = OtaUpdate::begin()?;
+            move |mut request: Request| {
+                info!("Got request for OTA size {:?}", request.content_len());
+                let status = &upload_status;
+                let mut response = Response::new(200);
+                let mut writer = ImageWriter::new(image_policy(false)?, public_key);
 
-                for byte in request.bytes() {
-                    let byte = byte.unwrap();
-                    if n == 4 && !body_started {
-                        info!("Body started");
-                        body_started = true;
+                // The body also holds the form around the file, so the image size isn't known.
+                status.started(None);
+                match receive_firmware(&mut request, &mut writer, status) {
+                    Ok(0) => {
+                        warn!("Upload contains no firmware file");
+                        status.failed(&anyhow!("No firmware file uploaded"));
+                        response.status = 400;
+                        response =
+                            response.body(Body::Bytes(b"No firmware file uploaded".to_vec()));
+                        Ok(response)
                     }
-                    if byte == 10 || byte == 13 {
-                        n += 1;
-                    } else {
-                        n = 0;
-                    }
-                    if body_started {
-                        if count == BLOCK_SIZE {
-                            ota.write(&bytea)?;
-                            bytea = [0_u8; BLOCK_SIZE];
-                            count = 0;
-                            written_blocks += 1;
-                            info!("Wrote {} bytes", written_blocks * BLOCK_SIZE);
-                        }
-                        bytea[count] = byte;
-                        count += 1;
-                    } else {
-                        match std::str::from_utf8(&[byte]) {
-                            Ok(s) => print!("{}", s),
-                            Err(_e) => {
-                                continue;
-                            }
+                    Ok(_) => {
+                        status.verifying();
+                        match writer.finish() {
+                            Ok(ota) => install(ota, status),
+                            Err(e) => Ok(upload_error(e, status)),
                         }
                     }
+                    Err(e) => Ok(upload_error(e, status)),
                 }
-                info!("Done writing");
+            },
+        ))?;
+        let upload_status = status.clone();
+        registry = registry.handler(Handler::new(
+            "/ota/raw",
+            embedded_svc::httpd::Method::Put,
+            move |mut request: Request| {
+                let status = &upload_status;
                 let mut response = Response::new(200);
-                // Performs validation of the newly written app image and completes the OTA update.
-                let mut completed_ota = match ota.finalize() {
-                    Ok(ota) => ota,
-                    Err(e) => {
-                        error!("Error finalizing OTA: {:?}", e);
-                        response.status = 500;
-                        response = response.body(Body::Bytes(b"Error finalizing OTA".to_vec()));
-                        return Ok(response);
-                    }
+                let Some(expected_len) = request.content_len().filter(|len| *len > 0) else {
+                    response.status = 411;
+                    response = response.body(Body::Bytes(b"Content-Length is required".to_vec()));
+                    return Ok(response);
                 };
-
-                // Sets the newly written to partition as the next partition to boot from.
-                match completed_ota.set_as_boot_partition() {
-                    Ok(_) => {}
+                let sha256 = match request.header(SHA256_HEADER) {
+                    Some(sha256) => parse_sha256(&sha256),
+                    None => Err(ChecksumError::InvalidSha256),
+                };
+                let sha256 = match sha256 {
+                    Ok(sha256) => sha256,
                     Err(e) => {
-                        error!("Error setting OTA as boot partition: {:?}", e);
-                        response.status = 500;
-                        response = response
-                            .body(Body::Bytes(b"Error setting OTA as boot partition".to_vec()));
+                        warn!("Invalid {} header: {}", SHA256_HEADER, e);
+                        response.status = 400;
+                        response = response.body(Body::from(format!(
+                            "Missing or invalid {SHA256_HEADER} header: {e}"
+                        )));
                         return Ok(response);
                     }
                 };
+                // The SHA-256 is what the signature is checked against, and the upload is
+                // only installed if it hashes to it.
+                let allow_downgrade = match request.header(DOWNGRADE_HEADER) {
+                    Some(signature) => match verify_downgrade(&public_key, &sha256, &signature) {
+                        Ok(()) => true,
+                        Err(e) => {
+                            warn!("Invalid {} header: {}", DOWNGRADE_HEADER, e);
+                            response.status = 403;
+                            response = response
+                                .body(Body::from(format!("Invalid {DOWNGRADE_HEADER} header")));
+                            return Ok(response);
+                        }
+                    },
+                    None => false,
+                };
+                info!("Got raw OTA upload of {} bytes", expected_len);
+                let mut writer = ImageWriter::new(image_policy(allow_downgrade)?, public_key);
 
-                // Restarts the CPU, booting into the newly written app.
-                completed_ota.restart();
-                Ok(response)
+                // Dropping the writer on errors aborts the update.
+                status.started(Some(expected_len));
+                let check = ImageCheck::new(expected_len, sha256);
+                let written = receive_raw(&mut request, &mut writer, check, status);
+                match written.and_then(|_| {
+                    status.verifying();
+                    writer.finish()
+                }) {
+                    Ok(ota) => install(ota, status),
+                    Err(e) => Ok(upload_error(e, status)),
+                }
             },
         ))?;
+        registry = registry.handler(Handler::new(
+            "/ota/status",
+            embedded_svc::httpd::Method::Get,
+            move |_request: Request| json(&status.get()),
+        ))?;
+        registry = registry.handler(Handler::new(
+            "/partitions",
+            embedded_svc::httpd::Method::Get,
+            |_request: Request| json(&ota::partitions()?),
+        ))?;
         let server = registry.start(&configuration)?;
         Ok(OtaServer { server })
     }
 }
+
+/// How much of the request body is read at a time.
+const CHUNK_SIZE: usize = 1024;
+
+/// How long the device waits to restart after an update, so that the answer reaches the client.
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+fn json(value: &impl Serialize) -> Result<Response> {
+    Ok(Response::new(200)
+        .header("Content-Type", "application/json")
+        .body(Body::Bytes(serde_json::to_vec(value)?)))
+}
+
+/// Answers a failed upload, telling the client whether it sent something wrong.
+fn upload_error(e: anyhow::Error, status: &StatusTracker) -> Response {
+    status.failed(&e);
+    let mut response = Response::new(400);
+    if e.is::<SignatureError>() {
+        warn!("Rejected upload: {}", e);
+        response.status = 403;
+        response = response.body(Body::from(format!("Rejected upload: {e}")));
+    } else if e.is::<PolicyViolation>() {
+        warn!("Rejected upload: {}", e);
+        response.status = 409;
+        response = response.body(Body::from(format!("Rejected upload: {e}")));
+    } else if e.is::<MultipartError>() || e.is::<ChecksumError>() || e.is::<ImageError>() {
+        warn!("Invalid upload: {}", e);
+        response = response.body(Body::from(format!("Invalid upload: {e}")));
+    } else {
+        error!("Error writing OTA: {:?}", e);
+        response.status = 500;
+        response = response.body(Body::Bytes(b"Error writing OTA".to_vec()));
+    }
+    response
+}
+
+/// Validates a fully written update and reboots into it shortly after answering, or answers why
+/// that failed.
+fn install(ota: OtaUpdate<EspOta>, status: &StatusTracker) -> Result<Response> {
+    let mut response = Response::new(200);
+    // Performs validation of the newly written app image and completes the OTA update.
+    let mut completed_ota = match ota.finalize() {
+        Ok(ota) => ota,
+        Err(e) => {
+            error!("Error finalizing OTA: {:?}", e);
+            status.failed(&e.into());
+            response.status = 500;
+            response = response.body(Body::Bytes(b"Error finalizing OTA".to_vec()));
+            return Ok(response);
+        }
+    };
+
+    // Sets the newly written to partition as the next partition to boot from.
+    match completed_ota.set_as_boot_partition() {
+        Ok(_) => {}
+        Err(e) => {
+            error!("Error setting OTA as boot partition: {:?}", e);
+            status.failed(&e.into());
+            response.status = 500;
+            response = response.body(Body::Bytes(b"Error setting OTA as boot partition".to_vec()));
+            return Ok(response);
+        }
+    };
+
+    // Restarts the CPU once the client got the answer, booting into the newly written app.
+    status.rebooting();
+    info!("Restarting into the new app in {:?}", RESTART_DELAY);
+    thread::spawn(|| {
+        thread::sleep(RESTART_DELAY);
+        ota::restart()
+    });
+    response = response.body(Body::Bytes(b"Update installed".to_vec()));
+    Ok(response)
+}
+
+/// The policy for uploads replacing the running app, which may downgrade it if the upload
+/// carries a downgrade signature.
+fn image_policy(allow_downgrade: bool) -> Result<ImagePolicy> {
+    let chip = Chip::from_id(esp_idf_sys::CONFIG_IDF_FIRMWARE_CHIP_ID as u16);
+    let mut policy = ImagePolicy::for_running(chip, &ota::running_app()?);
+    policy.allow_downgrade = allow_downgrade;
+    Ok(policy)
+}
+
+/// Writes the first file of a `multipart/form-data` upload to `writer`, returning its size.
+///
+/// Multipart errors are returned as [`MultipartError`], so the client can be told that the
+/// upload was malformed.
+fn receive_firmware(
+    request: &mut Request,
+    writer: &mut ImageWriter,
+    status: &StatusTracker,
+) -> Result<usize> {
+    #[derive(PartialEq)]
+    enum Firmware {
+        Pending,
+        Writing,
+        Written,
+    }
+
+    let content_type = request.content_type().unwrap_or_default();
+    let mut upload = Multipart::from_content_type(&content_type)?;
+    let mut firmware = Firmware::Pending;
+    let mut written = 0;
+    let mut chunk = [0_u8; CHUNK_SIZE];
+
+    while !upload.is_finished() {
+        let read = request.read(&mut chunk)?;
+        if read == 0 {
+            break;
+        }
+        upload.feed(&chunk[..read], |event| -> Result<()> {
+            match event {
+                Event::Part(part) if firmware == Firmware::Pending && part.filename.is_some() => {
+                    info!("Receiving firmware {:?}", part.filename);
+                    firmware = Firmware::Writing;
+                }
+                Event::Data(data) if firmware == Firmware::Writing => {
+                    writer.write(data)?;
+                    written += data.len();
+                    status.wrote(data.len());
+                }
+                Event::PartEnd if firmware == Firmware::Writing => firmware = Firmware::Written,
+                _ => {}
+            }
+            Ok(())
+        })?;
+    }
+    upload.finish()?;
+    Ok(written)
+}
+
+/// Writes a raw image body to `writer`, returning its size once `check` accepted it.
+///
+/// Images that don't match the announced length or SHA-256 are returned as [`ChecksumError`],
+/// and nothing past the announced length is written.
+fn receive_raw(
+    request: &mut Request,
+    writer: &mut ImageWriter,
+    mut check: ImageCheck,
+    status: &StatusTracker,
+) -> Result<usize> {
+    let mut chunk = [0_u8; CHUNK_SIZE];
+    loop {
+        let read = request.read(&mut chunk)?;
+        if read == 0 {
+            break;
+        }
+        check.update(&chunk[..read])?;
+        writer.write(&chunk[..read])?;
+        status.wrote(read);
+    }
+    let written = check.received();
+    check.finish()?;
+    Ok(written)
+}
@@ -0,0 +1,657 @@
+//! Streaming `multipart/form-data` parser for firmware uploads.
+//!
+//! The request body is fed in chunks of any size as they are read from the connection, and only
+//! the bytes between a part's headers and the boundary that ends it are passed on, so neither the
+//! part headers nor the closing boundary end up in the flashed image.
+//!
+//! Nothing is allocated: the state kept between chunks lives in fixed buffers, small enough for
+//! the stack of the httpd task.
+
+use core::{fmt, str};
+
+/// Longest boundary allowed by RFC 2046.
+pub const MAX_BOUNDARY_LEN: usize = 70;
+/// Longest part header line that is accepted, line break excluded.
+pub const MAX_HEADER_LEN: usize = 256;
+/// Longest part name, filename or content type that is accepted.
+pub const MAX_FIELD_LEN: usize = 128;
+
+/// `\r\n--` followed by the boundary.
+const MAX_DELIMITER_LEN: usize = MAX_BOUNDARY_LEN + 4;
+
+/// Why a body couldn't be parsed.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MultipartError {
+    /// The Content-Type isn't `multipart/form-data`.
+    NotMultipart,
+    /// The boundary is missing, too long or contains characters RFC 2046 doesn't allow.
+    InvalidBoundary,
+    /// A boundary is followed by something other than a line break or `--`.
+    InvalidDelimiter,
+    /// A part header line is longer than [`MAX_HEADER_LEN`], or a value in it is longer than
+    /// [`MAX_FIELD_LEN`].
+    HeaderTooLong,
+    /// A part header isn't UTF-8 or has an unterminated quoted string.
+    InvalidHeader,
+    /// The body ended before the closing boundary.
+    Truncated,
+}
+
+impl fmt::Display for MultipartError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use MultipartError::*;
+        match self {
+            NotMultipart => "Content-Type is not multipart/form-data",
+            InvalidBoundary => "Missing or invalid multipart boundary",
+            InvalidDelimiter => "Multipart boundary is not followed by a line break or `--`",
+            HeaderTooLong => "Multipart part header is too long",
+            InvalidHeader => "Multipart part header is malformed",
+            Truncated => "Multipart body ended before the closing boundary",
+        }
+        .fmt(f)
+    }
+}
+
+impl std::error::Error for MultipartError {}
+
+/// Extracts the boundary from the value of a `multipart/form-data` Content-Type header.
+pub fn boundary(content_type: &str) -> Result<&str, MultipartError> {
+    let mut params = content_type.split(';');
+    let mime = params.next().unwrap_or_default().trim();
+    if !mime.eq_ignore_ascii_case("multipart/form-data") {
+        return Err(MultipartError::NotMultipart);
+    }
+    let boundary = params
+        .filter_map(|param| param.split_once('='))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("boundary"))
+        .map(|(_, value)| value.trim())
+        .ok_or(MultipartError::InvalidBoundary)?;
+    let boundary = boundary
+        .strip_prefix('"')
+        .and_then(|quoted| quoted.strip_suffix('"'))
+        .unwrap_or(boundary);
+    let valid = (1..=MAX_BOUNDARY_LEN).contains(&boundary.len())
+        && !boundary.ends_with(' ')
+        && boundary
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"'()+_,-./:=? ".contains(&b));
+    if valid {
+        Ok(boundary)
+    } else {
+        Err(MultipartError::InvalidBoundary)
+    }
+}
+
+fn trim_start(bytes: &[u8]) -> &[u8] {
+    let start = bytes
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .unwrap_or(bytes.len());
+    &bytes[start..]
+}
+
+fn trim(bytes: &[u8]) -> &[u8] {
+    let bytes = trim_start(bytes);
+    let end = bytes
+        .iter()
+        .rposition(|b| !b.is_ascii_whitespace())
+        .map_or(0, |last| last + 1);
+    &bytes[..end]
+}
+
+/// What the parser found next in the body.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Event<'a> {
+    /// A part starts, its body follows as [`Event::Data`].
+    Part(Part<'a>),
+    /// The next bytes of the current part's body.
+    Data(&'a [u8]),
+    /// The current part ended with the last [`Event::Data`].
+    PartEnd,
+}
+
+/// The headers of a part that matter for uploads.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Part<'a> {
+    /// The form field name from Content-Disposition.
+    pub name: Option<&'a str>,
+    /// Only set for file inputs.
+    pub filename: Option<&'a str>,
+    pub content_type: Option<&'a str>,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum State {
+    /// Skipping whatever comes before the first boundary.
+    Preamble,
+    /// Right after a boundary.
+    AfterBoundary,
+    /// After the first `-` of the closing boundary's `--`.
+    ClosingDash,
+    /// After the `\r` ending a boundary line.
+    BoundaryLineEnd,
+    Headers,
+    Body,
+    /// After the closing boundary, anything that follows is ignored.
+    Epilogue,
+}
+
+/// A value of at most `N` bytes.
+#[derive(Debug)]
+struct Field<const N: usize> {
+    bytes: [u8; N],
+    len: usize,
+    present: bool,
+}
+
+impl<const N: usize> Field<N> {
+    fn new() -> Self {
+        Self {
+            bytes: [0; N],
+            len: 0,
+            present: false,
+        }
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+        self.present = false;
+    }
+
+    fn push(&mut self, byte: u8) -> Result<(), MultipartError> {
+        let slot = self
+            .bytes
+            .get_mut(self.len)
+            .ok_or(MultipartError::HeaderTooLong)?;
+        *slot = byte;
+        self.len += 1;
+        self.present = true;
+        Ok(())
+    }
+
+    fn set(&mut self, value: &[u8]) -> Result<(), MultipartError> {
+        self.clear();
+        self.present = true;
+        value.iter().try_for_each(|&b| self.push(b))
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+
+    fn as_str(&self) -> Result<Option<&str>, MultipartError> {
+        if !self.present {
+            return Ok(None);
+        }
+        str::from_utf8(self.as_bytes())
+            .map(Some)
+            .map_err(|_| MultipartError::InvalidHeader)
+    }
+}
+
+/// The headers of the current part.
+#[derive(Debug)]
+struct PartHeaders {
+    name: Field<MAX_FIELD_LEN>,
+    filename: Field<MAX_FIELD_LEN>,
+    content_type: Field<MAX_FIELD_LEN>,
+}
+
+impl PartHeaders {
+    fn clear(&mut self) {
+        self.name.clear();
+        self.filename.clear();
+        self.content_type.clear();
+    }
+
+    fn parse_line(&mut self, line: &[u8]) -> Result<(), MultipartError> {
+        let colon = line
+            .iter()
+            .position(|&b| b == b':')
+            .ok_or(MultipartError::InvalidHeader)?;
+        let name = trim(&line[..colon]);
+        let value = trim(&line[colon + 1..]);
+        if name.eq_ignore_ascii_case(b"content-disposition") {
+            self.parse_disposition(value)
+        } else if name.eq_ignore_ascii_case(b"content-type") {
+            self.content_type.set(value)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Picks `name` and `filename` out of e.g. `form-data; name="file"; filename="app.bin"`.
+    fn parse_disposition(&mut self, value: &[u8]) -> Result<(), MultipartError> {
+        // The disposition type is always `form-data` for forms.
+        let mut rest = match value.iter().position(|&b| b == b';') {
+            Some(semicolon) => &value[semicolon + 1..],
+            None => return Ok(()),
+        };
+        while !rest.is_empty() {
+            let Some(eq) = rest.iter().position(|&b| b == b'=' || b == b';') else {
+                return Ok(());
+            };
+            if rest[eq] == b';' {
+                rest = &rest[eq + 1..];
+                continue;
+            }
+            let param = trim(&rest[..eq]);
+            let mut field = if param.eq_ignore_ascii_case(b"name") {
+                Some(&mut self.name)
+            } else if param.eq_ignore_ascii_case(b"filename") {
+                Some(&mut self.filename)
+            } else {
+                None
+            };
+            if let Some(field) = field.as_deref_mut() {
+                field.clear();
+                field.present = true;
+            }
+            rest = trim_start(&rest[eq + 1..]);
+
+            let end = if rest.first() == Some(&b'"') {
+                let mut escaped = false;
+                let mut end = None;
+                for (i, &b) in rest.iter().enumerate().skip(1) {
+                    if !escaped && b == b'"' {
+                        end = Some(i + 1);
+                        break;
+                    }
+                    escaped = !escaped && b == b'\\';
+                    if let (false, Some(field)) = (escaped, field.as_deref_mut()) {
+                        field.push(b)?;
+                    }
+                }
+                end.ok_or(MultipartError::InvalidHeader)?
+            } else {
+                let end = rest.iter().position(|&b| b == b';').unwrap_or(rest.len());
+                if let Some(field) = field {
+                    field.set(trim(&rest[..end]))?;
+                }
+                end
+            };
+            rest = &rest[end..];
+            rest = match rest.iter().position(|&b| b == b';') {
+                Some(semicolon) => &rest[semicolon + 1..],
+                None => &[],
+            };
+        }
+        Ok(())
+    }
+
+    fn part(&self) -> Result<Part<'_>, MultipartError> {
+        Ok(Part {
+            name: self.name.as_str()?,
+            filename: self.filename.as_str()?,
+            content_type: self.content_type.as_str()?,
+        })
+    }
+}
+
+/// Parses a `multipart/form-data` body fed to it in chunks.
+#[derive(Debug)]
+pub struct Multipart {
+    delimiter: [u8; MAX_DELIMITER_LEN],
+    delimiter_len: usize,
+    state: State,
+    /// How many bytes of the delimiter the input matched up to now.
+    matched: usize,
+    /// How many of those came from earlier chunks, and so still have to be passed on if the
+    /// delimiter doesn't match after all.
+    held: usize,
+    line: Field<MAX_HEADER_LEN>,
+    headers: PartHeaders,
+    in_part: bool,
+}
+
+impl Multipart {
+    /// Starts parsing a body that uses `boundary`.
+    pub fn new(boundary: &str) -> Result<Self, MultipartError> {
+        if boundary.is_empty() || boundary.len() > MAX_BOUNDARY_LEN {
+            return Err(MultipartError::InvalidBoundary);
+        }
+        let mut delimiter = [0; MAX_DELIMITER_LEN];
+        let delimiter_len = boundary.len() + 4;
+        delimiter[..4].copy_from_slice(b"\r\n--");
+        delimiter[4..delimiter_len].copy_from_slice(boundary.as_bytes());
+        Ok(Self {
+            delimiter,
+            delimiter_len,
+            state: State::Preamble,
+            // The first boundary may start the body without a line break before it.
+            matched: 2,
+            held: 2,
+            line: Field::new(),
+            headers: PartHeaders {
+                name: Field::new(),
+                filename: Field::new(),
+                content_type: Field::new(),
+            },
+            in_part: false,
+        })
+    }
+
+    /// Starts parsing a body with the boundary from its Content-Type header.
+    pub fn from_content_type(content_type: &str) -> Result<Self, MultipartError> {
+        Self::new(boundary(content_type)?)
+    }
+
+    /// Parses the next chunk of the body, calling `on_event` with what it contains.
+    ///
+    /// Data slices never contain bytes that could still turn out to be a boundary, so a part's
+    /// body is passed on exactly once all of it arrived.
+    pub fn feed<E: From<MultipartError>>(
+        &mut self,
+        mut input: &[u8],
+        mut on_event: impl FnMut(Event<'_>) -> Result<(), E>,
+    ) -> Result<(), E> {
+        while let Some(&byte) = input.first() {
+            match self.state {
+                State::Preamble | State::Body => {
+                    let in_body = self.state == State::Body;
+                    let Some(consumed) = self.scan(input, in_body, &mut on_event)? else {
+                        return Ok(());
+                    };
+                    input = &input[consumed..];
+                    if in_body {
+                        on_event(Event::PartEnd)?;
+                        self.in_part = false;
+                    }
+                    self.state = State::AfterBoundary;
+                }
+                State::Epilogue => return Ok(()),
+                State::AfterBoundary => {
+                    self.state = match byte {
+                        b'-' => State::ClosingDash,
+                        b'\r' => State::BoundaryLineEnd,
+                        // Transport padding.
+                        b' ' | b'\t' => State::AfterBoundary,
+                        _ => return Err(MultipartError::InvalidDelimiter.into()),
+                    };
+                    input = &input[1..];
+                }
+                State::ClosingDash => {
+                    if byte != b'-' {
+                        return Err(MultipartError::InvalidDelimiter.into());
+                    }
+                    self.state = State::Epilogue;
+                    input = &input[1..];
+                }
+                State::BoundaryLineEnd => {
+                    if byte != b'\n' {
+                        return Err(MultipartError::InvalidDelimiter.into());
+                    }
+                    self.line.clear();
+                    self.headers.clear();
+                    self.state = State::Headers;
+                    input = &input[1..];
+                }
+                State::Headers => {
+                    let end = input.iter().position(|&b| b == b'\n');
+                    let take = end.map_or(input.len(), |end| end + 1);
+                    for &b in &input[..end.unwrap_or(input.len())] {
+                        self.line.push(b)?;
+                    }
+                    input = &input[take..];
+                    if end.is_none() {
+                        continue;
+                    }
+                    let line = self.line.as_bytes();
+                    let line = line.strip_suffix(b"\r").unwrap_or(line);
+                    if line.is_empty() {
+                        on_event(Event::Part(self.headers.part()?))?;
+                        self.in_part = true;
+                        self.matched = 0;
+                        self.held = 0;
+                        self.state = State::Body;
+                    } else {
+                        self.headers.parse_line(line)?;
+                    }
+                    self.line.clear();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that the whole body was fed.
+    pub fn finish(&self) -> Result<(), MultipartError> {
+        match self.state {
+            State::Epilogue => Ok(()),
+            _ => Err(MultipartError::Truncated),
+        }
+    }
+
+    /// Whether the closing boundary was seen, after which the rest of the body can be skipped.
+    pub fn is_finished(&self) -> bool {
+        self.state == State::Epilogue
+    }
+
+    /// Looks for the delimiter in `input`, passing on the bytes before it if `emit` is set.
+    ///
+    /// Returns how many bytes were consumed if the delimiter was found.
+    fn scan<E>(
+        &mut self,
+        input: &[u8],
+        emit: bool,
+        on_event: &mut impl FnMut(Event<'_>) -> Result<(), E>,
+    ) -> Result<Option<usize>, E> {
+        let delimiter = &self.delimiter[..self.delimiter_len];
+        for (i, &byte) in input.iter().enumerate() {
+            if byte == delimiter[self.matched] {
+                self.matched += 1;
+                if self.matched == delimiter.len() {
+                    let end = i + 1 - (self.matched - self.held);
+                    if emit && end > 0 {
+                        on_event(Event::Data(&input[..end]))?;
+                    }
+                    self.matched = 0;
+                    self.held = 0;
+                    return Ok(Some(i + 1));
+                }
+                continue;
+            }
+            // The bytes held back weren't the delimiter after all. As a `\r` only starts the
+            // delimiter, none of them can start another match.
+            if self.held > 0 {
+                if emit {
+                    on_event(Event::Data(&delimiter[..self.held]))?;
+                }
+                self.held = 0;
+            }
+            self.matched = usize::from(byte == delimiter[0]);
+        }
+        let end = input.len() - (self.matched - self.held);
+        if emit && end > 0 {
+            on_event(Event::Data(&input[..end]))?;
+        }
+        self.held = self.matched;
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &[u8] = b"--XyZ\r\n\
+        Content-Disposition: form-data; name=\"note\"\r\n\
+        \r\n\
+        hello\r\n\
+        --XyZ\r\n\
+        Content-Disposition: form-data; name=\"file\"; filename=\"app.bin\"\r\n\
+        Content-Type: application/octet-stream\r\n\
+        \r\n\
+        \xe9\x01\r\n--Xy\r\n--XyY\r\r\n\
+        --XyZ--\r\n";
+
+    /// Almost boundaries, which belong to the body.
+    const FIRMWARE: &[u8] = b"\xe9\x01\r\n--Xy\r\n--XyY\r";
+
+    #[derive(Debug, Default, PartialEq)]
+    struct Parsed {
+        parts: Vec<(Option<String>, Option<String>, Option<String>)>,
+        bodies: Vec<Vec<u8>>,
+        ended: usize,
+    }
+
+    fn parse(boundary: &str, body: &[u8], chunk_size: usize) -> Result<Parsed, MultipartError> {
+        let mut parser = Multipart::new(boundary)?;
+        let mut parsed = Parsed::default();
+        for chunk in body.chunks(chunk_size) {
+            parser.feed(chunk, |event| {
+                match event {
+                    Event::Part(part) => {
+                        parsed.parts.push((
+                            part.name.map(Into::into),
+                            part.filename.map(Into::into),
+                            part.content_type.map(Into::into),
+                        ));
+                        parsed.bodies.push(Vec::new());
+                    }
+                    Event::Data(data) => parsed.bodies.last_mut().unwrap().extend(data),
+                    Event::PartEnd => parsed.ended += 1,
+                }
+                Ok::<_, MultipartError>(())
+            })?;
+        }
+        parser.finish()?;
+        Ok(parsed)
+    }
+
+    #[test]
+    fn extracts_the_boundary() {
+        let boundary = boundary("multipart/form-data; boundary=----WebKitFormBoundaryAbc123");
+        assert_eq!(boundary, Ok("----WebKitFormBoundaryAbc123"));
+        let quoted = super::boundary("Multipart/Form-Data;charset=utf-8; Boundary=\"a b:c\"");
+        assert_eq!(quoted, Ok("a b:c"));
+
+        assert_eq!(
+            super::boundary("application/octet-stream"),
+            Err(MultipartError::NotMultipart)
+        );
+        assert_eq!(
+            super::boundary("multipart/form-data"),
+            Err(MultipartError::InvalidBoundary)
+        );
+        assert_eq!(
+            super::boundary("multipart/form-data; boundary=\"\""),
+            Err(MultipartError::InvalidBoundary)
+        );
+        let too_long = format!("multipart/form-data; boundary={}", "x".repeat(71));
+        assert_eq!(
+            super::boundary(&too_long),
+            Err(MultipartError::InvalidBoundary)
+        );
+    }
+
+    #[test]
+    fn passes_on_exactly_the_part_bodies() {
+        let parsed = parse("XyZ", BODY, BODY.len()).unwrap();
+        assert_eq!(
+            parsed.parts,
+            vec![
+                (Some("note".into()), None, None),
+                (
+                    Some("file".into()),
+                    Some("app.bin".into()),
+                    Some("application/octet-stream".into())
+                ),
+            ]
+        );
+        assert_eq!(parsed.bodies, vec![b"hello".to_vec(), FIRMWARE.to_vec()]);
+        assert_eq!(parsed.ended, 2);
+    }
+
+    #[test]
+    fn does_not_depend_on_chunk_sizes() {
+        let whole = parse("XyZ", BODY, BODY.len()).unwrap();
+        for chunk_size in 1..BODY.len() {
+            assert_eq!(
+                parse("XyZ", BODY, chunk_size).unwrap(),
+                whole,
+                "{chunk_size}"
+            );
+        }
+    }
+
+    #[test]
+    fn skips_preamble_and_epilogue() {
+        let body = b"ignored\r\n--b\r\n\r\nbody\r\n--b-- \r\nignored too";
+        let parsed = parse("b", body, 3).unwrap();
+        assert_eq!(parsed.parts, vec![(None, None, None)]);
+        assert_eq!(parsed.bodies, vec![b"body".to_vec()]);
+    }
+
+    #[test]
+    fn parses_quoted_and_unquoted_parameters() {
+        let body = b"--b\r\n\
+            content-disposition: form-data;name=file; filename=\"my \\\"new\\\" app;.bin\"\r\n\
+            X-Other: ignored\r\n\
+            \r\n\
+            \r\n--b--";
+        let parsed = parse("b", body, 7).unwrap();
+        assert_eq!(
+            parsed.parts,
+            vec![(
+                Some("file".into()),
+                Some("my \"new\" app;.bin".into()),
+                None
+            )]
+        );
+        assert_eq!(parsed.bodies, vec![Vec::<u8>::new()]);
+    }
+
+    #[test]
+    fn rejects_malformed_bodies() {
+        assert_eq!(
+            parse("b", b"--b\r\n\r\nbody", 4),
+            Err(MultipartError::Truncated)
+        );
+        assert_eq!(
+            parse("b", b"--bx\r\n\r\n\r\n--b--", 4),
+            Err(MultipartError::InvalidDelimiter)
+        );
+        assert_eq!(
+            parse("b", b"--b\r\nno colon\r\n\r\n\r\n--b--", 4),
+            Err(MultipartError::InvalidHeader)
+        );
+        let unterminated = b"--b\r\nContent-Disposition: form-data; name=\"file\r\n\r\n\r\n--b--";
+        assert_eq!(
+            parse("b", unterminated, 4),
+            Err(MultipartError::InvalidHeader)
+        );
+        let long_line = format!("--b\r\nX: {}\r\n\r\n\r\n--b--", "x".repeat(MAX_HEADER_LEN));
+        assert_eq!(
+            parse("b", long_line.as_bytes(), 64),
+            Err(MultipartError::HeaderTooLong)
+        );
+    }
+
+    #[test]
+    fn stops_on_handler_errors() {
+        #[derive(Debug, PartialEq)]
+        enum Error {
+            Full,
+            Multipart(MultipartError),
+        }
+        impl From<MultipartError> for Error {
+            fn from(e: MultipartError) -> Self {
+                Error::Multipart(e)
+            }
+        }
+
+        let mut parser = Multipart::new("XyZ").unwrap();
+        let result = parser.feed(BODY, |event| match event {
+            Event::Data(_) => Err(Error::Full),
+            _ => Ok(()),
+        });
+        assert_eq!(result, Err(Error::Full));
+        let mut parser = Multipart::new("XyZ").unwrap();
+        let result = parser.feed(b"--XyZ!", |_| Ok(()));
+        assert_eq!(
+            result,
+            Err(Error::Multipart(MultipartError::InvalidDelimiter))
+        );
+    }
+}
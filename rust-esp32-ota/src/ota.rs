@@ -1,140 +1,57 @@
-use core::fmt;
-use core::mem;
-use core::ptr;
-use esp_idf_sys::{
-    esp_ota_abort, esp_ota_begin, esp_ota_end, esp_ota_get_next_update_partition, esp_ota_handle_t,
-    esp_ota_mark_app_invalid_rollback_and_reboot, esp_ota_mark_app_valid_cancel_rollback,
-    esp_ota_set_boot_partition, esp_ota_write, esp_partition_t, esp_restart, ESP_ERR_FLASH_OP_FAIL,
-    ESP_ERR_FLASH_OP_TIMEOUT, ESP_ERR_INVALID_ARG, ESP_ERR_INVALID_SIZE, ESP_ERR_INVALID_STATE,
-    ESP_ERR_NOT_FOUND, ESP_ERR_NO_MEM, ESP_ERR_OTA_PARTITION_CONFLICT, ESP_ERR_OTA_ROLLBACK_FAILED,
+use crate::boot::ImageState;
+pub use crate::error::{Error, ErrorKind, Result};
+use crate::ffi::{
+    esp_err_t, esp_ota_handle_t, OtaFfi, ESP_ERR_FLASH_OP_FAIL, ESP_ERR_FLASH_OP_TIMEOUT,
+    ESP_ERR_INVALID_ARG, ESP_ERR_INVALID_STATE, ESP_ERR_NOT_FOUND, ESP_ERR_NOT_SUPPORTED,
+    ESP_ERR_NO_MEM, ESP_ERR_OTA_PARTITION_CONFLICT, ESP_ERR_OTA_ROLLBACK_FAILED,
     ESP_ERR_OTA_ROLLBACK_INVALID_STATE, ESP_ERR_OTA_SELECT_INFO_INVALID,
-    ESP_ERR_OTA_VALIDATE_FAILED, ESP_FAIL, ESP_OK, OTA_SIZE_UNKNOWN,
+    ESP_ERR_OTA_VALIDATE_FAILED, ESP_FAIL, ESP_OK,
 };
 
-pub type Result<T> = core::result::Result<T, Error>;
-
-/// An error that can happen during ESP OTA operations.
-#[derive(Debug)]
-pub struct Error {
-    kind: ErrorKind,
-}
-
-impl Error {
-    pub(crate) fn from_kind(kind: ErrorKind) -> Self {
-        Self { kind }
-    }
-
-    /// Returns the kind of error as an enum, that can be matched on.
-    pub fn kind(&self) -> ErrorKind {
-        self.kind
-    }
-}
-
-impl fmt::Display for Error {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        self.kind.fmt(f)
-    }
-}
-
-impl std::error::Error for Error {}
-
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
-#[non_exhaustive]
-pub enum ErrorKind {
-    /// No suitable partition for writing OTA update to found.
-    NoOtaPartition,
-    /// Cannot allocate memory for OTA operation.
-    AllocFailed,
-    /// Rollback enabled, but the currently running application is still pending. The currently
-    /// running application must confirm itself before downloading and flashing a new app.
-    InvalidRollbackState,
-    /// First byte of image contains invalid app image magic byte.
-    InvalidMagicByte,
-    /// Flash write operation timed out.
-    FlashTimeout,
-    /// Flash write operation failed.
-    FlashFailed,
-    /// OTA data partition has invalid contents.
-    InvalidOtaPartitionData,
-    /// The [`OtaUpdate`] handle was finalized before any app image was written to it.
-    NothingWritten,
-    /// OTA image is invalid (either not a valid app image, or - if secure boot is enabled - signature failed to verify.)
-    InvalidImage,
-    /// If flash encryption is enabled, this result indicates an internal error writing the final encrypted bytes to flash.
-    WritingEncryptedFailed,
-    /// The rollback failed.
-    RollbackFailed,
-    /// The rollback is not possible due to flash does not have any apps.
-    RollbackFailedNoApps,
-}
-
-impl fmt::Display for ErrorKind {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        use ErrorKind::*;
-        match self {
-            NoOtaPartition => "No suitable partition for writing OTA update to found",
-            AllocFailed => "Cannot allocate memory for OTA operation",
-            InvalidRollbackState => {
-                "Rollback enabled, but the currently running application is still pending"
-            }
-            InvalidMagicByte => "First byte of image contains invalid app image magic byte",
-            FlashTimeout => "Flash write operation timed out",
-            FlashFailed => "Flash write operation failed",
-            InvalidOtaPartitionData => "OTA data partition has invalid contents",
-            NothingWritten => "OtaUpdate was never written to",
-            InvalidImage => "OTA image is invalid",
-            WritingEncryptedFailed => "Internal error writing the final encrypted bytes to flash",
-            RollbackFailed => "The rollback failed",
-            RollbackFailedNoApps => {
-                "The rollback is not possible due to flash does not have any apps"
-            }
-        }
-        .fmt(f)
-    }
-}
-
 /// Represents an ongoing OTA update.
 ///
 /// Dropping this object before calling [`finalize`](OtaUpdate::finalize) will abort the update.
 #[derive(Debug)]
-pub struct OtaUpdate {
-    partition: *const esp_partition_t,
-    ota_handle: esp_ota_handle_t,
+pub struct OtaUpdate<F: OtaFfi> {
+    ffi: F,
+    partition: F::Partition,
+    /// Taken once the update ended, so that dropping it doesn't abort it.
+    ota_handle: Option<esp_ota_handle_t>,
 }
 
-impl OtaUpdate {
+impl<F: OtaFfi> OtaUpdate<F> {
     /// Starts an OTA update to the next OTA compatible partition.
     ///
     /// Finds next partition round-robin, starting from the current running partition.
     /// The entire partition is erased.
-    pub fn begin() -> Result<Self> {
-        let partition = unsafe { esp_ota_get_next_update_partition(ptr::null()) };
-        if partition.is_null() {
-            return Err(Error::from_kind(ErrorKind::NoOtaPartition));
-        }
+    pub fn begin(ffi: F) -> Result<Self> {
+        let partition = ffi
+            .ota_get_next_update_partition()
+            .ok_or(Error::from_kind(ErrorKind::NoOtaPartition))?;
 
         let mut ota_handle = 0;
-        match unsafe { esp_ota_begin(partition, OTA_SIZE_UNKNOWN as usize, &mut ota_handle) } {
+        match ffi.ota_begin(partition, &mut ota_handle) {
             ESP_OK => Ok(()),
             ESP_ERR_INVALID_ARG => panic!("Invalid partition or out_handle"),
             ESP_ERR_NO_MEM => Err(Error::from_kind(ErrorKind::AllocFailed)),
             ESP_ERR_OTA_PARTITION_CONFLICT => Err(Error::from_kind(ErrorKind::NoOtaPartition)),
-            ESP_ERR_NOT_FOUND => panic!("Partition argument not found in partition table"),
             ESP_ERR_OTA_SELECT_INFO_INVALID => {
                 Err(Error::from_kind(ErrorKind::InvalidOtaPartitionData))
             }
-            ESP_ERR_INVALID_SIZE => panic!("Partition doesn’t fit in configured flash size"),
             ESP_ERR_FLASH_OP_TIMEOUT => Err(Error::from_kind(ErrorKind::FlashTimeout)),
             ESP_ERR_FLASH_OP_FAIL => Err(Error::from_kind(ErrorKind::FlashFailed)),
             ESP_ERR_OTA_ROLLBACK_INVALID_STATE => {
                 Err(Error::from_kind(ErrorKind::InvalidRollbackState))
             }
-            code => panic!("Unexpected esp_ota_begin return code: {}", code),
+            // Includes a partition missing from the partition table, or one that doesn't fit
+            // the configured flash size.
+            code => Err(unexpected(&ffi, "esp_ota_begin", code)),
         }?;
 
         Ok(Self {
+            ffi,
             partition,
-            ota_handle,
+            ota_handle: Some(ota_handle),
         })
     }
 
@@ -145,10 +62,8 @@ impl OtaUpdate {
     ///
     /// The format of the app image can be read about in the main README and crate documentation.
     pub fn write(&mut self, app_image_chunk: &[u8]) -> Result<()> {
-        let chunk_ptr = app_image_chunk.as_ptr() as *const _;
-        let chunk_len = app_image_chunk.len();
-
-        match unsafe { esp_ota_write(self.ota_handle, chunk_ptr, chunk_len) } {
+        let ota_handle = self.ota_handle.expect("OTA update already ended");
+        match self.ffi.ota_write(ota_handle, app_image_chunk) {
             ESP_OK => Ok(()),
             ESP_ERR_INVALID_ARG => panic!("Invalid OTA handle"),
             ESP_ERR_OTA_VALIDATE_FAILED => Err(Error::from_kind(ErrorKind::InvalidMagicByte)),
@@ -157,7 +72,7 @@ impl OtaUpdate {
             ESP_ERR_OTA_SELECT_INFO_INVALID => {
                 Err(Error::from_kind(ErrorKind::InvalidOtaPartitionData))
             }
-            code => panic!("Unexpected esp_ota_write return code: {code}"),
+            code => Err(unexpected(&self.ffi, "esp_ota_write", code)),
         }
     }
 
@@ -165,36 +80,44 @@ impl OtaUpdate {
     ///
     /// Unless you also call [`set_as_boot_partition`](CompletedOtaUpdate::set_as_boot_partition) the new app will not
     /// start.
-    pub fn finalize(self) -> Result<CompletedOtaUpdate> {
-        match unsafe { esp_ota_end(self.ota_handle) } {
+    pub fn finalize(mut self) -> Result<CompletedOtaUpdate<F>>
+    where
+        F: Clone,
+    {
+        // `esp_ota_end` frees the handle whatever it returns, so it mustn't be aborted on drop.
+        let ota_handle = self.ota_handle.take().expect("OTA update already ended");
+        let ffi = self.ffi.clone();
+        match ffi.ota_end(ota_handle) {
             ESP_OK => Ok(()),
             ESP_ERR_NOT_FOUND => panic!("Invalid OTA handle"),
             ESP_ERR_INVALID_ARG => Err(Error::from_kind(ErrorKind::NothingWritten)),
             ESP_ERR_OTA_VALIDATE_FAILED => Err(Error::from_kind(ErrorKind::InvalidImage)),
             ESP_ERR_INVALID_STATE => Err(Error::from_kind(ErrorKind::WritingEncryptedFailed)),
-            code => panic!("Unexpected esp_ota_end return code: {code}"),
+            code => Err(unexpected(&ffi, "esp_ota_end", code)),
         }?;
 
-        let partition = self.partition;
-        mem::forget(self);
-
-        Ok(CompletedOtaUpdate { partition })
+        Ok(CompletedOtaUpdate {
+            ffi,
+            partition: self.partition,
+        })
     }
 
-    /// Returns a raw pointer to the partition that the new app is/will be written to.
-    pub fn raw_partition(&self) -> *const esp_partition_t {
+    /// Returns the partition that the new app is/will be written to, an
+    /// `EspPartition` on the device.
+    pub fn raw_partition(&self) -> F::Partition {
         self.partition
     }
 }
 
-impl Drop for OtaUpdate {
+impl<F: OtaFfi> Drop for OtaUpdate<F> {
     fn drop(&mut self) {
-        #[cfg(feature = "log")]
+        let Some(ota_handle) = self.ota_handle.take() else {
+            return;
+        };
         log::debug!("Aborting OTA update");
 
-        let abort_result_code = unsafe { esp_ota_abort(self.ota_handle) };
+        let abort_result_code = self.ffi.ota_abort(ota_handle);
         if abort_result_code != ESP_OK {
-            #[cfg(feature = "log")]
             log::error!(
                 "Aborting the OTA update returned an unexpected code: {}",
                 abort_result_code
@@ -203,37 +126,60 @@ impl Drop for OtaUpdate {
     }
 }
 
-pub struct CompletedOtaUpdate {
-    partition: *const esp_partition_t,
+pub struct CompletedOtaUpdate<F: OtaFfi> {
+    ffi: F,
+    partition: F::Partition,
 }
 
-impl CompletedOtaUpdate {
+impl<F: OtaFfi> CompletedOtaUpdate<F> {
     /// Sets the boot partition to the newly flashed OTA partition.
     pub fn set_as_boot_partition(&mut self) -> Result<()> {
-        match unsafe { esp_ota_set_boot_partition(self.partition) } {
+        match self.ffi.ota_set_boot_partition(self.partition) {
             ESP_OK => Ok(()),
             ESP_ERR_INVALID_ARG => panic!("Invalid partition sent to esp_ota_set_boot_partition"),
             ESP_ERR_OTA_VALIDATE_FAILED => Err(Error::from_kind(ErrorKind::InvalidImage)),
-            ESP_ERR_NOT_FOUND => panic!("OTA data partition not found"),
             ESP_ERR_FLASH_OP_TIMEOUT => Err(Error::from_kind(ErrorKind::FlashTimeout)),
             ESP_ERR_FLASH_OP_FAIL => Err(Error::from_kind(ErrorKind::FlashFailed)),
-            code => panic!("Unexpected esp_ota_set_boot_partition code: {}", code),
+            // Includes the OTA data partition missing from the partition table.
+            code => Err(unexpected(&self.ffi, "esp_ota_set_boot_partition", code)),
         }
     }
 
+    /// Returns the partition that the new app was written to, an
+    /// `EspPartition` on the device.
+    pub fn raw_partition(&self) -> F::Partition {
+        self.partition
+    }
+}
+
+#[cfg(target_os = "espidf")]
+impl CompletedOtaUpdate<EspOta> {
     /// Restarts the CPU. If [`set_as_boot_partition`](CompletedOtaUpdate::set_as_boot_partition) was
     /// called and completed successfully, the CPU will boot into the newly written app.
     ///
     /// After successful restart, CPU reset reason will be SW_CPU_RESET. Peripherals
     /// (except for WiFi, BT, UART0, SPI1, and legacy timers) are not reset.
     pub fn restart(self) -> ! {
-        unsafe { esp_restart() }
-        unreachable!("esp_restart returned");
+        restart()
     }
+}
 
-    /// Returns a raw pointer to the partition that the new app was written to.
-    pub fn raw_partition(&self) -> *const esp_partition_t {
-        self.partition
+fn unexpected<F: OtaFfi>(ffi: &F, function: &'static str, code: esp_err_t) -> Error {
+    Error::unexpected(function, code, ffi.err_to_name(code))
+}
+
+/// Returns the state of the running app in the OTA data.
+pub fn running_state<F: OtaFfi>(ffi: &F) -> Result<ImageState> {
+    partition_state(ffi, ffi.ota_get_running_partition())
+}
+
+fn partition_state<F: OtaFfi>(ffi: &F, partition: F::Partition) -> Result<ImageState> {
+    let mut state = 0;
+    match ffi.ota_get_state_partition(partition, &mut state) {
+        ESP_OK => Ok(ImageState::from_esp(state)),
+        // Not an OTA app partition, e.g. the factory app, or no state recorded for it.
+        ESP_ERR_NOT_SUPPORTED | ESP_ERR_NOT_FOUND => Ok(ImageState::Undefined),
+        code => Err(unexpected(ffi, "esp_ota_get_state_partition", code)),
     }
 }
 
@@ -241,13 +187,17 @@ impl CompletedOtaUpdate {
 ///
 /// Should be called (at least) the first time a new app starts up after
 /// being flashed.
-pub fn mark_app_valid() {
-    match unsafe { esp_ota_mark_app_valid_cancel_rollback() } {
-        ESP_OK => (),
-        code => panic!(
-            "Unexpected esp_ota_mark_app_valid_cancel_rollback code: {}",
-            code
-        ),
+pub fn mark_app_valid<F: OtaFfi>(ffi: &F) -> Result<()> {
+    match ffi.ota_mark_app_valid_cancel_rollback() {
+        ESP_OK => Ok(()),
+        // Marking it valid writes the OTA data.
+        ESP_ERR_FLASH_OP_TIMEOUT => Err(Error::from_kind(ErrorKind::FlashTimeout)),
+        ESP_ERR_FLASH_OP_FAIL => Err(Error::from_kind(ErrorKind::FlashFailed)),
+        code => Err(unexpected(
+            ffi,
+            "esp_ota_mark_app_valid_cancel_rollback",
+            code,
+        )),
     }
 }
 
@@ -255,13 +205,337 @@ pub fn mark_app_valid() {
 ///
 /// If rolling back failed, it returns an error, otherwise this function never returns,
 /// as the CPU is rebooting.
-pub fn rollback_and_reboot() -> Result<core::convert::Infallible> {
-    match unsafe { esp_ota_mark_app_invalid_rollback_and_reboot() } {
+pub fn rollback_and_reboot<F: OtaFfi>(ffi: &F) -> Result<core::convert::Infallible> {
+    match ffi.ota_mark_app_invalid_rollback_and_reboot() {
         ESP_FAIL => Err(Error::from_kind(ErrorKind::RollbackFailed)),
         ESP_ERR_OTA_ROLLBACK_FAILED => Err(Error::from_kind(ErrorKind::RollbackFailedNoApps)),
-        code => panic!(
-            "Unexpected esp_ota_mark_app_invalid_rollback_and_reboot code: {}",
-            code
-        ),
+        code => Err(unexpected(
+            ffi,
+            "esp_ota_mark_app_invalid_rollback_and_reboot",
+            code,
+        )),
+    }
+}
+
+#[cfg(target_os = "espidf")]
+mod esp {
+    use super::*;
+    use crate::ffi::EspPartition;
+    use crate::image::{AppDescriptor, ImageError, APP_DESC_LEN};
+    use crate::status::{PartitionInfo, Partitions};
+    use core::mem;
+    use core::slice;
+    use esp_idf_sys::{
+        esp_app_desc_t, esp_ota_get_app_description, esp_ota_get_partition_description, esp_restart,
+    };
+
+    /// Returns the description ESP-IDF embedded in the running app.
+    pub fn running_app() -> core::result::Result<AppDescriptor, ImageError> {
+        // Points into the mapped flash of the running app, so it is always valid.
+        let desc = unsafe { esp_ota_get_app_description() };
+        AppDescriptor::parse(unsafe { slice::from_raw_parts(desc.cast::<u8>(), APP_DESC_LEN) })
+    }
+
+    /// Returns the app written to `partition`, if it holds a valid one.
+    fn partition_app(partition: EspPartition) -> Option<AppDescriptor> {
+        let mut desc = mem::MaybeUninit::<esp_app_desc_t>::uninit();
+        match unsafe { esp_ota_get_partition_description(partition.as_ptr(), desc.as_mut_ptr()) } {
+            ESP_OK => {
+                let bytes =
+                    unsafe { slice::from_raw_parts(desc.as_ptr().cast::<u8>(), APP_DESC_LEN) };
+                AppDescriptor::parse(bytes).ok()
+            }
+            // Erased, or the image in it is broken.
+            _ => None,
+        }
+    }
+
+    fn partition_info(partition: EspPartition) -> Result<PartitionInfo> {
+        let app = partition_app(partition);
+        let state = partition_state(&EspOta, partition)?;
+        let partition = partition.get();
+        let label = unsafe {
+            slice::from_raw_parts(partition.label.as_ptr().cast::<u8>(), partition.label.len())
+        };
+        Ok(PartitionInfo::new(
+            label,
+            partition.subtype,
+            partition.address,
+            partition.size,
+            state,
+            app.as_ref(),
+        ))
+    }
+
+    /// Describes the running partition, the one that boots next and the one the next update goes to.
+    pub fn partitions() -> Result<Partitions> {
+        Ok(Partitions {
+            running: partition_info(EspOta.ota_get_running_partition())?,
+            boot: EspOta
+                .ota_get_boot_partition()
+                .map(partition_info)
+                .transpose()?,
+            next_update: EspOta
+                .ota_get_next_update_partition()
+                .map(partition_info)
+                .transpose()?,
+        })
+    }
+
+    /// Restarts the CPU, into the boot partition.
+    pub fn restart() -> ! {
+        unsafe { esp_restart() }
+        unreachable!("esp_restart returned");
+    }
+}
+
+#[cfg(target_os = "espidf")]
+pub use self::esp::{partitions, restart, running_app};
+#[cfg(target_os = "espidf")]
+pub use crate::ffi::EspOta;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffi::{
+        err_name, esp_ota_img_states_t, ESP_ERR_INVALID_SIZE, ESP_ERR_OTA_ROLLBACK_FAILED,
+    };
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+
+    /// Returns the codes it was told to, `ESP_OK` otherwise, and records the calls.
+    #[derive(Debug, Default)]
+    struct Mock {
+        codes: HashMap<&'static str, esp_err_t>,
+        no_partition: bool,
+        state: esp_ota_img_states_t,
+        calls: RefCell<Vec<&'static str>>,
+    }
+
+    impl Mock {
+        fn returning(function: &'static str, code: esp_err_t) -> Self {
+            Mock {
+                codes: HashMap::from([(function, code)]),
+                ..Default::default()
+            }
+        }
+
+        fn call(&self, function: &'static str) -> esp_err_t {
+            self.calls.borrow_mut().push(function);
+            self.codes.get(function).copied().unwrap_or(ESP_OK)
+        }
+
+        fn calls(&self) -> Vec<&'static str> {
+            self.calls.borrow().clone()
+        }
+    }
+
+    impl OtaFfi for Mock {
+        /// 0 is running, 1 is the next update.
+        type Partition = u8;
+
+        fn ota_get_running_partition(&self) -> u8 {
+            0
+        }
+
+        fn ota_get_boot_partition(&self) -> Option<u8> {
+            Some(0)
+        }
+
+        fn ota_get_next_update_partition(&self) -> Option<u8> {
+            (!self.no_partition).then_some(1)
+        }
+
+        fn ota_begin(&self, partition: u8, handle: &mut esp_ota_handle_t) -> esp_err_t {
+            assert_eq!(partition, 1);
+            *handle = 7;
+            self.call("begin")
+        }
+
+        fn ota_write(&self, handle: esp_ota_handle_t, _data: &[u8]) -> esp_err_t {
+            assert_eq!(handle, 7);
+            self.call("write")
+        }
+
+        fn ota_end(&self, handle: esp_ota_handle_t) -> esp_err_t {
+            assert_eq!(handle, 7);
+            self.call("end")
+        }
+
+        fn ota_abort(&self, handle: esp_ota_handle_t) -> esp_err_t {
+            assert_eq!(handle, 7);
+            self.call("abort")
+        }
+
+        fn ota_set_boot_partition(&self, partition: u8) -> esp_err_t {
+            assert_eq!(partition, 1);
+            self.call("set_boot_partition")
+        }
+
+        fn ota_get_state_partition(
+            &self,
+            partition: u8,
+            state: &mut esp_ota_img_states_t,
+        ) -> esp_err_t {
+            assert_eq!(partition, 0);
+            *state = self.state;
+            self.call("get_state_partition")
+        }
+
+        fn ota_mark_app_valid_cancel_rollback(&self) -> esp_err_t {
+            self.call("mark_app_valid")
+        }
+
+        fn ota_mark_app_invalid_rollback_and_reboot(&self) -> esp_err_t {
+            self.call("rollback")
+        }
+
+        fn err_to_name(&self, code: esp_err_t) -> &'static str {
+            err_name(code)
+        }
+    }
+
+    fn kind<T>(result: Result<T>) -> ErrorKind {
+        match result {
+            Ok(_) => panic!("expected an error"),
+            Err(e) => e.kind(),
+        }
+    }
+
+    #[test]
+    fn installs_updates() {
+        let mock = Mock::default();
+        let mut update = OtaUpdate::begin(&mock).unwrap();
+        update.write(b"image").unwrap();
+        let mut completed = update.finalize().unwrap();
+        completed.set_as_boot_partition().unwrap();
+        assert_eq!(completed.raw_partition(), 1);
+        assert_eq!(
+            mock.calls(),
+            vec!["begin", "write", "end", "set_boot_partition"]
+        );
+    }
+
+    #[test]
+    fn aborts_dropped_updates_only() {
+        let mock = Mock::returning("write", ESP_ERR_FLASH_OP_FAIL);
+        let mut update = OtaUpdate::begin(&mock).unwrap();
+        assert_eq!(kind(update.write(b"image")), ErrorKind::FlashFailed);
+        drop(update);
+        assert_eq!(mock.calls(), vec!["begin", "write", "abort"]);
+
+        // The handle is gone after `esp_ota_end`, even if it failed.
+        let mock = Mock::returning("end", ESP_ERR_OTA_VALIDATE_FAILED);
+        let update = OtaUpdate::begin(&mock).unwrap();
+        assert_eq!(kind(update.finalize()), ErrorKind::InvalidImage);
+        assert_eq!(mock.calls(), vec!["begin", "end"]);
+    }
+
+    #[test]
+    fn maps_documented_codes() {
+        let begin = |code| kind(OtaUpdate::begin(&Mock::returning("begin", code)));
+        assert_eq!(begin(ESP_ERR_NO_MEM), ErrorKind::AllocFailed);
+        assert_eq!(
+            begin(ESP_ERR_OTA_PARTITION_CONFLICT),
+            ErrorKind::NoOtaPartition
+        );
+        assert_eq!(
+            begin(ESP_ERR_OTA_ROLLBACK_INVALID_STATE),
+            ErrorKind::InvalidRollbackState
+        );
+        let mock = Mock {
+            no_partition: true,
+            ..Default::default()
+        };
+        assert_eq!(kind(OtaUpdate::begin(&mock)), ErrorKind::NoOtaPartition);
+
+        let write = |code| {
+            let mock = Mock::returning("write", code);
+            let mut update = OtaUpdate::begin(&mock).unwrap();
+            kind(update.write(b"image"))
+        };
+        assert_eq!(
+            write(ESP_ERR_OTA_VALIDATE_FAILED),
+            ErrorKind::InvalidMagicByte
+        );
+        assert_eq!(write(ESP_ERR_FLASH_OP_TIMEOUT), ErrorKind::FlashTimeout);
+
+        let finalize = |code| {
+            kind(
+                OtaUpdate::begin(&Mock::returning("end", code))
+                    .unwrap()
+                    .finalize(),
+            )
+        };
+        assert_eq!(finalize(ESP_ERR_INVALID_ARG), ErrorKind::NothingWritten);
+        assert_eq!(
+            finalize(ESP_ERR_INVALID_STATE),
+            ErrorKind::WritingEncryptedFailed
+        );
+
+        let mock = Mock::returning("set_boot_partition", ESP_ERR_OTA_VALIDATE_FAILED);
+        let mut completed = OtaUpdate::begin(&mock).unwrap().finalize().unwrap();
+        assert_eq!(
+            kind(completed.set_as_boot_partition()),
+            ErrorKind::InvalidImage
+        );
+
+        let mock = Mock::returning("rollback", ESP_ERR_OTA_ROLLBACK_FAILED);
+        assert_eq!(
+            kind(rollback_and_reboot(&mock)),
+            ErrorKind::RollbackFailedNoApps
+        );
+        let mock = Mock::returning("rollback", ESP_FAIL);
+        assert_eq!(kind(rollback_and_reboot(&mock)), ErrorKind::RollbackFailed);
+    }
+
+    #[test]
+    fn returns_unexpected_codes() {
+        let e = OtaUpdate::begin(&Mock::returning("begin", ESP_ERR_INVALID_SIZE)).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Unexpected(ESP_ERR_INVALID_SIZE));
+        assert_eq!(e.code_name(), Some("ESP_ERR_INVALID_SIZE"));
+        assert_eq!(
+            e.to_string(),
+            "Unexpected esp_ota_begin return code 260 (ESP_ERR_INVALID_SIZE)"
+        );
+
+        let mock = Mock::returning("set_boot_partition", ESP_ERR_NOT_FOUND);
+        let mut completed = OtaUpdate::begin(&mock).unwrap().finalize().unwrap();
+        assert_eq!(
+            kind(completed.set_as_boot_partition()),
+            ErrorKind::Unexpected(ESP_ERR_NOT_FOUND)
+        );
+
+        let e = mark_app_valid(&Mock::returning("mark_app_valid", 0x1234)).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Unexpected(0x1234));
+        assert_eq!(e.code_name(), Some("UNKNOWN ERROR"));
+        assert_eq!(
+            kind(rollback_and_reboot(&Mock::default())),
+            ErrorKind::Unexpected(ESP_OK)
+        );
+        let mock = Mock::returning("get_state_partition", ESP_ERR_INVALID_ARG);
+        assert_eq!(
+            kind(running_state(&mock)),
+            ErrorKind::Unexpected(ESP_ERR_INVALID_ARG)
+        );
+    }
+
+    #[test]
+    fn reads_app_states() {
+        let mock = Mock {
+            state: 1,
+            ..Default::default()
+        };
+        assert_eq!(running_state(&mock).unwrap(), ImageState::PendingVerify);
+        let factory = Mock::returning("get_state_partition", ESP_ERR_NOT_SUPPORTED);
+        assert_eq!(running_state(&factory).unwrap(), ImageState::Undefined);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid OTA handle")]
+    fn panics_on_invalid_handles() {
+        let mock = Mock::returning("write", ESP_ERR_INVALID_ARG);
+        let mut update = OtaUpdate::begin(&mock).unwrap();
+        let _ = update.write(b"image");
     }
 }
@@ -0,0 +1,395 @@
+//! Pulling updates from the http-server instead of waiting for an upload.
+//!
+//! The device asks the server for the release it should run every so often (see
+//! [`types::firmware`]). When one is offered, it downloads the image in chunks straight into an
+//! OTA update, resuming it if the connection drops (see [`crate::download`]), checks its size,
+//! SHA-256 and signature on the way, and reports back whether it installed it before restarting
+//! into it.
+
+use core::fmt;
+
+use ed25519_dalek::VerifyingKey;
+use types::firmware::FirmwareUpdate;
+
+use crate::{
+    checksum::{parse_sha256, ImageCheck},
+    signature::verify_downgrade,
+};
+
+/// Why an offer couldn't be used.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum PullError {
+    /// The server answered with neither an update nor `204 No Content`.
+    Status(u16),
+    /// The offer isn't valid JSON, or has no size or no valid SHA-256.
+    InvalidOffer,
+}
+
+impl fmt::Display for PullError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PullError::Status(status) => write!(f, "Server answered {status}"),
+            PullError::InvalidOffer => write!(f, "Server offered an invalid update"),
+        }
+    }
+}
+
+impl std::error::Error for PullError {}
+
+/// An update the server offered, with what it must download as.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Offer {
+    pub update: FirmwareUpdate,
+    pub size: usize,
+    pub sha256: [u8; 32],
+}
+
+impl Offer {
+    /// Checks that the download matches the offer.
+    pub fn image_check(&self) -> ImageCheck {
+        ImageCheck::new(self.size, self.sha256)
+    }
+
+    /// Whether the offer carries a downgrade signature of the image by `key`, which lets it
+    /// replace a newer app.
+    pub fn allows_downgrade(&self, key: &VerifyingKey) -> bool {
+        self.update
+            .downgrade
+            .as_deref()
+            .is_some_and(|signature| verify_downgrade(key, &self.sha256, signature).is_ok())
+    }
+}
+
+/// Reads the answer to a poll: an offer with `200 OK`, or nothing to do with `204 No Content`.
+pub fn parse_offer(status: u16, body: &[u8]) -> Result<Option<Offer>, PullError> {
+    match status {
+        200 => {}
+        204 => return Ok(None),
+        _ => return Err(PullError::Status(status)),
+    }
+    let update: FirmwareUpdate =
+        serde_json::from_slice(body).map_err(|_| PullError::InvalidOffer)?;
+    let size = usize::try_from(update.size)
+        .ok()
+        .filter(|size| *size > 0)
+        .ok_or(PullError::InvalidOffer)?;
+    let sha256 = parse_sha256(&update.sha256).map_err(|_| PullError::InvalidOffer)?;
+    Ok(Some(Offer {
+        update,
+        size,
+        sha256,
+    }))
+}
+
+/// The URL of `path` on `server`, e.g. `http://192.168.1.10:3000`, unless `path` is a URL
+/// already.
+pub fn resolve_url(server: &str, path: &str) -> String {
+    if path.starts_with("http://") || path.starts_with("https://") {
+        return path.to_string();
+    }
+    format!(
+        "{}/{}",
+        server.trim_end_matches('/'),
+        path.trim_start_matches('/')
+    )
+}
+
+/// Decides when to ask the server for an update.
+///
+/// Times are seconds since boot, like for health reports.
+#[derive(Debug)]
+pub struct PollSchedule {
+    interval_secs: u64,
+    last_polled_secs: Option<u64>,
+}
+
+impl PollSchedule {
+    pub fn new(interval_secs: u64) -> Self {
+        PollSchedule {
+            interval_secs,
+            last_polled_secs: None,
+        }
+    }
+
+    /// Whether a poll is due, which the first one always is.
+    pub fn due(&self, uptime_secs: u64) -> bool {
+        match self.last_polled_secs {
+            Some(last) => uptime_secs.saturating_sub(last) >= self.interval_secs,
+            None => true,
+        }
+    }
+
+    /// Records a poll, whether it worked or not, so the next one is due an interval later.
+    pub fn polled(&mut self, uptime_secs: u64) {
+        self.last_polled_secs = Some(uptime_secs);
+    }
+}
+
+#[cfg(target_os = "espidf")]
+mod client {
+    use anyhow::{anyhow, Result};
+    use ed25519_dalek::VerifyingKey;
+    use embedded_svc::{
+        http::{client::Client, Method, Status},
+        io::{Read, Write},
+    };
+    use esp_idf_svc::http::client::{Configuration, EspHttpConnection};
+    use log::{info, warn};
+    use types::firmware::{latest_uri, UpdateOutcome, UpdateReport, REPORTS_PATH};
+
+    use super::{parse_offer, resolve_url, Offer, PullError};
+    use crate::{
+        download::{self, Resume, Transfer},
+        image::{Chip, ImagePolicy},
+        ota::{self, CompletedOtaUpdate, EspOta, OtaUpdate},
+        writer::ImageWriter,
+    };
+
+    /// Largest answer to a poll that is read, far more than an offer takes.
+    const MAX_OFFER_LEN: usize = 1024;
+
+    fn configuration() -> Configuration {
+        Configuration {
+            // Reads that stall this long fail, so that the download is resumed.
+            timeout: Some(core::time::Duration::from_secs(30)),
+            // Lets `https://` servers be verified against the bundled CA certificates.
+            crt_bundle_attach: Some(esp_idf_sys::esp_crt_bundle_attach),
+            ..Default::default()
+        }
+    }
+
+    fn client() -> Result<Client<EspHttpConnection>> {
+        Ok(Client::wrap(EspHttpConnection::new(&configuration())?))
+    }
+
+    /// Asks `server` for an update and installs it, restarting into it if that worked.
+    ///
+    /// Returns when there is no update, or after reporting why installing it failed.
+    pub fn install_latest(server: &str, device_id: &[u8], public_key: VerifyingKey) -> Result<()> {
+        let running = ota::running_app()?;
+        let Some(offer) = poll(server, device_id, &running.version)? else {
+            info!("Firmware {} is up to date", running.version);
+            return Ok(());
+        };
+        info!(
+            "Updating firmware {} to {}, {} bytes",
+            running.version, offer.update.version, offer.size
+        );
+        let chip = Chip::from_id(esp_idf_sys::CONFIG_IDF_FIRMWARE_CHIP_ID as u16);
+        let mut policy = ImagePolicy::for_running(chip, &running);
+        // Older releases are only installed to back out of a newer one, which takes a signature
+        // of the signing key rather than the server's word.
+        policy.allow_downgrade = offer.allows_downgrade(&public_key);
+        let installed = download(server, &offer, ImageWriter::new(policy, public_key))
+            .and_then(|ota| Ok(ota.finalize()?))
+            .and_then(|mut completed: CompletedOtaUpdate<EspOta>| {
+                completed.set_as_boot_partition()?;
+                Ok(completed)
+            });
+        let report = UpdateReport {
+            device_id: device_id.to_vec(),
+            version: offer.update.version.clone(),
+            outcome: match installed {
+                Ok(_) => UpdateOutcome::Installed,
+                Err(_) => UpdateOutcome::Failed,
+            },
+            message: installed.as_ref().err().map(|e| format!("{e:#}")),
+        };
+        if let Err(e) = send_report(server, &report) {
+            warn!("Reporting the update failed: {:?}", e);
+        }
+        let completed = installed?;
+        info!("Restarting into firmware {}", offer.update.version);
+        completed.restart();
+    }
+
+    fn poll(server: &str, device_id: &[u8], current: &str) -> Result<Option<Offer>> {
+        let mut client = client()?;
+        let uri = resolve_url(server, &latest_uri(device_id, current));
+        let mut response = client.get(&uri)?.submit()?;
+        let status = response.status();
+        let mut body = [0; MAX_OFFER_LEN];
+        let mut len = 0;
+        while len < body.len() {
+            let read = response.read(&mut body[len..])?;
+            if read == 0 {
+                break;
+            }
+            len += read;
+        }
+        Ok(parse_offer(status, &body[..len])?)
+    }
+
+    /// Downloads an image, over a new connection for every attempt since the last one may be
+    /// broken.
+    struct EspTransfer {
+        uri: String,
+        connection: Option<EspHttpConnection>,
+    }
+
+    impl Transfer for EspTransfer {
+        fn request(&mut self, offset: usize) -> Result<(u16, Option<String>)> {
+            self.connection = None;
+            let mut connection = EspHttpConnection::new(&configuration())?;
+            let range = format!("bytes={offset}-");
+            let headers: &[(&str, &str)] = match offset {
+                0 => &[],
+                _ => &[("Range", &range)],
+            };
+            connection.initiate_request(Method::Get, &self.uri, headers)?;
+            connection.initiate_response()?;
+            let status = connection.status();
+            let content_range = connection.header("Content-Range").map(str::to_string);
+            self.connection = Some(connection);
+            Ok((status, content_range))
+        }
+
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            match &mut self.connection {
+                Some(connection) => Ok(connection.read(buf)?),
+                None => Err(anyhow!("No request to read the answer of")),
+            }
+        }
+    }
+
+    /// Writes the offered image to a new update, which is aborted if anything goes wrong.
+    ///
+    /// Interrupted downloads are resumed where they stopped, into the same update.
+    fn download(server: &str, offer: &Offer, mut writer: ImageWriter) -> Result<OtaUpdate<EspOta>> {
+        let mut transfer = EspTransfer {
+            uri: resolve_url(server, &offer.update.url),
+            connection: None,
+        };
+        let mut check = offer.image_check();
+        download::download(&mut transfer, &mut check, &Resume::default(), |chunk| {
+            writer.write(chunk)
+        })?;
+        check.finish()?;
+        writer.finish()
+    }
+
+    fn send_report(server: &str, report: &UpdateReport) -> Result<()> {
+        let body = serde_json::to_vec(report)?;
+        let content_length = body.len().to_string();
+        let headers = [
+            ("Content-Type", "application/json"),
+            ("Content-Length", content_length.as_str()),
+        ];
+        let mut client = client()?;
+        let uri = resolve_url(server, REPORTS_PATH);
+        let mut request = client.post(&uri, &headers)?;
+        request.write_all(&body)?;
+        request.flush()?;
+        let response = request.submit()?;
+        match response.status() {
+            200..=299 => Ok(()),
+            status => Err(PullError::Status(status).into()),
+        }
+    }
+}
+
+#[cfg(target_os = "espidf")]
+pub use self::client::install_latest;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signature::sign_downgrade;
+    use ed25519_dalek::SigningKey;
+
+    /// SHA-256 of `abc`.
+    const ABC_SHA256: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    fn offer(size: u64, sha256: &str) -> FirmwareUpdate {
+        FirmwareUpdate {
+            version: "0.3.0".into(),
+            size,
+            sha256: sha256.into(),
+            url: "/firmware/releases/0.3.0/image".into(),
+            downgrade: None,
+        }
+    }
+
+    fn offer_json(size: u64, sha256: &str) -> Vec<u8> {
+        serde_json::to_vec(&offer(size, sha256)).unwrap()
+    }
+
+    #[test]
+    fn parses_offers() {
+        let offer = parse_offer(200, &offer_json(3, ABC_SHA256))
+            .unwrap()
+            .unwrap();
+        assert_eq!(offer.size, 3);
+        assert_eq!(offer.update.version, "0.3.0");
+        let mut check = offer.image_check();
+        check.update(b"abc").unwrap();
+        assert_eq!(check.finish(), Ok(()));
+
+        assert_eq!(parse_offer(204, b""), Ok(None));
+        assert_eq!(parse_offer(404, b""), Err(PullError::Status(404)));
+        assert_eq!(parse_offer(200, b"{}"), Err(PullError::InvalidOffer));
+        assert_eq!(
+            parse_offer(200, &offer_json(0, ABC_SHA256)),
+            Err(PullError::InvalidOffer)
+        );
+        assert_eq!(
+            parse_offer(200, &offer_json(3, "abc")),
+            Err(PullError::InvalidOffer)
+        );
+    }
+
+    #[test]
+    fn allows_downgrades_only_when_signed() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let sha256 = parse_sha256(ABC_SHA256).unwrap();
+        let signature: String = sign_downgrade(&key, &sha256)
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        let parse = |downgrade: Option<&str>| {
+            let update = FirmwareUpdate {
+                downgrade: downgrade.map(str::to_string),
+                ..offer(3, ABC_SHA256)
+            };
+            let json = serde_json::to_vec(&update).unwrap();
+            parse_offer(200, &json).unwrap().unwrap()
+        };
+
+        assert!(!parse(None).allows_downgrade(&key.verifying_key()));
+        assert!(parse(Some(&signature)).allows_downgrade(&key.verifying_key()));
+        let other_key = SigningKey::from_bytes(&[8; 32]).verifying_key();
+        assert!(!parse(Some(&signature)).allows_downgrade(&other_key));
+        assert!(!parse(Some("yes")).allows_downgrade(&key.verifying_key()));
+    }
+
+    #[test]
+    fn resolves_image_urls() {
+        assert_eq!(
+            resolve_url(
+                "http://192.168.1.10:3000/",
+                "/firmware/latest?current=0.3.0"
+            ),
+            "http://192.168.1.10:3000/firmware/latest?current=0.3.0"
+        );
+        assert_eq!(
+            resolve_url("https://updates.example.com", "firmware/reports"),
+            "https://updates.example.com/firmware/reports"
+        );
+        assert_eq!(
+            resolve_url(
+                "http://192.168.1.10:3000",
+                "https://cdn.example.com/app.bin"
+            ),
+            "https://cdn.example.com/app.bin"
+        );
+    }
+
+    #[test]
+    fn polls_every_interval() {
+        let mut schedule = PollSchedule::new(600);
+        assert!(schedule.due(5));
+        schedule.polled(5);
+        assert!(!schedule.due(604));
+        assert!(schedule.due(605));
+    }
+}
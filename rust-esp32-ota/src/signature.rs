@@ -0,0 +1,257 @@
+//! Ed25519 signatures of firmware images, so that only images signed by the holder of the
+//! signing key can be booted.
+//!
+//! A signed image is the image as `espflash save-image` writes it, followed by a trailer: the
+//! signature of the image's SHA-256, then [`TRAILER_MAGIC`]. The trailer is added by the
+//! `sign-image` tool and held back while the image is written, so it never reaches flash.
+//!
+//! Pulled updates and raw uploads may also carry a downgrade signature, which lets a device
+//! install a signed image older than the running app, see [`sign_downgrade`].
+
+use core::fmt;
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey, SIGNATURE_LENGTH};
+use sha2::{Digest, Sha256};
+
+use crate::checksum::decode_hex;
+
+/// Ends the trailer of a signed image.
+pub const TRAILER_MAGIC: [u8; 8] = *b"OTASIG01";
+/// Length of the trailer of a signed image.
+pub const TRAILER_LEN: usize = SIGNATURE_LENGTH + TRAILER_MAGIC.len();
+/// Precedes the SHA-256 a downgrade signature signs, so that it can't pass for an image
+/// signature.
+const DOWNGRADE_CONTEXT: &[u8] = b"OTA downgrade\0";
+/// Header of raw uploads carrying a hex encoded [`sign_downgrade`] signature of the upload.
+pub const DOWNGRADE_HEADER: &str = "X-Downgrade-Signature";
+
+/// Why a signed image was rejected.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SignatureError {
+    /// The public key isn't 64 hexadecimal digits of a valid Ed25519 key.
+    InvalidPublicKey,
+    /// The image doesn't end with a signature trailer.
+    Unsigned,
+    /// The signature doesn't match the image and public key.
+    Invalid,
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignatureError::InvalidPublicKey => "Invalid Ed25519 public key",
+            SignatureError::Unsigned => "Image is not signed",
+            SignatureError::Invalid => "Image signature is invalid",
+        }
+        .fmt(f)
+    }
+}
+
+impl std::error::Error for SignatureError {}
+
+/// Parses a hex-encoded Ed25519 public key.
+pub fn parse_public_key(hex: &str) -> Result<VerifyingKey, SignatureError> {
+    let bytes = decode_hex(hex).ok_or(SignatureError::InvalidPublicKey)?;
+    VerifyingKey::from_bytes(&bytes).map_err(|_| SignatureError::InvalidPublicKey)
+}
+
+/// Returns the trailer that signs `image`.
+pub fn sign(key: &SigningKey, image: &[u8]) -> [u8; TRAILER_LEN] {
+    let signature = key.sign(&Sha256::digest(image));
+    let mut trailer = [0; TRAILER_LEN];
+    trailer[..SIGNATURE_LENGTH].copy_from_slice(&signature.to_bytes());
+    trailer[SIGNATURE_LENGTH..].copy_from_slice(&TRAILER_MAGIC);
+    trailer
+}
+
+/// Signs that the signed image with the SHA-256 `signed_sha256`, trailer included, may replace
+/// newer apps.
+pub fn sign_downgrade(key: &SigningKey, signed_sha256: &[u8; 32]) -> [u8; SIGNATURE_LENGTH] {
+    key.sign(&[DOWNGRADE_CONTEXT, signed_sha256].concat())
+        .to_bytes()
+}
+
+/// Checks a hex encoded signature from [`sign_downgrade`].
+pub fn verify_downgrade(
+    key: &VerifyingKey,
+    signed_sha256: &[u8; 32],
+    signature: &str,
+) -> Result<(), SignatureError> {
+    let signature: [u8; SIGNATURE_LENGTH] = decode_hex(signature).ok_or(SignatureError::Invalid)?;
+    key.verify(
+        &[DOWNGRADE_CONTEXT, signed_sha256].concat(),
+        &Signature::from_bytes(&signature),
+    )
+    .map_err(|_| SignatureError::Invalid)
+}
+
+/// Verifies a signed image while it streams past.
+#[derive(Debug, Clone)]
+pub struct SignedImage {
+    key: VerifyingKey,
+    hasher: Sha256,
+    /// The last bytes seen, which may turn out to be the trailer.
+    tail: [u8; TRAILER_LEN],
+    tail_len: usize,
+}
+
+impl SignedImage {
+    pub fn new(key: VerifyingKey) -> Self {
+        Self {
+            key,
+            hasher: Sha256::new(),
+            tail: [0; TRAILER_LEN],
+            tail_len: 0,
+        }
+    }
+
+    /// Takes the next chunk of the signed image, passing the bytes that are certainly part of
+    /// the image on to `write`.
+    pub fn update<E>(
+        &mut self,
+        chunk: &[u8],
+        mut write: impl FnMut(&[u8]) -> Result<(), E>,
+    ) -> Result<(), E> {
+        // Everything but the last `TRAILER_LEN` bytes seen is part of the image.
+        let overflow = (self.tail_len + chunk.len()).saturating_sub(TRAILER_LEN);
+        let from_tail = overflow.min(self.tail_len);
+        if from_tail > 0 {
+            self.hasher.update(&self.tail[..from_tail]);
+            write(&self.tail[..from_tail])?;
+            self.tail.copy_within(from_tail..self.tail_len, 0);
+            self.tail_len -= from_tail;
+        }
+        let (image, rest) = chunk.split_at(overflow - from_tail);
+        if !image.is_empty() {
+            self.hasher.update(image);
+            write(image)?;
+        }
+        self.tail[self.tail_len..self.tail_len + rest.len()].copy_from_slice(rest);
+        self.tail_len += rest.len();
+        Ok(())
+    }
+
+    /// Checks the signature once all of the signed image was passed to
+    /// [`update`](SignedImage::update).
+    pub fn verify(self) -> Result<(), SignatureError> {
+        if self.tail_len != TRAILER_LEN || self.tail[SIGNATURE_LENGTH..] != TRAILER_MAGIC {
+            return Err(SignatureError::Unsigned);
+        }
+        let mut signature = [0; SIGNATURE_LENGTH];
+        signature.copy_from_slice(&self.tail[..SIGNATURE_LENGTH]);
+        self.key
+            .verify(&self.hasher.finalize(), &Signature::from_bytes(&signature))
+            .map_err(|_| SignatureError::Invalid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(seed: u8) -> SigningKey {
+        SigningKey::from_bytes(&[seed; 32])
+    }
+
+    fn signed(key: &SigningKey, image: &[u8]) -> Vec<u8> {
+        let mut signed = image.to_vec();
+        signed.extend(sign(key, image));
+        signed
+    }
+
+    /// Streams `signed` in chunks, returning what was written and the verification result.
+    fn stream(
+        key: &SigningKey,
+        signed: &[u8],
+        chunk_size: usize,
+    ) -> (Vec<u8>, Result<(), SignatureError>) {
+        let mut image = SignedImage::new(key.verifying_key());
+        let mut written = Vec::new();
+        for chunk in signed.chunks(chunk_size) {
+            image
+                .update(chunk, |bytes| {
+                    written.extend(bytes);
+                    Ok::<_, ()>(())
+                })
+                .unwrap();
+        }
+        (written, image.verify())
+    }
+
+    #[test]
+    fn parses_public_keys() {
+        let key = key(7).verifying_key();
+        let hex: String = key.as_bytes().iter().map(|b| format!("{b:02x}")).collect();
+        assert_eq!(parse_public_key(&hex), Ok(key));
+        assert_eq!(
+            parse_public_key(&hex[2..]),
+            Err(SignatureError::InvalidPublicKey)
+        );
+    }
+
+    #[test]
+    fn writes_only_the_image_of_signed_images() {
+        let key = key(7);
+        let image: Vec<u8> = (0..=255).cycle().take(1000).collect();
+        let signed = signed(&key, &image);
+        for chunk_size in [1, 7, TRAILER_LEN - 1, TRAILER_LEN, 100, signed.len()] {
+            let (written, verified) = stream(&key, &signed, chunk_size);
+            assert_eq!(written, image, "{chunk_size}");
+            assert_eq!(verified, Ok(()), "{chunk_size}");
+        }
+    }
+
+    #[test]
+    fn rejects_unsigned_and_tampered_images() {
+        let key = key(7);
+        let image = vec![0xe9; 500];
+
+        assert_eq!(stream(&key, &image, 64).1, Err(SignatureError::Unsigned));
+        assert_eq!(stream(&key, &[], 64).1, Err(SignatureError::Unsigned));
+
+        let mut tampered = signed(&key, &image);
+        tampered[100] ^= 1;
+        assert_eq!(stream(&key, &tampered, 64).1, Err(SignatureError::Invalid));
+
+        let other_key = signed(&self::key(8), &image);
+        assert_eq!(stream(&key, &other_key, 64).1, Err(SignatureError::Invalid));
+    }
+
+    #[test]
+    fn verifies_downgrade_signatures() {
+        let key = key(7);
+        let sha256 = [0xab; 32];
+        let hex: String = sign_downgrade(&key, &sha256)
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        assert_eq!(
+            verify_downgrade(&key.verifying_key(), &sha256, &hex),
+            Ok(())
+        );
+
+        let other_image = [0xac; 32];
+        let other_key = self::key(8).verifying_key();
+        for (key, sha256, hex) in [
+            (key.verifying_key(), &other_image, hex.as_str()),
+            (other_key, &sha256, hex.as_str()),
+            (key.verifying_key(), &sha256, &hex[2..]),
+        ] {
+            assert_eq!(
+                verify_downgrade(&key, sha256, hex),
+                Err(SignatureError::Invalid)
+            );
+        }
+        // An image signature doesn't allow downgrades.
+        let image_signature: String = key
+            .sign(&sha256)
+            .to_bytes()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        assert_eq!(
+            verify_downgrade(&key.verifying_key(), &sha256, &image_signature),
+            Err(SignatureError::Invalid)
+        );
+    }
+}
@@ -0,0 +1,640 @@
+//! A simulated flash for [`OtaFfi`], so that updates and rollbacks can be run on the host.
+//!
+//! [`SimulatedFlash`] keeps its app partitions and the OTA data in memory and behaves like
+//! ESP-IDF with rollback enabled: set boot partitions start out new, boot pending verification,
+//! and are aborted by the next restart unless they were marked valid. Any function can be made to
+//! fail once with [`SimulatedFlash::fail`], and [`SimulatedFlash::restart`] runs the bootloader.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::{
+    boot::ImageState,
+    ffi::{
+        err_name, esp_err_t, esp_ota_handle_t, esp_ota_img_states_t, OtaFfi, ESP_ERR_INVALID_ARG,
+        ESP_ERR_INVALID_SIZE, ESP_ERR_NOT_FOUND, ESP_ERR_NOT_SUPPORTED,
+        ESP_ERR_OTA_PARTITION_CONFLICT, ESP_ERR_OTA_ROLLBACK_FAILED,
+        ESP_ERR_OTA_ROLLBACK_INVALID_STATE, ESP_ERR_OTA_VALIDATE_FAILED, ESP_FAIL, ESP_OK,
+    },
+    image::IMAGE_MAGIC,
+};
+
+/// `esp_ota_img_states_t` values.
+const NEW: esp_ota_img_states_t = 0;
+const PENDING_VERIFY: esp_ota_img_states_t = 1;
+const VALID: esp_ota_img_states_t = 2;
+const INVALID: esp_ota_img_states_t = 3;
+const ABORTED: esp_ota_img_states_t = 4;
+
+/// An [`OtaFfi`] function, to make fail.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Op {
+    Begin,
+    Write,
+    End,
+    Abort,
+    SetBootPartition,
+    GetStatePartition,
+    MarkAppValid,
+    RollbackAndReboot,
+}
+
+/// An app partition.
+#[derive(Debug)]
+struct Partition {
+    label: String,
+    factory: bool,
+    data: Vec<u8>,
+    /// Whether `data` is a whole image, which `esp_ota_end()` checked.
+    complete: bool,
+    /// The sequence number and state in the OTA data, for OTA partitions that were set to boot.
+    otadata: Option<(u32, esp_ota_img_states_t)>,
+}
+
+impl Partition {
+    fn state(&self) -> Option<esp_ota_img_states_t> {
+        self.otadata.map(|(_, state)| state)
+    }
+
+    /// Whether the bootloader may start it.
+    fn bootable(&self) -> bool {
+        self.complete && !matches!(self.state(), Some(INVALID | ABORTED))
+    }
+}
+
+#[derive(Debug)]
+struct Flash {
+    partitions: Vec<Partition>,
+    size: usize,
+    running: usize,
+    updates: HashMap<esp_ota_handle_t, usize>,
+    next_handle: esp_ota_handle_t,
+    sequence: u32,
+    failures: HashMap<Op, esp_err_t>,
+    restarts: usize,
+}
+
+impl Flash {
+    fn is_ota(&self, partition: usize) -> bool {
+        self.partitions
+            .get(partition)
+            .is_some_and(|partition| !partition.factory)
+    }
+
+    /// The OTA partition the OTA data selects, like `esp_ota_get_boot_partition()`.
+    fn selected(&self) -> Option<usize> {
+        (0..self.partitions.len())
+            .filter(|&i| {
+                let partition = &self.partitions[i];
+                partition.otadata.is_some() && partition.bootable()
+            })
+            .max_by_key(|&i| self.partitions[i].otadata.map(|(sequence, _)| sequence))
+    }
+
+    /// The partition booted without a selection in the OTA data: the factory app, or else the
+    /// first OTA app.
+    fn fallback(&self) -> Option<usize> {
+        self.partitions
+            .iter()
+            .position(|partition| partition.factory && partition.complete)
+            .or_else(|| {
+                self.partitions
+                    .iter()
+                    .position(|partition| !partition.factory && partition.bootable())
+            })
+    }
+
+    fn set_state(&mut self, partition: usize, state: esp_ota_img_states_t) {
+        let sequence = self.partitions[partition]
+            .otadata
+            .map_or(0, |(sequence, _)| sequence);
+        self.partitions[partition].otadata = Some((sequence, state));
+    }
+
+    /// Runs the bootloader: apps that weren't marked valid since their first boot are aborted,
+    /// and new ones start pending verification.
+    fn restart(&mut self) {
+        self.updates.clear();
+        self.restarts += 1;
+        loop {
+            match self.selected() {
+                Some(partition) if self.partitions[partition].state() == Some(PENDING_VERIFY) => {
+                    self.set_state(partition, ABORTED);
+                }
+                Some(partition) => {
+                    if self.partitions[partition].state() == Some(NEW) {
+                        self.set_state(partition, PENDING_VERIFY);
+                    }
+                    self.running = partition;
+                    return;
+                }
+                None => {
+                    self.running = self.fallback().expect("no app to boot");
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Flash with a factory app or not, and OTA partitions of the same size.
+#[derive(Debug)]
+pub struct SimulatedFlash {
+    flash: RefCell<Flash>,
+}
+
+impl SimulatedFlash {
+    /// A factory partition running an app, and `ota_partitions` empty OTA partitions.
+    pub fn with_factory(ota_partitions: usize, size: usize) -> Self {
+        let mut flash = Self::without_factory(ota_partitions, size);
+        let factory = Partition {
+            label: "factory".into(),
+            factory: true,
+            data: vec![IMAGE_MAGIC],
+            complete: true,
+            otadata: None,
+        };
+        flash.flash.get_mut().partitions.insert(0, factory);
+        flash
+    }
+
+    /// `ota_partitions` OTA partitions, the first one running an app that was flashed over
+    /// serial, so without OTA data.
+    pub fn without_factory(ota_partitions: usize, size: usize) -> Self {
+        let mut partitions: Vec<Partition> = (0..ota_partitions)
+            .map(|i| Partition {
+                label: format!("ota_{i}"),
+                factory: false,
+                data: Vec::new(),
+                complete: false,
+                otadata: None,
+            })
+            .collect();
+        if let Some(first) = partitions.first_mut() {
+            first.data = vec![IMAGE_MAGIC];
+            first.complete = true;
+        }
+        SimulatedFlash {
+            flash: RefCell::new(Flash {
+                partitions,
+                size,
+                running: 0,
+                updates: HashMap::new(),
+                next_handle: 1,
+                sequence: 0,
+                failures: HashMap::new(),
+                restarts: 0,
+            }),
+        }
+    }
+
+    /// Makes the next call of `op` return `code`, without doing anything.
+    pub fn fail(&self, op: Op, code: esp_err_t) {
+        self.flash.borrow_mut().failures.insert(op, code);
+    }
+
+    /// Restarts, booting the app the OTA data selects, or the one before it if that was pending
+    /// verification when restarting. Updates that weren't finished are lost.
+    pub fn restart(&self) {
+        self.flash.borrow_mut().restart();
+    }
+
+    /// How often it restarted, including rolling back.
+    pub fn restarts(&self) -> usize {
+        self.flash.borrow().restarts
+    }
+
+    /// Finds a partition by its label.
+    pub fn partition(&self, label: &str) -> Option<usize> {
+        self.flash
+            .borrow()
+            .partitions
+            .iter()
+            .position(|partition| partition.label == label)
+    }
+
+    pub fn label(&self, partition: usize) -> String {
+        self.flash.borrow().partitions[partition].label.clone()
+    }
+
+    pub fn running(&self) -> String {
+        let flash = self.flash.borrow();
+        flash.partitions[flash.running].label.clone()
+    }
+
+    /// What was written to a partition since it was last erased.
+    pub fn data(&self, label: &str) -> Vec<u8> {
+        let partition = self.partition(label).expect("no such partition");
+        self.flash.borrow().partitions[partition].data.clone()
+    }
+
+    /// The state of a partition in the OTA data, [`ImageState::Undefined`] if it has none.
+    pub fn state(&self, label: &str) -> ImageState {
+        let partition = self.partition(label).expect("no such partition");
+        let state = self.flash.borrow().partitions[partition].state();
+        state.map_or(ImageState::Undefined, ImageState::from_esp)
+    }
+
+    /// Updates that were begun and neither ended nor aborted.
+    pub fn open_updates(&self) -> usize {
+        self.flash.borrow().updates.len()
+    }
+
+    /// Returns the injected failure of `op`, if there is one.
+    fn failure(&self, op: Op) -> Option<esp_err_t> {
+        self.flash.borrow_mut().failures.remove(&op)
+    }
+}
+
+impl OtaFfi for SimulatedFlash {
+    /// The index of the partition.
+    type Partition = usize;
+
+    fn ota_get_running_partition(&self) -> usize {
+        self.flash.borrow().running
+    }
+
+    fn ota_get_boot_partition(&self) -> Option<usize> {
+        let flash = self.flash.borrow();
+        flash.selected().or_else(|| flash.fallback())
+    }
+
+    fn ota_get_next_update_partition(&self) -> Option<usize> {
+        let flash = self.flash.borrow();
+        let ota: Vec<usize> = (0..flash.partitions.len())
+            .filter(|&i| flash.is_ota(i))
+            .collect();
+        // The one after the running OTA partition, or the first one after the factory app.
+        let next = match ota.iter().position(|&i| i == flash.running) {
+            Some(running) => ota[(running + 1) % ota.len()],
+            None => *ota.first()?,
+        };
+        (next != flash.running).then_some(next)
+    }
+
+    fn ota_begin(&self, partition: usize, handle: &mut esp_ota_handle_t) -> esp_err_t {
+        if let Some(code) = self.failure(Op::Begin) {
+            return code;
+        }
+        let mut flash = self.flash.borrow_mut();
+        if !flash.is_ota(partition) {
+            return ESP_ERR_INVALID_ARG;
+        }
+        if partition == flash.running {
+            return ESP_ERR_OTA_PARTITION_CONFLICT;
+        }
+        let running = flash.running;
+        if flash.partitions[running].state() == Some(PENDING_VERIFY) {
+            return ESP_ERR_OTA_ROLLBACK_INVALID_STATE;
+        }
+        // Erasing it leaves nothing to boot, so its OTA data is dropped too.
+        let erased = &mut flash.partitions[partition];
+        erased.data.clear();
+        erased.complete = false;
+        erased.otadata = None;
+        *handle = flash.next_handle;
+        flash.next_handle += 1;
+        flash.updates.insert(*handle, partition);
+        ESP_OK
+    }
+
+    fn ota_write(&self, handle: esp_ota_handle_t, data: &[u8]) -> esp_err_t {
+        if let Some(code) = self.failure(Op::Write) {
+            return code;
+        }
+        let mut flash = self.flash.borrow_mut();
+        let Some(&partition) = flash.updates.get(&handle) else {
+            return ESP_ERR_INVALID_ARG;
+        };
+        let size = flash.size;
+        let written = &mut flash.partitions[partition].data;
+        if written.is_empty() && data.first().is_some_and(|&b| b != IMAGE_MAGIC) {
+            return ESP_ERR_OTA_VALIDATE_FAILED;
+        }
+        if written.len() + data.len() > size {
+            return ESP_ERR_INVALID_SIZE;
+        }
+        written.extend_from_slice(data);
+        ESP_OK
+    }
+
+    fn ota_end(&self, handle: esp_ota_handle_t) -> esp_err_t {
+        let mut flash = self.flash.borrow_mut();
+        // The handle is freed whatever happens.
+        let Some(partition) = flash.updates.remove(&handle) else {
+            return ESP_ERR_NOT_FOUND;
+        };
+        drop(flash);
+        if let Some(code) = self.failure(Op::End) {
+            return code;
+        }
+        let mut flash = self.flash.borrow_mut();
+        let ended = &mut flash.partitions[partition];
+        if ended.data.is_empty() {
+            return ESP_ERR_INVALID_ARG;
+        }
+        ended.complete = true;
+        ESP_OK
+    }
+
+    fn ota_abort(&self, handle: esp_ota_handle_t) -> esp_err_t {
+        if let Some(code) = self.failure(Op::Abort) {
+            return code;
+        }
+        match self.flash.borrow_mut().updates.remove(&handle) {
+            Some(_) => ESP_OK,
+            None => ESP_ERR_NOT_FOUND,
+        }
+    }
+
+    fn ota_set_boot_partition(&self, partition: usize) -> esp_err_t {
+        if let Some(code) = self.failure(Op::SetBootPartition) {
+            return code;
+        }
+        let mut flash = self.flash.borrow_mut();
+        let Some(selected) = flash.partitions.get(partition) else {
+            return ESP_ERR_INVALID_ARG;
+        };
+        if !selected.complete {
+            return ESP_ERR_OTA_VALIDATE_FAILED;
+        }
+        if selected.factory {
+            // Erases the OTA data, so the factory app boots.
+            for partition in &mut flash.partitions {
+                partition.otadata = None;
+            }
+            return ESP_OK;
+        }
+        flash.sequence += 1;
+        flash.partitions[partition].otadata = Some((flash.sequence, NEW));
+        ESP_OK
+    }
+
+    fn ota_get_state_partition(
+        &self,
+        partition: usize,
+        state: &mut esp_ota_img_states_t,
+    ) -> esp_err_t {
+        if let Some(code) = self.failure(Op::GetStatePartition) {
+            return code;
+        }
+        let flash = self.flash.borrow();
+        if !flash.is_ota(partition) {
+            return ESP_ERR_NOT_SUPPORTED;
+        }
+        match flash.partitions[partition].state() {
+            Some(recorded) => {
+                *state = recorded;
+                ESP_OK
+            }
+            None => ESP_ERR_NOT_FOUND,
+        }
+    }
+
+    fn ota_mark_app_valid_cancel_rollback(&self) -> esp_err_t {
+        if let Some(code) = self.failure(Op::MarkAppValid) {
+            return code;
+        }
+        let mut flash = self.flash.borrow_mut();
+        let running = flash.running;
+        if flash.partitions[running].state() == Some(PENDING_VERIFY) {
+            flash.set_state(running, VALID);
+        }
+        ESP_OK
+    }
+
+    /// Rolling back restarts, which never returns on the device, so `ESP_OK` is returned after
+    /// the restart instead.
+    fn ota_mark_app_invalid_rollback_and_reboot(&self) -> esp_err_t {
+        if let Some(code) = self.failure(Op::RollbackAndReboot) {
+            return code;
+        }
+        let mut flash = self.flash.borrow_mut();
+        let running = flash.running;
+        if !flash.is_ota(running) {
+            return ESP_FAIL;
+        }
+        let previous =
+            (0..flash.partitions.len()).any(|i| i != running && flash.partitions[i].bootable());
+        if !previous {
+            return ESP_ERR_OTA_ROLLBACK_FAILED;
+        }
+        flash.set_state(running, INVALID);
+        flash.restart();
+        ESP_OK
+    }
+
+    fn err_to_name(&self, code: esp_err_t) -> &'static str {
+        err_name(code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        boot::{BootConfig, BootValidation, Check, Checks, Decision},
+        error::ErrorKind,
+        ffi::ESP_ERR_FLASH_OP_FAIL,
+        ota::{self, OtaUpdate},
+    };
+
+    /// A fake image, starting with the magic byte like real ones.
+    fn image(len: usize) -> Vec<u8> {
+        let mut image: Vec<u8> = (0..len).map(|i| i as u8).collect();
+        image[0] = IMAGE_MAGIC;
+        image
+    }
+
+    /// Writes `image` in chunks and sets it as the boot partition.
+    fn install(flash: &SimulatedFlash, image: &[u8]) -> ota::Result<usize> {
+        let mut update = OtaUpdate::begin(flash)?;
+        for chunk in image.chunks(100) {
+            update.write(chunk)?;
+        }
+        let mut completed = update.finalize()?;
+        completed.set_as_boot_partition()?;
+        Ok(completed.raw_partition())
+    }
+
+    #[test]
+    fn installs_and_boots_updates() {
+        let flash = SimulatedFlash::with_factory(2, 4096);
+        let first = image(1000);
+        let partition = install(&flash, &first).unwrap();
+        assert_eq!(flash.label(partition), "ota_0");
+        assert_eq!(flash.data("ota_0"), first);
+        assert_eq!(flash.state("ota_0"), ImageState::New);
+        assert_eq!(flash.ota_get_boot_partition(), Some(partition));
+        assert_eq!(flash.running(), "factory");
+        assert_eq!(ota::running_state(&flash).unwrap(), ImageState::Undefined);
+
+        flash.restart();
+        assert_eq!(flash.running(), "ota_0");
+        assert_eq!(
+            ota::running_state(&flash).unwrap(),
+            ImageState::PendingVerify
+        );
+        ota::mark_app_valid(&flash).unwrap();
+        assert_eq!(flash.state("ota_0"), ImageState::Valid);
+
+        // Updates alternate between the OTA partitions.
+        let partition = install(&flash, &image(500)).unwrap();
+        assert_eq!(flash.label(partition), "ota_1");
+        flash.restart();
+        assert_eq!(flash.running(), "ota_1");
+        assert_eq!(
+            flash.label(flash.ota_get_next_update_partition().unwrap()),
+            "ota_0"
+        );
+    }
+
+    #[test]
+    fn aborts_unconfirmed_apps_on_restart() {
+        let flash = SimulatedFlash::with_factory(2, 4096);
+        install(&flash, &image(1000)).unwrap();
+        flash.restart();
+        ota::mark_app_valid(&flash).unwrap();
+        install(&flash, &image(800)).unwrap();
+        flash.restart();
+        assert_eq!(flash.running(), "ota_1");
+
+        // Crashing before it was marked valid boots the previous app again.
+        flash.restart();
+        assert_eq!(flash.running(), "ota_0");
+        assert_eq!(flash.state("ota_1"), ImageState::Aborted);
+        assert_eq!(flash.state("ota_0"), ImageState::Valid);
+    }
+
+    #[test]
+    fn rolls_back_failed_apps() {
+        let flash = SimulatedFlash::with_factory(2, 4096);
+        install(&flash, &image(1000)).unwrap();
+        flash.restart();
+
+        // A new app can't be installed before this one was validated.
+        let e = OtaUpdate::begin(&flash).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidRollbackState);
+
+        assert_eq!(flash.ota_mark_app_invalid_rollback_and_reboot(), ESP_OK);
+        assert_eq!(flash.running(), "factory");
+        assert_eq!(flash.state("ota_0"), ImageState::Invalid);
+        assert_eq!(flash.restarts(), 2);
+
+        // The factory app has nothing to roll back to.
+        let e = ota::rollback_and_reboot(&flash).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::RollbackFailed);
+    }
+
+    #[test]
+    fn needs_an_app_to_roll_back_to() {
+        let flash = SimulatedFlash::without_factory(2, 4096);
+        assert_eq!(flash.running(), "ota_0");
+        let e = ota::rollback_and_reboot(&flash).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::RollbackFailedNoApps);
+
+        install(&flash, &image(1000)).unwrap();
+        flash.restart();
+        assert_eq!(flash.running(), "ota_1");
+        flash.ota_mark_app_invalid_rollback_and_reboot();
+        // The app flashed over serial runs again.
+        assert_eq!(flash.running(), "ota_0");
+    }
+
+    #[test]
+    fn validates_boot_with_the_checks() {
+        let flash = SimulatedFlash::with_factory(2, 4096);
+        install(&flash, &image(1000)).unwrap();
+        flash.restart();
+        let config = BootConfig {
+            checks: Checks::parse("wifi,server").unwrap(),
+            deadline_secs: 60,
+        };
+        assert_eq!(
+            ota::running_state(&flash).unwrap(),
+            ImageState::PendingVerify
+        );
+        let mut validation = BootValidation::new(config);
+        validation.passed(Check::Wifi);
+        assert_eq!(validation.decide(10), Decision::Pending);
+        validation.passed(Check::Server);
+        assert_eq!(validation.decide(20), Decision::MarkValid);
+        ota::mark_app_valid(&flash).unwrap();
+        flash.restart();
+        assert_eq!(flash.running(), "ota_0");
+        assert_eq!(flash.state("ota_0"), ImageState::Valid);
+    }
+
+    #[test]
+    fn keeps_the_running_app_when_updates_fail() {
+        let flash = SimulatedFlash::with_factory(2, 4096);
+
+        // Flash failing mid-upload aborts the update.
+        flash.fail(Op::Write, ESP_ERR_FLASH_OP_FAIL);
+        let e = install(&flash, &image(1000)).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::FlashFailed);
+        assert_eq!(flash.open_updates(), 0);
+
+        // Images not starting with the magic byte are refused.
+        let e = install(&flash, &[0; 100]).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidMagicByte);
+
+        // Images larger than the partition don't fit.
+        let e = install(&flash, &image(5000)).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Unexpected(ESP_ERR_INVALID_SIZE));
+        assert_eq!(e.code_name(), Some("ESP_ERR_INVALID_SIZE"));
+
+        // An image that doesn't validate can't be booted.
+        flash.fail(Op::End, ESP_ERR_OTA_VALIDATE_FAILED);
+        let e = install(&flash, &image(1000)).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidImage);
+        assert_eq!(
+            flash.ota_set_boot_partition(flash.partition("ota_0").unwrap()),
+            ESP_ERR_OTA_VALIDATE_FAILED
+        );
+
+        flash.fail(Op::SetBootPartition, ESP_ERR_FLASH_OP_FAIL);
+        let e = install(&flash, &image(1000)).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::FlashFailed);
+
+        flash.restart();
+        assert_eq!(flash.running(), "factory");
+        assert_eq!(flash.open_updates(), 0);
+    }
+
+    #[test]
+    fn loses_updates_interrupted_by_restarts() {
+        let flash = SimulatedFlash::with_factory(2, 4096);
+        let mut update = OtaUpdate::begin(&flash).unwrap();
+        update.write(&image(500)).unwrap();
+        flash.restart();
+        assert_eq!(flash.running(), "factory");
+        assert_eq!(flash.open_updates(), 0);
+        assert_eq!(flash.state("ota_0"), ImageState::Undefined);
+        // The handle died with the restart, which only fails aborting it.
+        drop(update);
+
+        // The half written partition is erased again by the next update.
+        install(&flash, &image(300)).unwrap();
+        assert_eq!(flash.data("ota_0"), image(300));
+    }
+
+    #[test]
+    fn reports_unexpected_codes() {
+        let flash = SimulatedFlash::with_factory(2, 4096);
+        flash.fail(Op::MarkAppValid, 0x1234);
+        let e = ota::mark_app_valid(&flash).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Unexpected(0x1234));
+        assert_eq!(
+            e.to_string(),
+            "Unexpected esp_ota_mark_app_valid_cancel_rollback return code 4660 (UNKNOWN ERROR)"
+        );
+        flash.fail(Op::GetStatePartition, ESP_ERR_INVALID_ARG);
+        assert_eq!(
+            ota::running_state(&flash).unwrap_err().kind(),
+            ErrorKind::Unexpected(ESP_ERR_INVALID_ARG)
+        );
+        // Only once.
+        assert_eq!(ota::running_state(&flash).unwrap(), ImageState::Undefined);
+    }
+}
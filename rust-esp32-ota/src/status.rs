@@ -0,0 +1,254 @@
+//! What the OTA server reports about updates and partitions, on `GET /ota/status` and
+//! `GET /partitions`.
+
+use std::sync::{Arc, Mutex, PoisonError};
+
+use serde::Serialize;
+
+use crate::{
+    boot::ImageState,
+    error::{Error, ErrorKind},
+    image::{c_string, AppDescriptor},
+};
+
+/// Where the last update got to.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum OtaStatus {
+    /// No update was started since boot.
+    #[default]
+    Idle,
+    /// The image is being received and written.
+    InProgress {
+        /// Bytes of the image passed to the OTA update so far.
+        written: usize,
+        /// Size of the image, if it was announced, like `PUT /ota/raw` does.
+        total: Option<usize>,
+    },
+    /// The image was written and its checksum, signature and headers are being verified.
+    Verifying,
+    /// The update was installed and the device is about to restart into it.
+    Rebooting,
+    Failed {
+        /// The kind of OTA error, unless something else failed, like the signature check.
+        error: Option<ErrorKind>,
+        message: String,
+    },
+}
+
+/// The status of updates, shared between the handlers of the OTA server.
+#[derive(Debug, Clone, Default)]
+pub struct StatusTracker(Arc<Mutex<OtaStatus>>);
+
+impl StatusTracker {
+    pub fn get(&self) -> OtaStatus {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    fn set(&self, status: OtaStatus) {
+        *self.0.lock().unwrap_or_else(PoisonError::into_inner) = status;
+    }
+
+    pub fn started(&self, total: Option<usize>) {
+        self.set(OtaStatus::InProgress { written: 0, total });
+    }
+
+    /// Accounts for the next `len` bytes of the image.
+    pub fn wrote(&self, len: usize) {
+        if let OtaStatus::InProgress { written, .. } =
+            &mut *self.0.lock().unwrap_or_else(PoisonError::into_inner)
+        {
+            *written += len;
+        }
+    }
+
+    pub fn verifying(&self) {
+        self.set(OtaStatus::Verifying);
+    }
+
+    pub fn rebooting(&self) {
+        self.set(OtaStatus::Rebooting);
+    }
+
+    pub fn failed(&self, e: &anyhow::Error) {
+        self.set(OtaStatus::Failed {
+            error: e.downcast_ref::<Error>().map(Error::kind),
+            message: e.to_string(),
+        });
+    }
+}
+
+/// The app in a partition, from its [`AppDescriptor`].
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct AppInfo {
+    pub project_name: String,
+    pub version: String,
+    pub secure_version: u32,
+    pub idf_version: String,
+    pub compile_date: String,
+    pub compile_time: String,
+    /// Hex encoded SHA-256 of the app's ELF file.
+    pub app_elf_sha256: String,
+}
+
+impl From<&AppDescriptor> for AppInfo {
+    fn from(app: &AppDescriptor) -> Self {
+        AppInfo {
+            project_name: app.project_name.clone(),
+            version: app.version.clone(),
+            secure_version: app.secure_version,
+            idf_version: app.idf_version.clone(),
+            compile_date: app.compile_date.clone(),
+            compile_time: app.compile_time.clone(),
+            app_elf_sha256: app
+                .app_elf_sha256
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect(),
+        }
+    }
+}
+
+/// An app partition and what it holds.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct PartitionInfo {
+    pub label: String,
+    /// `factory`, `ota_0` to `ota_15` or `test`.
+    pub subtype: String,
+    pub address: u32,
+    pub size: u32,
+    pub state: ImageState,
+    /// The app in the partition, unless it holds none.
+    pub app: Option<AppInfo>,
+}
+
+impl PartitionInfo {
+    /// Describes a partition from the fields of its `esp_partition_t`.
+    pub fn new(
+        label: &[u8],
+        subtype: u32,
+        address: u32,
+        size: u32,
+        state: ImageState,
+        app: Option<&AppDescriptor>,
+    ) -> Self {
+        PartitionInfo {
+            label: c_string(label),
+            subtype: app_subtype_name(subtype),
+            address,
+            size,
+            state,
+            app: app.map(AppInfo::from),
+        }
+    }
+}
+
+/// Names an app partition subtype, `esp_partition_subtype_t`, like partition tables do.
+pub fn app_subtype_name(subtype: u32) -> String {
+    match subtype {
+        0x00 => "factory".into(),
+        0x10..=0x1f => format!("ota_{}", subtype - 0x10),
+        0x20 => "test".into(),
+        _ => format!("{subtype:#04x}"),
+    }
+}
+
+/// The partitions involved in updates.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct Partitions {
+    /// The partition the app runs from.
+    pub running: PartitionInfo,
+    /// The partition the bootloader boots next, which differs from the running one after an
+    /// update was installed.
+    pub boot: Option<PartitionInfo>,
+    /// The partition the next update will be written to.
+    pub next_update: Option<PartitionInfo>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn tracks_updates() {
+        let status = StatusTracker::default();
+        assert_eq!(status.get(), OtaStatus::Idle);
+        // Only counted while in progress.
+        status.wrote(10);
+        assert_eq!(status.get(), OtaStatus::Idle);
+
+        status.started(Some(2048));
+        status.wrote(1024);
+        status.clone().wrote(512);
+        assert_eq!(
+            status.get(),
+            OtaStatus::InProgress {
+                written: 1536,
+                total: Some(2048)
+            }
+        );
+        status.verifying();
+        status.rebooting();
+        assert_eq!(status.get(), OtaStatus::Rebooting);
+    }
+
+    #[test]
+    fn reports_failures_with_their_kind() {
+        let status = StatusTracker::default();
+        status.failed(&Error::from_kind(ErrorKind::FlashFailed).into());
+        assert_eq!(
+            status.get(),
+            OtaStatus::Failed {
+                error: Some(ErrorKind::FlashFailed),
+                message: "Flash write operation failed".into(),
+            }
+        );
+        let json = serde_json::to_value(status.get()).unwrap();
+        assert_eq!(json["state"], "failed");
+        assert_eq!(json["error"], "flash_failed");
+
+        status.failed(&anyhow!("Signature doesn't match"));
+        let json = serde_json::to_value(status.get()).unwrap();
+        assert_eq!(json["error"], serde_json::Value::Null);
+        assert_eq!(json["message"], "Signature doesn't match");
+    }
+
+    #[test]
+    fn serializes_progress() {
+        let status = OtaStatus::InProgress {
+            written: 4096,
+            total: None,
+        };
+        assert_eq!(
+            serde_json::to_string(&status).unwrap(),
+            r#"{"state":"in_progress","written":4096,"total":null}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&OtaStatus::Idle).unwrap(),
+            r#"{"state":"idle"}"#
+        );
+    }
+
+    #[test]
+    fn describes_partitions() {
+        assert_eq!(app_subtype_name(0x00), "factory");
+        assert_eq!(app_subtype_name(0x11), "ota_1");
+        assert_eq!(app_subtype_name(0x20), "test");
+        assert_eq!(app_subtype_name(0x42), "0x42");
+
+        let mut label = [0_u8; 17];
+        label[..5].copy_from_slice(b"ota_0");
+        let partition =
+            PartitionInfo::new(&label, 0x10, 0x110000, 0x100000, ImageState::Valid, None);
+        let json = serde_json::to_value(&partition).unwrap();
+        assert_eq!(json["label"], "ota_0");
+        assert_eq!(json["subtype"], "ota_0");
+        assert_eq!(json["address"], 0x110000);
+        assert_eq!(json["state"], "valid");
+        assert_eq!(json["app"], serde_json::Value::Null);
+    }
+}
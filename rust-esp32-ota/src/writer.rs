@@ -0,0 +1,100 @@
+//! Writing a signed image to a new OTA update as it arrives, shared by uploads and pulled
+//! updates.
+
+use anyhow::Result;
+use ed25519_dalek::VerifyingKey;
+use log::info;
+
+use crate::{
+    image::{AppImage, HeaderBuffer, ImageError, ImagePolicy, HEADER_LEN},
+    ota::{EspOta, OtaUpdate},
+    signature::SignedImage,
+};
+
+/// How often the progress is logged.
+const PROGRESS_STEP: usize = 64 * 1024;
+
+/// Writes a signed image to a new OTA update, once its headers passed the policy.
+///
+/// The update only begins then, so that rejected images don't cost erasing the partition.
+pub struct ImageWriter {
+    signature: SignedImage,
+    flash: FlashWriter,
+}
+
+impl ImageWriter {
+    pub fn new(policy: ImagePolicy, public_key: VerifyingKey) -> Self {
+        Self {
+            signature: SignedImage::new(public_key),
+            flash: FlashWriter {
+                policy,
+                header: HeaderBuffer::default(),
+                ota: None,
+                written: 0,
+            },
+        }
+    }
+
+    /// Writes the next chunk of the signed image.
+    ///
+    /// Images that can't be parsed are returned as [`ImageError`], and images that the policy
+    /// rejects as [`PolicyViolation`](crate::image::PolicyViolation).
+    pub fn write(&mut self, chunk: &[u8]) -> Result<()> {
+        self.signature
+            .update(chunk, |image| self.flash.write(image))
+    }
+
+    /// Returns the update once all of the image was written and its signature verified.
+    ///
+    /// Images that aren't signed by the public key are returned as
+    /// [`SignatureError`](crate::signature::SignatureError).
+    pub fn finish(self) -> Result<OtaUpdate<EspOta>> {
+        let ota = self.flash.ota.ok_or(ImageError::TooShort)?;
+        self.signature.verify()?;
+        info!("Done writing {} bytes", self.flash.written);
+        Ok(ota)
+    }
+}
+
+/// Writes the image itself, without the signature trailer.
+struct FlashWriter {
+    policy: ImagePolicy,
+    header: HeaderBuffer,
+    ota: Option<OtaUpdate<EspOta>>,
+    written: usize,
+}
+
+impl FlashWriter {
+    fn write(&mut self, mut chunk: &[u8]) -> Result<()> {
+        if !self.header.is_full() {
+            let taken = self.header.fill(chunk);
+            chunk = &chunk[taken..];
+            if !self.header.is_full() {
+                return Ok(());
+            }
+            let image = AppImage::parse(self.header.as_bytes())?;
+            info!(
+                "Receiving {} {} for {} built with ESP-IDF {}",
+                image.app.project_name, image.app.version, image.header.chip, image.app.idf_version
+            );
+            self.policy.check(&image)?;
+            let mut ota = OtaUpdate::begin(EspOta)?;
+            ota.write(self.header.as_bytes())?;
+            self.ota = Some(ota);
+            self.written = HEADER_LEN;
+        }
+        if let (Some(ota), false) = (&mut self.ota, chunk.is_empty()) {
+            ota.write(chunk)?;
+            log_progress(self.written, chunk.len());
+            self.written += chunk.len();
+        }
+        Ok(())
+    }
+}
+
+/// Logs the progress every [`PROGRESS_STEP`] bytes.
+fn log_progress(written: usize, chunk_len: usize) {
+    if (written + chunk_len) / PROGRESS_STEP != written / PROGRESS_STEP {
+        info!("Wrote {} bytes", written + chunk_len);
+    }
+}
@@ -11,86 +11,36 @@ use esp_idf_hal::{
 use esp_idf_svc::{eventloop::*, log::EspLogger, netif::*, ping, sntp::*, wifi::*};
 use esp_idf_sys::MACSTR;
 use log::{debug, error, info, warn};
+use rust_esp32_ota::{
+    boot::{self, BootConfig, BootValidation, Check, Checks},
+    pull::{self, PollSchedule},
+    signature::parse_public_key,
+};
 use scd4x::scd4x::Scd4x;
-use std::{
-    env,
-    io::{Read, Write},
-    net::{Ipv4Addr, TcpStream},
-    thread,
-    time::*,
+use std::{env, net::Ipv4Addr, thread, time::*};
+use transport::Transport;
+use types::{
+    firmware,
+    health::{self, Heartbeat, ResetReason},
+    Climate, DeviceHealth, MetricRequestBody, Topic,
 };
-use types::{Climate, MetricRequestBody, Topic};
+
+mod transport;
 
 const SSID: &str = env!("WIFI_SSID");
 const PASS: &str = env!("WIFI_PASSWORD");
+#[cfg(not(feature = "mqtt"))]
 const TCP_SERVER: &str = env!("ESP_TCP_SERVER");
+#[cfg(feature = "mqtt")]
+const MQTT_BROKER_URL: &str = env!("ESP_MQTT_BROKER_URL");
+#[cfg(feature = "mqtt")]
+const MQTT_TOPIC_PREFIX: &str = match option_env!("ESP_MQTT_TOPIC_PREFIX") {
+    Some(prefix) => prefix,
+    None => types::mqtt::DEFAULT_PREFIX,
+};
 const LOOP_DELAY_MS: &str = env!("ESP_LOOP_DELAY_MS");
-
-type HttpResponse = (u16, Vec<String>, Vec<String>);
-
-fn post_metric(body: MetricRequestBody) -> Result<HttpResponse> {
-    info!("Posting metric to {TCP_SERVER}...", TCP_SERVER = TCP_SERVER);
-    let mut stream = TcpStream::connect(TCP_SERVER)?;
-    let body = serde_json::to_string(&body)?;
-    let request = format!(
-        "POST /metric HTTP/1.1\r\n\
-        Host: {server}\r\n\
-        User-Agent: esp32\r\n\
-        Accept: */*\r\n\
-        Content-Type: application/json\r\n\
-        Content-Length: {size}\r\n\
-        \r\n\
-        {body}\r\n",
-        server = TCP_SERVER,
-        size = body.len(),
-        body = body
-    );
-    stream.write_all(request.as_bytes())?;
-    let mut result = Vec::new();
-    let mut num_bytes_read;
-    let mut iterations = 0;
-    loop {
-        result.resize(1024, 0);
-        num_bytes_read = stream.read(&mut result)?;
-        std::thread::sleep(Duration::from_millis(100));
-        if num_bytes_read > 0 || iterations > 10 {
-            break;
-        } else {
-            iterations += 1;
-        }
-    }
-    drop(stream);
-    let result = std::str::from_utf8(&result)?;
-    if num_bytes_read == 0 {
-        return Err(anyhow!(
-            "No bytes read from {TCP_SERVER}",
-            TCP_SERVER = TCP_SERVER
-        ));
-    }
-    let mut lines = result.lines();
-    let status_line = lines.next().unwrap();
-    let status_line = status_line.split(' ').collect::<Vec<_>>();
-    let status_code = status_line[1].parse::<u16>()?;
-    let mut headers = Vec::new();
-    for line in lines.clone() {
-        if line.is_empty() {
-            break;
-        }
-        headers.push(line.to_string());
-    }
-    let mut body = Vec::new();
-    let mut start_body = false;
-    for line in lines {
-        if line.is_empty() {
-            start_body = true;
-            continue;
-        }
-        if start_body {
-            body.push(line.to_string());
-        }
-    }
-    Ok((status_code, headers, body))
-}
+/// Server to pull firmware updates from, e.g. `http://192.168.1.10:3000`. Unset turns them off.
+const FIRMWARE_SERVER: Option<&str> = option_env!("ESP_FIRMWARE_SERVER");
 
 fn unix_now() -> Duration {
     let start = SystemTime::now();
@@ -99,6 +49,73 @@ fn unix_now() -> Duration {
         .expect("Time went backwards")
 }
 
+/// Seconds between health reports, `ESP_HEALTH_INTERVAL_SECS` or every five minutes.
+fn health_interval_secs() -> u64 {
+    option_env!("ESP_HEALTH_INTERVAL_SECS")
+        .map(|secs| {
+            secs.parse()
+                .expect("ESP_HEALTH_INTERVAL_SECS must be a number")
+        })
+        .unwrap_or(health::DEFAULT_INTERVAL_SECS)
+}
+
+/// Seconds between asking `ESP_FIRMWARE_SERVER` for an update, `ESP_FIRMWARE_POLL_SECS` or every
+/// hour.
+fn firmware_poll_secs() -> u64 {
+    option_env!("ESP_FIRMWARE_POLL_SECS")
+        .map(|secs| {
+            secs.parse()
+                .expect("ESP_FIRMWARE_POLL_SECS must be a number")
+        })
+        .unwrap_or(firmware::DEFAULT_POLL_INTERVAL_SECS)
+}
+
+/// Checks a new app has to pass after an OTA update, `ESP_BOOT_CHECKS` or all of them, within
+/// `ESP_BOOT_DEADLINE_SECS` or two minutes.
+fn boot_config() -> BootConfig {
+    BootConfig {
+        checks: Checks::parse(option_env!("ESP_BOOT_CHECKS").unwrap_or("wifi,sensor,server"))
+            .expect("ESP_BOOT_CHECKS must list wifi, sensor or server"),
+        deadline_secs: option_env!("ESP_BOOT_DEADLINE_SECS")
+            .map(|secs| {
+                secs.parse()
+                    .expect("ESP_BOOT_DEADLINE_SECS must be a number")
+            })
+            .unwrap_or(boot::DEFAULT_DEADLINE_SECS),
+    }
+}
+
+fn metric(topic: Topic) -> MetricRequestBody {
+    let now = unix_now();
+    MetricRequestBody {
+        topic,
+        timestamp: now.as_secs() as i64,
+        timestamp_millis: now.subsec_millis() as u16,
+        device_id: MACSTR.to_vec(),
+    }
+}
+
+fn uptime_secs() -> u64 {
+    health::uptime_secs(unsafe { esp_idf_sys::esp_timer_get_time() })
+}
+
+fn device_health(heartbeat: &Heartbeat) -> DeviceHealth {
+    let mut ap_info = esp_idf_sys::wifi_ap_record_t::default();
+    let wifi_rssi_dbm = match unsafe { esp_idf_sys::esp_wifi_sta_get_ap_info(&mut ap_info) } {
+        esp_idf_sys::ESP_OK => Some(ap_info.rssi),
+        _ => None,
+    };
+    DeviceHealth {
+        uptime_secs: uptime_secs(),
+        free_heap_bytes: unsafe { esp_idf_sys::esp_get_free_heap_size() },
+        min_free_heap_bytes: unsafe { esp_idf_sys::esp_get_minimum_free_heap_size() },
+        wifi_rssi_dbm,
+        reset_reason: ResetReason::from_esp(unsafe { esp_idf_sys::esp_reset_reason() }),
+        firmware_version: env!("CARGO_PKG_VERSION").into(),
+        failed_sends: heartbeat.failed_sends(),
+    }
+}
+
 fn main() -> Result<()> {
     esp_idf_sys::link_patches();
     EspLogger::initialize_default();
@@ -106,16 +123,27 @@ fn main() -> Result<()> {
     let pins = peripherals.pins;
     let sysloop = EspSystemEventLoop::take()?;
 
+    // Restarting before the checks passed rolls back as well.
+    let mut boot_validation = BootValidation::after_ota_boot(boot_config())?;
+
     // Ntp Time Sync
     let sntp = EspSntp::new_default()?;
     let mut time_synced = false;
 
     // Connect to Wifi
     let mut wifi = wifi(peripherals.modem, sysloop)?;
+    if let Some(validation) = &mut boot_validation {
+        validation.passed(Check::Wifi);
+    }
 
     // Create OtaServer
     let _ota_server = OtaServer::new()?;
 
+    #[cfg(not(feature = "mqtt"))]
+    let mut transport = transport::HttpTransport::new(TCP_SERVER);
+    #[cfg(feature = "mqtt")]
+    let mut transport = transport::MqttTransport::new(MQTT_BROKER_URL, MQTT_TOPIC_PREFIX, MACSTR)?;
+
     let i2c_config = Config {
         baudrate: Hertz(115200),
         scl_pullup_enabled: false,
@@ -124,13 +152,33 @@ fn main() -> Result<()> {
     let sda_pin = pins.gpio21;
     let scl_pin = pins.gpio22;
     let i2c_driver = unsafe { I2cDriver::new(I2C0::new(), sda_pin, scl_pin, &i2c_config).unwrap() };
-    let mut scd4x_sensor = scd4x_sensor(i2c_driver)?;
+    let (mut scd4x_sensor, self_test_ok) = scd4x_sensor(i2c_driver)?;
+    if let Some(validation) = &mut boot_validation {
+        if self_test_ok {
+            validation.passed(Check::Sensor);
+        } else {
+            validation.failed(Check::Sensor);
+        }
+    }
 
     info!("Starting SCD4x low power periodic measurements...");
     scd4x_sensor
         .start_low_power_periodic_measurements()
         .map_err(|e| anyhow!("Failed to start low power periodic measurements: {:?}", e))?;
 
+    let mut heartbeat = Heartbeat::new(health_interval_secs());
+
+    // Pulled updates must be signed like uploaded ones.
+    let firmware_updates = match FIRMWARE_SERVER {
+        Some(server) => {
+            let public_key = option_env!("OTA_PUBLIC_KEY")
+                .expect("OTA_PUBLIC_KEY is required with ESP_FIRMWARE_SERVER");
+            Some((server, parse_public_key(public_key)?))
+        }
+        None => None,
+    };
+    let mut update_schedule = PollSchedule::new(firmware_poll_secs());
+
     info!("Starting loop with {}ms delay...", LOOP_DELAY_MS);
     loop {
         thread::sleep(Duration::from_millis(
@@ -138,12 +186,10 @@ fn main() -> Result<()> {
                 .parse()
                 .expect("ESP_LOOP_DELAY_MS must be a number"),
         ));
-        if !scd4x_sensor
-            .data_ready_status()
-            .map_err(|e| anyhow!("Failed to get data ready status: {:?}", e))?
-        {
-            debug!("No data ready yet, skipping loop iteration");
-            continue;
+        if let Some(validation) = &boot_validation {
+            if validation.apply()? {
+                boot_validation = None;
+            }
         }
         let time_sync_status = sntp.get_sync_status();
         if time_sync_status != SyncStatus::Completed && !time_synced {
@@ -158,6 +204,42 @@ fn main() -> Result<()> {
             continue;
         }
 
+        // The running app has to be marked valid before it can be updated again.
+        if let (Some((server, public_key)), None) = (firmware_updates, &boot_validation) {
+            if update_schedule.due(uptime_secs()) {
+                update_schedule.polled(uptime_secs());
+                // Only returns if there was no update or installing it failed.
+                if let Err(e) = pull::install_latest(server, MACSTR, public_key) {
+                    error!("Pulling a firmware update failed: {:?}", e);
+                }
+            }
+        }
+
+        if heartbeat.due(uptime_secs()) {
+            let health = device_health(&heartbeat);
+            debug!("Reporting health: {:?}", health);
+            match transport.send(metric(Topic::DeviceHealth(health))) {
+                Ok(()) => {
+                    heartbeat.sent(uptime_secs());
+                    if let Some(validation) = &mut boot_validation {
+                        validation.passed(Check::Server);
+                    }
+                }
+                Err(e) => {
+                    error!("Sending health failed: {:?}", e);
+                    heartbeat.send_failed();
+                }
+            }
+        }
+
+        if !scd4x_sensor
+            .data_ready_status()
+            .map_err(|e| anyhow!("Failed to get data ready status: {:?}", e))?
+        {
+            debug!("No data ready yet, skipping loop iteration");
+            continue;
+        }
+
         let mut climate = Climate::default();
         let data = scd4x_sensor
             .measurement()
@@ -165,23 +247,22 @@ fn main() -> Result<()> {
         climate.temperature_celsius = data.temperature;
         climate.co2_ppm = data.co2.into();
         climate.humidity = data.humidity;
-        let payload = MetricRequestBody {
-            topic: Topic::Climate(climate),
-            timestamp: unix_now().as_secs() as i64,
-            device_id: MACSTR.to_vec(),
-        };
-        match post_metric(payload) {
-            Ok((status_code, _headers, _body)) => {
-                info!("POST /metric returned status code {}", status_code);
+        match transport.send(metric(Topic::Climate(climate))) {
+            Ok(()) => {
+                if let Some(validation) = &mut boot_validation {
+                    validation.passed(Check::Server);
+                }
             }
             Err(e) => {
-                error!("POST /metric failed: {:?}", e);
+                error!("Sending metric failed: {:?}", e);
+                heartbeat.send_failed();
             }
         }
     }
 }
 
-fn scd4x_sensor(i2c: I2cDriver) -> Result<Scd4x<I2cDriver, FreeRtos>> {
+/// Initializes the sensor, returning it with whether it passed its self-test.
+fn scd4x_sensor(i2c: I2cDriver) -> Result<(Scd4x<I2cDriver, FreeRtos>, bool)> {
     info!("Initializing SCD4x...");
     let mut sensor = Scd4x::new(i2c, FreeRtos);
     sensor.wake_up();
@@ -197,12 +278,16 @@ fn scd4x_sensor(i2c: I2cDriver) -> Result<Scd4x<I2cDriver, FreeRtos>> {
         .map_err(|e| anyhow!("Failed to read serial number: {:?}", e))?;
     info!("Initialized SCD4x with serial: {:#04x}", serial);
 
-    sensor
+    let self_test_ok = sensor
         .self_test_is_ok()
         .map_err(|e| anyhow!("Failed to run self-test: {:?}", e))?;
-    info!("SCD4x self-test passed");
+    if self_test_ok {
+        info!("SCD4x self-test passed");
+    } else {
+        error!("SCD4x self-test failed");
+    }
 
-    Ok(sensor)
+    Ok((sensor, self_test_ok))
 }
 
 fn wifi(
@@ -0,0 +1,191 @@
+use anyhow::{anyhow, Result};
+use log::info;
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    time::Duration,
+};
+use types::MetricRequestBody;
+
+/// Sends metrics to the server. The implementation is selected at build time with the `mqtt`
+/// feature.
+pub trait Transport {
+    fn send(&mut self, body: MetricRequestBody) -> Result<()>;
+}
+
+type HttpResponse = (u16, Vec<String>, Vec<String>);
+
+/// Posts each metric to `POST /metric` on a new connection.
+pub struct HttpTransport {
+    server: &'static str,
+}
+
+impl HttpTransport {
+    pub fn new(server: &'static str) -> Self {
+        HttpTransport { server }
+    }
+}
+
+impl Transport for HttpTransport {
+    fn send(&mut self, body: MetricRequestBody) -> Result<()> {
+        let (status_code, _headers, _body) = post_metric(self.server, body)?;
+        info!("POST /metric returned status code {}", status_code);
+        // Rejected and throttled metrics weren't sent either.
+        if !(200..300).contains(&status_code) {
+            return Err(anyhow!("POST /metric returned status code {}", status_code));
+        }
+        Ok(())
+    }
+}
+
+fn post_metric(server: &str, body: MetricRequestBody) -> Result<HttpResponse> {
+    info!("Posting metric to {server}...", server = server);
+    let mut stream = TcpStream::connect(server)?;
+    let body = serde_json::to_string(&body)?;
+    let request = format!(
+        "POST /metric HTTP/1.1\r\n\
+        Host: {server}\r\n\
+        User-Agent: esp32\r\n\
+        Accept: */*\r\n\
+        Content-Type: application/json\r\n\
+        Content-Length: {size}\r\n\
+        \r\n\
+        {body}\r\n",
+        server = server,
+        size = body.len(),
+        body = body
+    );
+    stream.write_all(request.as_bytes())?;
+    let mut result = Vec::new();
+    let mut num_bytes_read;
+    let mut iterations = 0;
+    loop {
+        result.resize(1024, 0);
+        num_bytes_read = stream.read(&mut result)?;
+        std::thread::sleep(Duration::from_millis(100));
+        if num_bytes_read > 0 || iterations > 10 {
+            break;
+        } else {
+            iterations += 1;
+        }
+    }
+    drop(stream);
+    let result = std::str::from_utf8(&result)?;
+    if num_bytes_read == 0 {
+        return Err(anyhow!("No bytes read from {server}", server = server));
+    }
+    let mut lines = result.lines();
+    let status_line = lines.next().unwrap();
+    let status_line = status_line.split(' ').collect::<Vec<_>>();
+    let status_code = status_line[1].parse::<u16>()?;
+    let mut headers = Vec::new();
+    for line in lines.clone() {
+        if line.is_empty() {
+            break;
+        }
+        headers.push(line.to_string());
+    }
+    let mut body = Vec::new();
+    let mut start_body = false;
+    for line in lines {
+        if line.is_empty() {
+            start_body = true;
+            continue;
+        }
+        if start_body {
+            body.push(line.to_string());
+        }
+    }
+    Ok((status_code, headers, body))
+}
+
+#[cfg(feature = "mqtt")]
+pub use self::mqtt::MqttTransport;
+
+#[cfg(feature = "mqtt")]
+mod mqtt {
+    use super::Transport;
+    use anyhow::Result;
+    use embedded_svc::mqtt::client::{Event, QoS};
+    use esp_idf_svc::mqtt::client::{EspMqttClient, LwtConfiguration, MqttClientConfiguration};
+    use log::{info, warn};
+    use std::{
+        sync::{mpsc, Arc, Mutex},
+        thread,
+        time::Duration,
+    };
+    use types::{mqtt, MetricRequestBody};
+
+    /// Publishes each metric with QoS 1 over a persistent connection to an MQTT broker.
+    ///
+    /// The device's status topic is set to online on every connect and to offline by the
+    /// broker, through the last will, when the connection is lost.
+    pub struct MqttTransport {
+        client: Arc<Mutex<EspMqttClient>>,
+        prefix: &'static str,
+    }
+
+    impl MqttTransport {
+        pub fn new(url: &str, prefix: &'static str, device_id: &[u8]) -> Result<Self> {
+            let offline = mqtt::status_message(prefix, device_id, false);
+            let conf = MqttClientConfiguration {
+                keep_alive_interval: Some(Duration::from_secs(30)),
+                lwt: Some(LwtConfiguration {
+                    topic: &offline.topic,
+                    payload: &offline.payload,
+                    qos: QoS::AtLeastOnce,
+                    retain: offline.retain,
+                }),
+                ..Default::default()
+            };
+            info!("Connecting to MQTT broker {}...", url);
+            let (connected_tx, connected) = mpsc::channel();
+            let client = EspMqttClient::new(url, &conf, move |event| match event {
+                Ok(Event::Connected(_)) => {
+                    info!("MQTT connected");
+                    // The callback runs in the MQTT task, which publishing waits for.
+                    let _ = connected_tx.send(());
+                }
+                Ok(Event::Disconnected) => warn!("MQTT disconnected"),
+                Ok(_) => {}
+                Err(e) => warn!("MQTT error: {:?}", e),
+            })?;
+            let client = Arc::new(Mutex::new(client));
+
+            // Replaces the retained offline status the broker published after a lost connection.
+            let online = mqtt::status_message(prefix, device_id, true);
+            let status_client = client.clone();
+            thread::Builder::new()
+                .name("mqtt-status".into())
+                .spawn(move || {
+                    for () in connected {
+                        let enqueued = status_client.lock().unwrap().enqueue(
+                            &online.topic,
+                            QoS::AtLeastOnce,
+                            online.retain,
+                            &online.payload,
+                        );
+                        if let Err(e) = enqueued {
+                            warn!("Publishing the online status failed: {:?}", e);
+                        }
+                    }
+                })?;
+            Ok(MqttTransport { client, prefix })
+        }
+    }
+
+    impl Transport for MqttTransport {
+        fn send(&mut self, body: MetricRequestBody) -> Result<()> {
+            for message in mqtt::metric_messages(self.prefix, &body)? {
+                info!("Publishing metric to {}...", message.topic);
+                self.client.lock().unwrap().publish(
+                    &message.topic,
+                    QoS::AtLeastOnce,
+                    message.retain,
+                    &message.payload,
+                )?;
+            }
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,185 @@
+//! Pull-based firmware updates, shared by the firmware (poller) and the http-server (release host).
+//!
+//! Devices poll [`latest_uri`], which is answered with a [`FirmwareUpdate`] to install or
+//! `204 No Content`, download the image from [`FirmwareUpdate::url`] and POST an
+//! [`UpdateReport`] to [`REPORTS_PATH`] with how that went.
+
+use core::cmp::Ordering;
+
+use serde::{Deserialize, Serialize};
+
+use crate::mqtt::encode_device_id;
+
+/// Path devices poll for updates.
+pub const LATEST_PATH: &str = "/firmware/latest";
+/// Path devices POST [`UpdateReport`]s to.
+pub const REPORTS_PATH: &str = "/firmware/reports";
+
+/// Poll interval used when none is configured.
+pub const DEFAULT_POLL_INTERVAL_SECS: u64 = 3600;
+
+/// A release the device should install instead of the running firmware.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct FirmwareUpdate {
+    pub version: String,
+    /// Size of the image in bytes.
+    pub size: u64,
+    /// Lowercase hex encoded SHA-256 of the image.
+    pub sha256: String,
+    /// Path of the image on the server.
+    pub url: String,
+    /// Hex encoded signature by the release signing key that lets the device install this
+    /// release even if it is older than the running one, to roll back a broken release.
+    /// Devices refuse older releases without it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub downgrade: Option<String>,
+}
+
+/// How installing an update went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UpdateOutcome {
+    /// The image was downloaded, verified and set as the boot partition, and the device is
+    /// restarting into it.
+    Installed,
+    /// Downloading or verifying the image failed, and the device keeps running its firmware.
+    Failed,
+}
+
+impl UpdateOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UpdateOutcome::Installed => "installed",
+            UpdateOutcome::Failed => "failed",
+        }
+    }
+
+    /// The inverse of [`UpdateOutcome::as_str`].
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "installed" => Some(UpdateOutcome::Installed),
+            "failed" => Some(UpdateOutcome::Failed),
+            _ => None,
+        }
+    }
+}
+
+/// What a device reports after trying to install a [`FirmwareUpdate`].
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct UpdateReport {
+    pub device_id: Vec<u8>,
+    /// The version of the update.
+    pub version: String,
+    pub outcome: UpdateOutcome,
+    /// Why it failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+/// Builds the path and query a device polls, e.g.
+/// `/firmware/latest?device_id=a1b2c3&current=0.3.0`.
+pub fn latest_uri(device_id: &[u8], current: &str) -> String {
+    format!(
+        "{}?device_id={}&current={}",
+        LATEST_PATH,
+        encode_device_id(device_id),
+        encode_query_value(current)
+    )
+}
+
+/// Percent-encodes everything but unreserved characters, so that e.g. the `+` of
+/// `0.3.0+build.1` isn't read as a space.
+fn encode_query_value(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// Compares versions like `1.2.3`, `v1.2` or `0.3.0-4-gabcdef`, as `git describe` produces them,
+/// by their numeric components, so `1.10` is newer than `1.9`.
+///
+/// Anything after the numeric components is ignored, and `None` is returned if either version
+/// doesn't start with a number.
+pub fn compare_versions(a: &str, b: &str) -> Option<Ordering> {
+    fn components(version: &str) -> Option<impl Iterator<Item = u64> + '_> {
+        let version = version.strip_prefix('v').unwrap_or(version);
+        let numeric = version
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .map_or(version, |end| &version[..end]);
+        if !numeric.starts_with(|c: char| c.is_ascii_digit()) {
+            return None;
+        }
+        Some(
+            numeric
+                .split('.')
+                .map_while(|component| component.parse().ok()),
+        )
+    }
+
+    let mut a = components(a)?;
+    let mut b = components(b)?;
+    loop {
+        match (a.next(), b.next()) {
+            (None, None) => return Some(Ordering::Equal),
+            (a, b) => match a.unwrap_or(0).cmp(&b.unwrap_or(0)) {
+                Ordering::Equal => continue,
+                ordering => return Some(ordering),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_latest_uri() {
+        assert_eq!(
+            latest_uri(&[0xa1, 0xb2, 0xc3], "0.3.0"),
+            "/firmware/latest?device_id=a1b2c3&current=0.3.0"
+        );
+        assert_eq!(
+            latest_uri(&[1], "v0.3.0+build 1&x"),
+            "/firmware/latest?device_id=01&current=v0.3.0%2Bbuild%201%26x"
+        );
+    }
+
+    #[test]
+    fn outcomes_round_trip() {
+        for outcome in [UpdateOutcome::Installed, UpdateOutcome::Failed] {
+            assert_eq!(UpdateOutcome::from_name(outcome.as_str()), Some(outcome));
+            let json = serde_json::to_string(&outcome).unwrap();
+            assert_eq!(json, format!("\"{}\"", outcome.as_str()));
+        }
+        assert_eq!(UpdateOutcome::from_name("rebooting"), None);
+    }
+
+    #[test]
+    fn report_message_is_optional() {
+        let report: UpdateReport =
+            serde_json::from_str(r#"{"device_id":[1,2],"version":"0.3.0","outcome":"installed"}"#)
+                .unwrap();
+        assert_eq!(report.message, None);
+        assert_eq!(report.outcome, UpdateOutcome::Installed);
+    }
+
+    #[test]
+    fn compares_versions() {
+        assert_eq!(compare_versions("1.10.0", "1.9.3"), Some(Ordering::Greater));
+        assert_eq!(compare_versions("v0.2", "0.2.0"), Some(Ordering::Equal));
+        assert_eq!(
+            compare_versions("0.3.0-4-gabcdef-dirty", "0.3.1"),
+            Some(Ordering::Less)
+        );
+        assert_eq!(compare_versions("1", "1.0.1"), Some(Ordering::Less));
+        assert_eq!(compare_versions("dev", "1.0.0"), None);
+    }
+}
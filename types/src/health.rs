@@ -0,0 +1,234 @@
+//! Health reporting shared by the firmware (reporter) and the http-server (store).
+//!
+//! The firmware reads the raw values from ESP-IDF and sends a [`DeviceHealth`] every
+//! [`Heartbeat`] interval, so the conversions live here where they can be tested on the host.
+//!
+//! [`DeviceHealth`]: crate::DeviceHealth
+
+use serde::{Deserialize, Serialize};
+
+/// Reporting interval used when none is configured.
+pub const DEFAULT_INTERVAL_SECS: u64 = 300;
+
+/// Why a device booted last, mirroring ESP-IDF's `esp_reset_reason_t`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResetReason {
+    PowerOn,
+    /// Reset by the external pin.
+    External,
+    /// `esp_restart`, e.g. after an OTA update.
+    Software,
+    Panic,
+    InterruptWatchdog,
+    TaskWatchdog,
+    /// Any other watchdog.
+    Watchdog,
+    DeepSleep,
+    Brownout,
+    Sdio,
+    /// Also used for reasons added by later ESP-IDF or firmware versions.
+    #[default]
+    #[serde(other)]
+    Unknown,
+}
+
+impl ResetReason {
+    const ALL: [ResetReason; 11] = [
+        ResetReason::PowerOn,
+        ResetReason::External,
+        ResetReason::Software,
+        ResetReason::Panic,
+        ResetReason::InterruptWatchdog,
+        ResetReason::TaskWatchdog,
+        ResetReason::Watchdog,
+        ResetReason::DeepSleep,
+        ResetReason::Brownout,
+        ResetReason::Sdio,
+        ResetReason::Unknown,
+    ];
+
+    /// Converts the value returned by `esp_reset_reason()`.
+    pub fn from_esp(reason: u32) -> Self {
+        match reason {
+            1 => ResetReason::PowerOn,
+            2 => ResetReason::External,
+            3 => ResetReason::Software,
+            4 => ResetReason::Panic,
+            5 => ResetReason::InterruptWatchdog,
+            6 => ResetReason::TaskWatchdog,
+            7 => ResetReason::Watchdog,
+            8 => ResetReason::DeepSleep,
+            9 => ResetReason::Brownout,
+            10 => ResetReason::Sdio,
+            _ => ResetReason::Unknown,
+        }
+    }
+
+    /// The name it is serialized as.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ResetReason::PowerOn => "power_on",
+            ResetReason::External => "external",
+            ResetReason::Software => "software",
+            ResetReason::Panic => "panic",
+            ResetReason::InterruptWatchdog => "interrupt_watchdog",
+            ResetReason::TaskWatchdog => "task_watchdog",
+            ResetReason::Watchdog => "watchdog",
+            ResetReason::DeepSleep => "deep_sleep",
+            ResetReason::Brownout => "brownout",
+            ResetReason::Sdio => "sdio",
+            ResetReason::Unknown => "unknown",
+        }
+    }
+
+    /// Parses a name returned by [`ResetReason::as_str`], unknown names included.
+    pub fn from_name(name: &str) -> Self {
+        Self::ALL
+            .into_iter()
+            .find(|reason| reason.as_str() == name)
+            .unwrap_or_default()
+    }
+
+    /// Whether the device went down because something went wrong rather than on purpose.
+    pub fn is_crash(&self) -> bool {
+        matches!(
+            self,
+            ResetReason::Panic
+                | ResetReason::InterruptWatchdog
+                | ResetReason::TaskWatchdog
+                | ResetReason::Watchdog
+                | ResetReason::Brownout
+        )
+    }
+}
+
+/// Decides when a device reports its health and counts the metrics it failed to send.
+///
+/// Times are seconds since boot, so the schedule works before the clock is synchronized.
+#[derive(Debug)]
+pub struct Heartbeat {
+    interval_secs: u64,
+    last_sent_secs: Option<u64>,
+    failed_sends: u32,
+}
+
+impl Heartbeat {
+    pub fn new(interval_secs: u64) -> Self {
+        Heartbeat {
+            interval_secs,
+            last_sent_secs: None,
+            failed_sends: 0,
+        }
+    }
+
+    /// Whether a report is due, which the first one always is.
+    pub fn due(&self, uptime_secs: u64) -> bool {
+        match self.last_sent_secs {
+            Some(last) => uptime_secs.saturating_sub(last) >= self.interval_secs,
+            None => true,
+        }
+    }
+
+    /// Records that a report was sent, so the next one is due an interval later.
+    pub fn sent(&mut self, uptime_secs: u64) {
+        self.last_sent_secs = Some(uptime_secs);
+    }
+
+    /// Records a metric, or report, that couldn't be sent.
+    pub fn send_failed(&mut self) {
+        self.failed_sends = self.failed_sends.saturating_add(1);
+    }
+
+    pub fn failed_sends(&self) -> u32 {
+        self.failed_sends
+    }
+}
+
+/// Converts the microseconds returned by `esp_timer_get_time()` into whole seconds.
+pub fn uptime_secs(uptime_micros: i64) -> u64 {
+    u64::try_from(uptime_micros / 1_000_000).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DeviceHealth, MetricRequestBody, Topic};
+
+    #[test]
+    fn converts_esp_reset_reasons() {
+        assert_eq!(ResetReason::from_esp(0), ResetReason::Unknown);
+        assert_eq!(ResetReason::from_esp(1), ResetReason::PowerOn);
+        assert_eq!(ResetReason::from_esp(4), ResetReason::Panic);
+        assert_eq!(ResetReason::from_esp(9), ResetReason::Brownout);
+        assert_eq!(ResetReason::from_esp(42), ResetReason::Unknown);
+        assert!(ResetReason::from_esp(6).is_crash());
+        assert!(!ResetReason::from_esp(3).is_crash());
+    }
+
+    #[test]
+    fn reset_reason_names_match_serde() {
+        for reason in ResetReason::ALL {
+            let json = serde_json::to_string(&reason).unwrap();
+            assert_eq!(json, format!("\"{}\"", reason.as_str()));
+            assert_eq!(ResetReason::from_name(reason.as_str()), reason);
+        }
+        assert_eq!(ResetReason::from_name("cosmic_ray"), ResetReason::Unknown);
+        let reason: ResetReason = serde_json::from_str("\"cosmic_ray\"").unwrap();
+        assert_eq!(reason, ResetReason::Unknown);
+    }
+
+    #[test]
+    fn heartbeat_is_due_every_interval() {
+        let mut heartbeat = Heartbeat::new(60);
+        assert!(heartbeat.due(0));
+        heartbeat.sent(5);
+        assert!(!heartbeat.due(5));
+        assert!(!heartbeat.due(64));
+        assert!(heartbeat.due(65));
+        heartbeat.sent(70);
+        assert!(!heartbeat.due(100));
+
+        heartbeat.send_failed();
+        heartbeat.send_failed();
+        assert_eq!(heartbeat.failed_sends(), 2);
+    }
+
+    #[test]
+    fn converts_uptime() {
+        assert_eq!(uptime_secs(1_999_999), 1);
+        assert_eq!(uptime_secs(-1), 0);
+    }
+
+    #[test]
+    fn health_round_trips() {
+        let body = MetricRequestBody {
+            topic: Topic::DeviceHealth(DeviceHealth {
+                uptime_secs: 3600,
+                free_heap_bytes: 120_000,
+                min_free_heap_bytes: 90_000,
+                wifi_rssi_dbm: Some(-67),
+                reset_reason: ResetReason::TaskWatchdog,
+                firmware_version: "0.1.0".into(),
+                failed_sends: 2,
+            }),
+            timestamp: 1_684_000_000,
+            timestamp_millis: 0,
+            device_id: vec![0xab],
+        };
+        let json: serde_json::Value = serde_json::to_value(&body).unwrap();
+        assert_eq!(
+            json["topic"]["DeviceHealth"]["reset_reason"],
+            "task_watchdog"
+        );
+        assert_eq!(json["topic"]["DeviceHealth"]["wifi_rssi_dbm"], -67);
+
+        let decoded: MetricRequestBody = serde_json::from_value(json).unwrap();
+        let (Topic::DeviceHealth(decoded), Topic::DeviceHealth(health)) =
+            (&decoded.topic, &body.topic)
+        else {
+            panic!("not a health report");
+        };
+        assert_eq!(decoded, health);
+    }
+}
@@ -1,5 +1,11 @@
 use serde::{Deserialize, Serialize};
 
+use health::ResetReason;
+
+pub mod firmware;
+pub mod health;
+pub mod mqtt;
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct HttpResponseBody {
     /// String encoded to bytes
@@ -11,12 +17,16 @@ pub struct MetricRequestBody {
     pub topic: Topic,
     /// Unix timestamp in seconds this will overflow in the year 2106
     pub timestamp: i64,
+    /// Milliseconds past `timestamp`, below 1000. Left out by older firmware.
+    #[serde(default)]
+    pub timestamp_millis: u16,
     pub device_id: Vec<u8>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub enum Topic {
     Climate(Climate),
+    DeviceHealth(DeviceHealth),
 }
 
 #[derive(Debug, Default, Deserialize, Serialize)]
@@ -25,3 +35,20 @@ pub struct Climate {
     pub humidity: f32,
     pub co2_ppm: i32,
 }
+
+/// A snapshot of the state of a device, reported periodically besides its readings.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct DeviceHealth {
+    /// Seconds since the device booted.
+    pub uptime_secs: u64,
+    pub free_heap_bytes: u32,
+    /// Lowest free heap since the device booted.
+    pub min_free_heap_bytes: u32,
+    /// Signal strength of the access point, `None` while not connected.
+    pub wifi_rssi_dbm: Option<i8>,
+    /// Why the device booted last.
+    pub reset_reason: ResetReason,
+    pub firmware_version: String,
+    /// Metrics that couldn't be sent since the device booted.
+    pub failed_sends: u32,
+}
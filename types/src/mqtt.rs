@@ -0,0 +1,240 @@
+//! MQTT topic layout shared by the firmware (publisher) and the http-server (subscriber).
+//!
+//! Topics look like `<prefix>/<device_id>/<kind>`, e.g. `esp32/a1b2c3/climate`, where the
+//! device id is the lowercase hex encoding of [`MetricRequestBody::device_id`].
+
+use crate::{MetricRequestBody, Topic};
+
+/// Prefix used when none is configured.
+pub const DEFAULT_PREFIX: &str = "esp32";
+
+/// Payload of the retained [`status_topic`] message while the device is connected.
+pub const STATUS_ONLINE: &[u8] = b"online";
+/// Payload of the retained [`status_topic`] message once the device is gone, sent by the
+/// broker as the device's last will.
+pub const STATUS_OFFLINE: &[u8] = b"offline";
+
+/// The kind of message carried on a topic, i.e. its last path segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TopicKind {
+    Climate,
+    DeviceHealth,
+}
+
+impl TopicKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TopicKind::Climate => "climate",
+            TopicKind::DeviceHealth => "health",
+        }
+    }
+
+    /// Returns the kind of topic a metric payload should be published on.
+    pub fn of(topic: &Topic) -> Self {
+        match topic {
+            Topic::Climate(_) => TopicKind::Climate,
+            Topic::DeviceHealth(_) => TopicKind::DeviceHealth,
+        }
+    }
+
+    fn from_segment(segment: &str) -> Option<Self> {
+        match segment {
+            "climate" => Some(TopicKind::Climate),
+            "health" => Some(TopicKind::DeviceHealth),
+            _ => None,
+        }
+    }
+}
+
+/// A topic that was successfully parsed by [`parse_topic`].
+#[derive(Debug, PartialEq, Eq)]
+pub struct ParsedTopic {
+    pub device_id: Vec<u8>,
+    pub kind: TopicKind,
+}
+
+/// Builds the topic a device publishes `kind` messages on.
+pub fn topic(prefix: &str, device_id: &[u8], kind: TopicKind) -> String {
+    format!(
+        "{}/{}/{}",
+        prefix,
+        encode_device_id(device_id),
+        kind.as_str()
+    )
+}
+
+/// Builds the wildcard filter matching `kind` messages from every device.
+pub fn subscription(prefix: &str, kind: TopicKind) -> String {
+    format!("{}/+/{}", prefix, kind.as_str())
+}
+
+/// Builds the topic the most recent `kind` message of a device is retained on.
+///
+/// This is kept apart from [`topic`] so that subscribers ingesting every message don't receive
+/// the retained copy again each time they subscribe.
+pub fn latest_topic(prefix: &str, device_id: &[u8], kind: TopicKind) -> String {
+    format!("{}/latest", topic(prefix, device_id, kind))
+}
+
+/// Builds the topic a device announces whether it is online on.
+pub fn status_topic(prefix: &str, device_id: &[u8]) -> String {
+    format!("{}/{}/status", prefix, encode_device_id(device_id))
+}
+
+/// A message ready to be handed to an MQTT client.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Message {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub retain: bool,
+}
+
+/// Encodes a metric into the messages a device publishes for it: one on its [`topic`] to be
+/// ingested, and a retained copy on its [`latest_topic`].
+pub fn metric_messages(prefix: &str, body: &MetricRequestBody) -> serde_json::Result<[Message; 2]> {
+    let kind = TopicKind::of(&body.topic);
+    let payload = serde_json::to_vec(body)?;
+    Ok([
+        Message {
+            topic: topic(prefix, &body.device_id, kind),
+            payload: payload.clone(),
+            retain: false,
+        },
+        Message {
+            topic: latest_topic(prefix, &body.device_id, kind),
+            payload,
+            retain: true,
+        },
+    ])
+}
+
+/// Builds the retained message announcing whether a device is online.
+pub fn status_message(prefix: &str, device_id: &[u8], online: bool) -> Message {
+    Message {
+        topic: status_topic(prefix, device_id),
+        payload: if online {
+            STATUS_ONLINE
+        } else {
+            STATUS_OFFLINE
+        }
+        .to_vec(),
+        retain: true,
+    }
+}
+
+/// Parses a topic built by [`topic`], returning `None` if it doesn't follow the layout.
+pub fn parse_topic(prefix: &str, topic: &str) -> Option<ParsedTopic> {
+    let rest = topic.strip_prefix(prefix)?.strip_prefix('/')?;
+    let (device_id, kind) = rest.split_once('/')?;
+    Some(ParsedTopic {
+        device_id: decode_device_id(device_id)?,
+        kind: TopicKind::from_segment(kind)?,
+    })
+}
+
+/// Encodes a device id as lowercase hex so it is safe to use as a topic segment.
+pub fn encode_device_id(device_id: &[u8]) -> String {
+    device_id.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Decodes a device id encoded by [`encode_device_id`].
+pub fn decode_device_id(encoded: &str) -> Option<Vec<u8>> {
+    if encoded.is_empty() {
+        return None;
+    }
+    encoded
+        .as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [hi, lo] if hi.is_ascii_hexdigit() && lo.is_ascii_hexdigit() => {
+                u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn topic_round_trips() {
+        let device_id = b"\x01\xab\xff".to_vec();
+        let topic = topic(DEFAULT_PREFIX, &device_id, TopicKind::Climate);
+        assert_eq!(topic, "esp32/01abff/climate");
+        assert_eq!(
+            parse_topic(DEFAULT_PREFIX, &topic),
+            Some(ParsedTopic {
+                device_id,
+                kind: TopicKind::Climate
+            })
+        );
+    }
+
+    #[test]
+    fn health_has_its_own_topic() {
+        let topic = topic(DEFAULT_PREFIX, &[0xab], TopicKind::DeviceHealth);
+        assert_eq!(topic, "esp32/ab/health");
+        assert_eq!(
+            parse_topic(DEFAULT_PREFIX, &topic).map(|parsed| parsed.kind),
+            Some(TopicKind::DeviceHealth)
+        );
+    }
+
+    #[test]
+    fn subscription_uses_single_level_wildcard() {
+        assert_eq!(
+            subscription("lab/esp32", TopicKind::Climate),
+            "lab/esp32/+/climate"
+        );
+    }
+
+    #[test]
+    fn metric_is_published_live_and_retained() {
+        let body = MetricRequestBody {
+            topic: Topic::Climate(Default::default()),
+            timestamp: 1_684_000_000,
+            timestamp_millis: 0,
+            device_id: vec![0xab],
+        };
+        let [live, latest] = metric_messages(DEFAULT_PREFIX, &body).unwrap();
+        assert_eq!(live.topic, "esp32/ab/climate");
+        assert!(!live.retain);
+        assert_eq!(latest.topic, "esp32/ab/climate/latest");
+        assert!(latest.retain);
+        assert_eq!(live.payload, latest.payload);
+
+        let decoded: MetricRequestBody = serde_json::from_slice(&live.payload).unwrap();
+        assert_eq!(decoded.device_id, body.device_id);
+        assert_eq!(decoded.timestamp, body.timestamp);
+        // The retained copy must not be picked up by subscribers of the live topic.
+        assert_eq!(parse_topic(DEFAULT_PREFIX, &latest.topic), None);
+    }
+
+    #[test]
+    fn status_messages_are_retained() {
+        let online = status_message(DEFAULT_PREFIX, &[1], true);
+        let offline = status_message(DEFAULT_PREFIX, &[1], false);
+        assert_eq!(online.topic, "esp32/01/status");
+        assert_eq!(online.topic, offline.topic);
+        assert_eq!(online.payload, STATUS_ONLINE);
+        assert_eq!(offline.payload, STATUS_OFFLINE);
+        assert!(online.retain && offline.retain);
+    }
+
+    #[test]
+    fn rejects_malformed_topics() {
+        for topic in [
+            "esp32/01ab/humidity",
+            "esp32/01ab/climate/extra",
+            "esp32//climate",
+            "esp32/0/climate",
+            "esp32/zz/climate",
+            "other/01ab/climate",
+            "esp32x/01ab/climate",
+        ] {
+            assert_eq!(parse_topic(DEFAULT_PREFIX, topic), None, "{}", topic);
+        }
+    }
+}